@@ -0,0 +1,269 @@
+//! A whole backup run, as the `ssbt` command performs it: listing the files,
+//! the hooks, and writing the archive (or repository snapshot).
+
+use anyhow::{Context, anyhow};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::Config;
+use crate::cancel;
+use crate::error::SsbtError;
+use crate::fs_utils::{FileWalk, apply_size_limit, encode_size, list_total_files};
+use crate::k8s;
+use crate::process::{BackupOutcome, process_files_within_tokio, process_walk_within_tokio};
+use crate::repo;
+use crate::run_lock;
+use crate::shell_exec::execute_and_stream_command;
+use crate::vss::ShadowCopies;
+
+/// What a run found to back up, and how long its steps took. Filled in as
+/// the run goes, so also known (in part) for runs failing later.
+#[derive(Debug, Clone, Default)]
+pub struct Totals {
+    pub files: usize,
+    pub size: u64,
+    /// Files left out to stay within `max_size`.
+    pub left_out: Vec<PathBuf>,
+    /// The steps that ran, in order: `listing`, `before`, `snapshot` (with
+    /// `vss`), `archiving` (or `repository`), which includes the uploads,
+    /// and `after`.
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+impl Totals {
+    fn timed<T>(&mut self, phase: &'static str, step: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = step();
+        self.phases.push((phase, started.elapsed()));
+        result
+    }
+}
+
+/// Entry point for programs embedding ssbt.
+///
+/// ```no_run
+/// use ssbt_lib::{Backup, Config};
+///
+/// let config = Config {
+///     output: Some(vec!["/backups/site-%date%.tar.gz".to_string()]),
+///     paths: Some(vec!["/srv/site".to_string().into()]),
+///     format: Some("tar".to_string()),
+///     compress: Some(true),
+///     ..Config::default()
+/// };
+/// let outcome = Backup::run(config)?;
+/// println!("stored {:?}", outcome.archives);
+/// # anyhow::Ok(())
+/// ```
+pub struct Backup;
+
+impl Backup {
+    /// Backs up `config.paths` (or `files_from`) to the outputs, or the
+    /// repository, holding the lock of the outputs for the duration of the
+    /// run. The options are those of the configuration file; notifications,
+    /// the catalog and `--json` are left to the caller. Blocks, running the
+    /// upload on its own Tokio runtime, so call it from a blocking thread in
+    /// async programs. [`cancel::cancel`] stops it. With `dry`, no archive
+    /// is written; the outcome lists what it would hold and where it would go.
+    pub fn run(config: Config) -> Result<BackupOutcome, SsbtError> {
+        let _lock =
+            run_lock::acquire(&config).map_err(|e| SsbtError::classify(e, SsbtError::Config))?;
+        Self::run_with_totals(config, &mut Totals::default())
+    }
+
+    /// [`Backup::run`] for callers already holding the lock (see
+    /// [`run_lock::acquire`]), recording the totals as they become known.
+    pub fn run_with_totals(
+        config: Config,
+        totals: &mut Totals,
+    ) -> Result<BackupOutcome, SsbtError> {
+        if config.repo.is_none() && config.output.iter().flatten().all(|o| o.is_empty()) {
+            return Err(SsbtError::Config(anyhow!(
+                "an output or a repository is required"
+            )));
+        }
+        if config.repo.is_some()
+            && (config.sources.iter().flatten().next().is_some()
+                || config.virtual_files.iter().flatten().next().is_some()
+                || config.stdin_name.is_some()
+                || config.k8s.is_some())
+        {
+            return Err(SsbtError::Config(anyhow!(
+                "sources, virtual_files, stdin_name and k8s can't be backed up into a repository"
+            )));
+        }
+        if config.stream_walk.unwrap_or(false) {
+            let needs_list = [
+                ("repo", config.repo.is_some()),
+                ("files_from", config.files_from.is_some()),
+                ("since_manifest", config.since_manifest.is_some()),
+                ("vss", config.vss.unwrap_or(false)),
+                (
+                    "on_size_exceeded",
+                    config
+                        .on_size_exceeded
+                        .as_deref()
+                        .is_some_and(|policy| !policy.eq_ignore_ascii_case("fail")),
+                ),
+            ];
+            if let Some((option, _)) = needs_list.iter().find(|(_, set)| *set) {
+                return Err(SsbtError::Config(anyhow!(
+                    "stream_walk can't be combined with {option}, which needs the whole file list first"
+                )));
+            }
+        }
+        if config.vss.unwrap_or(false) && !cfg!(windows) {
+            return Err(SsbtError::Config(anyhow!(
+                "vss is only available on Windows"
+            )));
+        }
+        let on_success = config.on_success.clone().unwrap_or_default();
+        let on_failure = config.on_failure.clone().unwrap_or_default();
+        let result = run_hooked_backup(config, totals);
+        let (hooks, env) = match &result {
+            Ok(outcome) => (on_success, hook_env(totals, Some(outcome), None)),
+            Err(e) => (on_failure, hook_env(totals, None, Some(e))),
+        };
+        for hook in hooks.iter().filter(|h| !h.is_empty()) {
+            // Reporting hooks don't change the outcome of the backup
+            if let Err(e) = execute_and_stream_command(hook, &env) {
+                warn!("{e:#}");
+            }
+        }
+        result
+    }
+}
+
+/// Variables describing the run to hooks. Without `outcome` or `error`,
+/// the backup is still running.
+fn hook_env(
+    totals: &Totals,
+    outcome: Option<&BackupOutcome>,
+    error: Option<&SsbtError>,
+) -> Vec<(&'static str, String)> {
+    let status = match (outcome, error) {
+        (_, Some(_)) => "failure",
+        (Some(_), None) => "success",
+        (None, None) => "running",
+    };
+    let mut env = vec![
+        ("SSBT_STATUS", status.to_string()),
+        ("SSBT_FILE_COUNT", totals.files.to_string()),
+        ("SSBT_TOTAL_SIZE", totals.size.to_string()),
+    ];
+    if let Some(outcome) = outcome {
+        if let Some(first) = outcome.archives.first() {
+            env.push(("SSBT_ARCHIVE_PATH", first.clone()));
+        }
+        env.push(("SSBT_ARCHIVES", outcome.archives.join("\n")));
+        env.push(("SSBT_ARCHIVE_SIZE", outcome.archive_size.to_string()));
+    }
+    if let Some(error) = error {
+        env.push(("SSBT_ERROR", format!("{error:#}")));
+    }
+    env
+}
+
+/// Runs `before`, the backup itself and `after`, recording totals in `totals`
+/// as they become known.
+fn run_hooked_backup(mut config: Config, totals: &mut Totals) -> Result<BackupOutcome, SsbtError> {
+    let listing = Instant::now();
+    k8s::resolve_namespaces(&mut config).map_err(SsbtError::Walk)?;
+    // With `stream_walk`, the files are found while the archive is written
+    let walk = match config.stream_walk.unwrap_or(false) && !config.dry.unwrap_or(false) {
+        true => Some(FileWalk::from_config(&config)?),
+        false => None,
+    };
+    let mut files = Vec::new();
+    if walk.is_none() {
+        files = list_total_files(&config)?;
+        let listed = files.clone();
+        let total = apply_size_limit(&config, &mut files);
+        totals.phases.push(("listing", listing.elapsed()));
+        let total = total?;
+        if files.len() < listed.len() {
+            let kept: HashSet<&PathBuf> = files.iter().collect();
+            totals.left_out = listed
+                .into_iter()
+                .filter(|file| !kept.contains(file))
+                .collect();
+        }
+        totals.files = files.len();
+        totals.size = total;
+        info!("Total files: {}", files.len());
+        info!("Total size: {}", encode_size(total));
+    }
+    cancel::check()?;
+    if let Some(before) = config.before.as_deref().filter(|x| !x.is_empty()) {
+        let env = hook_env(totals, None, None);
+        let ran = totals.timed("before", || execute_and_stream_command(before, &env));
+        // A Ctrl-C reaches the command too
+        cancel::check()?;
+        ran.context("before command failed")
+            .map_err(SsbtError::Hook)?;
+    }
+    let after = config.after.clone().filter(|x| !x.is_empty());
+    let after_on_failure = config.after_on_failure.unwrap_or(false);
+    // Made after `before`, which may quiesce the programs writing the files,
+    // and deleted before `after`
+    let shadow_copies = match config.vss.unwrap_or(false) {
+        true => Some(
+            totals
+                .timed("snapshot", || ShadowCopies::create(&files))
+                .map_err(SsbtError::Walk)?,
+        ),
+        false => None,
+    };
+    let shadow_volumes = shadow_copies
+        .as_ref()
+        .map(|copies| copies.volumes().clone())
+        .unwrap_or_default();
+    let result = if config.repo.is_some() {
+        let size = totals.size;
+        totals.timed("repository", || {
+            repo::run_backup(&config, files, shadow_volumes)
+                .map(|()| BackupOutcome {
+                    bytes_read: size,
+                    ..BackupOutcome::default()
+                })
+                .map_err(|e| SsbtError::classify(e, SsbtError::Packaging))
+        })
+    } else if let Some(walk) = walk {
+        let result = totals.timed("archiving", || {
+            process_walk_within_tokio(config, walk.clone(), shadow_volumes)
+        });
+        totals.files = walk.files();
+        totals.size = walk.size();
+        if result.is_ok() {
+            info!("Total files: {}", totals.files);
+            info!("Total size: {}", encode_size(totals.size));
+        }
+        result
+    } else {
+        totals.timed("archiving", || {
+            process_files_within_tokio(config, files, shadow_volumes)
+        })
+    };
+    drop(shadow_copies);
+    match (result, after) {
+        (Ok(outcome), Some(after)) => {
+            let env = hook_env(totals, Some(&outcome), None);
+            totals
+                .timed("after", || execute_and_stream_command(&after, &env))
+                .context("after command failed")
+                .map_err(SsbtError::Hook)?;
+            Ok(outcome)
+        }
+        (Err(e), Some(after)) if after_on_failure => {
+            // The backup error is what gets reported
+            let env = hook_env(totals, None, Some(&e));
+            if let Err(hook) = totals.timed("after", || execute_and_stream_command(&after, &env)) {
+                warn!("after command failed: {hook:#}");
+            }
+            Err(e)
+        }
+        (result, _) => result,
+    }
+}
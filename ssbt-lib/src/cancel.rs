@@ -0,0 +1,73 @@
+//! Ctrl-C (SIGINT) and SIGTERM handling. The first signal cancels the run:
+//! the archive stream is aborted through the same path as a failed archive,
+//! so partial local files are deleted and uploads are cancelled instead of
+//! completed, and the failure hooks run. A second signal exits at once.
+//! Programs embedding the library can call [`cancel`] instead.
+
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::Notify;
+use tracing::warn;
+
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+static WAKE: Notify = Notify::const_new();
+
+/// The run was cancelled by a signal.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Exit code of interrupted runs, as shells report processes ended by SIGINT.
+pub const EXIT_CODE: u8 = 130;
+
+/// Installs the signal handler.
+pub fn install() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if is_cancelled() {
+            std::process::exit(EXIT_CODE.into());
+        }
+        warn!("Interrupted, cleaning up (press Ctrl-C again to quit at once)");
+        cancel();
+    })?;
+    Ok(())
+}
+
+/// Cancels the running backups of this process, as the first signal does.
+/// They fail with [`Interrupted`].
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+    WAKE.notify_waiters();
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Fails with [`Interrupted`] once the run was cancelled.
+pub fn check() -> Result<(), Interrupted> {
+    match is_cancelled() {
+        true => Err(Interrupted),
+        false => Ok(()),
+    }
+}
+
+/// Completes when the run gets cancelled.
+pub async fn cancelled() {
+    loop {
+        let woken = WAKE.notified();
+        tokio::pin!(woken);
+        // Registered before checking, so a signal in between isn't missed
+        woken.as_mut().enable();
+        if is_cancelled() {
+            return;
+        }
+        woken.await;
+    }
+}
@@ -0,0 +1,349 @@
+//! `docker://` paths: files of Docker volumes (`docker://volume`) and
+//! containers (`docker://container:/path`), read through the Docker Engine
+//! API. The tar stream Docker sends is written into the archive entry by
+//! entry as it arrives (see [`crate::packaging::streamed`]), named
+//! `<volume>/...` and `<container>/<path>/...`, so nothing is staged on
+//! disk. They are read again for each archive written.
+//!
+//! Volumes are read through a helper container that mounts them read-only,
+//! created (never started) from `docker.helper_image` and removed again.
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde_json::json;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::{Component, Path};
+use std::time::Duration;
+use tokio::io::DuplexStream;
+use tokio::sync::mpsc;
+use tokio_util::io::SyncIoBridge;
+use tracing::{info, warn};
+
+use crate::packaging::Packager;
+use crate::{Config, DockerConfig, SourcePath};
+
+pub const SCHEME: &str = "docker://";
+
+const DEFAULT_SOCKET: &str = "unix:///var/run/docker.sock";
+const DEFAULT_HELPER_IMAGE: &str = "busybox:latest";
+/// Where the helper container mounts the volume.
+const VOLUME_MOUNT: &str = "/volume";
+
+/// A `docker://` path.
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    Volume(String),
+    Container { name: String, path: String },
+}
+
+impl Source {
+    fn parse(source: &str) -> Result<Self> {
+        let rest = source
+            .strip_prefix(SCHEME)
+            .ok_or_else(|| anyhow!("{source} isn't a docker:// path"))?;
+        match rest.split_once(':') {
+            Some((name, path)) if !name.is_empty() && path.starts_with('/') => {
+                Ok(Source::Container {
+                    name: name.to_string(),
+                    path: path.to_string(),
+                })
+            }
+            None if !rest.is_empty() && !rest.contains('/') => Ok(Source::Volume(rest.to_string())),
+            _ => bail!(
+                "invalid path {source} (expected docker://volume or docker://container:/path)"
+            ),
+        }
+    }
+
+    fn label(&self) -> String {
+        match self {
+            Source::Volume(name) => name.clone(),
+            Source::Container { name, path } => {
+                let path = path.trim_matches('/');
+                match path.is_empty() {
+                    true => name.clone(),
+                    false => format!("{name}/{path}"),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Volume(name) => write!(f, "{SCHEME}{name}"),
+            Source::Container { name, path } => write!(f, "{SCHEME}{name}:{path}"),
+        }
+    }
+}
+
+/// Whether `path` is read from Docker.
+pub fn is_docker_path(path: &str) -> bool {
+    path.starts_with(SCHEME)
+}
+
+/// The `docker://` paths of a run, streamed into the archive after the
+/// files.
+#[derive(Debug, Clone, Default)]
+pub struct DockerSources {
+    sources: Vec<Source>,
+    config: DockerConfig,
+}
+
+/// A file of a `docker://` path: its entry name and contents.
+type StreamedFile = (String, DuplexStream);
+
+impl DockerSources {
+    /// The `docker://` paths of `config.paths`. Nothing is read from Docker
+    /// until the archive is written.
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let sources = config
+            .paths
+            .iter()
+            .flatten()
+            .map(SourcePath::path)
+            .filter(|p| is_docker_path(p))
+            .map(Source::parse)
+            .collect::<Result<_>>()?;
+        Ok(DockerSources {
+            sources,
+            config: config.docker.clone().unwrap_or_default(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Streams the files of every path into `packager`. Directories, links
+    /// and special files carry no data and are left out.
+    pub async fn write_into(&self, packager: &mut dyn Packager) -> Result<()> {
+        for source in &self.sources {
+            info!("Streaming {source} into the archive");
+            let (sender, mut files) = mpsc::channel::<StreamedFile>(1);
+            let reading = {
+                let (source, config) = (source.clone(), self.config.clone());
+                tokio::task::spawn_blocking(move || {
+                    DockerClient::from_config(&config)?.fetch(&source, &sender)
+                })
+            };
+            while let Some((name, mut contents)) = files.recv().await {
+                packager.add_stream(&name, &mut contents).await?;
+            }
+            // A failed read ends the file being written early, so it fails
+            // the archive
+            reading
+                .await?
+                .with_context(|| format!("reading {source}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Talks to the Docker Engine API.
+struct DockerClient {
+    client: reqwest::blocking::Client,
+    base: String,
+    helper_image: String,
+}
+
+impl DockerClient {
+    /// Connects to `docker.host`, or `DOCKER_HOST` like the docker CLI.
+    fn from_config(docker: &DockerConfig) -> Result<Self> {
+        let docker = docker.clone();
+        let host = docker
+            .host
+            .or_else(|| std::env::var("DOCKER_HOST").ok())
+            .unwrap_or_else(|| DEFAULT_SOCKET.to_string());
+        let builder = reqwest::blocking::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            // Archives of large volumes take their time
+            .timeout(None);
+        let (builder, base) = if let Some(socket) = host.strip_prefix("unix://") {
+            #[cfg(unix)]
+            {
+                (builder.unix_socket(socket), "http://docker".to_string())
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = socket;
+                bail!("unix:// Docker hosts aren't supported on this platform");
+            }
+        } else if let Some(address) = host.strip_prefix("tcp://") {
+            (builder, format!("http://{}", address.trim_end_matches('/')))
+        } else if host.starts_with("http://") || host.starts_with("https://") {
+            (builder, host.trim_end_matches('/').to_string())
+        } else {
+            bail!("unsupported Docker host {host} (expected unix:// or tcp://)");
+        };
+        Ok(DockerClient {
+            client: builder.build()?,
+            base,
+            helper_image: docker
+                .helper_image
+                .unwrap_or_else(|| DEFAULT_HELPER_IMAGE.to_string()),
+        })
+    }
+
+    /// Sends the files of `source` to `files` while they are read.
+    fn fetch(&self, source: &Source, files: &mpsc::Sender<StreamedFile>) -> Result<()> {
+        let label = source.label();
+        match source {
+            Source::Container { name, path } => self.stream(name, path, &label, files),
+            Source::Volume(volume) => {
+                self.check(self.client.get(self.url(&format!("/volumes/{volume}"))))
+                    .with_context(|| format!("looking up volume {volume}"))?;
+                let helper = self.create_helper(volume)?;
+                let streamed = self.stream(&helper, VOLUME_MOUNT, &label, files);
+                let removed = self.check(
+                    self.client
+                        .delete(self.url(&format!("/containers/{helper}")))
+                        .query(&[("force", "true")]),
+                );
+                if let Err(e) = removed {
+                    warn!("removing helper container {helper} failed: {e:#}");
+                }
+                streamed
+            }
+        }
+    }
+
+    /// Creates a container mounting `volume`, pulling the helper image if
+    /// needed, and returns its id.
+    fn create_helper(&self, volume: &str) -> Result<String> {
+        let body = json!({
+            "Image": self.helper_image,
+            "Labels": { "ssbt.helper": "true" },
+            "HostConfig": { "Binds": [format!("{volume}:{VOLUME_MOUNT}:ro")] },
+        });
+        let body = body.to_string();
+        let create = || {
+            self.client
+                .post(self.url("/containers/create"))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone())
+        };
+        let mut response = create().send()?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            info!("Pulling {}", self.helper_image);
+            let (image, tag) = split_image(&self.helper_image);
+            // The progress is streamed, reading it waits for the pull
+            let pulled = self
+                .check(
+                    self.client
+                        .post(self.url("/images/create"))
+                        .query(&[("fromImage", image), ("tag", tag)]),
+                )
+                .with_context(|| format!("pulling {}", self.helper_image))?;
+            pulled.text()?;
+            response = create().send()?;
+        }
+        let response = error_for_status(response)
+            .with_context(|| format!("creating a helper container for volume {volume}"))?;
+        let created: serde_json::Value = serde_json::from_reader(response)?;
+        created["Id"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("Docker returned no container id"))
+    }
+
+    /// Reads `path` of `container` as a tar archive, sending each file
+    /// named `label/...` to `files`.
+    fn stream(
+        &self,
+        container: &str,
+        path: &str,
+        label: &str,
+        files: &mpsc::Sender<StreamedFile>,
+    ) -> Result<()> {
+        let response = self.check(
+            self.client
+                .get(self.url(&format!("/containers/{container}/archive")))
+                .query(&[("path", path)]),
+        )?;
+        let mut archive = tar::Archive::new(response);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let kind = entry.header().entry_type();
+            let name = entry.path()?.into_owned();
+            if kind.is_dir() {
+                continue;
+            }
+            let Some(name) = entry_name(label, &name) else {
+                warn!("skipping {}, its name leaves {path}", name.display());
+                continue;
+            };
+            if !kind.is_file() {
+                warn!("skipping {name}, only the files of docker:// paths are backed up");
+                continue;
+            }
+            let (contents, writer) = tokio::io::duplex(64 * 1024);
+            if files.blocking_send((name, contents)).is_err() {
+                // The archive failed
+                return Ok(());
+            }
+            let mut writer = SyncIoBridge::new(writer);
+            copy(&mut entry, &mut writer)?;
+        }
+        Ok(())
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base)
+    }
+
+    fn check(
+        &self,
+        request: reqwest::blocking::RequestBuilder,
+    ) -> Result<reqwest::blocking::Response> {
+        error_for_status(request.send()?)
+    }
+}
+
+/// Fails with the message the Docker daemon gave for an error status.
+fn error_for_status(response: reqwest::blocking::Response) -> Result<reqwest::blocking::Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status();
+    let body: serde_json::Value = serde_json::from_reader(response).unwrap_or_default();
+    match body["message"].as_str() {
+        Some(message) => bail!("{message}"),
+        None => bail!("Docker returned status {status}"),
+    }
+}
+
+/// The entry name of the tar entry `name`, whose first component is the
+/// path read: `label` followed by the rest. `None` for names with `..`.
+fn entry_name(label: &str, name: &Path) -> Option<String> {
+    let mut entry = label.to_string();
+    for component in name.components().skip(1) {
+        match component {
+            Component::Normal(part) => {
+                entry.push('/');
+                entry.push_str(&part.to_string_lossy());
+            }
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(entry)
+}
+
+/// Copies the contents of a file into the archive. If the archive failed
+/// and stopped reading, the copy ends quietly; the archive reports why.
+fn copy(contents: &mut impl Read, writer: &mut impl io::Write) -> Result<()> {
+    match io::copy(contents, writer) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        copied => Ok(copied.map(drop)?),
+    }
+}
+
+/// `image:tag`, with the tag defaulting to `latest`.
+fn split_image(image: &str) -> (&str, &str) {
+    match image.rsplit_once(':') {
+        Some((name, tag)) if !tag.contains('/') => (name, tag),
+        _ => (image, "latest"),
+    }
+}
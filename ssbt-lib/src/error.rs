@@ -0,0 +1,68 @@
+//! What went wrong in a backup run, so callers can tell a bad configuration
+//! from unreadable files or an unreachable destination without parsing
+//! messages.
+
+use crate::cancel::Interrupted;
+use crate::fs_utils::SizeLimitExceeded;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SsbtError {
+    /// The options are invalid or contradict each other.
+    #[error("{0:#}")]
+    Config(anyhow::Error),
+    /// The files to back up couldn't be listed or read.
+    #[error("{0:#}")]
+    Walk(anyhow::Error),
+    /// The archive (or repository snapshot) couldn't be written.
+    #[error("{0:#}")]
+    Packaging(anyhow::Error),
+    /// Outputs failed to store the archive. `status` is the HTTP status an
+    /// HTTP, WebDAV or GCS destination answered with, if that's what failed.
+    #[error("{error:#}")]
+    Upload {
+        status: Option<u16>,
+        error: anyhow::Error,
+    },
+    #[error(transparent)]
+    SizeLimitExceeded(#[from] SizeLimitExceeded),
+    /// The run was cancelled (see [`crate::cancel`]).
+    #[error("interrupted")]
+    Interrupted,
+    /// A `before` or `after` command failed.
+    #[error("{0:#}")]
+    Hook(anyhow::Error),
+}
+
+impl From<Interrupted> for SsbtError {
+    fn from(_: Interrupted) -> Self {
+        SsbtError::Interrupted
+    }
+}
+
+impl SsbtError {
+    /// `error` as `kind`, unless it already tells what failed: an
+    /// [`SsbtError`], [`SizeLimitExceeded`] or [`Interrupted`].
+    pub fn classify(error: anyhow::Error, kind: fn(anyhow::Error) -> SsbtError) -> SsbtError {
+        let error = match error.downcast::<SsbtError>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        let error = match error.downcast::<SizeLimitExceeded>() {
+            Ok(exceeded) => return exceeded.into(),
+            Err(error) => error,
+        };
+        match error.is::<Interrupted>() {
+            true => SsbtError::Interrupted,
+            false => kind(error),
+        }
+    }
+}
+
+/// A server answered a request with an error status.
+#[derive(Debug, thiserror::Error)]
+#[error("{what} failed with status: {status}")]
+pub struct HttpStatusError {
+    /// The request, e.g. `Upload`.
+    pub what: String,
+    pub status: reqwest::StatusCode,
+}
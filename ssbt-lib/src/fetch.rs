@@ -0,0 +1,302 @@
+use anyhow::{Context, Result, bail};
+use futures::StreamExt;
+use reqwest::{StatusCode, header};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+use tempfile::TempPath;
+use tokio::io::AsyncWriteExt;
+use tokio::runtime::Handle;
+use tracing::info;
+
+use crate::Config;
+use crate::packaging::encrypt;
+use crate::sink::gcs::{self, GcsUpload};
+use crate::sink::send_net::{HttpUpload, http_client};
+
+/// An archive available on the local filesystem, either the original file
+/// or a temporary download that is removed when this value is dropped.
+pub struct FetchedArchive {
+    path: PathBuf,
+    _temp: Option<TempPath>,
+}
+
+impl FetchedArchive {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Makes the archive at `location` readable locally: a path or `file://` URL
+/// is used in place, http(s) and `gs://` archives are downloaded using the
+/// authentication and headers from `config`.
+pub async fn fetch_archive(location: &str, config: &Config) -> Result<FetchedArchive> {
+    let client = http_client(config)?;
+    let response = if location.starts_with("http://") || location.starts_with("https://") {
+        let http = HttpUpload::from_config(location, config)?;
+        let response = http
+            .authorize(client.get(location))
+            .send()
+            .await
+            .with_context(|| format!("downloading {location}"))?;
+        if !response.status().is_success() {
+            bail!("Download failed with status: {}", response.status());
+        }
+        response
+    } else if location.starts_with("gs://") {
+        gcs::download(&client, &GcsUpload::from_config(location, config)?)
+            .await
+            .with_context(|| format!("downloading {location}"))?
+    } else if location.contains("://") && !location.starts_with("file://") {
+        bail!(
+            "can't read archives from {location} (expected a path, or a file://, http(s):// or gs:// URL)"
+        );
+    } else {
+        let path = PathBuf::from(location.strip_prefix("file://").unwrap_or(location));
+        if !path.is_file() {
+            if let Some(temp) = join_volumes(&path)? {
+                return Ok(FetchedArchive {
+                    path: temp.to_path_buf(),
+                    _temp: Some(temp),
+                });
+            }
+            bail!("archive {} does not exist", path.display());
+        }
+        return Ok(FetchedArchive { path, _temp: None });
+    };
+
+    let temp = tempfile::NamedTempFile::new()?.into_temp_path();
+    let mut file = tokio::fs::File::create(&temp).await?;
+    let mut body = response.bytes_stream();
+    while let Some(chunk) = body.next().await {
+        file.write_all(&chunk?).await?;
+    }
+    file.flush().await?;
+
+    Ok(FetchedArchive {
+        path: temp.to_path_buf(),
+        _temp: Some(temp),
+    })
+}
+
+/// The first request of a [`RangeReader`], and the smallest after a seek.
+const MIN_CHUNK: u64 = 64 * 1024;
+/// Sequential reads request twice as much each time, up to this.
+const MAX_CHUNK: u64 = 8 * 1024 * 1024;
+
+/// Reads a remote archive with HTTP Range requests, so a zip archive can be
+/// read like a local file while only the parts actually read are downloaded.
+///
+/// The requests are made on the runtime the reader was opened on, so it is
+/// meant to be read from a blocking task of that runtime.
+pub struct RangeReader {
+    url: String,
+    http: HttpUpload,
+    client: reqwest::Client,
+    runtime: Handle,
+    len: u64,
+    position: u64,
+    /// The last response, starting at `buffer_start`.
+    buffer: Vec<u8>,
+    buffer_start: u64,
+    chunk: u64,
+    downloaded: u64,
+    requests: usize,
+}
+
+impl RangeReader {
+    /// Opens the zip archive at an http(s) `location` with the authentication
+    /// and headers from `config`. `None` if `location` isn't http(s), the
+    /// server doesn't answer Range requests or the archive isn't a zip (e.g.
+    /// encrypted with age); [`fetch_archive`] downloads those whole.
+    pub async fn open_zip(location: &str, config: &Config) -> Result<Option<Self>> {
+        if !location.starts_with("http://") && !location.starts_with("https://") {
+            return Ok(None);
+        }
+        let mut reader = RangeReader {
+            url: location.to_string(),
+            http: HttpUpload::from_config(location, config)?,
+            client: http_client(config)?,
+            runtime: Handle::current(),
+            len: 0,
+            position: 0,
+            buffer: Vec::new(),
+            buffer_start: 0,
+            chunk: MIN_CHUNK,
+            downloaded: 0,
+            requests: 0,
+        };
+        let Some((first, len)) = reader.get(0, MIN_CHUNK - 1).await? else {
+            return Ok(None);
+        };
+        // A local file header, or the end of central directory of an empty zip
+        if !first.starts_with(b"PK\x03\x04") && !first.starts_with(b"PK\x05\x06") {
+            return Ok(None);
+        }
+        reader.len = len;
+        reader.buffer = first;
+        Ok(Some(reader))
+    }
+
+    /// Requests the bytes `start..=end`, returning them and the size of the
+    /// whole archive; `None` if the server ignores the Range header.
+    async fn get(&mut self, start: u64, end: u64) -> Result<Option<(Vec<u8>, u64)>> {
+        let response = self
+            .http
+            .authorize(self.client.get(&self.url))
+            .header(header::RANGE, format!("bytes={start}-{end}"))
+            .send()
+            .await
+            .with_context(|| format!("downloading {}", self.url))?;
+        self.requests += 1;
+        if response.status() != StatusCode::PARTIAL_CONTENT {
+            return Ok(None);
+        }
+        // `bytes 0-65535/1234567`
+        let len = response
+            .headers()
+            .get(header::CONTENT_RANGE)
+            .and_then(|range| range.to_str().ok())
+            .and_then(|range| range.rsplit_once('/'))
+            .and_then(|(_, len)| len.parse().ok());
+        let Some(len) = len else {
+            return Ok(None);
+        };
+        let body = response.bytes().await?.to_vec();
+        self.downloaded += body.len() as u64;
+        Ok(Some((body, len)))
+    }
+
+    /// Replaces the buffer with the bytes from `position` on.
+    fn fill(&mut self) -> io::Result<()> {
+        let buffer_end = self.buffer_start + self.buffer.len() as u64;
+        // Reading on from the last response is likely the data of an entry,
+        // and worth larger requests; a seek is likely to a header
+        self.chunk = if self.position == buffer_end {
+            (self.chunk * 2).min(MAX_CHUNK)
+        } else {
+            MIN_CHUNK
+        };
+        let (start, end) = (
+            self.position,
+            (self.position + self.chunk).min(self.len) - 1,
+        );
+        let runtime = self.runtime.clone();
+        let (body, _) = runtime
+            .block_on(self.get(start, end))
+            .map_err(io::Error::other)?
+            .ok_or_else(|| {
+                io::Error::other(format!("{} stopped answering Range requests", self.url))
+            })?;
+        if body.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.buffer = body;
+        self.buffer_start = start;
+        Ok(())
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.len {
+            return Ok(0);
+        }
+        let buffer_end = self.buffer_start + self.buffer.len() as u64;
+        if self.position < self.buffer_start || self.position >= buffer_end {
+            self.fill()?;
+        }
+        let offset = (self.position - self.buffer_start) as usize;
+        let count = buf.len().min(self.buffer.len() - offset);
+        buf[..count].copy_from_slice(&self.buffer[offset..offset + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Seek for RangeReader {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        let position = match position {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+        self.position = position
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.position)
+    }
+}
+
+impl Drop for RangeReader {
+    fn drop(&mut self) {
+        // Not opened after all
+        if self.len == 0 {
+            return;
+        }
+        info!(
+            "Downloaded {} of {} bytes of {} in {} range requests",
+            self.downloaded, self.len, self.url, self.requests
+        );
+    }
+}
+
+/// Joins the volumes `<path>.001`, `.002`, ... of a split archive into a
+/// temporary file; `None` if there are none.
+fn join_volumes(path: &Path) -> Result<Option<TempPath>> {
+    let volume = |number: u32| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{number:03}"));
+        PathBuf::from(name)
+    };
+    if !volume(1).is_file() {
+        return Ok(None);
+    }
+    let temp = tempfile::NamedTempFile::new()?;
+    let mut output = temp.as_file();
+    let mut number = 1;
+    while volume(number).is_file() {
+        let mut input = File::open(volume(number))?;
+        io::copy(&mut input, &mut output)
+            .with_context(|| format!("reading {}", volume(number).display()))?;
+        number += 1;
+    }
+    Ok(Some(temp.into_temp_path()))
+}
+
+/// Whether the archive is age encrypted.
+pub fn is_encrypted(path: &Path) -> Result<bool> {
+    let mut magic = Vec::with_capacity(encrypt::MAGIC.len());
+    File::open(path)?
+        .take(encrypt::MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+    Ok(encrypt::is_encrypted(&magic))
+}
+
+/// Decrypts an age encrypted archive into a temporary file, so it can be read
+/// like any other. Unencrypted archives are returned unchanged.
+///
+/// `identity_files` override the identities from `config`.
+pub fn decrypt_archive(
+    archive: FetchedArchive,
+    identity_files: &[String],
+    config: &Config,
+) -> Result<FetchedArchive> {
+    if !is_encrypted(archive.path())? {
+        return Ok(archive);
+    }
+    let identities = encrypt::identities(identity_files, config)?;
+
+    let mut file = File::open(archive.path())?;
+    file.rewind()?;
+    let mut reader = encrypt::decrypt_reader(&identities, BufReader::new(file))?;
+    let mut temp = tempfile::NamedTempFile::new()?;
+    io::copy(&mut reader, &mut temp).context("decrypting archive")?;
+
+    let temp = temp.into_temp_path();
+    Ok(FetchedArchive {
+        path: temp.to_path_buf(),
+        _temp: Some(temp),
+    })
+}
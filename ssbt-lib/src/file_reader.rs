@@ -0,0 +1,372 @@
+use anyhow::{Context, Result, anyhow, bail};
+use std::{
+    fs::{self, File, Metadata},
+    io::{Seek, SeekFrom},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+    thread,
+    time::{Duration, SystemTime},
+};
+use tempfile::TempPath;
+
+use crate::Config;
+use crate::vss::ShadowVolumes;
+use tracing::{debug, warn};
+
+/// What to do when a file can't be opened or read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReadErrorPolicy {
+    /// Abort the whole backup.
+    #[default]
+    Fail,
+    /// Leave the file out of the archive and list it at the end of the run
+    /// and in the manifest.
+    Skip,
+    /// Like `skip`, with a warning as soon as the file fails.
+    Warn,
+    /// Try again up to `read_retries` times, then fail.
+    Retry,
+}
+
+impl FromStr for ReadErrorPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "fail" => Ok(ReadErrorPolicy::Fail),
+            "skip" => Ok(ReadErrorPolicy::Skip),
+            "warn" => Ok(ReadErrorPolicy::Warn),
+            "retry" => Ok(ReadErrorPolicy::Retry),
+            other => Err(anyhow!(
+                "unsupported on_read_error policy: {other} (expected fail, skip, warn or retry)"
+            )),
+        }
+    }
+}
+
+/// What to do about a file that changed while it was read, which makes its
+/// entry a mix of old and new contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChangePolicy {
+    /// Archive it, warn, and mark it in the manifest and the report.
+    #[default]
+    Flag,
+    /// Copy it aside and read it once more if it changed meanwhile (see
+    /// `stabilize`), flagging it if it changed again.
+    Reread,
+    /// Abort the whole backup.
+    Fail,
+}
+
+impl FromStr for ChangePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "flag" => Ok(ChangePolicy::Flag),
+            "reread" => Ok(ChangePolicy::Reread),
+            "fail" => Ok(ChangePolicy::Fail),
+            other => Err(anyhow!(
+                "unsupported on_change policy: {other} (expected flag, reread or fail)"
+            )),
+        }
+    }
+}
+
+/// How source files are read while archiving.
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    pub on_error: ReadErrorPolicy,
+    /// Extra attempts for `retry` and for re-reading files that changed in `stabilize` mode.
+    pub retries: u32,
+    /// Copy each file aside before archiving and re-copy it if its size or mtime
+    /// changed meanwhile, so a half-written file never ends up in the archive unnoticed.
+    pub stabilize: bool,
+    /// Files that changed while being read (or kept changing while copied).
+    pub on_change: ChangePolicy,
+    /// Shadow copies the files are read from (`vss`).
+    pub shadow_volumes: ShadowVolumes,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            on_error: ReadErrorPolicy::default(),
+            retries: 3,
+            stabilize: false,
+            on_change: ChangePolicy::default(),
+            shadow_volumes: ShadowVolumes::default(),
+        }
+    }
+}
+
+impl ReadOptions {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let defaults = ReadOptions::default();
+        Ok(ReadOptions {
+            on_error: match config.on_read_error.as_deref() {
+                Some(policy) => policy.parse()?,
+                None => defaults.on_error,
+            },
+            retries: config.read_retries.unwrap_or(defaults.retries),
+            stabilize: config.stabilize.unwrap_or(defaults.stabilize),
+            on_change: match config.on_change.as_deref() {
+                Some(policy) => policy.parse()?,
+                None => defaults.on_change,
+            },
+            shadow_volumes: defaults.shadow_volumes,
+        })
+    }
+}
+
+/// Files that didn't make it into the archive as-is.
+#[derive(Debug, Clone, Default)]
+pub struct ReadReport {
+    /// Files left out because of read errors, with the error message.
+    pub skipped: Vec<(PathBuf, String)>,
+    /// Files that changed while being read, or kept changing while being
+    /// copied (the last copy was archived).
+    pub unstable: Vec<PathBuf>,
+}
+
+/// A source file ready to be streamed into an archive.
+pub struct SourceFile {
+    pub file: File,
+    /// Metadata of the original file (not of the stabilized copy).
+    pub metadata: Metadata,
+    /// Keeps the stabilized copy alive until the entry has been written.
+    pub copy: Option<TempPath>,
+    /// Extended attributes of the original file, if they were read.
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// A symlink stored as a link rather than through its target.
+pub struct SourceLink {
+    pub target: PathBuf,
+    /// Metadata of the link itself.
+    pub metadata: Metadata,
+}
+
+impl SourceLink {
+    /// Reads the link at `path`, or returns `None` if it isn't a symlink.
+    pub fn read(path: &Path) -> Result<Option<SourceLink>> {
+        let metadata =
+            fs::symlink_metadata(path).with_context(|| format!("reading {}", path.display()))?;
+        if !metadata.is_symlink() {
+            return Ok(None);
+        }
+        let target = fs::read_link(path).with_context(|| format!("reading {}", path.display()))?;
+        Ok(Some(SourceLink { target, metadata }))
+    }
+}
+
+/// Opens source files according to [`ReadOptions`] and collects a [`ReadReport`].
+/// Shared between the archive writers, which may run on blocking threads.
+#[derive(Debug, Default)]
+pub struct SourceReader {
+    options: ReadOptions,
+    report: Mutex<ReadReport>,
+}
+
+impl SourceReader {
+    pub fn new(options: ReadOptions) -> Self {
+        SourceReader {
+            options,
+            report: Mutex::new(ReadReport::default()),
+        }
+    }
+
+    /// Opens `path` for archiving. Returns `Ok(None)` if the file was skipped.
+    ///
+    /// Only errors up to this point are covered by the policy: once an entry has
+    /// started streaming it can't be taken back, which is why `stabilize` reads
+    /// the whole file before its entry is written.
+    pub fn open(&self, path: &Path) -> Result<Option<SourceFile>> {
+        let attempts = match self.options.on_error {
+            ReadErrorPolicy::Retry => self.options.retries + 1,
+            _ => 1,
+        };
+
+        let mut last_error = None;
+        for attempt in 0..attempts {
+            if attempt > 0 {
+                warn!(
+                    "Retrying {} (attempt {}/{})",
+                    path.display(),
+                    attempt + 1,
+                    attempts
+                );
+                thread::sleep(Duration::from_millis(500 * attempt as u64));
+            }
+            match self.acquire(path) {
+                Ok(source) => return Ok(Some(source)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        let error = last_error.expect("at least one attempt is made");
+        if let ReadErrorPolicy::Skip | ReadErrorPolicy::Warn = self.options.on_error {
+            match self.options.on_error {
+                ReadErrorPolicy::Warn => warn!("skipping {}: {:#}", path.display(), error),
+                _ => debug!("skipping {}: {:#}", path.display(), error),
+            }
+            let mut report = self.report.lock().unwrap();
+            // A retried upload reads the sources again
+            if !report.skipped.iter().any(|(skipped, _)| skipped == path) {
+                report
+                    .skipped
+                    .push((path.to_path_buf(), format!("{error:#}")));
+            }
+            return Ok(None);
+        }
+        Err(error)
+    }
+
+    /// Why `path` was skipped, if it was.
+    pub fn skip_reason(&self, path: &Path) -> Option<String> {
+        let report = self.report.lock().unwrap();
+        report
+            .skipped
+            .iter()
+            .find(|(skipped, _)| skipped == path)
+            .map(|(_, reason)| reason.clone())
+    }
+
+    /// Checks that `path` still has the size and mtime it was `opened`
+    /// with, once its entry is written. A change is handled according to
+    /// `on_change`; returns whether there was one. Copies aside can't
+    /// change, see [`SourceReader::is_unstable`] for them.
+    pub fn check_unchanged(&self, path: &Path, opened: &Metadata) -> Result<bool> {
+        let now = match fs::metadata(self.options.shadow_volumes.source_path(path).as_ref()) {
+            Ok(now) => now,
+            Err(e) => {
+                self.changed(path, &format!("went away while being read ({e})"))?;
+                return Ok(true);
+            }
+        };
+        if fingerprint(&now) == fingerprint(opened) {
+            return Ok(false);
+        }
+        self.changed(path, "changed while being read")?;
+        Ok(true)
+    }
+
+    /// Whether `path` kept changing while being copied aside.
+    pub fn is_unstable(&self, path: &Path) -> bool {
+        let report = self.report.lock().unwrap();
+        report.unstable.iter().any(|unstable| unstable == path)
+    }
+
+    /// Records that `path` `what` (e.g. "changed while being read"), or
+    /// fails with `on_change: fail`.
+    fn changed(&self, path: &Path, what: &str) -> Result<()> {
+        if self.options.on_change == ChangePolicy::Fail {
+            bail!("{} {what}", path.display());
+        }
+        warn!("{} {what}", path.display());
+        let mut report = self.report.lock().unwrap();
+        // A retried upload reads the sources again
+        if !report.unstable.iter().any(|unstable| unstable == path) {
+            report.unstable.push(path.to_path_buf());
+        }
+        Ok(())
+    }
+
+    /// Returns a snapshot of everything recorded so far.
+    pub fn report(&self) -> ReadReport {
+        self.report.lock().unwrap().clone()
+    }
+
+    fn acquire(&self, path: &Path) -> Result<SourceFile> {
+        // Under `vss`, the names stay those of the live files
+        let live = path;
+        let source = self.options.shadow_volumes.source_path(path);
+        let path = source.as_ref();
+        if !self.options.stabilize && self.options.on_change != ChangePolicy::Reread {
+            let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+            let metadata = file.metadata()?;
+            return Ok(SourceFile {
+                file,
+                metadata,
+                copy: None,
+                xattrs: Vec::new(),
+            });
+        }
+
+        // `reread` alone copies once more; `stabilize` as often as configured
+        let retries = match self.options.stabilize {
+            true => self.options.retries,
+            false => 1,
+        };
+        let mut last_copy = None;
+        for _ in 0..=retries {
+            let before = fs::metadata(path).with_context(|| format!("reading {path:?}"))?;
+            let (file, copy) = copy_aside(path)?;
+            let after = fs::metadata(path).with_context(|| format!("reading {path:?}"))?;
+
+            if fingerprint(&before) == fingerprint(&after) {
+                return Ok(SourceFile {
+                    file,
+                    metadata: before,
+                    copy: Some(copy),
+                    xattrs: Vec::new(),
+                });
+            }
+            last_copy = Some((file, after, copy));
+        }
+
+        self.changed(live, "kept changing while being read")?;
+        let (file, metadata, copy) = last_copy.expect("at least one copy is made");
+        Ok(SourceFile {
+            file,
+            metadata,
+            copy: Some(copy),
+            xattrs: Vec::new(),
+        })
+    }
+}
+
+/// Reads the extended attributes of `path`. Attributes that can't be read,
+/// or have names that aren't UTF-8, are left out.
+pub fn read_xattrs(path: &Path) -> Vec<(String, Vec<u8>)> {
+    let Ok(names) = xattr::list(path) else {
+        return Vec::new();
+    };
+    names
+        .filter_map(|name| {
+            let value = xattr::get(path, &name).ok()??;
+            Some((name.into_string().ok()?, value))
+        })
+        .collect()
+}
+
+/// The Unix file type and permission bits (`st_mode`) of a file; `None` on
+/// other platforms.
+pub fn unix_mode(metadata: &Metadata) -> Option<u32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some(metadata.mode())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Copies `path` into a temporary file and returns it rewound for reading.
+fn copy_aside(path: &Path) -> Result<(File, TempPath)> {
+    let mut source = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut copy = tempfile::NamedTempFile::new()?;
+    std::io::copy(&mut source, &mut copy).with_context(|| format!("reading {}", path.display()))?;
+
+    let (mut file, temp_path) = copy.into_parts();
+    file.seek(SeekFrom::Start(0))?;
+    Ok((file, temp_path))
+}
+
+fn fingerprint(metadata: &Metadata) -> (u64, Option<SystemTime>) {
+    (metadata.len(), metadata.modified().ok())
+}
@@ -0,0 +1,1064 @@
+use crate::docker;
+use crate::error::SsbtError;
+use crate::journal;
+use crate::naming::{name_pattern, volume_of};
+use crate::process::{EntryNaming, outermost};
+use crate::sink::save_file::is_local_output;
+use crate::{Config, SourcePath};
+use anyhow::{Context, Result, anyhow, bail};
+use std::{
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Local, NaiveDate};
+use glob::{MatchOptions, Pattern};
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// How symlinks found while walking a directory are backed up. Directories
+/// given on the command line are always walked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Back up what the link points to; links that lead back into a
+    /// directory being walked are reported and left out.
+    #[default]
+    Follow,
+    /// Leave symlinks out.
+    Skip,
+    /// Store the link itself as a symlink entry (tar and zip).
+    Store,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "store" => Ok(SymlinkPolicy::Store),
+            other => Err(anyhow!(
+                "unsupported symlinks policy: {other} (expected follow, skip or store)"
+            )),
+        }
+    }
+}
+
+impl SymlinkPolicy {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        config
+            .symlinks
+            .as_deref()
+            .map_or(Ok(SymlinkPolicy::default()), str::parse)
+    }
+}
+
+/// Name of the gitignore-style files picked up in backed up directories.
+pub const IGNORE_FILE: &str = ".ssbtignore";
+
+/// Recursively lists all files from `config.paths`, keeping those that match
+/// one of the `config.include` patterns (if any) and excluding any that match
+/// `config.skip` patterns or the gitignore-style rules of `config.exclude_from`
+/// and `.ssbtignore` files, as well as files outside the size and age limits
+/// (`skip_larger_than`, `modified_within`, `modified_before`). The run's
+/// own files are left out, with a warning: archives of its local outputs
+/// with their `.part` files, volumes and journals, the local repository and
+/// the catalog. Directories
+/// are read on `config.walk_threads` threads, and the files of each path
+/// sorted by name unless `config.sort_files` is false, so the list doesn't
+/// change between runs over the same files.
+/// When `config.files_from` is set, the list is read from that file (or
+/// stdin) instead, in its order. Invalid patterns fail with [`SsbtError::Config`], paths that
+/// can't be read with [`SsbtError::Walk`].
+pub fn list_total_files(config: &Config) -> Result<Vec<PathBuf>, SsbtError> {
+    let filters = Filters::from_config(config).map_err(SsbtError::Config)?;
+    list_files(config, &filters).map_err(SsbtError::Walk)
+}
+
+fn list_files(config: &Config, filters: &Filters) -> Result<Vec<PathBuf>> {
+    let mut result = Vec::new();
+
+    if let Some(source) = &config.files_from {
+        for path in read_file_list(source)? {
+            if path.is_file() {
+                if filters.keeps_listed(&path, Path::new("")) {
+                    result.push(path);
+                }
+            } else if !path.exists() {
+                warn!("listed file {} does not exist", path.display());
+            }
+        }
+        filters.own_files.report();
+        return Ok(result);
+    }
+
+    let threads = walk_threads(config);
+    for path in walk_roots(config)? {
+        if path.is_file() {
+            let root = path.parent().unwrap_or(Path::new(""));
+            if filters.keeps_listed(&path, root) {
+                result.push(path);
+            }
+        } else {
+            let found = Mutex::new(Vec::new());
+            walk_tree(&path, filters, threads, |files| {
+                found.lock().unwrap().extend(files);
+                Ok(())
+            })?;
+            let mut found = found.into_inner().unwrap();
+            // Component by component, the order of a walk taking the
+            // entries of each directory by name
+            if config.sort_files.unwrap_or(true) {
+                found.sort();
+            }
+            result.extend(found);
+        }
+    }
+    filters.own_files.report();
+
+    Ok(result)
+}
+
+/// `config.walk_threads`, or the number of CPUs.
+fn walk_threads(config: &Config) -> usize {
+    config
+        .walk_threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |cpus| cpus.get()))
+}
+
+/// The existing files and directories of `config.paths`, leaving out those
+/// inside another one and `docker://` paths, which are streamed into the
+/// archive instead.
+fn walk_roots(config: &Config) -> Result<Vec<PathBuf>> {
+    let mut roots = Vec::new();
+    for p in config.paths.iter().flatten().map(SourcePath::path) {
+        let path = PathBuf::from(p);
+        if !docker::is_docker_path(p) && path.exists() {
+            roots.push(path);
+        }
+    }
+    // Paths inside another one would be walked twice
+    Ok(outermost(&roots.iter().collect::<Vec<_>>()))
+}
+
+/// Files a streamed walk may find ahead of the archive taking them, which
+/// keeps its memory flat however big the tree.
+const WALK_AHEAD: usize = 1024;
+
+/// A walk of `config.paths` handing out files as it finds them, so the
+/// archive is written while the walk goes on (`stream_walk`). It filters
+/// like [`list_total_files`], but the files come in the order the walk finds
+/// them, and `files_from` isn't read.
+#[derive(Clone)]
+pub struct FileWalk {
+    roots: Vec<PathBuf>,
+    filters: Arc<Filters>,
+    threads: usize,
+    naming: EntryNaming,
+    /// Files and bytes found by the last walk started.
+    files: Arc<AtomicUsize>,
+    size: Arc<AtomicU64>,
+}
+
+impl FileWalk {
+    /// Invalid patterns fail with [`SsbtError::Config`], `docker://` paths
+    /// not fetched yet with [`SsbtError::Walk`].
+    pub fn from_config(config: &Config) -> Result<Self, SsbtError> {
+        Ok(FileWalk {
+            roots: walk_roots(config).map_err(SsbtError::Walk)?,
+            filters: Arc::new(Filters::from_config(config).map_err(SsbtError::Config)?),
+            threads: walk_threads(config),
+            naming: EntryNaming::from_config(config, None).map_err(SsbtError::Config)?,
+            files: Arc::default(),
+            size: Arc::default(),
+        })
+    }
+
+    /// How the files found are named (see [`EntryNaming`]).
+    pub fn naming(&self) -> EntryNaming {
+        self.naming.clone()
+    }
+
+    /// Starts the walk on a thread of its own. Files arrive in the receiver
+    /// as they are found, and the walk waits while [`WALK_AHEAD`] of them
+    /// are pending; an error ends it. Dropping the receiver stops the walk.
+    pub fn start(&self) -> mpsc::Receiver<Result<PathBuf>> {
+        let (sender, receiver) = mpsc::channel(WALK_AHEAD);
+        self.files.store(0, Ordering::Relaxed);
+        self.size.store(0, Ordering::Relaxed);
+        let walk = self.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = walk.send_all(&sender) {
+                let _ = sender.blocking_send(Err(e));
+            }
+        });
+        receiver
+    }
+
+    /// Files found by the last walk, so far.
+    pub fn files(&self) -> usize {
+        self.files.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of the files found by the last walk, so far.
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    fn send_all(&self, sender: &mpsc::Sender<Result<PathBuf>>) -> Result<()> {
+        let send = |files: Vec<PathBuf>| {
+            for file in files {
+                let size = match file.is_file() {
+                    true => fs::metadata(&file).map_or(0, |m| m.len()),
+                    false => 0,
+                };
+                self.files.fetch_add(1, Ordering::Relaxed);
+                self.size.fetch_add(size, Ordering::Relaxed);
+                sender
+                    .blocking_send(Ok(file))
+                    .map_err(|_| anyhow!("the archive stopped taking files"))?;
+            }
+            Ok(())
+        };
+        for root in &self.roots {
+            if root.is_file() {
+                let parent = root.parent().unwrap_or(Path::new(""));
+                if self.filters.keeps_listed(root, parent) {
+                    send(vec![root.clone()])?;
+                }
+            } else {
+                walk_tree(root, &self.filters, self.threads, send)?;
+            }
+        }
+        self.filters.own_files.report();
+        Ok(())
+    }
+}
+
+/// A directory waiting to be walked, with what applies to it.
+struct DirJob {
+    dir: PathBuf,
+    /// The ignore rules in effect, innermost last.
+    rules: Vec<Arc<Gitignore>>,
+    /// The canonical paths of `dir` and the directories above it, to catch
+    /// loops.
+    ancestors: Vec<PathBuf>,
+}
+
+/// Directories still to walk, shared by the walking threads.
+#[derive(Default)]
+struct WalkQueue {
+    state: Mutex<WalkState>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct WalkState {
+    jobs: Vec<DirJob>,
+    /// Threads walking a directory, which may queue more.
+    busy: usize,
+    error: Option<anyhow::Error>,
+}
+
+impl WalkQueue {
+    /// The next directory to walk; `None` once all are walked or one failed.
+    fn next(&self) -> Option<DirJob> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.error.is_some() {
+                return None;
+            }
+            if let Some(job) = state.jobs.pop() {
+                state.busy += 1;
+                return Some(job);
+            }
+            if state.busy == 0 {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Records the subdirectories found by walking a directory taken with
+    /// `next`, or why it failed.
+    fn done(&self, walked: Result<Vec<DirJob>>) {
+        let mut state = self.state.lock().unwrap();
+        state.busy -= 1;
+        match walked {
+            Ok(jobs) => state.jobs.extend(jobs),
+            Err(e) => {
+                state.error.get_or_insert(e);
+            }
+        }
+        self.changed.notify_all();
+    }
+}
+
+/// Walks the directory `root` and its subdirectories, reading up to
+/// `threads` directories at once (which pays off most on network file
+/// systems), and hands the files of each directory to `found`, in no
+/// particular order. An error of `found` stops the walk.
+fn walk_tree(
+    root: &Path,
+    filters: &Filters,
+    threads: usize,
+    found: impl Fn(Vec<PathBuf>) -> Result<()> + Sync,
+) -> Result<()> {
+    let queue = WalkQueue::default();
+    queue.state.lock().unwrap().jobs.push(DirJob {
+        dir: root.to_path_buf(),
+        rules: filters
+            .exclude_rules(root)?
+            .map(Arc::new)
+            .into_iter()
+            .collect(),
+        ancestors: vec![fs::canonicalize(root)?],
+    });
+
+    std::thread::scope(|scope| {
+        for _ in 0..threads.max(1) {
+            scope.spawn(|| {
+                while let Some(job) = queue.next() {
+                    let mut files = Vec::new();
+                    let walked = walk_dir(job, root, filters, &mut files)
+                        .and_then(|subdirs| found(files).map(|()| subdirs));
+                    queue.done(walked);
+                }
+            });
+        }
+    });
+
+    match queue.state.into_inner().unwrap().error {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Adds the files of `job.dir` to `found`, returning its subdirectories to
+/// walk next. With `preserve_empty_dirs`, a directory holding nothing that
+/// isn't skipped or ignored is added itself.
+fn walk_dir(
+    job: DirJob,
+    root: &Path,
+    filters: &Filters,
+    found: &mut Vec<PathBuf>,
+) -> Result<Vec<DirJob>> {
+    let DirJob {
+        dir,
+        mut rules,
+        ancestors,
+    } = job;
+    if filters.ssbtignore
+        && let Some(own_rules) = read_ignore_file(&dir)?
+    {
+        rules.push(Arc::new(own_rules));
+    }
+
+    let mut subdirs = Vec::new();
+    let mut empty = true;
+    for entry in fs::read_dir(&dir).with_context(|| format!("reading directory {dir:?}"))? {
+        let entry = entry?;
+        let path = entry.path();
+        let is_link = entry.file_type()?.is_symlink();
+        let is_dir = path.is_dir();
+
+        if filters.is_skipped(&path) || is_ignored(&path, is_dir, &rules) {
+            continue;
+        }
+        if filters.own_files.leaves_out(&path) {
+            continue;
+        }
+
+        if is_link {
+            match filters.symlinks {
+                SymlinkPolicy::Skip => continue,
+                SymlinkPolicy::Store => {
+                    empty = false;
+                    if filters.is_included(&path, root) && filters.is_selected(&path) {
+                        found.push(path);
+                    }
+                    continue;
+                }
+                SymlinkPolicy::Follow if !path.exists() => {
+                    warn!("skipping dangling symlink {}", path.display());
+                    continue;
+                }
+                SymlinkPolicy::Follow => {}
+            }
+        }
+
+        empty = false;
+        if is_dir {
+            let canonical =
+                fs::canonicalize(&path).with_context(|| format!("resolving {}", path.display()))?;
+            if ancestors.contains(&canonical) {
+                warn!(
+                    "not following {}, it loops back to {}",
+                    path.display(),
+                    canonical.display()
+                );
+                continue;
+            }
+            let mut ancestors = ancestors.clone();
+            ancestors.push(canonical);
+            subdirs.push(DirJob {
+                dir: path,
+                rules: rules.clone(),
+                ancestors,
+            });
+        } else if filters.is_included(&path, root) && filters.is_selected(&path) {
+            found.push(path);
+        }
+    }
+    if empty && filters.empty_dirs && filters.is_included(&dir, root) && filters.is_selected(&dir) {
+        found.push(dir);
+    }
+    Ok(subdirs)
+}
+
+/// How `skip` and `include` patterns match. On Windows, `\\` and `/` are
+/// interchangeable in them (glob takes care of that) and, as on its file
+/// systems, case doesn't matter.
+pub const PATTERN_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: !cfg!(windows),
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+/// The patterns and ignore files deciding which walked files are backed up.
+struct Filters {
+    include: Vec<Pattern>,
+    skip: Vec<Pattern>,
+    /// Gitignore-style files applying to every backed up directory.
+    exclude_from: Vec<PathBuf>,
+    /// Whether `.ssbtignore` files are read.
+    ssbtignore: bool,
+    symlinks: SymlinkPolicy,
+    /// Files above this many bytes are left out.
+    larger_than: Option<u64>,
+    /// Files modified before this time are left out.
+    modified_after: Option<SystemTime>,
+    /// Files modified at or after this time are left out.
+    modified_before: Option<SystemTime>,
+    /// Whether directories with nothing to back up are listed themselves.
+    /// Repositories only store files, so they never get them.
+    empty_dirs: bool,
+    own_files: OwnFiles,
+}
+
+impl Filters {
+    fn from_config(config: &Config) -> Result<Self> {
+        fn compile(kind: &str, patterns: &Option<Vec<String>>) -> Result<Vec<Pattern>> {
+            patterns
+                .iter()
+                .flatten()
+                .map(|p| Pattern::new(p).with_context(|| format!("invalid {kind} pattern: {p}")))
+                .collect()
+        }
+        Ok(Filters {
+            include: compile("include", &config.include)?,
+            skip: compile("skip", &config.skip)?,
+            exclude_from: config
+                .exclude_from
+                .iter()
+                .flatten()
+                .map(PathBuf::from)
+                .collect(),
+            ssbtignore: config.ssbtignore.unwrap_or(true),
+            symlinks: SymlinkPolicy::from_config(config)?,
+            larger_than: config
+                .skip_larger_than
+                .as_deref()
+                .map(|size| parse_size(size).context("invalid skip_larger_than"))
+                .transpose()?,
+            modified_after: config
+                .modified_within
+                .as_deref()
+                .map(|age| parse_time(age).context("invalid modified_within"))
+                .transpose()?,
+            modified_before: config
+                .modified_before
+                .as_deref()
+                .map(|age| parse_time(age).context("invalid modified_before"))
+                .transpose()?,
+            empty_dirs: config.preserve_empty_dirs.unwrap_or(true) && config.repo.is_none(),
+            own_files: OwnFiles::from_config(config)?,
+        })
+    }
+
+    /// The `exclude_from` rules, anchored at the backed up directory `root`.
+    fn exclude_rules(&self, root: &Path) -> Result<Option<Gitignore>> {
+        if self.exclude_from.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = GitignoreBuilder::new(root);
+        for file in &self.exclude_from {
+            if let Some(e) = builder.add(file) {
+                return Err(e).with_context(|| format!("reading exclude file {}", file.display()));
+            }
+        }
+        Ok(Some(builder.build()?))
+    }
+
+    fn is_skipped(&self, path: &Path) -> bool {
+        let path_str = path.to_string_lossy();
+        self.skip
+            .iter()
+            .any(|p| p.matches_with(&path_str, PATTERN_OPTIONS))
+    }
+
+    /// Whether a file is within the size and age limits. Files whose
+    /// metadata can't be read are kept, for the read error policy to handle.
+    fn is_selected(&self, path: &Path) -> bool {
+        if self.larger_than.is_none()
+            && self.modified_after.is_none()
+            && self.modified_before.is_none()
+        {
+            return true;
+        }
+        let Ok(metadata) = fs::metadata(path).or_else(|_| fs::symlink_metadata(path)) else {
+            return true;
+        };
+        if self.larger_than.is_some_and(|limit| metadata.len() > limit) {
+            return false;
+        }
+        let Ok(modified) = metadata.modified() else {
+            return true;
+        };
+        self.modified_after.is_none_or(|after| modified >= after)
+            && self.modified_before.is_none_or(|before| modified < before)
+    }
+
+    /// Whether a file passes the include patterns, matched against its path
+    /// as walked and relative to the backed up directory `root`. Without
+    /// include patterns every file does.
+    fn is_included(&self, path: &Path, root: &Path) -> bool {
+        if self.include.is_empty() {
+            return true;
+        }
+        let full = path.to_string_lossy();
+        let relative = path.strip_prefix(root).unwrap_or(path).to_string_lossy();
+        self.include.iter().any(|p| {
+            p.matches_with(&full, PATTERN_OPTIONS) || p.matches_with(&relative, PATTERN_OPTIONS)
+        })
+    }
+
+    /// Whether a file given by name rather than walked (a path of
+    /// `config.paths` or a line of `files_from`) is backed up.
+    fn keeps_listed(&self, path: &Path, root: &Path) -> bool {
+        if self.own_files.leaves_out(path) {
+            return false;
+        }
+        self.is_included(path, root) && !self.is_skipped(path) && self.is_selected(path)
+    }
+}
+
+/// What a run writes itself, which it would otherwise back up when a
+/// backed up path holds it, adding to an archive while writing it. All
+/// paths are resolved (see [`resolve`]) so that however an output or a
+/// backed up path is spelled, the same file compares equal.
+struct OwnFiles {
+    /// Names of the archives of the local outputs. Their time and random
+    /// placeholders match any name, as the archive isn't named yet, which
+    /// leaves out the archives of earlier runs as well.
+    archives: Vec<Pattern>,
+    /// The last component of each of `archives`, checked first so that only
+    /// files named like an archive get resolved.
+    names: Vec<Pattern>,
+    /// The local repository and the catalog.
+    paths: Vec<PathBuf>,
+    /// Files left out by the current walk, reported once it's done.
+    left_out: AtomicUsize,
+}
+
+impl OwnFiles {
+    fn from_config(config: &Config) -> Result<Self> {
+        let mut own = OwnFiles {
+            archives: Vec::new(),
+            names: Vec::new(),
+            paths: Vec::new(),
+            left_out: AtomicUsize::new(0),
+        };
+        match &config.repo {
+            Some(repo) if !repo.contains("://") => own.paths.push(resolve(Path::new(repo))?),
+            Some(_) => {}
+            None => {
+                let outputs = match config.output.as_deref() {
+                    Some(outputs) if !outputs.is_empty() => outputs.to_vec(),
+                    _ => vec![".".to_string()],
+                };
+                for output in outputs.iter().filter(|o| is_local_output(o, config)) {
+                    let output = resolve(Path::new(output))?;
+                    let pattern = name_pattern(&output.to_string_lossy(), config)?;
+                    let name = pattern.as_str().rsplit('/').next().unwrap_or_default();
+                    own.names.push(Pattern::new(name)?);
+                    own.archives.push(pattern);
+                }
+            }
+        }
+        if let Some(catalog) = catalog_path(config) {
+            own.paths.push(resolve(&catalog)?);
+        }
+        Ok(own)
+    }
+
+    /// Whether the run wrote `path`, counting it for [`OwnFiles::report`].
+    fn leaves_out(&self, path: &Path) -> bool {
+        if !self.contains(path) {
+            return false;
+        }
+        debug!("leaving out {}, written by ssbt", path.display());
+        self.left_out.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    fn contains(&self, path: &Path) -> bool {
+        let Some(name) = path.file_name() else {
+            return false;
+        };
+        let archive_name = archive_of(&name.to_string_lossy()).to_string();
+        if !self.names.iter().any(|p| p.matches(&archive_name))
+            && !self.paths.iter().any(|p| p.file_name() == Some(name))
+        {
+            return false;
+        }
+        let Ok(path) = resolve(path) else {
+            return false;
+        };
+        if self.paths.contains(&path) {
+            return true;
+        }
+        let path = path.to_string_lossy();
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        self.archives
+            .iter()
+            .any(|pattern| pattern.matches_with(archive_of(&path), options))
+    }
+
+    /// Warns once about the files the walk left out, rather than for each.
+    fn report(&self) {
+        let count = self.left_out.swap(0, Ordering::Relaxed);
+        if count > 0 {
+            warn!(
+                "left out {count} files written by ssbt: archives of its local outputs with their \
+                 .part files, volumes and journals, the repository or the catalog"
+            );
+        }
+    }
+}
+
+/// The archive `file` belongs to, if it is a `.part` file, a journal or a
+/// volume of one.
+fn archive_of(file: &str) -> &str {
+    let journal = format!(".{}", journal::EXTENSION);
+    let archive = file
+        .strip_suffix(".part")
+        .or_else(|| file.strip_suffix(&journal))
+        .unwrap_or(file);
+    volume_of(archive).unwrap_or(archive)
+}
+
+/// `path` made absolute, with its closest existing ancestor canonicalized
+/// (resolving symlinks and `..`) and `..` in the rest applied.
+fn resolve(path: &Path) -> Result<PathBuf> {
+    let path = std::path::absolute(path)?;
+    let mut rest = Vec::new();
+    let mut existing = path.as_path();
+    let mut resolved = loop {
+        match fs::canonicalize(existing) {
+            Ok(canonical) => break canonical,
+            Err(_) => {
+                rest.extend(existing.components().next_back());
+                existing = existing
+                    .parent()
+                    .context("no ancestor of the path exists")?;
+            }
+        }
+    };
+    for component in rest.into_iter().rev() {
+        match component {
+            std::path::Component::ParentDir => {
+                resolved.pop();
+            }
+            std::path::Component::CurDir => {}
+            component => resolved.push(component),
+        }
+    }
+    Ok(resolved)
+}
+
+/// Reads the `.ssbtignore` of `dir`, if it has one.
+fn read_ignore_file(dir: &Path) -> Result<Option<Gitignore>> {
+    let path = dir.join(IGNORE_FILE);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    match Gitignore::new(&path) {
+        (_, Some(e)) => Err(e).with_context(|| format!("reading {}", path.display())),
+        (rules, None) => Ok(Some(rules)),
+    }
+}
+
+/// Applies ignore rules like git does: the innermost file with a matching
+/// rule decides, and within a file the last matching rule, so `!pattern`
+/// re-includes what an earlier rule excluded.
+fn is_ignored(path: &Path, is_dir: bool, rules: &[Arc<Gitignore>]) -> bool {
+    for rules in rules.iter().rev() {
+        match rules.matched(path, is_dir) {
+            Match::Ignore(_) => return true,
+            Match::Whitelist(_) => return false,
+            Match::None => {}
+        }
+    }
+    false
+}
+
+/// Reads a file list from `source` (`-` for stdin), as produced by `find` or `find -print0`.
+/// Entries are NUL-delimited if the input contains any NUL byte, newline-delimited otherwise.
+/// Names don't have to be UTF-8, except on Windows, where they are read lossily.
+fn read_file_list(source: &str) -> Result<Vec<PathBuf>> {
+    let mut content = Vec::new();
+    if source == "-" {
+        std::io::stdin()
+            .read_to_end(&mut content)
+            .context("reading file list from stdin")?;
+    } else {
+        content = fs::read(source).with_context(|| format!("reading file list {source}"))?;
+    }
+
+    let separator = if content.contains(&0) { b'\0' } else { b'\n' };
+    Ok(content
+        .split(|byte| *byte == separator)
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty())
+        .map(path_from_bytes)
+        .collect())
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Parses a duration such as `90s`, `30m`, `12h`, `7d` or `2w`; a bare
+/// number counts as seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let s = s.trim().to_ascii_lowercase();
+    let (multiplier, number_str) = if let Some(number) = s.strip_suffix('w') {
+        (7 * 24 * 3600, number)
+    } else if let Some(number) = s.strip_suffix('d') {
+        (24 * 3600, number)
+    } else if let Some(number) = s.strip_suffix('h') {
+        (3600, number)
+    } else if let Some(number) = s.strip_suffix('m') {
+        (60, number)
+    } else if let Some(number) = s.strip_suffix('s') {
+        (1, number)
+    } else {
+        (1, s.as_str())
+    };
+
+    let number: f64 = number_str
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid duration format: {}", s))?;
+    if !number.is_finite() || number < 0.0 {
+        bail!("Invalid duration format: {}", s);
+    }
+    Ok(Duration::from_secs_f64(number * multiplier as f64))
+}
+
+/// Parses a point in time: an age relative to now (`7d`, see
+/// [`parse_duration`]), a local date (`2024-01-31`, at midnight) or an
+/// RFC 3339 timestamp.
+pub fn parse_time(s: &str) -> Result<SystemTime> {
+    let s = s.trim();
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        let midnight = date.and_hms_opt(0, 0, 0).expect("midnight exists");
+        let local = midnight
+            .and_local_timezone(Local)
+            .earliest()
+            .with_context(|| format!("{s} has no local midnight"))?;
+        return Ok(local.into());
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.into());
+    }
+    let age = parse_duration(s).with_context(|| {
+        format!("{s} is neither an age (7d), a date (2024-01-31) nor a timestamp")
+    })?;
+    SystemTime::now()
+        .checked_sub(age)
+        .with_context(|| format!("{s} is too far in the past"))
+}
+
+/// What to do when the files add up to more than `max_size`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SizeExceededPolicy {
+    /// Fail with [`SizeLimitExceeded`].
+    #[default]
+    Fail,
+    /// Leave out the largest files until the rest fits.
+    SkipLargest,
+    /// Leave out the least recently modified files until the rest fits.
+    OldestFirst,
+}
+
+impl FromStr for SizeExceededPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().replace('_', "-").as_str() {
+            "fail" => Ok(SizeExceededPolicy::Fail),
+            "skip-largest" => Ok(SizeExceededPolicy::SkipLargest),
+            "oldest-first" => Ok(SizeExceededPolicy::OldestFirst),
+            other => Err(anyhow!(
+                "unsupported on_size_exceeded policy: {other} (expected fail, skip-largest or oldest-first)"
+            )),
+        }
+    }
+}
+
+/// The files to back up, or the archive built from them, add up to more than
+/// `max_size`. The CLI exits with code 42 on this error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeLimitExceeded {
+    pub total: u64,
+    pub limit: u64,
+    /// Whether the archive outgrew the limit while being written; `total`
+    /// is then the size it had reached.
+    pub archive: bool,
+}
+
+impl std::fmt::Display for SizeLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.archive {
+            write!(
+                f,
+                "archive grew to {} bytes, past the limit of {} bytes; it was not stored",
+                self.total, self.limit
+            )
+        } else {
+            write!(
+                f,
+                "total size {} bytes exceeds limit {} bytes",
+                self.total, self.limit
+            )
+        }
+    }
+}
+
+impl std::error::Error for SizeLimitExceeded {}
+
+/// Computes the total size of `files` and enforces the `max_size` limit
+/// according to `on_size_exceeded`: fails with [`SizeLimitExceeded`], or
+/// removes files from the list until the rest fits. Returns the total size
+/// of the files left.
+pub fn apply_size_limit(config: &Config, files: &mut Vec<PathBuf>) -> Result<u64, SsbtError> {
+    size_limit(config, files).map_err(|e| SsbtError::classify(e, SsbtError::Config))
+}
+
+fn size_limit(config: &Config, files: &mut Vec<PathBuf>) -> Result<u64> {
+    let mut sizes = Vec::with_capacity(files.len());
+    for path in files.iter() {
+        let size = match path.is_file() {
+            true => fs::metadata(path)?.len(),
+            false => 0,
+        };
+        sizes.push(size);
+    }
+    let mut total: u64 = sizes.iter().sum();
+
+    let Some(limit_str) = get_max_size_str(config) else {
+        return Ok(total);
+    };
+    let limit = parse_size(&limit_str)?;
+    if limit == 0 || total <= limit {
+        return Ok(total);
+    }
+
+    let policy = match config.on_size_exceeded.as_deref() {
+        Some(policy) => policy.parse()?,
+        None => SizeExceededPolicy::default(),
+    };
+    let mut order: Vec<usize> = (0..files.len()).collect();
+    match policy {
+        SizeExceededPolicy::Fail => bail!(SizeLimitExceeded {
+            total,
+            limit,
+            archive: false,
+        }),
+        SizeExceededPolicy::SkipLargest => {
+            order.sort_by_key(|&i| std::cmp::Reverse(sizes[i]));
+        }
+        SizeExceededPolicy::OldestFirst => {
+            let modified: Vec<_> = files
+                .iter()
+                .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+                .collect();
+            order.sort_by_key(|&i| modified[i]);
+        }
+    }
+
+    let mut left_out = vec![false; files.len()];
+    let mut count = 0;
+    for i in order {
+        if total <= limit {
+            break;
+        }
+        if sizes[i] == 0 {
+            continue;
+        }
+        warn!(
+            "leaving out {} ({}) to stay within max_size",
+            files[i].display(),
+            encode_size(sizes[i])
+        );
+        total -= sizes[i];
+        left_out[i] = true;
+        count += 1;
+    }
+    let mut index = 0;
+    files.retain(|_| {
+        index += 1;
+        !left_out[index - 1]
+    });
+    warn!(
+        "left out {count} file(s) to keep the backup within {}",
+        encode_size(limit)
+    );
+    Ok(total)
+}
+
+/// Extract max_size either as numeric or from string units like 10Mi, 5Gi
+fn get_max_size_str(config: &Config) -> Option<String> {
+    config
+        .max_size
+        .filter(|val| *val > 0)
+        .map(|val| val.to_string())
+}
+
+/// Parse human-readable sizes in both binary (Ki/Mi/Gi or KiB/MiB/GiB) and decimal (KB/MB/GB) units.
+/// Examples: "512Mi", "10Gi", "2GiB", "1MB", "500kb", "1024", "2.5GB"
+pub fn parse_size(s: &str) -> Result<u64> {
+    let mut s = s.trim().to_ascii_lowercase();
+    if s.ends_with("ib") {
+        s.pop();
+    }
+
+    let (multiplier, number_str) = if s.ends_with("ki") {
+        (1024_u64, &s[..s.len() - 2])
+    } else if s.ends_with("mi") {
+        (1024_u64.pow(2), &s[..s.len() - 2])
+    } else if s.ends_with("gi") {
+        (1024_u64.pow(3), &s[..s.len() - 2])
+    } else if s.ends_with("ti") {
+        (1024_u64.pow(4), &s[..s.len() - 2])
+    } else if s.ends_with("kb") {
+        (1000_u64, &s[..s.len() - 2])
+    } else if s.ends_with("mb") {
+        (1000_u64.pow(2), &s[..s.len() - 2])
+    } else if s.ends_with("gb") {
+        (1000_u64.pow(3), &s[..s.len() - 2])
+    } else if s.ends_with("tb") {
+        (1000_u64.pow(4), &s[..s.len() - 2])
+    } else {
+        (1_u64, s.as_str())
+    };
+
+    let number: f64 = number_str
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid size format: {}", s))?;
+
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Convert bytes into a human-friendly string using binary (KiB, MiB, GiB...) units.
+pub fn encode_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes == 0 {
+        return "0 B".to_string();
+    }
+
+    let mut size = bytes as f64;
+    let mut unit_index = 0;
+
+    while size >= 1024.0 && unit_index < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit_index += 1;
+    }
+
+    // Format with one decimal if needed (e.g., 1.0 MiB → 1 MiB)
+    if (size * 10.0) % 10.0 == 0.0 {
+        format!("{:.0} {}", size, UNITS[unit_index])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit_index])
+    }
+}
+
+/// Joins an archive or snapshot entry name onto `target`, rejecting absolute
+/// paths and `..` so that restoring can't write outside of it.
+pub fn safe_join(target: &Path, name: &str) -> Result<PathBuf> {
+    let relative = Path::new(name);
+    if relative
+        .components()
+        .any(|c| !matches!(c, std::path::Component::Normal(_)))
+    {
+        bail!("refusing to restore unsafe path {name}");
+    }
+    Ok(target.join(relative))
+}
+
+/// The catalog recording every run: `config.catalog`, else
+/// `$XDG_DATA_HOME/ssbt/catalog.json` or `~/.local/share/ssbt/catalog.json`.
+/// `None` if it is turned off (`catalog: off`) or there is no home directory
+/// to keep it in.
+pub fn catalog_path(config: &Config) -> Option<PathBuf> {
+    match config.catalog.as_deref() {
+        Some("off" | "false" | "none") => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(
+            std::env::var_os("XDG_DATA_HOME")
+                .filter(|dir| !dir.is_empty())
+                .map(PathBuf::from)
+                .or_else(|| {
+                    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share"))
+                })
+                // Windows
+                .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))?
+                .join("ssbt/catalog.json"),
+        ),
+    }
+}
+
+/// Writes `data` to a temporary file next to `path` and renames it into place,
+/// so an interrupted run never leaves a truncated blob or index behind.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+    temp.write_all(data)?;
+    temp.as_file().sync_all()?;
+    temp.persist(path)?;
+    Ok(())
+}
@@ -0,0 +1,151 @@
+//! Incremental backups: only files that changed since a previous run, as
+//! recorded in its manifest, are archived.
+
+use anyhow::{Context, Result, bail};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use crate::Config;
+use crate::fetch::{decrypt_archive, fetch_archive};
+use crate::packaging::manifest::{
+    Manifest, ManifestBase, ManifestEntry, is_manifest, read_manifest, sha256,
+};
+use crate::packaging::{ArchiveEntry, EntryVisitor, visit_entries};
+
+/// Reads the manifest of a previous run from `location`: a manifest file, or
+/// an archive containing one, as a path or any URL `ssbt restore` accepts.
+pub async fn load_manifest(
+    location: &str,
+    config: &Config,
+    zip_password: Option<&str>,
+) -> Result<Manifest> {
+    let file = fetch_archive(location, config).await?;
+    let file = decrypt_archive(file, &[], config)?;
+    manifest_of(file.path(), zip_password)
+        .with_context(|| format!("reading {location}"))?
+        .with_context(|| format!("{location} contains no ssbt-manifest.json"))
+}
+
+/// Reads the manifest file at `path`, or the manifest inside the archive at
+/// `path`; `None` if the archive holds none.
+pub fn manifest_of(path: &Path, zip_password: Option<&str>) -> Result<Option<Manifest>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let starts_with_brace = reader
+        .fill_buf()?
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .is_some_and(|b| *b == b'{');
+    if starts_with_brace {
+        return read_manifest(&mut reader).map(Some);
+    }
+
+    let mut finder = ManifestFinder(None);
+    visit_entries(path, zip_password, &mut finder)?;
+    Ok(finder.0)
+}
+
+struct ManifestFinder(Option<Manifest>);
+
+impl EntryVisitor for ManifestFinder {
+    fn wants(&self, name: &str) -> bool {
+        is_manifest(name)
+    }
+
+    fn visit(&mut self, _entry: &ArchiveEntry, data: &mut dyn Read) -> Result<()> {
+        self.0 = Some(read_manifest(data)?);
+        Ok(())
+    }
+}
+
+/// Drops the entries (archive name, path) that are unchanged since
+/// `previous`, recording in `manifest` what was left out and what was deleted.
+///
+/// A file is unchanged if its size and modification time match; if only the
+/// time differs, its SHA-256 decides.
+pub fn select_changed(
+    entries: Vec<(String, PathBuf)>,
+    previous: &Manifest,
+    location: &str,
+    manifest: &mut Manifest,
+) -> Result<Vec<(String, PathBuf)>> {
+    let known: HashMap<&Path, &ManifestEntry> = previous
+        .files
+        .iter()
+        .chain(&previous.unchanged)
+        .map(|entry| (entry.source.as_path(), entry))
+        .collect();
+
+    let mut seen = HashSet::new();
+    let mut changed = Vec::new();
+    for (name, path) in entries {
+        let source = std::path::absolute(&path)?;
+        seen.insert(source.clone());
+        let Some(old) = known.get(source.as_path()) else {
+            changed.push((name, path));
+            continue;
+        };
+        // Unreadable files are left to the archive writer's read error policy
+        let Ok(metadata) = path.metadata() else {
+            changed.push((name, path));
+            continue;
+        };
+        let modified = metadata.modified().ok().map(Into::into);
+        let same = metadata.len() == old.size
+            && (modified == old.modified || same_contents(&path, &old.sha256));
+        if same {
+            manifest.unchanged.push(ManifestEntry {
+                name,
+                modified,
+                ..(*old).clone()
+            });
+        } else {
+            changed.push((name, path));
+        }
+    }
+
+    manifest.deleted = known
+        .iter()
+        .filter(|(source, _)| !seen.contains(**source))
+        .map(|(_, entry)| entry.name.clone())
+        .collect();
+    manifest.deleted.sort();
+    manifest.base = Some(ManifestBase {
+        location: location.to_string(),
+        created: previous.created,
+    });
+    Ok(changed)
+}
+
+fn same_contents(path: &Path, sha256_hex: &str) -> bool {
+    File::open(path)
+        .and_then(|mut file| sha256(&mut file))
+        .is_ok_and(|(_, hash)| hash == sha256_hex)
+}
+
+/// Checks that the incremental options are used together.
+pub fn validate(config: &Config, watching: bool) -> Result<()> {
+    let incremental = config.incremental.unwrap_or(false);
+    match (incremental, &config.since_manifest) {
+        // `ssbt watch` starts with a full backup, the base of the next one
+        (true, None) if watching => validate(
+            &Config {
+                since_manifest: Some(String::new()),
+                ..config.clone()
+            },
+            false,
+        ),
+        (true, None) => bail!("incremental backups need since_manifest (--since-manifest)"),
+        (false, Some(_)) => bail!("since_manifest is only used with incremental (--incremental)"),
+        (true, Some(_)) if config.manifest == Some(false) => {
+            bail!("incremental backups need the manifest, remove manifest: false / --no-manifest")
+        }
+        (true, Some(_)) if config.repo.is_some() => {
+            bail!("incremental is not supported for repositories, which deduplicate anyway")
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,105 @@
+//! The resume journal: while an archive is written to a local output,
+//! `<archive>.journal` records the run's configuration and every file
+//! entered into the archive. A run that completes (or fails cleanly) removes
+//! it; one that was killed or lost power leaves it behind for `ssbt resume`.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::warn;
+
+use crate::Config;
+use crate::packaging::manifest::{Manifest, ManifestEntry};
+
+pub const EXTENSION: &str = "journal";
+
+/// First line of a journal.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalHeader {
+    /// The archive being written, volumes `<archive>.001`, ... with `split`.
+    pub archive: PathBuf,
+    pub split: bool,
+    /// The merged configuration of the run, secrets included.
+    pub config: Config,
+    /// The manifest before any file was added: incremental runs already
+    /// know their base and unchanged files.
+    pub manifest: Manifest,
+}
+
+/// A journal being written.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+/// `<archive>.journal`
+pub fn journal_path(archive: &Path) -> PathBuf {
+    let mut name = archive.as_os_str().to_owned();
+    name.push(format!(".{EXTENSION}"));
+    PathBuf::from(name)
+}
+
+impl Journal {
+    pub fn create(header: &JournalHeader) -> Result<Self> {
+        let path = journal_path(&header.archive);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // The configuration may hold passwords
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(&path)
+            .with_context(|| format!("creating {}", path.display()))?;
+        writeln!(file, "{}", serde_json::to_string(header)?)?;
+        file.sync_data()?;
+        Ok(Journal {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Records a file entered into the archive. The entry may still be on
+    /// its way to the disk; `ssbt resume` checks the archive itself.
+    pub fn record(&self, entry: &ManifestEntry) -> Result<()> {
+        let line = format!("{}\n", serde_json::to_string(entry)?);
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(line.as_bytes())
+            .with_context(|| format!("writing {}", self.path.display()))
+    }
+
+    /// Removes the journal once the archive was completed or cleaned up.
+    pub fn remove(&self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("failed to remove {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// Reads a journal left behind by an interrupted run. A last line cut off
+/// mid-write is ignored.
+pub fn read(path: &Path) -> Result<(JournalHeader, Vec<ManifestEntry>)> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut lines = BufReader::new(file).lines();
+    let header = lines
+        .next()
+        .with_context(|| format!("{} is empty", path.display()))??;
+    let header: JournalHeader =
+        serde_json::from_str(&header).with_context(|| format!("reading {}", path.display()))?;
+    let mut entries = Vec::new();
+    for line in lines {
+        match serde_json::from_str(&line?) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => break,
+        }
+    }
+    Ok((header, entries))
+}
@@ -0,0 +1,372 @@
+//! `k8s`: the resources of Kubernetes namespaces, read through the API
+//! server and stored as one multi-document YAML entry per namespace,
+//! `k8s/<namespace>.yaml`, that `kubectl apply -f` takes back. Run as a
+//! CronJob in the cluster, ssbt finds the API server and its service account
+//! token on its own. The contents of PersistentVolumeClaims are backed up by
+//! mounting them into the job's pod and listing the mount paths in `paths`.
+//!
+//! Objects are cleaned of what the cluster fills in (`status`, `uid`,
+//! `resourceVersion`, `managedFields`...), and objects a controller owns
+//! (the Jobs of a CronJob, say) are left out, their owner recreates them.
+
+use anyhow::{Context, Result, anyhow, bail};
+use serde_json::Value;
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::packaging::streamed::{StreamSource, StreamedEntry};
+use crate::secrets::resolve_secret;
+use crate::{Config, KubernetesConfig};
+
+/// Where Kubernetes mounts the service account of a pod.
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Types saved when `k8s.resources` isn't set.
+const DEFAULT_RESOURCES: &[&str] = &[
+    "configmaps",
+    "secrets",
+    "serviceaccounts",
+    "services",
+    "persistentvolumeclaims",
+    "deployments",
+    "statefulsets",
+    "daemonsets",
+    "cronjobs",
+    "jobs",
+    "ingresses",
+    "networkpolicies",
+    "roles",
+    "rolebindings",
+    "horizontalpodautoscalers",
+    "poddisruptionbudgets",
+];
+
+/// Metadata the cluster fills in, which `kubectl apply` refuses or ignores.
+const GENERATED_METADATA: &[&str] = &[
+    "creationTimestamp",
+    "generation",
+    "managedFields",
+    "resourceVersion",
+    "selfLink",
+    "uid",
+];
+
+const LAST_APPLIED: &str = "kubectl.kubernetes.io/last-applied-configuration";
+
+/// The API group version of the resource types known by their name alone.
+fn group_version(resource: &str) -> Option<&'static str> {
+    Some(match resource {
+        "configmaps"
+        | "secrets"
+        | "serviceaccounts"
+        | "services"
+        | "persistentvolumeclaims"
+        | "limitranges"
+        | "resourcequotas" => "v1",
+        "deployments" | "statefulsets" | "daemonsets" => "apps/v1",
+        "cronjobs" | "jobs" => "batch/v1",
+        "ingresses" | "networkpolicies" => "networking.k8s.io/v1",
+        "roles" | "rolebindings" => "rbac.authorization.k8s.io/v1",
+        "horizontalpodautoscalers" => "autoscaling/v2",
+        "poddisruptionbudgets" => "policy/v1",
+        _ => return None,
+    })
+}
+
+/// The API path listing `resource` in `namespace`.
+fn collection_path(resource: &str, namespace: &str) -> Result<String> {
+    let (group_version, plural) = match resource.rsplit_once('/') {
+        Some((group_version, plural)) => (group_version, plural),
+        None => (
+            group_version(resource).ok_or_else(|| {
+                anyhow!("unknown resource type {resource} (name it group/version/{resource})")
+            })?,
+            resource,
+        ),
+    };
+    // The core group is served under /api, the others under /apis
+    let root = match group_version.contains('/') {
+        true => "apis",
+        false => "api",
+    };
+    Ok(format!(
+        "/{root}/{group_version}/namespaces/{namespace}/{plural}"
+    ))
+}
+
+/// Fills in `k8s.namespaces`: `*` becomes every namespace of the cluster,
+/// and none the namespace of the pod ssbt runs in. Blocks on the API server.
+pub fn resolve_namespaces(config: &mut Config) -> Result<()> {
+    let Some(k8s) = &mut config.k8s else {
+        return Ok(());
+    };
+    let mut namespaces = k8s.namespaces.clone().unwrap_or_default();
+    if namespaces.is_empty() {
+        let own = fs::read_to_string(format!("{SERVICE_ACCOUNT}/namespace"))
+            .context("k8s.namespaces isn't set and ssbt isn't running in a pod")?;
+        namespaces.push(own.trim().to_string());
+    }
+    if namespaces.iter().any(|namespace| namespace == "*") {
+        namespaces = Cluster::connect(k8s)?
+            .namespaces()
+            .context("listing the namespaces")?;
+    }
+    k8s.namespaces = Some(namespaces);
+    Ok(())
+}
+
+/// An entry for each namespace of `config.k8s`, resolved by
+/// [`resolve_namespaces`].
+pub fn streamed_entries(config: &Config) -> Result<Vec<StreamedEntry>> {
+    let Some(k8s) = &config.k8s else {
+        return Ok(Vec::new());
+    };
+    let resources: Vec<String> = match &k8s.resources {
+        Some(resources) if !resources.is_empty() => resources.clone(),
+        _ => DEFAULT_RESOURCES.iter().map(|r| r.to_string()).collect(),
+    };
+    // Unknown types fail now rather than halfway through the archive
+    for resource in &resources {
+        collection_path(resource, "default").context("k8s.resources")?;
+    }
+    let namespaces = k8s.namespaces.clone().unwrap_or_default();
+    if namespaces.is_empty() || namespaces.iter().any(|namespace| namespace == "*") {
+        bail!("k8s.namespaces must be resolved first");
+    }
+    let resources = Arc::new(resources);
+    Ok(namespaces
+        .into_iter()
+        .map(|namespace| {
+            let k8s = k8s.clone();
+            let resources = Arc::clone(&resources);
+            StreamedEntry {
+                name: format!("k8s/{namespace}.yaml"),
+                source: StreamSource::Generated {
+                    what: format!("the resources of Kubernetes namespace {namespace}"),
+                    generate: Arc::new(move |out| {
+                        Cluster::connect(&k8s)?.write_namespace(&namespace, &resources, out)
+                    }),
+                },
+            }
+        })
+        .collect())
+}
+
+/// Talks to the Kubernetes API server.
+struct Cluster {
+    client: reqwest::blocking::Client,
+    base: String,
+    token: Option<String>,
+}
+
+impl Cluster {
+    fn connect(config: &KubernetesConfig) -> Result<Self> {
+        let base = match &config.api_server {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => {
+                let host = std::env::var("KUBERNETES_SERVICE_HOST").map_err(|_| {
+                    anyhow!("k8s.api_server isn't set and ssbt isn't running in a pod")
+                })?;
+                let port =
+                    std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_string());
+                match host.contains(':') {
+                    true => format!("https://[{host}]:{port}"),
+                    false => format!("https://{host}:{port}"),
+                }
+            }
+        };
+        let token = match &config.token {
+            Some(token) => Some(resolve_secret(token)?),
+            // Projected tokens are rotated, it's read again on every run
+            None => fs::read_to_string(format!("{SERVICE_ACCOUNT}/token"))
+                .ok()
+                .map(|token| token.trim().to_string()),
+        };
+        let mut builder = reqwest::blocking::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(60));
+        let ca_cert = config.ca_cert.clone().or_else(|| {
+            let own = format!("{SERVICE_ACCOUNT}/ca.crt");
+            Path::new(&own).is_file().then_some(own)
+        });
+        if let Some(path) = ca_cert {
+            let pem = fs::read(&path).with_context(|| format!("reading {path}"))?;
+            let certificate = reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("invalid certificate {path}"))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+        Ok(Cluster {
+            client: builder.build()?,
+            base,
+            token,
+        })
+    }
+
+    /// The object at `path`, or `None` if there is none.
+    fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<Option<Value>> {
+        let mut request = self.client.get(format!("{}{path}", self.base)).query(query);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send()?;
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            let body: Value = serde_json::from_reader(response).unwrap_or_default();
+            match body["message"].as_str() {
+                Some(message) => bail!("{message}"),
+                None => bail!("the API server returned status {status}"),
+            }
+        }
+        Ok(Some(serde_json::from_reader(response)?))
+    }
+
+    /// Every object of the collection at `path`, with its `apiVersion` and
+    /// `kind`, or `None` if the API server doesn't serve it.
+    fn list(&self, path: &str) -> Result<Option<Vec<Value>>> {
+        let mut objects = Vec::new();
+        let mut next = String::new();
+        loop {
+            let mut query = vec![("limit", "500")];
+            if !next.is_empty() {
+                query.push(("continue", &next));
+            }
+            let Some(mut page) = self.get(path, &query)? else {
+                return Ok(None);
+            };
+            // Objects in a list don't repeat what they are
+            let api_version = page["apiVersion"].clone();
+            let kind = page["kind"]
+                .as_str()
+                .and_then(|kind| kind.strip_suffix("List"))
+                .map(|kind| Value::String(kind.to_string()))
+                .unwrap_or_default();
+            if let Value::Array(items) = page["items"].take() {
+                for mut object in items {
+                    object["apiVersion"] = api_version.clone();
+                    object["kind"] = kind.clone();
+                    objects.push(object);
+                }
+            }
+            match page["metadata"]["continue"].as_str() {
+                Some(token) if !token.is_empty() => next = token.to_string(),
+                _ => return Ok(Some(objects)),
+            }
+        }
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let namespaces = self
+            .list("/api/v1/namespaces")?
+            .ok_or_else(|| anyhow!("the API server serves no namespaces"))?;
+        Ok(namespaces
+            .iter()
+            .filter_map(|namespace| namespace["metadata"]["name"].as_str())
+            .map(str::to_string)
+            .collect())
+    }
+
+    /// Writes the objects of `resources` in `namespace` as YAML documents.
+    fn write_namespace(
+        &self,
+        namespace: &str,
+        resources: &[String],
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        // Listing a namespace that doesn't exist finds nothing rather than failing
+        if self
+            .get(&format!("/api/v1/namespaces/{namespace}"), &[])?
+            .is_none()
+        {
+            bail!("namespace {namespace} doesn't exist");
+        }
+        let mut out = BufWriter::new(out);
+        for resource in resources {
+            let path = collection_path(resource, namespace)?;
+            let Some(objects) = self
+                .list(&path)
+                .with_context(|| format!("listing {resource} in {namespace}"))?
+            else {
+                warn!("The API server doesn't serve {resource}, skipping them");
+                continue;
+            };
+            for mut object in objects.into_iter().filter(is_kept) {
+                clean(&mut object);
+                out.write_all(b"---\n")?;
+                serde_yaml::to_writer(&mut out, &in_kubectl_order(object)?)?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Leaves out objects the cluster makes on its own: those a controller owns,
+/// service account tokens and the CA bundle of every namespace.
+fn is_kept(object: &Value) -> bool {
+    let metadata = &object["metadata"];
+    let owned = metadata["ownerReferences"]
+        .as_array()
+        .is_some_and(|owners| owners.iter().any(|owner| owner["controller"] == true));
+    let generated = match object["kind"].as_str() {
+        Some("Secret") => object["type"] == "kubernetes.io/service-account-token",
+        Some("ConfigMap") => metadata["name"] == "kube-root-ca.crt",
+        _ => false,
+    };
+    !owned && !generated
+}
+
+/// Removes what the cluster fills in, so the object applies to a new cluster.
+fn clean(object: &mut Value) {
+    let Some(fields) = object.as_object_mut() else {
+        return;
+    };
+    fields.remove("status");
+    if let Some(metadata) = fields.get_mut("metadata").and_then(Value::as_object_mut) {
+        for field in GENERATED_METADATA {
+            metadata.remove(*field);
+        }
+        if let Some(annotations) = metadata
+            .get_mut("annotations")
+            .and_then(Value::as_object_mut)
+        {
+            annotations.remove(LAST_APPLIED);
+        }
+        if metadata
+            .get("annotations")
+            .and_then(Value::as_object)
+            .is_some_and(|annotations| annotations.is_empty())
+        {
+            metadata.remove("annotations");
+        }
+    }
+    // The addresses of a Service are allocated, another cluster may not have them
+    if fields.get("kind").is_some_and(|kind| kind == "Service")
+        && let Some(spec) = fields.get_mut("spec").and_then(Value::as_object_mut)
+    {
+        spec.remove("clusterIP");
+        spec.remove("clusterIPs");
+    }
+}
+
+/// `object` with `apiVersion`, `kind` and `metadata` first, as `kubectl`
+/// prints it.
+fn in_kubectl_order(object: Value) -> Result<serde_yaml::Value> {
+    let serde_yaml::Value::Mapping(mut fields) = serde_yaml::to_value(object)? else {
+        bail!("the API server returned an object that isn't a mapping");
+    };
+    let mut ordered = serde_yaml::Mapping::new();
+    for key in ["apiVersion", "kind", "metadata"] {
+        if let Some(value) = fields.remove(key) {
+            ordered.insert(key.into(), value);
+        }
+    }
+    ordered.extend(fields);
+    Ok(serde_yaml::Value::Mapping(ordered))
+}
@@ -0,0 +1,222 @@
+use anyhow::{Context, Result, bail};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{Datelike, Local, Timelike, Utc};
+use glob::Pattern;
+use rand::Rng;
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::Config;
+
+/// Expands `input` into the path of an archive: placeholders in its
+/// directories as well as its file name, which directories get a default
+/// for (see [`split_template`]).
+pub fn create_file_name(input: &str, config: &Config) -> Result<PathBuf> {
+    let (dir, file_name_template) = split_template(input)?;
+    let values = replacements(config);
+    let dir = expand_with(&dir.to_string_lossy(), &values)?;
+    Ok(PathBuf::from(dir).join(expand_with(&file_name_template, &values)?))
+}
+
+/// Replaces the placeholders in `template` (case-insensitive), and every
+/// `%{format}` with the UTC time in that strftime format, leaving the rest
+/// as it is. Fails on an invalid format.
+pub fn expand_placeholders(template: &str, config: &Config) -> Result<String> {
+    expand_with(template, &replacements(config))
+}
+
+fn expand_with(template: &str, values: &[(String, String)]) -> Result<String> {
+    let now = Utc::now();
+    let mut name = replace_formats(template, |format| {
+        let items: Vec<_> = StrftimeItems::new(format).collect();
+        if items.contains(&Item::Error) {
+            bail!("invalid time format %{{{format}}} in {template}");
+        }
+        Ok(now.format_with_items(items.into_iter()).to_string())
+    })?;
+    for (pattern, value) in values {
+        name = replace_case_insensitive(&name, pattern, value);
+    }
+    Ok(name)
+}
+
+/// Replaces every `%{format}` in `template` with what `value` makes of
+/// the format.
+fn replace_formats(template: &str, value: impl Fn(&str) -> Result<String>) -> Result<String> {
+    let mut result = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("%{")
+        && let Some(len) = rest[start + 2..].find('}')
+    {
+        result.push_str(&rest[..start]);
+        result.push_str(&value(&rest[start + 2..start + 2 + len])?);
+        rest = &rest[start + 3 + len..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Every placeholder with its value for a name created now, for a run with
+/// `config`: the built-in ones, then the variables of `name_vars`.
+fn replacements(config: &Config) -> Vec<(String, String)> {
+    // Current time info
+    let now_utc = Utc::now();
+    let now_local = Local::now();
+
+    let date = now_utc.format("%Y-%m-%d").to_string();
+    let time = now_utc.format("%H-%M-%S").to_string();
+    let datetime = now_utc.format("%Y-%m-%d_%H-%M-%S").to_string();
+    let weekday = now_utc.format("%a").to_string();
+
+    let rand5 = random_string(5);
+    let rand12 = random_string(12);
+
+    let pwd = env::current_dir()
+        .ok()
+        .and_then(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+        .unwrap_or_else(|| "unknown".into());
+    let hostname = hostname::get()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|_| "unknown".into());
+    let user = env::var("USER")
+        .or_else(|_| env::var("USERNAME"))
+        .unwrap_or_else(|_| "unknown".into());
+
+    let builtin = [
+        ("%hostname%", hostname),
+        ("%user%", user),
+        (
+            "%format%",
+            config.format.as_deref().unwrap_or("zip").to_lowercase(),
+        ),
+        (
+            "%profile%",
+            config.profile.clone().unwrap_or_else(|| "default".into()),
+        ),
+        ("%seq%", config.seq.unwrap_or(1).to_string()),
+        ("%datetime%", datetime),
+        ("%rand%", rand5),
+        ("%longrand%", rand12),
+        ("%pwd%", pwd),
+        ("%date%", date),
+        ("%time%", time),
+        ("%hh%", format!("{:02}", now_utc.hour())),
+        ("%mm%", format!("{:02}", now_utc.minute())),
+        ("%ss%", format!("{:02}", now_utc.second())),
+        ("%dd%", format!("{:02}", now_utc.day())),
+        ("%ww%", weekday),
+        ("%yyyy%", format!("{:04}", now_utc.year())),
+        ("%yy%", format!("{:02}", now_utc.year() % 100)),
+        ("%ms%", format!("{:03}", now_utc.timestamp_subsec_millis())),
+        ("%unix%", format!("{}", now_utc.timestamp())),
+        ("%ltime%", now_local.format("%Y-%m-%d_%H-%M-%S").to_string()),
+        ("%lh%", format!("{:02}", now_local.hour())),
+        ("%ld%", format!("{:02}", now_local.day())),
+    ];
+    let vars = config.name_vars.iter().flatten();
+    builtin
+        .into_iter()
+        .map(|(placeholder, value)| (placeholder.to_string(), value))
+        .chain(vars.map(|(name, value)| (format!("%{name}%"), value.clone())))
+        .collect()
+}
+
+/// Splits an output into its directory and the file name template;
+/// directories get the default `backup_%datetime%_%rand%.zip`.
+fn split_template(input: &str) -> Result<(&Path, String)> {
+    let input_path = Path::new(input);
+
+    // Determine if input ends with a file or a directory
+    let is_file = input_path
+        .extension()
+        .map(|ext| !ext.is_empty())
+        .unwrap_or(false);
+
+    if is_file {
+        let name = input_path
+            .file_name()
+            .context("Invalid file name in path")?
+            .to_string_lossy()
+            .to_string();
+        Ok((input_path.parent().unwrap_or_else(|| Path::new(".")), name))
+    } else {
+        Ok((input_path, "backup_%datetime%_%rand%.zip".to_string()))
+    }
+}
+
+/// The directory archives for `input` are written to.
+pub fn output_dir(input: &str) -> Result<PathBuf> {
+    Ok(split_template(input)?.0.to_path_buf())
+}
+
+/// A glob matching every path [`create_file_name`] can produce for `input`,
+/// each placeholder standing for any run of characters within a path
+/// component.
+pub fn name_pattern(input: &str, config: &Config) -> Result<Pattern> {
+    let (dir, name) = split_template(input)?;
+    let placeholders = replacements(config);
+    let dir = dir.to_string_lossy();
+    let pattern = if dir.is_empty() {
+        wildcards(&name, &placeholders)?
+    } else {
+        format!(
+            "{}/{}",
+            wildcards(dir.trim_end_matches('/'), &placeholders)?,
+            wildcards(&name, &placeholders)?
+        )
+    };
+    Ok(Pattern::new(&pattern)?)
+}
+
+/// Escapes `template` for a glob, with `*` for its placeholders and time
+/// formats.
+fn wildcards(template: &str, placeholders: &[(String, String)]) -> Result<String> {
+    let mut template = replace_formats(template, |_| Ok("\0".to_string()))?;
+    for (placeholder, _) in placeholders {
+        template = replace_case_insensitive(&template, placeholder, "\0");
+    }
+    Ok(template
+        .split('\0')
+        .map(Pattern::escape)
+        .collect::<Vec<_>>()
+        .join("*"))
+}
+
+/// The archive `location` is a volume of, for names ending in `.001` and so on.
+pub fn volume_of(location: &str) -> Option<&str> {
+    let (archive, number) = location.rsplit_once('.')?;
+    (number.len() == 3 && number.bytes().all(|b| b.is_ascii_digit())).then_some(archive)
+}
+
+/// Generates a random lowercase alphanumeric string.
+fn random_string(len: usize) -> String {
+    const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+    let mut rng = rand::rng();
+    (0..len)
+        .map(|_| {
+            let idx = rng.random_range(0..CHARS.len());
+            CHARS[idx] as char
+        })
+        .collect()
+}
+
+/// Helper for case-insensitive substring replacement
+fn replace_case_insensitive(s: &str, pattern: &str, replacement: &str) -> String {
+    let mut result = String::new();
+    let lower_s = s.to_lowercase();
+    let lower_pattern = pattern.to_lowercase();
+
+    let mut last_end = 0;
+    let mut search_start = 0;
+
+    while let Some(pos) = lower_s[search_start..].find(&lower_pattern) {
+        let abs_pos = search_start + pos;
+        result.push_str(&s[last_end..abs_pos]);
+        result.push_str(replacement);
+        last_end = abs_pos + pattern.len();
+        search_start = last_end;
+    }
+
+    result.push_str(&s[last_end..]);
+    result
+}
@@ -0,0 +1,185 @@
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use std::fs::Metadata;
+use std::io::{self, Read};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tokio_util::io::SyncIoBridge;
+
+use super::{ArchiveOutput, Packager, SourceEntry, buffer_stream};
+use crate::file_reader::{SourceFile, SourceLink};
+
+/// Size of the chunks of a [`StreamedData`].
+const STREAM_CHUNK: usize = 64 * 1024;
+
+/// Entries handed to a synchronous archive writer, in order.
+pub struct Entries(mpsc::Receiver<(String, SourceEntry)>);
+
+impl Iterator for Entries {
+    type Item = (String, SourceEntry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.blocking_recv()
+    }
+}
+
+/// The contents of a [`SourceEntry::Stream`], read while they are produced.
+/// Ends when the stream does.
+pub struct StreamedData {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for StreamedData {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let count = buf.len().min(self.chunk.len() - self.position);
+        buf[..count].copy_from_slice(&self.chunk[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+/// Adapts a synchronous archive writer (the `tar`, `zip` and `sevenz-rust`
+/// crates) to [`Packager`]: the writer runs on a blocking thread, receives
+/// entries over a channel and writes into the async output through a bridge.
+pub struct BlockingPackager {
+    entries: mpsc::Sender<(String, SourceEntry)>,
+    worker: JoinHandle<Result<()>>,
+    /// Whether the writer handles [`SourceEntry::Hardlink`].
+    hardlinks: bool,
+    /// Whether the writer handles [`SourceEntry::Stream`].
+    streams: bool,
+}
+
+impl BlockingPackager {
+    /// Starts `write` on a blocking thread. It must consume all entries and
+    /// shut the bridge down once the archive is complete.
+    pub fn spawn<F>(output: ArchiveOutput, write: F) -> Self
+    where
+        F: FnOnce(SyncIoBridge<ArchiveOutput>, Entries) -> Result<()> + Send + 'static,
+    {
+        // Each queued entry holds an open file, so keep the queue short
+        let (sender, receiver) = mpsc::channel(2);
+        let bridge = SyncIoBridge::new(output);
+        let worker = tokio::task::spawn_blocking(move || write(bridge, Entries(receiver)));
+        BlockingPackager {
+            entries: sender,
+            worker,
+            hardlinks: false,
+            streams: false,
+        }
+    }
+
+    /// Hands hard links to the writer too, for formats with link entries.
+    pub fn with_hardlinks(mut self) -> Self {
+        self.hardlinks = true;
+        self
+    }
+
+    /// Hands streams to the writer as they are produced, for formats that
+    /// don't need the size of entries up front. Others get them through a
+    /// temporary file.
+    pub fn with_streams(mut self) -> Self {
+        self.streams = true;
+        self
+    }
+
+    async fn worker_result(worker: &mut JoinHandle<Result<()>>) -> Result<()> {
+        worker.await.map_err(|e| anyhow!(e))?
+    }
+
+    async fn send(&mut self, name: &str, entry: SourceEntry) -> Result<()> {
+        if self.entries.send((name.to_string(), entry)).await.is_err() {
+            // The writer only hangs up early when it failed
+            Self::worker_result(&mut self.worker).await?;
+            return Err(anyhow!("archive writer stopped before {name} was added"));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Packager for BlockingPackager {
+    async fn add_entry(&mut self, name: &str, source: SourceFile) -> Result<()> {
+        self.send(name, SourceEntry::File(source)).await
+    }
+
+    async fn add_stream(
+        &mut self,
+        name: &str,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<()> {
+        if !self.streams {
+            let source = buffer_stream(name, reader).await?;
+            return self.add_entry(name, source).await;
+        }
+        // A short queue, so a fast producer waits for the writer
+        let (sender, chunks) = mpsc::channel(4);
+        let data = StreamedData {
+            chunks,
+            chunk: Vec::new(),
+            position: 0,
+        };
+        self.send(name, SourceEntry::Stream(data)).await?;
+        let mut buffer = vec![0; STREAM_CHUNK];
+        loop {
+            let read = reader
+                .read(&mut buffer)
+                .await
+                .with_context(|| format!("reading {name}"))?;
+            if read == 0 {
+                return Ok(());
+            }
+            if sender.send(buffer[..read].to_vec()).await.is_err() {
+                Self::worker_result(&mut self.worker).await?;
+                return Err(anyhow!("archive writer stopped while {name} was added"));
+            }
+        }
+    }
+
+    async fn add_symlink(&mut self, name: &str, link: SourceLink) -> Result<()> {
+        self.send(name, SourceEntry::Symlink(link)).await
+    }
+
+    async fn add_directory(&mut self, name: &str, metadata: &Metadata) -> Result<()> {
+        self.send(name, SourceEntry::Directory(metadata.clone()))
+            .await
+    }
+
+    async fn add_hardlink(
+        &mut self,
+        name: &str,
+        original: &str,
+        metadata: &Metadata,
+    ) -> Result<bool> {
+        if !self.hardlinks {
+            return Ok(false);
+        }
+        let entry = SourceEntry::Hardlink {
+            original: original.to_string(),
+            metadata: metadata.clone(),
+        };
+        self.send(name, entry).await?;
+        Ok(true)
+    }
+
+    async fn finish(self: Box<Self>) -> Result<()> {
+        let BlockingPackager {
+            entries,
+            mut worker,
+            ..
+        } = *self;
+        drop(entries);
+        Self::worker_result(&mut worker).await
+    }
+}
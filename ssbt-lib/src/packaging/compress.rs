@@ -0,0 +1,105 @@
+use anyhow::{Result, anyhow, bail};
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
+use std::{io::Read, str::FromStr};
+
+use super::{ArchiveFormat, ArchiveOutput};
+use crate::Config;
+
+/// Compression applied to the whole archive stream (as opposed to zip's
+/// per-entry compression).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamCompression {
+    /// `.tar.gz`, readable everywhere.
+    #[default]
+    Gzip,
+    /// `.tar.zst`, faster and smaller than gzip.
+    Zstd,
+}
+
+impl FromStr for StreamCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "gzip" | "gz" => Ok(StreamCompression::Gzip),
+            "zstd" | "zst" => Ok(StreamCompression::Zstd),
+            other => Err(anyhow!(
+                "unsupported compression_algorithm: {other} (expected gzip or zstd)"
+            )),
+        }
+    }
+}
+
+impl StreamCompression {
+    /// Picks the stream compression for `format` from `compress` and
+    /// `compression_algorithm`. Zip and 7z archives compress per entry and never get one.
+    pub fn from_config(config: &Config, format: ArchiveFormat) -> Result<Option<Self>> {
+        let algorithm = config.compression_algorithm.as_deref();
+        match format {
+            ArchiveFormat::Zip => match algorithm {
+                None => Ok(None),
+                Some(a) if a.eq_ignore_ascii_case("deflate") => Ok(None),
+                Some(other) => bail!(
+                    "compression_algorithm {other} is only supported for tar, zip archives use deflate"
+                ),
+            },
+            ArchiveFormat::SevenZ => match algorithm {
+                None => Ok(None),
+                Some(a) if a.eq_ignore_ascii_case("lzma2") => Ok(None),
+                Some(other) => bail!(
+                    "compression_algorithm {other} is only supported for tar, 7z archives use lzma2"
+                ),
+            },
+            ArchiveFormat::Tar if config.compress.unwrap_or(false) => Ok(Some(
+                algorithm.map(str::parse).transpose()?.unwrap_or_default(),
+            )),
+            ArchiveFormat::Tar => Ok(None),
+            ArchiveFormat::Custom(name) => match algorithm {
+                None => Ok(None),
+                Some(other) => bail!("compression_algorithm {other} is not supported for {name}"),
+            },
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            StreamCompression::Gzip => "application/gzip",
+            StreamCompression::Zstd => "application/zstd",
+        }
+    }
+
+    /// Detects a compressed stream from its first bytes.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Some(StreamCompression::Gzip)
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(StreamCompression::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+/// Wraps `output` so everything written to it is compressed on the fly.
+/// Shutting the returned writer down writes the compressed trailer.
+pub fn compress_writer(compression: StreamCompression, output: ArchiveOutput) -> ArchiveOutput {
+    match compression {
+        StreamCompression::Gzip => Box::new(GzipEncoder::new(output)),
+        StreamCompression::Zstd => Box::new(ZstdEncoder::new(output)),
+    }
+}
+
+/// Wraps a synchronous reader so a compressed stream is transparently decompressed.
+pub fn decompress_reader<'a, R>(
+    compression: Option<StreamCompression>,
+    input: R,
+) -> Result<Box<dyn Read + 'a>>
+where
+    R: Read + 'a,
+{
+    Ok(match compression {
+        Some(StreamCompression::Gzip) => Box::new(flate2::read::GzDecoder::new(input)),
+        Some(StreamCompression::Zstd) => Box::new(zstd::stream::read::Decoder::new(input)?),
+        None => Box::new(input),
+    })
+}
@@ -0,0 +1,219 @@
+use age::secrecy::SecretString;
+use anyhow::{Context, Result, bail};
+use std::{
+    fmt, fs,
+    future::Future,
+    io::{self, BufRead, IsTerminal, Read},
+    pin::Pin,
+    task::{Context as TaskContext, Poll, ready},
+};
+use tokio::io::AsyncWrite;
+use tokio_util::compat::{Compat, FuturesAsyncWriteCompatExt, TokioAsyncWriteCompatExt};
+
+use super::ArchiveOutput;
+use crate::Config;
+use crate::secrets::resolve_secret;
+
+/// First line of every age file.
+pub const MAGIC: &[u8] = b"age-encryption.org/v1\n";
+
+/// Encryption of the whole archive stream in the age format. It is applied
+/// after compression and before any sink, so remote storage never sees plaintext.
+#[derive(Debug, Clone)]
+pub struct StreamEncryption {
+    pub recipients: Vec<Recipient>,
+}
+
+/// Someone an archive is encrypted to.
+#[derive(Debug, Clone)]
+pub enum Recipient {
+    /// An X25519 public key (`age1...`).
+    X25519(age::x25519::Recipient),
+    /// A passphrase, stretched with scrypt. Has to be the only recipient.
+    Passphrase(SecretString),
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recipient::X25519(key) => write!(f, "{key}"),
+            Recipient::Passphrase(_) => write!(f, "passphrase"),
+        }
+    }
+}
+
+impl StreamEncryption {
+    /// Reads `encrypt.recipients` or `encrypt.passphrase`; `None` without either.
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        if !is_configured(config) {
+            return Ok(None);
+        }
+        let encrypt = config.encrypt.as_ref().expect("checked above");
+
+        let mut recipients = Vec::new();
+        for value in encrypt.recipients.iter().flatten() {
+            recipients.extend(read_recipients(value)?);
+        }
+        if let Some(passphrase) = encrypt.passphrase.as_deref().filter(|p| !p.is_empty()) {
+            if !recipients.is_empty() {
+                bail!("encrypt.passphrase can't be combined with encrypt.recipients");
+            }
+            let passphrase = resolve_secret(passphrase)?;
+            if passphrase.is_empty() {
+                bail!("encryption passphrase is empty");
+            }
+            recipients.push(Recipient::Passphrase(passphrase.into()));
+        }
+        Ok(Some(StreamEncryption { recipients }))
+    }
+}
+
+/// Whether archives get encrypted, without resolving (or prompting for) secrets.
+pub fn is_configured(config: &Config) -> bool {
+    config.encrypt.as_ref().is_some_and(|encrypt| {
+        encrypt.recipients.as_ref().is_some_and(|r| !r.is_empty())
+            || encrypt.passphrase.as_ref().is_some_and(|p| !p.is_empty())
+    })
+}
+
+fn parse_recipient(value: &str) -> Result<Recipient> {
+    match value.parse() {
+        Ok(key) => Ok(Recipient::X25519(key)),
+        Err(_) => bail!("not an age recipient: {value} (expected age1...)"),
+    }
+}
+
+/// An `age1...` key, or a file listing one per line (`#` comments allowed).
+fn read_recipients(value: &str) -> Result<Vec<Recipient>> {
+    if value.starts_with("age1") {
+        return Ok(vec![parse_recipient(value)?]);
+    }
+    let content =
+        fs::read_to_string(value).with_context(|| format!("reading recipients file {value}"))?;
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_recipient)
+        .collect()
+}
+
+/// Identities for decrypting archives: the given identity files, or else
+/// `encrypt.identities` and `encrypt.passphrase`. Without any, the passphrase
+/// is asked for on the terminal.
+pub fn identities(files: &[String], config: &Config) -> Result<Vec<Box<dyn age::Identity>>> {
+    let encrypt = config.encrypt.clone().unwrap_or_default();
+    let files = match files {
+        [] => encrypt.identities.unwrap_or_default(),
+        files => files.to_vec(),
+    };
+
+    let mut identities = Vec::new();
+    for file in &files {
+        let parsed = age::IdentityFile::from_file(file.clone())
+            .and_then(|parsed| parsed.into_identities().map_err(io::Error::other))
+            .with_context(|| format!("reading identity file {file}"))?;
+        identities.extend(parsed);
+    }
+    if let Some(passphrase) = encrypt.passphrase.as_deref().filter(|p| !p.is_empty()) {
+        identities.push(passphrase_identity(resolve_secret(passphrase)?));
+    }
+    if identities.is_empty() {
+        if !std::io::stdin().is_terminal() {
+            bail!(
+                "archive is encrypted: pass --identity or set encrypt.identities or encrypt.passphrase"
+            );
+        }
+        identities.push(passphrase_identity(resolve_secret("prompt:Passphrase")?));
+    }
+    Ok(identities)
+}
+
+fn passphrase_identity(passphrase: String) -> Box<dyn age::Identity> {
+    Box::new(age::scrypt::Identity::new(passphrase.into()))
+}
+
+/// Whether a file starts like an age encrypted file.
+pub fn is_encrypted(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+/// Opens an age file, using the first of `identities` that matches a recipient.
+pub fn decrypt_reader<R: BufRead>(
+    identities: &[Box<dyn age::Identity>],
+    input: R,
+) -> Result<impl Read> {
+    let decryptor = age::Decryptor::new_buffered(input).context("reading the age header")?;
+    decryptor
+        .decrypt(identities.iter().map(|identity| identity.as_ref()))
+        .context("decrypting the age file key")
+}
+
+/// Wraps `output` so everything written to it is encrypted to `recipients`.
+/// Shutting the returned writer down writes the final chunk.
+pub fn encrypt_writer(recipients: &[Recipient], output: ArchiveOutput) -> Result<ArchiveOutput> {
+    let encryptor = match recipients {
+        [] => bail!("no age recipients given"),
+        [Recipient::Passphrase(passphrase)] => {
+            age::Encryptor::with_user_passphrase(passphrase.clone())
+        }
+        _ => {
+            let keys = recipients
+                .iter()
+                .map(|recipient| match recipient {
+                    Recipient::X25519(key) => Ok(key as &dyn age::Recipient),
+                    Recipient::Passphrase(_) => {
+                        bail!("a passphrase can't be combined with other recipients")
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
+            age::Encryptor::with_recipients(keys.into_iter())?
+        }
+    };
+    Ok(Box::new(EncryptWriter::Header(Box::pin(async move {
+        let writer = encryptor.wrap_async_output(output.compat_write()).await?;
+        Ok(writer.compat_write())
+    }))))
+}
+
+type PayloadWriter = Compat<age::stream::StreamWriter<Compat<ArchiveOutput>>>;
+
+/// The age stream writer, once the header has been written ahead of it.
+enum EncryptWriter {
+    Header(Pin<Box<dyn Future<Output = io::Result<PayloadWriter>> + Send>>),
+    Payload(PayloadWriter),
+}
+
+impl EncryptWriter {
+    fn poll_payload(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<&mut PayloadWriter>> {
+        if let EncryptWriter::Header(header) = self {
+            let payload = ready!(header.as_mut().poll(cx))?;
+            *self = EncryptWriter::Payload(payload);
+        }
+        match self {
+            EncryptWriter::Payload(payload) => Poll::Ready(Ok(payload)),
+            EncryptWriter::Header(_) => unreachable!("header written above"),
+        }
+    }
+}
+
+impl AsyncWrite for EncryptWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let payload = ready!(self.get_mut().poll_payload(cx))?;
+        Pin::new(payload).poll_write(cx, data)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let payload = ready!(self.get_mut().poll_payload(cx))?;
+        Pin::new(payload).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let payload = ready!(self.get_mut().poll_payload(cx))?;
+        Pin::new(payload).poll_shutdown(cx)
+    }
+}
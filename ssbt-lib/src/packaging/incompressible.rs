@@ -0,0 +1,115 @@
+//! Recognizes files that are compressed already, like photos, videos and
+//! other archives, so zip archives store them instead of deflating them once
+//! more for next to no gain.
+
+use std::{
+    collections::HashSet,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::Config;
+
+/// Extensions stored without compression unless `no_compress_extensions`
+/// says otherwise.
+pub const DEFAULT_EXTENSIONS: &[&str] = &[
+    // Images
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "heif", "avif", "jxl",
+    // Audio and video
+    "mp3", "m4a", "aac", "ogg", "opus", "flac", "mp4", "m4v", "mkv", "mov", "avi", "webm",
+    // Archives and compressed files
+    "zip", "gz", "tgz", "bz2", "xz", "txz", "zst", "lz4", "7z", "rar", "jar", "apk",
+    // Documents that are zip archives
+    "docx", "xlsx", "pptx", "odt", "ods", "odp", "epub",
+];
+
+/// How much of a file is read to estimate its entropy.
+const SAMPLE_SIZE: u64 = 64 * 1024;
+/// Shorter samples say too little; such files are cheap to compress anyway.
+const MIN_SAMPLE: usize = 4096;
+/// Bits per byte above which a sample looks compressed (or encrypted).
+/// Text stays below 5, executables around 6.
+const ENTROPY_THRESHOLD: f64 = 7.8;
+
+/// Decides which entries of a compressed zip archive are stored as they are:
+/// those with one of `extensions`, and files whose first bytes are as
+/// random as compressed data.
+#[derive(Debug, Clone)]
+pub struct Incompressible {
+    extensions: HashSet<String>,
+}
+
+impl Default for Incompressible {
+    fn default() -> Self {
+        Incompressible::new(DEFAULT_EXTENSIONS.iter().copied())
+    }
+}
+
+impl Incompressible {
+    /// Extensions are matched case-insensitively, with or without their dot.
+    pub fn new<S: AsRef<str>>(extensions: impl IntoIterator<Item = S>) -> Self {
+        Incompressible {
+            extensions: extensions
+                .into_iter()
+                .map(|extension| {
+                    let extension = extension.as_ref().trim();
+                    extension
+                        .strip_prefix('.')
+                        .unwrap_or(extension)
+                        .to_ascii_lowercase()
+                })
+                .filter(|extension| !extension.is_empty())
+                .collect(),
+        }
+    }
+
+    /// `no_compress_extensions`, or [`DEFAULT_EXTENSIONS`].
+    pub fn from_config(config: &Config) -> Self {
+        match &config.no_compress_extensions {
+            Some(extensions) => Incompressible::new(extensions),
+            None => Incompressible::default(),
+        }
+    }
+
+    /// Whether the entry `name` has one of the extensions.
+    pub fn by_name(&self, name: &str) -> bool {
+        Path::new(name)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .is_some_and(|extension| self.extensions.contains(&extension.to_ascii_lowercase()))
+    }
+
+    /// Whether the entry `name` is stored, sampling `file` if the name
+    /// doesn't tell. The file is left where it was.
+    pub fn check<F: Read + Seek>(&self, name: &str, file: &mut F) -> io::Result<bool> {
+        if self.by_name(name) {
+            return Ok(true);
+        }
+        let mut sample = Vec::new();
+        file.by_ref().take(SAMPLE_SIZE).read_to_end(&mut sample)?;
+        file.seek(SeekFrom::Current(-(sample.len() as i64)))?;
+        Ok(looks_compressed(&sample))
+    }
+}
+
+/// Whether the Shannon entropy of `sample` is close to the 8 bits per byte
+/// of random data.
+fn looks_compressed(sample: &[u8]) -> bool {
+    if sample.len() < MIN_SAMPLE {
+        return false;
+    }
+    let mut counts = [0usize; 256];
+    for &byte in sample {
+        counts[byte as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    let entropy: f64 = counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+    entropy > ENTROPY_THRESHOLD
+}
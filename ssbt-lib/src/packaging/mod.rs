@@ -0,0 +1,1157 @@
+use anyhow::{Context, Result, anyhow, bail};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use glob::Pattern;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet, hash_map::Entry},
+    fs::{self, File, Metadata},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, LazyLock, RwLock},
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::Config;
+use crate::docker::DockerSources;
+use crate::file_reader::{SourceFile, SourceLink, SourceReader, read_xattrs};
+use crate::fs_utils::{FileWalk, SymlinkPolicy, safe_join};
+use crate::journal::Journal;
+use crate::process::prepare_entry;
+use crate::progress::Progress;
+use crate::sources;
+use blocking::StreamedData;
+use compress::{StreamCompression, compress_writer};
+use encrypt::StreamEncryption;
+use futures::{Stream, StreamExt, stream, stream::BoxStream};
+use incompressible::Incompressible;
+use manifest::{MANIFEST_NAME, Manifest, ManifestEntry, SkippedEntry};
+use streamed::{StreamSource, StreamedEntry};
+use tracing::{info, warn};
+use zip::ZipEncryption;
+
+pub mod blocking;
+pub mod compress;
+pub mod encrypt;
+pub mod incompressible;
+pub mod manifest;
+pub mod sevenz;
+pub mod sparse;
+pub mod streamed;
+pub mod tar;
+pub mod zip;
+
+/// Destination of an archive. A packager owns it and shuts it down in `finish`.
+pub type ArchiveOutput = Box<dyn AsyncWrite + Unpin + Send>;
+
+/// Writes entries into an archive of one particular format.
+#[async_trait]
+pub trait Packager: Send {
+    /// Appends a file to the archive under `name`.
+    async fn add_entry(&mut self, name: &str, source: SourceFile) -> Result<()>;
+
+    /// Appends an entry holding everything `reader` yields (see
+    /// [`streamed`]). Formats needing the size of entries up front get it
+    /// through a temporary file; zip streams it.
+    async fn add_stream(
+        &mut self,
+        name: &str,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> Result<()> {
+        let source = buffer_stream(name, reader).await?;
+        self.add_entry(name, source).await
+    }
+
+    /// Appends a symlink pointing to `link.target`. Formats without symlink
+    /// entries leave it out with a warning.
+    async fn add_symlink(&mut self, name: &str, link: SourceLink) -> Result<()> {
+        let _ = link;
+        warn!("skipping symlink {name}, the archive format can't store links");
+        Ok(())
+    }
+
+    /// Appends an empty directory. Formats without directory entries leave
+    /// it out with a warning.
+    async fn add_directory(&mut self, name: &str, metadata: &Metadata) -> Result<()> {
+        let _ = metadata;
+        warn!("skipping empty directory {name}, the archive format can't store directories");
+        Ok(())
+    }
+
+    /// Appends `name` as a hard link to the earlier entry `original`, which
+    /// has the same contents. Returns `false` without writing anything if the
+    /// format has no such entries.
+    async fn add_hardlink(
+        &mut self,
+        name: &str,
+        original: &str,
+        metadata: &Metadata,
+    ) -> Result<bool> {
+        let _ = (name, original, metadata);
+        Ok(false)
+    }
+
+    /// Completes the archive and shuts the output down.
+    async fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Copies what `reader` yields into a temporary file, removed once the
+/// returned source is dropped, for [`Packager::add_stream`].
+pub async fn buffer_stream(
+    name: &str,
+    reader: &mut (dyn AsyncRead + Unpin + Send),
+) -> Result<SourceFile> {
+    let (file, path) = tempfile::NamedTempFile::new()?.into_parts();
+    let mut file = tokio::fs::File::from_std(file);
+    tokio::io::copy(reader, &mut file)
+        .await
+        .with_context(|| format!("buffering {name}"))?;
+    let mut file = file.into_std().await;
+    file.seek(io::SeekFrom::Start(0))?;
+    let metadata = file.metadata()?;
+    Ok(SourceFile {
+        file,
+        metadata,
+        copy: Some(path),
+        xattrs: Vec::new(),
+    })
+}
+
+/// Format-independent settings handed to packagers.
+#[derive(Debug, Clone)]
+pub struct PackagerOptions {
+    /// Compress entries (zip: deflate, 7z: stronger LZMA2 preset).
+    pub compress: bool,
+    /// Entries stored as they are when compressing zip archives.
+    pub incompressible: Incompressible,
+    /// Compression of the whole archive stream, for tar.
+    pub stream_compression: Option<StreamCompression>,
+    /// Entry encryption, for zip.
+    pub encryption: Option<ZipEncryption>,
+    /// Encryption of the whole archive stream, after compression.
+    pub stream_encryption: Option<StreamEncryption>,
+    /// Adds `ssbt-manifest.json` as the last entry, listing the archived
+    /// files next to what this template already holds.
+    pub manifest: Option<Manifest>,
+    /// Told about every file as it is archived.
+    pub progress: Option<Arc<Progress>>,
+    /// Records the manifest entry of every file archived, for `ssbt resume`.
+    pub journal: Option<Arc<Journal>>,
+    /// How many files are opened and hashed ahead of the one being written,
+    /// in parallel. 0 counts as 1.
+    pub jobs: usize,
+    /// Store symlinks in the file list as links instead of reading their targets.
+    pub store_symlinks: bool,
+    /// Keep permission bits (tar, zip), ownership and extended attributes (tar).
+    pub preserve_permissions: bool,
+    /// Store files with the same contents once: as hard links in tar, and as
+    /// `duplicate_of` entries in the manifest otherwise.
+    pub dedup: bool,
+    /// Store further names of a hard-linked file as hard links to the first
+    /// (tar).
+    pub hard_links: bool,
+    /// Entries filled with the output of programs, after the files.
+    pub streamed: Vec<StreamedEntry>,
+    /// Files of `docker://` paths, streamed after the files.
+    pub docker: DockerSources,
+}
+
+impl Default for PackagerOptions {
+    fn default() -> Self {
+        PackagerOptions {
+            compress: false,
+            incompressible: Incompressible::default(),
+            stream_compression: None,
+            encryption: None,
+            stream_encryption: None,
+            manifest: None,
+            progress: None,
+            journal: None,
+            jobs: 0,
+            store_symlinks: false,
+            preserve_permissions: true,
+            dedup: false,
+            hard_links: true,
+            streamed: Vec::new(),
+            docker: DockerSources::default(),
+        }
+    }
+}
+
+impl PackagerOptions {
+    /// The archive format `config` asks for and the options to write it
+    /// with: compression, encryption, the manifest and what else the
+    /// packager needs from the configuration.
+    pub fn from_config(config: &Config) -> Result<(ArchiveFormat, Self)> {
+        let format: ArchiveFormat = config.format.as_deref().unwrap_or("zip").parse()?;
+        let mut options = PackagerOptions {
+            compress: config.compress.unwrap_or(false),
+            incompressible: Incompressible::from_config(config),
+            stream_compression: StreamCompression::from_config(config, format)?,
+            encryption: ZipEncryption::from_config(config)?,
+            stream_encryption: StreamEncryption::from_config(config)?,
+            manifest: config
+                .manifest
+                .unwrap_or(true)
+                .then(|| Manifest::new(config)),
+            progress: None,
+            journal: None,
+            jobs: config.jobs.unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, |cpus| cpus.get())
+            }),
+            store_symlinks: SymlinkPolicy::from_config(config)? == SymlinkPolicy::Store,
+            preserve_permissions: config.preserve_permissions.unwrap_or(true),
+            dedup: config.dedup.unwrap_or(false),
+            hard_links: config.hard_links.unwrap_or(true),
+            streamed: sources::streamed_entries(config)?,
+            docker: DockerSources::from_config(config)?,
+        };
+        if let Some(name) = &config.stdin_name {
+            if config.files_from.as_deref() == Some("-") {
+                bail!("stdin_name and files_from can't both read standard input");
+            }
+            options.streamed.insert(
+                0,
+                StreamedEntry {
+                    name: name.clone(),
+                    source: StreamSource::Stdin,
+                },
+            );
+        }
+        if options.encryption.is_some() && format != ArchiveFormat::Zip {
+            bail!("zip_password is only supported for the zip format");
+        }
+        Ok((format, options))
+    }
+}
+
+/// Builds a packager for a custom format.
+pub type PackagerFactory = fn(ArchiveOutput, &PackagerOptions) -> Result<Box<dyn Packager>>;
+
+static CUSTOM_FORMATS: LazyLock<RwLock<HashMap<&'static str, PackagerFactory>>> =
+    LazyLock::new(Default::default);
+
+/// Makes `--format name` produce archives with `factory`. Built-in formats
+/// can't be replaced.
+pub fn register_format(name: &'static str, factory: PackagerFactory) -> Result<()> {
+    if matches!(name, "zip" | "tar" | "7z") {
+        bail!("format {name} is built in and can't be re-registered");
+    }
+    CUSTOM_FORMATS.write().unwrap().insert(name, factory);
+    Ok(())
+}
+
+/// Archive formats ssbt can write. All but custom ones can be read back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+    SevenZ,
+    /// A format added with [`register_format`].
+    Custom(&'static str),
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "zip" => Ok(ArchiveFormat::Zip),
+            "tar" => Ok(ArchiveFormat::Tar),
+            "7z" => Ok(ArchiveFormat::SevenZ),
+            other => CUSTOM_FORMATS
+                .read()
+                .unwrap()
+                .get_key_value(other)
+                .map(|(name, _)| ArchiveFormat::Custom(name))
+                .ok_or_else(|| anyhow!("unsupported format: {other} (expected zip, tar or 7z)")),
+        }
+    }
+}
+
+impl ArchiveFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+            ArchiveFormat::SevenZ => "application/x-7z-compressed",
+            ArchiveFormat::Custom(_) => "application/octet-stream",
+        }
+    }
+}
+
+/// Creates the packager for `format`, writing into `output`.
+pub fn create_packager(
+    format: ArchiveFormat,
+    output: ArchiveOutput,
+    options: &PackagerOptions,
+) -> Result<Box<dyn Packager>> {
+    let output = match &options.stream_encryption {
+        Some(encryption) => encrypt::encrypt_writer(&encryption.recipients, output)?,
+        None => output,
+    };
+    Ok(match format {
+        ArchiveFormat::Zip => zip::zip_packager(
+            output,
+            options.compress,
+            options.incompressible.clone(),
+            options.encryption.clone(),
+            options.preserve_permissions,
+        ),
+        ArchiveFormat::Tar => {
+            let output = match options.stream_compression {
+                Some(compression) => compress_writer(compression, output),
+                None => output,
+            };
+            tar::tar_packager(output, options.preserve_permissions)
+        }
+        ArchiveFormat::SevenZ => sevenz::sevenz_packager(output, options.compress),
+        ArchiveFormat::Custom(name) => {
+            let factory = CUSTOM_FORMATS
+                .read()
+                .unwrap()
+                .get(name)
+                .copied()
+                .ok_or_else(|| anyhow!("format {name} is not registered"))?;
+            factory(output, options)?
+        }
+    })
+}
+
+/// The files an archive is built from, as (archive name, path) pairs.
+#[derive(Clone)]
+pub enum ArchiveFiles {
+    /// Listed before the archive is started.
+    Listed(Vec<(String, PathBuf)>),
+    /// Found by a walk going on while the archive is written, and named
+    /// as [`FileWalk::naming`] says.
+    Walked(FileWalk),
+}
+
+impl ArchiveFiles {
+    /// The files in archive order. A walk starts over every time, so an
+    /// archive rebuilt for a retry gets the files as they are then.
+    pub fn entries(&self) -> BoxStream<'static, Result<(String, PathBuf)>> {
+        match self {
+            ArchiveFiles::Listed(files) => stream::iter(files.clone()).map(Ok).boxed(),
+            ArchiveFiles::Walked(walk) => {
+                let naming = walk.naming();
+                stream::unfold(walk.start(), |mut found| async move {
+                    found.recv().await.map(|file| (file, found))
+                })
+                .map(move |file| file.map(|path| prepare_entry(path, &naming)))
+                .boxed()
+            }
+        }
+    }
+}
+
+/// Streams files into an archive of the given format, returning the manifest
+/// added to it, if any.
+///
+/// Sources are opened through `reader`, so the read error and stabilize
+/// policies apply the same way to every format.
+pub async fn write_archive(
+    format: ArchiveFormat,
+    files: Vec<(String, PathBuf)>,
+    options: &PackagerOptions,
+    reader: &Arc<SourceReader>,
+    output: ArchiveOutput,
+) -> Result<Option<Manifest>> {
+    write_archive_from(format, stream::iter(files).map(Ok), options, reader, output).await
+}
+
+/// [`write_archive`] taking the files as they come, e.g. from a walk still
+/// going on (see [`ArchiveFiles`]). The first error of `files` fails the
+/// archive.
+pub async fn write_archive_from(
+    format: ArchiveFormat,
+    files: impl Stream<Item = Result<(String, PathBuf)>> + Send,
+    options: &PackagerOptions,
+    reader: &Arc<SourceReader>,
+    output: ArchiveOutput,
+) -> Result<Option<Manifest>> {
+    let mut packager = create_packager(format, output, options)?;
+    if let Some(progress) = &options.progress {
+        progress.restart();
+    }
+    let mut manifest = options.manifest.clone();
+    let hash = manifest.is_some() || options.dedup;
+    let store_symlinks = options.store_symlinks;
+    let preserve_permissions = options.preserve_permissions;
+
+    // Upcoming files are opened (and stabilized and hashed) on blocking
+    // threads while the packager writes the current one; `buffered` hands
+    // them over in archive order
+    let mut sources = std::pin::pin!(
+        files
+            .map(|file| {
+                let reader = reader.clone();
+                async move {
+                    let (archive_name, file_path) = file?;
+                    tokio::task::spawn_blocking(move || {
+                        if store_symlinks && let Some(link) = SourceLink::read(&file_path)? {
+                            let link = SourceEntry::Symlink(link);
+                            return Ok((archive_name, file_path, Some((link, None))));
+                        }
+                        if file_path.is_dir() {
+                            let metadata = fs::metadata(&file_path)
+                                .with_context(|| format!("reading {}", file_path.display()))?;
+                            let directory = SourceEntry::Directory(metadata);
+                            return Ok((archive_name, file_path, Some((directory, None))));
+                        }
+                        let Some(mut source) = reader.open(&file_path)? else {
+                            // Skipped by the read error policy
+                            return Ok((archive_name, file_path, None));
+                        };
+                        if preserve_permissions {
+                            source.xattrs = read_xattrs(&file_path);
+                        }
+                        // Hashing reads the file once more, usually from the page cache
+                        let entry = hash
+                            .then(|| ManifestEntry::hash(&archive_name, &file_path, &mut source))
+                            .transpose()?;
+                        let source = SourceEntry::File(source);
+                        anyhow::Ok((archive_name, file_path, Some((source, entry))))
+                    })
+                    .await?
+                }
+            })
+            .buffered(options.jobs.max(1))
+    );
+
+    // Archive name of the first file with each size and hash, for `dedup`
+    let mut originals: HashMap<(u64, String), String> = HashMap::new();
+    // Archive name of the first name of each hard-linked file, by inode
+    let mut linked: HashMap<(u64, u64), String> = HashMap::new();
+
+    while let Some(opened) = sources.next().await {
+        let (archive_name, file_path, opened) = opened?;
+        let Some((source, mut entry)) = opened else {
+            if let Some(manifest) = &mut manifest {
+                manifest.skipped.push(SkippedEntry {
+                    reason: reader.skip_reason(&file_path).unwrap_or_default(),
+                    source: std::path::absolute(&file_path)?,
+                    name: archive_name,
+                });
+            }
+            continue;
+        };
+        let source = match source {
+            SourceEntry::File(source) => source,
+            // Links and directories carry no data, and aren't listed in the manifest
+            SourceEntry::Symlink(link) => {
+                packager.add_symlink(&archive_name, link).await?;
+                continue;
+            }
+            SourceEntry::Directory(metadata) => {
+                packager.add_directory(&archive_name, &metadata).await?;
+                continue;
+            }
+            SourceEntry::Hardlink { .. } | SourceEntry::Stream(_) => {
+                unreachable!("only files, symlinks and directories are opened here")
+            }
+        };
+        let size = source.metadata.len();
+        let copied = source.copy.is_some();
+        let opened = source.metadata.clone();
+        if let Some(progress) = &options.progress {
+            progress.start_file(&archive_name, &source.file);
+        }
+
+        let link_of = inode(&source.metadata)
+            .filter(|_| options.hard_links)
+            .and_then(|inode| match linked.entry(inode) {
+                Entry::Occupied(original) => Some(original.get().clone()),
+                Entry::Vacant(slot) => {
+                    slot.insert(archive_name.clone());
+                    None
+                }
+            });
+        // Further names of a file are links whatever their contents; the
+        // format may have no such entries though
+        let hard_link = match &link_of {
+            Some(original) => {
+                packager
+                    .add_hardlink(&archive_name, original, &source.metadata)
+                    .await?
+            }
+            None => false,
+        };
+
+        let duplicate_of = entry
+            .as_ref()
+            .filter(|entry| options.dedup && entry.size > 0 && !hard_link)
+            .and_then(
+                |entry| match originals.entry((entry.size, entry.sha256.clone())) {
+                    Entry::Occupied(original) => Some(original.get().clone()),
+                    Entry::Vacant(slot) => {
+                        slot.insert(archive_name.clone());
+                        None
+                    }
+                },
+            );
+        let duplicate_of = if hard_link { link_of } else { duplicate_of };
+        let deduplicated = hard_link
+            || match &duplicate_of {
+                // Without link entries, only the manifest tells where the contents are
+                Some(original) => {
+                    packager
+                        .add_hardlink(&archive_name, original, &source.metadata)
+                        .await?
+                        || manifest.is_some()
+                }
+                None => false,
+            };
+        if deduplicated {
+            if let Some(entry) = &mut entry {
+                entry.duplicate_of = duplicate_of;
+                entry.hard_link = hard_link;
+            }
+        } else {
+            packager.add_entry(&archive_name, source).await?;
+        }
+        let changed = match copied {
+            true => reader.is_unstable(&file_path),
+            false => {
+                let reader = reader.clone();
+                tokio::task::spawn_blocking(move || reader.check_unchanged(&file_path, &opened))
+                    .await??
+            }
+        };
+        if let Some(entry) = &mut entry {
+            entry.changed = changed;
+        }
+        if let (Some(manifest), Some(entry)) = (&mut manifest, entry) {
+            if let Some(journal) = &options.journal {
+                journal.record(&entry)?;
+            }
+            manifest.files.push(entry);
+        }
+        if let Some(progress) = &options.progress {
+            progress.finish_file(size);
+        }
+    }
+
+    options.docker.write_into(packager.as_mut()).await?;
+    for streamed in &options.streamed {
+        info!("Streaming {} into {}", streamed.source, streamed.name);
+        let mut running = streamed.spawn()?;
+        packager
+            .add_stream(&streamed.name, &mut running.reader)
+            .await?;
+        running
+            .finish()
+            .await
+            .with_context(|| format!("writing {}", streamed.name))?;
+    }
+
+    if let Some(manifest) = &manifest {
+        packager
+            .add_entry(MANIFEST_NAME, manifest.to_source()?)
+            .await?;
+    }
+    packager.finish().await?;
+    Ok(manifest)
+}
+
+/// Device and inode number of a file with more than one name.
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// What is written for one file of the backup.
+pub enum SourceEntry {
+    File(SourceFile),
+    Symlink(SourceLink),
+    /// A file with the same contents as the earlier entry `original`.
+    Hardlink {
+        original: String,
+        metadata: Metadata,
+    },
+    /// Contents of unknown size, from [`Packager::add_stream`].
+    Stream(StreamedData),
+    /// A directory with nothing backed up in it.
+    Directory(Metadata),
+}
+
+/// A single entry of an existing archive, as shown by `ssbt list`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiveEntry {
+    pub name: String,
+    pub size: u64,
+    /// Stored size inside the archive, if the format records it separately.
+    pub compressed_size: Option<u64>,
+    pub modified: Option<DateTime<Utc>>,
+    pub is_dir: bool,
+    /// Whether the entry content is password protected.
+    pub encrypted: bool,
+    /// Unix permission bits, if the archive recorded them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// Owning user and group ids (tar).
+    #[serde(skip)]
+    pub owner: Option<(u32, u32)>,
+    /// Extended attributes (tar, read while visiting entries only).
+    #[serde(skip)]
+    pub xattrs: Vec<(String, Vec<u8>)>,
+    /// The holes of a sparse file were left out (tar), so restoring it
+    /// leaves them out too.
+    #[serde(skip)]
+    pub sparse: bool,
+}
+
+/// Estimates the size of an archive holding `entries` (archive name, file size).
+///
+/// Container overhead is computed per format; compression is not predicted,
+/// so for compressed archives the result is an upper bound rather than a guess
+/// (see [`estimate_compression_ratio`]).
+pub fn estimate_archive_size(format: ArchiveFormat, entries: &[(String, u64)]) -> u64 {
+    let data: u64 = entries.iter().map(|(_, size)| size).sum();
+    let names: u64 = entries.iter().map(|(name, _)| name.len() as u64).sum();
+    let count = entries.len() as u64;
+
+    match format {
+        // local header (30) + data descriptor (24, Zip64-sized) + central
+        // directory record (46) per entry, name stored twice, end record (22)
+        ArchiveFormat::Zip => data + count * (30 + 24 + 46) + names * 2 + 22,
+        // 512-byte header per entry (plus a GNU long-name block for long
+        // names), data padded to 512 bytes, two zero blocks at the end
+        ArchiveFormat::Tar => {
+            let padded: u64 = entries
+                .iter()
+                .map(|(name, size)| {
+                    let long_name = if name.len() > 100 {
+                        512 + (name.len() as u64).div_ceil(512) * 512
+                    } else {
+                        0
+                    };
+                    512 + long_name + size.div_ceil(512) * 512
+                })
+                .sum();
+            padded + 1024
+        }
+        // signature header (32), UTF-16 names and ~40 bytes of properties per
+        // entry in the (compressed) header; incompressible data is stored as-is
+        ArchiveFormat::SevenZ => data + 32 + count * 40 + names * 2,
+        ArchiveFormat::Custom(_) => data,
+    }
+}
+
+/// Files [`estimate_compression_ratio`] samples at most.
+const RATIO_SAMPLES: usize = 32;
+/// Bytes compressed from the start of each sampled file.
+const RATIO_SAMPLE_SIZE: u64 = 128 * 1024;
+
+/// Predicts the size compression leaves of the data of `entries` (archive
+/// name, file), as a fraction of it, by compressing the start of up to
+/// [`RATIO_SAMPLES`] of the files, spread over the list, as the archive of
+/// `config` would: deflate for compressed zips, except for the entries it
+/// stores as they are, the stream compression of compressed tarballs and
+/// LZMA2 for 7z. The ratio of each sample counts as much as the whole file
+/// it was taken from, so a few big files weigh more than many small ones.
+/// 1.0 when nothing is compressed or there is nothing to sample; files that
+/// can't be read are passed over.
+pub fn estimate_compression_ratio(
+    config: &Config,
+    format: ArchiveFormat,
+    entries: &[(String, PathBuf)],
+) -> Result<f64> {
+    enum Codec {
+        Deflate,
+        Zstd,
+        Lzma2(u32),
+    }
+    let compress = config.compress.unwrap_or(false);
+    let codec = match format {
+        ArchiveFormat::Zip if compress => Codec::Deflate,
+        ArchiveFormat::Tar => match StreamCompression::from_config(config, format)? {
+            Some(StreamCompression::Gzip) => Codec::Deflate,
+            Some(StreamCompression::Zstd) => Codec::Zstd,
+            None => return Ok(1.0),
+        },
+        ArchiveFormat::SevenZ => Codec::Lzma2(sevenz::preset(compress)),
+        _ => return Ok(1.0),
+    };
+    let incompressible = match format {
+        ArchiveFormat::Zip => Some(Incompressible::from_config(config)),
+        _ => None,
+    };
+
+    let files: Vec<_> = entries.iter().filter(|(_, path)| path.is_file()).collect();
+    let step = files.len().div_ceil(RATIO_SAMPLES).max(1);
+    // Sums of the file sizes, and of the sizes they compress to
+    let (mut sampled, mut compressed) = (0f64, 0f64);
+    for (name, path) in files.into_iter().step_by(step) {
+        let Ok(mut file) = File::open(path) else {
+            continue;
+        };
+        let Ok(file_size) = file.metadata().map(|m| m.len() as f64) else {
+            continue;
+        };
+        if let Some(incompressible) = &incompressible
+            && incompressible.check(name, &mut file).unwrap_or(false)
+        {
+            sampled += file_size;
+            compressed += file_size;
+            continue;
+        }
+        let mut sample = Vec::new();
+        if file
+            .take(RATIO_SAMPLE_SIZE)
+            .read_to_end(&mut sample)
+            .is_err()
+            || sample.is_empty()
+        {
+            continue;
+        }
+        let size = match codec {
+            Codec::Deflate => {
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&sample)?;
+                encoder.finish()?.len()
+            }
+            Codec::Zstd => zstd::bulk::compress(&sample, zstd::DEFAULT_COMPRESSION_LEVEL)?.len(),
+            Codec::Lzma2(preset) => {
+                let mut out = Vec::new();
+                let mut encoder = sevenz_rust::lzma::LZMA2Writer::new(
+                    sevenz_rust::lzma::CountingWriter::new(&mut out),
+                    &sevenz_rust::lzma::LZMA2Options::with_preset(preset),
+                );
+                encoder.write_all(&sample)?;
+                encoder.finish()?;
+                drop(encoder);
+                out.len()
+            }
+        };
+        sampled += file_size;
+        compressed += file_size * size as f64 / sample.len() as f64;
+    }
+    Ok(match sampled > 0.0 {
+        true => compressed / sampled,
+        false => 1.0,
+    })
+}
+
+/// Detects the archive format, and the compression of a compressed tarball,
+/// from its magic bytes.
+pub fn detect_format(path: &Path) -> Result<(ArchiveFormat, Option<StreamCompression>)> {
+    let mut header = [0u8; 512];
+    let mut file = File::open(path).with_context(|| format!("opening archive {path:?}"))?;
+    let read = file.read(&mut header)?;
+
+    if let Some(compression) = StreamCompression::detect(&header[..read]) {
+        let file = File::open(path)?;
+        let mut decompressed = compress::decompress_reader(Some(compression), file)?;
+        let mut inner = [0u8; 512];
+        let read = decompressed.read(&mut inner)?;
+        if read >= 262 && inner[257..262] == *b"ustar" {
+            return Ok((ArchiveFormat::Tar, Some(compression)));
+        }
+        anyhow::bail!("{} is compressed, but not a tar archive", path.display());
+    }
+
+    if read >= 4 && (header[..4] == *b"PK\x03\x04" || header[..4] == *b"PK\x05\x06") {
+        Ok((ArchiveFormat::Zip, None))
+    } else if read >= 262 && header[257..262] == *b"ustar" {
+        Ok((ArchiveFormat::Tar, None))
+    } else if read >= 6 && header[..6] == *b"7z\xBC\xAF\x27\x1C" {
+        Ok((ArchiveFormat::SevenZ, None))
+    } else {
+        anyhow::bail!("{} is not a zip, tar or 7z archive", path.display())
+    }
+}
+
+/// Reads the entry list of a zip, 7z or (possibly compressed) tar archive.
+pub async fn read_entries(path: &Path) -> Result<Vec<ArchiveEntry>> {
+    let (format, compression) = detect_format(path)?;
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || match format {
+        ArchiveFormat::Zip => zip::read_zip_entries(&path),
+        ArchiveFormat::Tar => tar::read_tar_entries(&path, compression),
+        ArchiveFormat::SevenZ => sevenz::read_7z_entries(&path),
+        ArchiveFormat::Custom(name) => bail!("reading {name} archives is not supported"),
+    })
+    .await?
+}
+
+/// Like [`read_entries`], for a zip archive read from `reader`, such as a
+/// [`crate::fetch::RangeReader`].
+pub async fn read_zip_entries<R>(reader: R) -> Result<Vec<ArchiveEntry>>
+where
+    R: Read + Seek + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || zip::zip_entries(reader)).await?
+}
+
+/// Receives the entries of an archive read with [`visit_entries`].
+pub trait EntryVisitor {
+    /// Whether to read the entry `name`. Skipped entries aren't decrypted,
+    /// so they don't need a password.
+    fn wants(&self, _name: &str) -> bool {
+        true
+    }
+
+    /// Consumes one file or directory entry.
+    fn visit(&mut self, entry: &ArchiveEntry, data: &mut dyn Read) -> Result<()>;
+
+    /// Consumes a hard link to the earlier entry `original` (tar). The
+    /// manifest lists such entries too, so they are ignored by default.
+    fn visit_hardlink(&mut self, entry: &ArchiveEntry, original: &str) -> Result<()> {
+        let _ = (entry, original);
+        Ok(())
+    }
+}
+
+/// Reads every entry of a zip, 7z or (possibly compressed) tar archive,
+/// decrypting zip entries with `password`.
+pub fn visit_entries(
+    path: &Path,
+    password: Option<&str>,
+    visitor: &mut dyn EntryVisitor,
+) -> Result<()> {
+    let (format, compression) = detect_format(path)?;
+    match format {
+        ArchiveFormat::Zip => zip::visit_zip_entries(path, password, visitor),
+        ArchiveFormat::Tar => tar::visit_tar_entries(path, compression, visitor),
+        ArchiveFormat::SevenZ => sevenz::visit_7z_entries(path, visitor),
+        ArchiveFormat::Custom(name) => bail!("reading {name} archives is not supported"),
+    }
+}
+
+/// Writes the entries of an archive below a directory.
+#[derive(Debug, Clone)]
+pub struct Extraction {
+    target: PathBuf,
+    patterns: Vec<Pattern>,
+    preserve_permissions: bool,
+    extracted: usize,
+    /// Names of the files written so far.
+    restored: HashSet<String>,
+    /// Deduplicated files (name, original name) listed in the manifest.
+    duplicates: Vec<(String, String)>,
+    /// Hard link entries with their original, made once the manifest tells
+    /// which are copies.
+    links: Vec<(ArchiveEntry, String)>,
+    /// Names of the files whose original wasn't restored, by the original,
+    /// for [`Originals`].
+    missing: HashMap<String, Vec<String>>,
+}
+
+impl Extraction {
+    /// Extracts into `target` the entries matching one of `patterns` (all if
+    /// empty), applying recorded permissions if `preserve_permissions`.
+    pub fn new(target: PathBuf, patterns: Vec<Pattern>, preserve_permissions: bool) -> Self {
+        Extraction {
+            target,
+            patterns,
+            preserve_permissions,
+            extracted: 0,
+            restored: HashSet::new(),
+            duplicates: Vec::new(),
+            links: Vec::new(),
+            missing: HashMap::new(),
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        let name = name.trim_start_matches("./").trim_end_matches('/');
+        self.patterns.is_empty() || self.patterns.iter().any(|p| p.matches(name))
+    }
+
+    /// Writes `entry` as a copy of the already restored `original`, or
+    /// keeps it for [`Originals`] if `original` wasn't restored.
+    fn copy_duplicate(&mut self, entry: &ArchiveEntry, original: &str) -> Result<()> {
+        let original = original.trim_start_matches("./");
+        if !self.restored.contains(original) {
+            self.miss(entry, original);
+            return Ok(());
+        }
+        let path = safe_join(&self.target, entry.name.trim_start_matches("./"))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(safe_join(&self.target, original)?, &path)
+            .with_context(|| format!("creating {}", path.display()))?;
+        self.finish_file(&path, entry)
+    }
+
+    /// Writes `entry` as another name of the already restored `original`,
+    /// or keeps it for [`Originals`] if `original` wasn't restored.
+    fn link_original(&mut self, entry: &ArchiveEntry, original: &str) -> Result<()> {
+        let original = original.trim_start_matches("./");
+        if !self.restored.contains(original) {
+            self.miss(entry, original);
+            return Ok(());
+        }
+        let path = safe_join(&self.target, entry.name.trim_start_matches("./"))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => result.with_context(|| format!("replacing {}", path.display()))?,
+        }
+        fs::hard_link(safe_join(&self.target, original)?, &path)
+            .with_context(|| format!("creating {}", path.display()))?;
+        self.restored
+            .insert(entry.name.trim_start_matches("./").to_string());
+        self.extracted += 1;
+        Ok(())
+    }
+
+    fn miss(&mut self, entry: &ArchiveEntry, original: &str) {
+        let name = entry.name.trim_start_matches("./").to_string();
+        // A tar hard link is listed as a duplicate in the manifest too
+        let names = self.missing.entry(original.to_string()).or_default();
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+
+    /// Writes the contents of the file `entry` below `target`, replacing an
+    /// existing file.
+    fn write_file(&mut self, entry: &ArchiveEntry, data: &mut dyn Read) -> Result<()> {
+        let name = entry.name.trim_start_matches("./");
+        let path = safe_join(&self.target, name)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut out =
+            File::create(&path).with_context(|| format!("creating {}", path.display()))?;
+        match entry.sparse {
+            true => sparse::write_sparse(data, &mut out),
+            false => io::copy(data, &mut out),
+        }
+        .with_context(|| format!("extracting {name}"))?;
+        drop(out);
+        self.finish_file(&path, entry)
+    }
+
+    /// Sets the time and permissions of a file just written, and counts it.
+    fn finish_file(&mut self, path: &Path, entry: &ArchiveEntry) -> Result<()> {
+        if let Some(modified) = entry.modified {
+            File::open(path)?.set_modified(modified.into())?;
+        }
+        if self.preserve_permissions {
+            apply_permissions(path, entry)?;
+        }
+        self.restored
+            .insert(entry.name.trim_start_matches("./").to_string());
+        self.extracted += 1;
+        Ok(())
+    }
+
+    /// Restores the hard link entries, as copies of their original if the
+    /// manifest lists them as duplicates (`dedup`), and then the other files
+    /// the manifest lists as duplicates.
+    fn restore_duplicates(&mut self) -> Result<()> {
+        let copies: HashSet<String> = self
+            .duplicates
+            .iter()
+            .map(|(name, _)| name.clone())
+            .collect();
+        for (entry, original) in std::mem::take(&mut self.links) {
+            match copies.contains(entry.name.trim_start_matches("./")) {
+                true => self.copy_duplicate(&entry, &original)?,
+                false => self.link_original(&entry, &original)?,
+            }
+        }
+        for (name, original) in std::mem::take(&mut self.duplicates) {
+            if self.restored.contains(&name) || !self.matches(&name) {
+                continue;
+            }
+            let entry = ArchiveEntry {
+                name,
+                size: 0,
+                compressed_size: None,
+                modified: None,
+                is_dir: false,
+                encrypted: false,
+                mode: None,
+                owner: None,
+                xattrs: Vec::new(),
+                sparse: false,
+            };
+            self.copy_duplicate(&entry, &original)?;
+        }
+        Ok(())
+    }
+}
+
+impl EntryVisitor for Extraction {
+    fn wants(&self, name: &str) -> bool {
+        // The manifest is read for the duplicates it lists, not restored
+        manifest::is_manifest(name) || self.matches(name)
+    }
+
+    /// Writes the entry below `target`, replacing an existing file.
+    fn visit(&mut self, entry: &ArchiveEntry, data: &mut dyn Read) -> Result<()> {
+        if manifest::is_manifest(&entry.name) {
+            let manifest = manifest::read_manifest(data)?;
+            self.duplicates = manifest
+                .files
+                .into_iter()
+                .filter(|file| !file.hard_link)
+                .filter_map(|file| Some((file.name, file.duplicate_of?)))
+                .collect();
+            return Ok(());
+        }
+        let name = entry.name.trim_start_matches("./").trim_end_matches('/');
+        let path = safe_join(&self.target, name)?;
+        if entry.is_dir {
+            fs::create_dir_all(&path)?;
+            if self.preserve_permissions {
+                apply_permissions(&path, entry)?;
+            }
+            return Ok(());
+        }
+        self.write_file(entry, data)
+    }
+
+    fn visit_hardlink(&mut self, entry: &ArchiveEntry, original: &str) -> Result<()> {
+        self.links.push((entry.clone(), original.to_string()));
+        Ok(())
+    }
+}
+
+/// A second pass over an archive for the originals of restored duplicates
+/// and hard links that didn't match the patterns themselves: their contents
+/// are written under the names of those files only.
+struct Originals<'a> {
+    extraction: &'a mut Extraction,
+    missing: HashMap<String, Vec<String>>,
+}
+
+impl<'a> Originals<'a> {
+    /// `None` if every file was restored in the first pass.
+    fn of(extraction: &'a mut Extraction) -> Option<Self> {
+        let missing = std::mem::take(&mut extraction.missing);
+        (!missing.is_empty()).then_some(Originals {
+            extraction,
+            missing,
+        })
+    }
+
+    /// Fails for the files whose original isn't in the archive.
+    fn finish(self) -> Result<()> {
+        match self.missing.into_iter().next() {
+            Some((original, names)) => bail!(
+                "can't restore {}, its contents are in {original}, which isn't in the archive",
+                names.join(", ")
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+impl EntryVisitor for Originals<'_> {
+    fn wants(&self, name: &str) -> bool {
+        self.missing.contains_key(name.trim_start_matches("./"))
+    }
+
+    fn visit(&mut self, entry: &ArchiveEntry, data: &mut dyn Read) -> Result<()> {
+        let Some(names) = self.missing.remove(entry.name.trim_start_matches("./")) else {
+            return Ok(());
+        };
+        let (first, others) = names.split_first().expect("missing originals have files");
+        let first = ArchiveEntry {
+            name: first.clone(),
+            ..entry.clone()
+        };
+        self.extraction.write_file(&first, data)?;
+        for name in others {
+            let copy = ArchiveEntry {
+                name: name.clone(),
+                ..entry.clone()
+            };
+            self.extraction.copy_duplicate(&copy, &first.name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Applies the ownership, extended attributes and mode recorded for `entry`.
+/// Ownership is only changed when permitted, i.e. when restoring as root, and
+/// attributes the file system rejects are reported and skipped.
+#[cfg(unix)]
+fn apply_permissions(path: &Path, entry: &ArchiveEntry) -> Result<()> {
+    use std::os::unix::fs::{PermissionsExt, chown};
+
+    // Before the mode, since changing the owner clears setuid and setgid bits
+    if let Some((uid, gid)) = entry.owner {
+        match chown(path, Some(uid), Some(gid)) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {}
+            result => {
+                result.with_context(|| format!("changing the owner of {}", path.display()))?
+            }
+        }
+    }
+    for (name, value) in &entry.xattrs {
+        if let Err(e) = xattr::set(path, name, value) {
+            warn!("can't set attribute {name} on {}: {e}", path.display());
+        }
+    }
+    if let Some(mode) = entry.mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .with_context(|| format!("setting the mode of {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn apply_permissions(_path: &Path, _entry: &ArchiveEntry) -> Result<()> {
+    Ok(())
+}
+
+/// Extracts a zip, 7z or (possibly compressed) tar archive, decrypting zip
+/// entries with `password`. Returns the number of files extracted.
+pub async fn extract_entries(
+    path: &Path,
+    mut extraction: Extraction,
+    password: Option<String>,
+) -> Result<usize> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        visit_entries(&path, password.as_deref(), &mut extraction)?;
+        extraction.restore_duplicates()?;
+        if let Some(mut originals) = Originals::of(&mut extraction) {
+            visit_entries(&path, password.as_deref(), &mut originals)?;
+            originals.finish()?;
+        }
+        Ok(extraction.extracted)
+    })
+    .await?
+}
+
+/// Like [`extract_entries`], for a zip archive read from `reader`, such as a
+/// [`crate::fetch::RangeReader`].
+pub async fn extract_zip_entries<R>(
+    reader: R,
+    mut extraction: Extraction,
+    password: Option<String>,
+) -> Result<usize>
+where
+    R: Read + Seek + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let mut reader = reader;
+        zip::visit_zip(&mut reader, password.as_deref(), &mut extraction)?;
+        extraction.restore_duplicates()?;
+        if let Some(mut originals) = Originals::of(&mut extraction) {
+            zip::visit_zip(&mut reader, password.as_deref(), &mut originals)?;
+            originals.finish()?;
+        }
+        Ok(extraction.extracted)
+    })
+    .await?
+}
@@ -0,0 +1,147 @@
+use anyhow::{Context, Result, bail};
+use sevenz_rust::{Password, SevenZArchiveEntry, SevenZReader, SevenZWriter, lzma::LZMA2Options};
+use std::{
+    io::{self, Seek},
+    path::Path,
+};
+
+use super::blocking::BlockingPackager;
+use super::{ArchiveEntry, ArchiveOutput, EntryVisitor, Packager, SourceEntry};
+use tracing::warn;
+
+/// LZMA2 preset used without `--compress`. 7z has no "store" method in
+/// sevenz-rust, so the fastest preset stands in for it.
+const FAST_PRESET: u32 = 1;
+const DEFAULT_PRESET: u32 = 6;
+
+/// The LZMA2 preset of an archive with or without `--compress`.
+pub fn preset(compress: bool) -> u32 {
+    match compress {
+        true => DEFAULT_PRESET,
+        false => FAST_PRESET,
+    }
+}
+
+/// Creates a 7z (LZMA2) packager.
+///
+/// The 7z header is written last and referenced from the start of the file,
+/// so the archive has to be built in a seekable temporary file and is copied to
+/// the output on `finish`; it needs as much free space in the temp directory as
+/// the compressed archive.
+pub fn sevenz_packager(output: ArchiveOutput, compress: bool) -> Box<dyn Packager> {
+    Box::new(BlockingPackager::spawn(
+        output,
+        move |mut bridge, entries| {
+            let temp = tempfile::tempfile().context("creating temporary 7z file")?;
+            let mut writer = SevenZWriter::new(temp)?;
+            writer.set_content_methods(vec![LZMA2Options::with_preset(preset(compress)).into()]);
+
+            for (archive_name, entry) in entries {
+                let source = match entry {
+                    SourceEntry::File(source) => source,
+                    SourceEntry::Symlink(_) => {
+                        warn!("skipping symlink {archive_name}, 7z archives can't store links");
+                        continue;
+                    }
+                    SourceEntry::Hardlink { .. } => {
+                        bail!("7z archives can't store hard links ({archive_name})")
+                    }
+                    SourceEntry::Directory(metadata) => {
+                        let mut entry = SevenZArchiveEntry::new();
+                        entry.name = archive_name.clone();
+                        entry.is_directory = true;
+                        set_modified(&mut entry, &metadata);
+                        writer
+                            .push_archive_entry::<&std::fs::File>(entry, None)
+                            .with_context(|| format!("adding {archive_name}"))?;
+                        continue;
+                    }
+                    SourceEntry::Stream(_) => unreachable!("7z takes streams as files"),
+                };
+                let mut entry = SevenZArchiveEntry::new();
+                entry.name = archive_name.clone();
+                entry.has_stream = true;
+                set_modified(&mut entry, &source.metadata);
+                writer
+                    .push_archive_entry(entry, Some(&source.file))
+                    .with_context(|| format!("adding {archive_name}"))?;
+            }
+
+            let mut archive = writer.finish()?;
+            archive.rewind()?;
+            std::io::copy(&mut archive, &mut bridge)?;
+            bridge.shutdown()?;
+            Ok(())
+        },
+    ))
+}
+
+fn set_modified(entry: &mut SevenZArchiveEntry, metadata: &std::fs::Metadata) {
+    if let Some(modified) = metadata.modified().ok().and_then(|m| m.try_into().ok()) {
+        entry.last_modified_date = modified;
+        entry.has_last_modified_date = true;
+    }
+}
+
+/// Reads the entry list of a 7z archive.
+pub fn read_7z_entries(path: &Path) -> Result<Vec<ArchiveEntry>> {
+    let reader = SevenZReader::open(path, Password::empty())
+        .with_context(|| format!("reading 7z archive {path:?}"))?;
+
+    Ok(reader
+        .archive()
+        .files
+        .iter()
+        .map(|entry| ArchiveEntry {
+            name: entry.name().to_string(),
+            size: entry.size(),
+            compressed_size: Some(entry.compressed_size),
+            modified: entry
+                .has_last_modified_date
+                .then(|| std::time::SystemTime::from(entry.last_modified_date()).into()),
+            is_dir: entry.is_directory(),
+            encrypted: false,
+            mode: None,
+            owner: None,
+            xattrs: Vec::new(),
+            sparse: false,
+        })
+        .collect())
+}
+
+/// Reads the entries of a 7z archive. Entries come out of solid blocks in
+/// order, so the ones not wanted are decoded and dropped.
+pub fn visit_7z_entries(path: &Path, visitor: &mut dyn EntryVisitor) -> Result<()> {
+    let mut reader = SevenZReader::open(path, Password::empty())
+        .with_context(|| format!("reading 7z archive {path:?}"))?;
+
+    let mut failure = None;
+    reader.for_each_entries(|entry, data| {
+        if visitor.wants(entry.name()) {
+            let info = ArchiveEntry {
+                name: entry.name().to_string(),
+                size: entry.size(),
+                compressed_size: Some(entry.compressed_size),
+                modified: entry
+                    .has_last_modified_date
+                    .then(|| std::time::SystemTime::from(entry.last_modified_date()).into()),
+                is_dir: entry.is_directory(),
+                encrypted: false,
+                mode: None,
+                owner: None,
+                xattrs: Vec::new(),
+                sparse: false,
+            };
+            if let Err(e) = visitor.visit(&info, data) {
+                failure = Some(e);
+                return Ok(false);
+            }
+        }
+        io::copy(data, &mut io::sink())?;
+        Ok(true)
+    })?;
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,209 @@
+//! Sparse files, like VM disk images, whose holes take no space on disk.
+//! Tar archives store only their data, as GNU sparse entries, which are
+//! restored with holes again; the manifest records how much of every sparse
+//! file is data. Other formats store the holes as zeros.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// The data of a sparse file, as (offset, length) pairs in file order; the
+/// rest are holes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segments(pub Vec<(u64, u64)>);
+
+impl Segments {
+    /// Bytes of data, without the holes.
+    pub fn data_size(&self) -> u64 {
+        self.0.iter().map(|(_, length)| length).sum()
+    }
+}
+
+/// Finds the data of `file` with `SEEK_DATA` and `SEEK_HOLE`. Returns `None`
+/// for files without holes, and where the file system or platform can't
+/// tell.
+pub fn find_segments(file: &File) -> io::Result<Option<Segments>> {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    {
+        seek_segments(file)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    {
+        let _ = file;
+        Ok(None)
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn seek_segments(file: &File) -> io::Result<Option<Segments>> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    /// The offset `lseek` moved to, or `None` past the last data.
+    fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+        // Safe: a plain syscall on a descriptor `file` keeps open
+        match unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, whence) } {
+            -1 => match io::Error::last_os_error() {
+                e if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+                e => Err(e),
+            },
+            offset => Ok(Some(offset as u64)),
+        }
+    }
+
+    let metadata = file.metadata()?;
+    let size = metadata.len();
+    // Fewer blocks than the size needs is what makes a file sparse
+    if size == 0 || metadata.blocks() * 512 >= size {
+        return Ok(None);
+    }
+
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let Some(start) = seek(file, offset, libc::SEEK_DATA)? else {
+            break;
+        };
+        let end = seek(file, start, libc::SEEK_HOLE)?
+            .unwrap_or(size)
+            .min(size);
+        if end <= start {
+            break;
+        }
+        segments.push((start, end - start));
+        offset = end;
+    }
+    // The file's own position is left where reading starts
+    seek(file, 0, libc::SEEK_SET)?;
+
+    if segments == [(0, size)] {
+        return Ok(None);
+    }
+    // Readers take the size of the file from where its last segment ends
+    if segments
+        .last()
+        .is_none_or(|&(start, length)| start + length < size)
+    {
+        segments.push((size, 0));
+    }
+    Ok(Some(Segments(segments)))
+}
+
+/// Turns `header` into that of a GNU sparse entry holding `segments` of a
+/// file of `size` bytes, returning the extension headers to write right
+/// after it, for segments beyond the four the header holds.
+pub fn tar_sparse_header(header: &mut tar::Header, size: u64, segments: &Segments) -> Vec<u8> {
+    header.set_entry_type(tar::EntryType::GNUSparse);
+    header.set_size(segments.data_size());
+    let gnu = header
+        .as_gnu_mut()
+        .expect("sparse entries have GNU headers");
+    gnu.set_real_size(size);
+    let (first, rest) = segments.0.split_at(segments.0.len().min(gnu.sparse.len()));
+    for (slot, &(offset, length)) in gnu.sparse.iter_mut().zip(first) {
+        slot.set_offset(offset);
+        slot.set_length(length);
+    }
+    gnu.set_is_extended(!rest.is_empty());
+
+    let mut extensions = Vec::new();
+    let mut chunks = rest
+        .chunks(tar::GnuExtSparseHeader::new().sparse().len())
+        .peekable();
+    while let Some(chunk) = chunks.next() {
+        let mut extension = tar::GnuExtSparseHeader::new();
+        for (slot, &(offset, length)) in extension.sparse_mut().iter_mut().zip(chunk) {
+            slot.set_offset(offset);
+            slot.set_length(length);
+        }
+        extension.set_is_extended(chunks.peek().is_some());
+        extensions.extend_from_slice(extension.as_bytes());
+    }
+    extensions
+}
+
+/// Reads the `segments` of `file`, one after the other. Like whole files in
+/// tar, a segment cut short by the file shrinking is padded with zeros.
+pub struct SegmentReader<'a> {
+    file: &'a File,
+    segments: std::vec::IntoIter<(u64, u64)>,
+    /// Offset and bytes left of the segment being read.
+    current: Option<(u64, u64)>,
+}
+
+impl<'a> SegmentReader<'a> {
+    pub fn new(file: &'a File, segments: &Segments) -> Self {
+        SegmentReader {
+            file,
+            segments: segments.0.clone().into_iter(),
+            current: None,
+        }
+    }
+}
+
+impl Read for SegmentReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let Some((offset, left)) = self.current.filter(|&(_, left)| left > 0) else {
+                match self.segments.next() {
+                    Some(segment) => {
+                        self.current = Some(segment);
+                        continue;
+                    }
+                    None => return Ok(0),
+                }
+            };
+            let wanted = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+            let read = match read_at(self.file, &mut buf[..wanted], offset)? {
+                0 => {
+                    buf[..wanted].fill(0);
+                    wanted
+                }
+                read => read,
+            };
+            self.current = Some((offset + read as u64, left - read as u64));
+            return Ok(read);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+/// Blocks of zeros this large are skipped rather than written when restoring.
+const HOLE_BLOCK: usize = 64 * 1024;
+
+/// Copies `data` into the new file `out`, seeking over blocks of zeros so
+/// they become holes where the file system supports them. Returns the bytes
+/// copied.
+pub fn write_sparse(data: &mut dyn Read, out: &mut File) -> io::Result<u64> {
+    let mut block = vec![0; HOLE_BLOCK];
+    let mut written = 0;
+    loop {
+        let mut filled = 0;
+        while filled < block.len() {
+            match data.read(&mut block[filled..])? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        if filled == 0 {
+            break;
+        }
+        if block[..filled].iter().all(|&byte| byte == 0) {
+            out.seek(SeekFrom::Current(filled as i64))?;
+        } else {
+            out.write_all(&block[..filled])?;
+        }
+        written += filled as u64;
+    }
+    // A trailing hole has no data to set the size
+    out.set_len(written)?;
+    Ok(written)
+}
@@ -0,0 +1,233 @@
+//! Entries holding the output of a program, such as a database dump or a
+//! `virtual_files` command, what is piped to ssbt (`--stdin-name`), or what
+//! ssbt writes itself (Kubernetes resources), streamed into the archive as it
+//! is produced without a temporary file. The program runs again each time
+//! the archive is written (for a retried upload, say); standard input can
+//! only be read once. Streamed entries aren't listed in the manifest.
+//!
+//! Only zip archives, whose entries don't need their size up front, take the
+//! stream as it comes; other formats get it through a temporary file (see
+//! [`super::Packager::add_stream`]).
+
+use anyhow::{Context, Result, anyhow, bail};
+use async_compression::tokio::bufread::{GzipEncoder, ZstdEncoder};
+use std::fmt;
+use std::io::{self, IsTerminal, Read, Write};
+use std::process::{Child, ChildStderr, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use tokio::io::{AsyncRead, BufReader};
+use tokio::task::JoinHandle;
+use tokio_util::io::SyncIoBridge;
+
+use crate::shell_exec::shell_command;
+
+/// How much of the error output is kept for the error message.
+const STDERR_LIMIT: usize = 4096;
+
+/// Whether standard input was streamed into an archive already.
+static STDIN_READ: AtomicBool = AtomicBool::new(false);
+
+/// Writes the contents of a [`StreamSource::Generated`] entry. It runs on a
+/// blocking thread, so it may do blocking I/O.
+pub type Generate = Arc<dyn Fn(&mut dyn Write) -> Result<()> + Send + Sync>;
+
+/// An archive entry filled with the standard output of a program, or with
+/// standard input. Names ending in `.gz` or `.zst` get the contents
+/// compressed accordingly.
+#[derive(Debug, Clone)]
+pub struct StreamedEntry {
+    pub name: String,
+    pub source: StreamSource,
+}
+
+#[derive(Clone)]
+pub enum StreamSource {
+    Program {
+        program: String,
+        args: Vec<String>,
+        /// Extra environment of the program, e.g. passwords kept off its
+        /// command line.
+        env: Vec<(String, String)>,
+    },
+    /// A command line run through the platform shell.
+    Shell(String),
+    Stdin,
+    /// Contents written by ssbt itself.
+    Generated {
+        /// What is written, for messages.
+        what: String,
+        generate: Generate,
+    },
+}
+
+// Leaves out the environment, which may hold passwords
+impl fmt::Debug for StreamSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamSource::Program { program, args, .. } => f
+                .debug_struct("Program")
+                .field("program", program)
+                .field("args", args)
+                .finish_non_exhaustive(),
+            StreamSource::Shell(command) => f.debug_tuple("Shell").field(command).finish(),
+            StreamSource::Stdin => f.write_str("Stdin"),
+            StreamSource::Generated { what, .. } => f
+                .debug_struct("Generated")
+                .field("what", what)
+                .finish_non_exhaustive(),
+        }
+    }
+}
+
+impl fmt::Display for StreamSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamSource::Program { program, .. } => f.write_str(program),
+            StreamSource::Shell(command) => write!(f, "`{command}`"),
+            StreamSource::Stdin => f.write_str("standard input"),
+            StreamSource::Generated { what, .. } => f.write_str(what),
+        }
+    }
+}
+
+impl StreamedEntry {
+    /// Starts the program (or takes standard input, or starts generating the
+    /// contents); the entry's contents are read from the returned stream.
+    pub fn spawn(&self) -> Result<RunningEntry> {
+        let command = match &self.source {
+            StreamSource::Program { program, args, env } => {
+                let mut command = Command::new(program);
+                command.args(args).envs(env.iter().map(|(k, v)| (k, v)));
+                Some(command)
+            }
+            StreamSource::Shell(line) => Some(shell_command(line)),
+            StreamSource::Stdin | StreamSource::Generated { .. } => None,
+        };
+        let (produce, child, stderr): (Produce, _, _) = match (command, &self.source) {
+            (Some(mut command), _) => {
+                let mut child = command
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()
+                    .with_context(|| format!("starting {}", self.source))?;
+                let stdout = child.stdout.take().expect("stdout is piped");
+                let stderr = child.stderr.take().expect("stderr is piped");
+                (copy_from(stdout), Some(child), Some(collect_stderr(stderr)))
+            }
+            (None, StreamSource::Generated { generate, .. }) => {
+                let generate = Arc::clone(generate);
+                let produce: Produce = Box::new(move |out| {
+                    // Keeps the whole chain of the error for the message
+                    generate(out).map_err(|e| io::Error::other(format!("{e:#}")))
+                });
+                (produce, None, None)
+            }
+            (None, _) => {
+                if io::stdin().is_terminal() {
+                    bail!("nothing is piped to standard input for {}", self.name);
+                }
+                if STDIN_READ.swap(true, Ordering::SeqCst) {
+                    bail!(
+                        "standard input was read already, {} can't be written again",
+                        self.name
+                    );
+                }
+                (copy_from(io::stdin()), None, None)
+            }
+        };
+
+        let (reader, writer) = tokio::io::duplex(64 * 1024);
+        let copy = tokio::task::spawn_blocking(move || {
+            let mut writer = SyncIoBridge::new(writer);
+            produce(&mut writer)
+        });
+
+        let reader: Box<dyn AsyncRead + Unpin + Send> = if self.name.ends_with(".gz") {
+            Box::new(GzipEncoder::new(BufReader::new(reader)))
+        } else if self.name.ends_with(".zst") {
+            Box::new(ZstdEncoder::new(BufReader::new(reader)))
+        } else {
+            Box::new(reader)
+        };
+        Ok(RunningEntry {
+            reader,
+            source: self.source.to_string(),
+            child,
+            copy,
+            stderr,
+        })
+    }
+}
+
+/// Writes the contents of an entry into the stream of the archive.
+type Produce = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send>;
+
+fn copy_from(mut output: impl Read + Send + 'static) -> Produce {
+    Box::new(move |out| io::copy(&mut output, out).map(drop))
+}
+
+/// Reads the error output of a program on a thread, keeping its end.
+fn collect_stderr(mut stderr: ChildStderr) -> thread::JoinHandle<String> {
+    thread::spawn(move || {
+        let mut text = Vec::new();
+        let mut chunk = [0; 4096];
+        while let Ok(read @ 1..) = stderr.read(&mut chunk) {
+            text.extend_from_slice(&chunk[..read]);
+            // Only the end tells what went wrong
+            if text.len() > STDERR_LIMIT {
+                text.drain(..text.len() - STDERR_LIMIT);
+            }
+        }
+        String::from_utf8_lossy(&text).trim().to_string()
+    })
+}
+
+/// A started [`StreamedEntry`]. Dropping it before [`RunningEntry::finish`]
+/// kills the program.
+pub struct RunningEntry {
+    pub reader: Box<dyn AsyncRead + Unpin + Send>,
+    /// The program, standard input or what is generated, for messages.
+    source: String,
+    child: Option<Child>,
+    copy: JoinHandle<io::Result<()>>,
+    stderr: Option<thread::JoinHandle<String>>,
+}
+
+impl RunningEntry {
+    /// Waits for the program, failing with the end of its error output if
+    /// it didn't succeed. Call once the whole stream was read.
+    pub async fn finish(mut self) -> Result<()> {
+        // On errors, dropping `self` kills the program
+        (&mut self.copy)
+            .await
+            .map_err(|e| anyhow!(e))?
+            .with_context(|| format!("reading {}", self.source))?;
+        let Some(mut child) = self.child.take() else {
+            return Ok(());
+        };
+        let stderr = self.stderr.take().expect("programs have their stderr read");
+        let (status, stderr) =
+            tokio::task::spawn_blocking(move || (child.wait(), stderr.join().unwrap_or_default()))
+                .await?;
+        let status = status?;
+        if !status.success() {
+            match stderr.is_empty() {
+                true => bail!("{} failed with {status}", self.source),
+                false => bail!("{} failed with {status}: {stderr}", self.source),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RunningEntry {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
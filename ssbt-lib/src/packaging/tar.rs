@@ -0,0 +1,334 @@
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use super::blocking::BlockingPackager;
+use super::compress::{StreamCompression, decompress_reader};
+use super::manifest::{MANIFEST_NAME, Manifest, is_manifest, sha256};
+use super::sparse::{self, SegmentReader};
+use super::{ArchiveEntry, ArchiveOutput, EntryVisitor, Packager, SourceEntry};
+use tracing::warn;
+
+/// Creates a tar packager. The `tar` crate is synchronous, so the archive is
+/// built on a blocking thread.
+///
+/// With `preserve_permissions`, entries keep their mode, owner and extended
+/// attributes (as `SCHILY.xattr` PAX records, like GNU tar); without, they
+/// are owned by root with mode 0644 or 0755.
+pub fn tar_packager(output: ArchiveOutput, preserve_permissions: bool) -> Box<dyn Packager> {
+    Box::new(
+        BlockingPackager::spawn(output, move |bridge, entries| {
+            let mut builder = tar::Builder::new(bridge);
+
+            for (archive_name, entry) in entries {
+                let source = match entry {
+                    SourceEntry::File(source) => source,
+                    SourceEntry::Symlink(link) => {
+                        let mut header = tar::Header::new_gnu();
+                        set_metadata(&mut header, &link.metadata, preserve_permissions);
+                        header.set_size(0);
+                        builder
+                            .append_link(&mut header, &archive_name, &link.target)
+                            .with_context(|| format!("adding {archive_name}"))?;
+                        continue;
+                    }
+                    SourceEntry::Directory(metadata) => {
+                        let mut header = tar::Header::new_gnu();
+                        set_metadata(&mut header, &metadata, preserve_permissions);
+                        header.set_size(0);
+                        builder
+                            .append_data(&mut header, format!("{archive_name}/"), io::empty())
+                            .with_context(|| format!("adding {archive_name}"))?;
+                        continue;
+                    }
+                    SourceEntry::Stream(_) => unreachable!("tar takes streams as files"),
+                    SourceEntry::Hardlink { original, metadata } => {
+                        let mut header = tar::Header::new_gnu();
+                        set_metadata(&mut header, &metadata, preserve_permissions);
+                        header.set_entry_type(tar::EntryType::Link);
+                        header.set_size(0);
+                        builder
+                            .append_link(&mut header, &archive_name, &original)
+                            .with_context(|| format!("adding {archive_name}"))?;
+                        continue;
+                    }
+                };
+                // The header needs the size up front; take it from the handle being
+                // read, which for stabilized files is the copy rather than the original.
+                let size = source.file.metadata()?.len();
+                let mut header = tar::Header::new_gnu();
+                set_metadata(&mut header, &source.metadata, preserve_permissions);
+                header.set_size(size);
+                if preserve_permissions && !source.xattrs.is_empty() {
+                    let records: Vec<_> = source
+                        .xattrs
+                        .iter()
+                        .map(|(name, value)| (format!("{XATTR_PREFIX}{name}"), value.as_slice()))
+                        .collect();
+                    builder.append_pax_extensions(
+                        records.iter().map(|(key, value)| (key.as_str(), *value)),
+                    )?;
+                }
+
+                // A file that shrinks while being read is zero-padded, and one that
+                // grows is cut off, so the entry always matches its header.
+                let appended = match sparse::find_segments(&source.file)? {
+                    // Only the data, after the headers listing the segments
+                    // the main header has no room for
+                    Some(segments) => {
+                        let extensions = sparse::tar_sparse_header(&mut header, size, &segments);
+                        let data = extensions
+                            .as_slice()
+                            .chain(SegmentReader::new(&source.file, &segments));
+                        builder.append_data(&mut header, &archive_name, data)
+                    }
+                    None => {
+                        let data = (&source.file)
+                            .take(size)
+                            .chain(std::io::repeat(0))
+                            .take(size);
+                        builder.append_data(&mut header, &archive_name, data)
+                    }
+                };
+                appended.with_context(|| format!("adding {archive_name}"))?;
+            }
+
+            builder.into_inner()?.shutdown()?;
+            Ok(())
+        })
+        .with_hardlinks(),
+    )
+}
+
+/// PAX record prefix of extended attributes.
+const XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+/// Fills in the header from `metadata`. Without `preserve_permissions` the
+/// owner is dropped and the mode reduced to 0644 or 0755, like
+/// `HeaderMode::Deterministic` does, but the modification time is kept.
+fn set_metadata(
+    header: &mut tar::Header,
+    metadata: &std::fs::Metadata,
+    preserve_permissions: bool,
+) {
+    header.set_metadata_in_mode(metadata, tar::HeaderMode::Complete);
+    if !preserve_permissions {
+        let executable = header.mode().is_ok_and(|mode| mode & 0o100 != 0);
+        header.set_mode(if executable { 0o755 } else { 0o644 });
+        header.set_uid(0);
+        header.set_gid(0);
+    }
+}
+
+/// Reads the entry list of a tar archive, decompressing it on the fly if needed.
+pub fn read_tar_entries(
+    path: &Path,
+    compression: Option<StreamCompression>,
+) -> Result<Vec<ArchiveEntry>> {
+    let file = File::open(path).with_context(|| format!("opening archive {path:?}"))?;
+    let mut archive = tar::Archive::new(decompress_reader(compression, file)?);
+
+    let mut result = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        result.push(ArchiveEntry {
+            name: entry.path()?.to_string_lossy().to_string(),
+            // With the holes of sparse files
+            size: entry.size(),
+            compressed_size: None,
+            modified: header
+                .mtime()
+                .ok()
+                .and_then(|t| chrono::DateTime::from_timestamp(t as i64, 0)),
+            is_dir: header.entry_type().is_dir(),
+            encrypted: false,
+            mode: header.mode().ok().map(|mode| mode & 0o7777),
+            owner: owner(header),
+            xattrs: Vec::new(),
+            sparse: header.entry_type().is_gnu_sparse(),
+        });
+    }
+    Ok(result)
+}
+
+/// Reads the files, directories and hard links of a tar archive. Other entry
+/// types, such as symlinks and devices, are skipped with a warning.
+pub fn visit_tar_entries(
+    path: &Path,
+    compression: Option<StreamCompression>,
+    visitor: &mut dyn EntryVisitor,
+) -> Result<()> {
+    let file = File::open(path).with_context(|| format!("opening archive {path:?}"))?;
+    let mut archive = tar::Archive::new(decompress_reader(compression, file)?);
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().to_string();
+        if !visitor.wants(&name) {
+            continue;
+        }
+        let kind = entry.header().entry_type();
+        let is_hardlink = kind == tar::EntryType::Link;
+        if !kind.is_file() && !kind.is_gnu_sparse() && !kind.is_dir() && !is_hardlink {
+            warn!("skipping {name}, {kind:?} entries are not supported");
+            continue;
+        }
+        let xattrs = read_xattrs(&mut entry)?;
+        let header = entry.header();
+        let info = ArchiveEntry {
+            name,
+            size: entry.size(),
+            compressed_size: None,
+            modified: header
+                .mtime()
+                .ok()
+                .and_then(|t| chrono::DateTime::from_timestamp(t as i64, 0)),
+            is_dir: kind.is_dir(),
+            encrypted: false,
+            mode: header.mode().ok().map(|mode| mode & 0o7777),
+            owner: owner(header),
+            xattrs,
+            sparse: kind.is_gnu_sparse(),
+        };
+        if is_hardlink {
+            let original = entry
+                .link_name()?
+                .with_context(|| format!("hard link {} has no target", info.name))?
+                .to_string_lossy()
+                .to_string();
+            visitor.visit_hardlink(&info, &original)?;
+            continue;
+        }
+        visitor.visit(&info, &mut entry)?;
+    }
+    // Reading to the end checks the gzip/zstd trailer
+    std::io::copy(&mut archive.into_inner(), &mut std::io::sink())?;
+    Ok(())
+}
+
+/// Finds where an interrupted, uncompressed tar archive can be cut: after
+/// the last of the leading entries that were written completely. `pieces`
+/// are its files in order, one or the volumes of a split archive. Files
+/// listed in `hashes` (name to SHA-256) must have exactly those contents,
+/// other files ending the search, since what isn't on disk yet after a
+/// power loss can read as zeros. The manifest is accepted as it is, so a
+/// finished archive lists it.
+///
+/// Returns the names of the complete entries and the offset after them.
+pub fn complete_entries(
+    pieces: &[PathBuf],
+    hashes: &HashMap<String, String>,
+) -> Result<(Vec<String>, u64)> {
+    let mut total = 0;
+    let mut reader: Box<dyn Read> = Box::new(io::empty());
+    for piece in pieces {
+        let file = File::open(piece).with_context(|| format!("opening {}", piece.display()))?;
+        total += file.metadata()?.len();
+        reader = Box::new(reader.chain(file));
+    }
+
+    let mut archive = tar::Archive::new(reader);
+    let mut names = Vec::new();
+    let mut end = 0;
+    // A damaged header ends the archive like a missing one
+    let Ok(entries) = archive.entries() else {
+        return Ok((names, end));
+    };
+    for entry in entries {
+        let Ok(mut entry) = entry else {
+            break;
+        };
+        // Where a sparse entry ends depends on its extension headers, which
+        // aren't exposed; resuming cuts the archive before it
+        if entry.header().entry_type().is_gnu_sparse() {
+            break;
+        }
+        let entry_end = entry.raw_file_position() + entry.size().next_multiple_of(512);
+        if entry_end > total {
+            break;
+        }
+        let Ok(name) = entry.path().map(|path| path.to_string_lossy().to_string()) else {
+            break;
+        };
+        if entry.header().entry_type().is_file() && entry.size() > 0 && !is_manifest(&name) {
+            let Some(expected) = hashes.get(&name) else {
+                break;
+            };
+            if !sha256(&mut entry).is_ok_and(|(_, hash)| &hash == expected) {
+                break;
+            }
+        }
+        names.push(name);
+        end = entry_end;
+    }
+    Ok((names, end))
+}
+
+/// Cuts the archive in `pieces` at `end` (see [`complete_entries`]) and
+/// completes it with `manifest` and the end-of-archive marker. Pieces past
+/// `end` are deleted. Returns the index of the piece now ending the archive.
+pub fn finish_interrupted(
+    pieces: &[PathBuf],
+    end: u64,
+    manifest: &Manifest,
+    preserve_permissions: bool,
+) -> Result<usize> {
+    let mut start = 0;
+    let mut last = None;
+    for (index, piece) in pieces.iter().enumerate() {
+        let len = fs::metadata(piece)?.len();
+        match last {
+            None if end <= start + len => {
+                OpenOptions::new()
+                    .write(true)
+                    .open(piece)?
+                    .set_len(end - start)?;
+                last = Some(index);
+            }
+            None => {}
+            Some(_) => {
+                fs::remove_file(piece).with_context(|| format!("removing {}", piece.display()))?
+            }
+        }
+        start += len;
+    }
+    let last = last.context("the archive ends before the given offset")?;
+
+    let file = OpenOptions::new().append(true).open(&pieces[last])?;
+    let mut builder = tar::Builder::new(file);
+    let source = manifest.to_source()?;
+    let mut header = tar::Header::new_gnu();
+    set_metadata(&mut header, &source.metadata, preserve_permissions);
+    header.set_size(source.metadata.len());
+    builder.append_data(&mut header, MANIFEST_NAME, source.file)?;
+    builder.into_inner()?.sync_all()?;
+    Ok(last)
+}
+
+fn owner(header: &tar::Header) -> Option<(u32, u32)> {
+    let uid = header.uid().ok()?.try_into().ok()?;
+    let gid = header.gid().ok()?.try_into().ok()?;
+    Some((uid, gid))
+}
+
+/// The extended attributes recorded for an entry in PAX records.
+fn read_xattrs<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Vec<(String, Vec<u8>)>> {
+    let Some(records) = entry.pax_extensions()? else {
+        return Ok(Vec::new());
+    };
+    let mut xattrs = Vec::new();
+    for record in records {
+        let record = record?;
+        if let Ok(key) = record.key()
+            && let Some(name) = key.strip_prefix(XATTR_PREFIX)
+        {
+            xattrs.push((name.to_string(), record.value_bytes().to_vec()));
+        }
+    }
+    Ok(xattrs)
+}
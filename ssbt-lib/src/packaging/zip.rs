@@ -0,0 +1,335 @@
+use super::blocking::BlockingPackager;
+use super::incompressible::Incompressible;
+use super::{ArchiveOutput, Packager, SourceEntry};
+use crate::Config;
+use crate::file_reader::unix_mode;
+use crate::secrets::resolve_secret;
+use anyhow::{Context, anyhow};
+use async_zip::tokio::write::ZipFileWriter;
+use async_zip::{Compression, ZipEntryBuilder};
+use chrono::{Datelike, Timelike};
+use std::path::Path;
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use tracing::warn;
+use zip::unstable::write::FileOptionsExt;
+use zip::write::SimpleFileOptions;
+
+/// Entry encryption methods for password-protected zip archives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ZipEncryptionMethod {
+    /// WinZip AE-2 AES-256, supported by 7-Zip, WinZip and most modern tools.
+    #[default]
+    Aes256,
+    /// Legacy PKWARE "traditional" encryption. Weak, but opens everywhere.
+    ZipCrypto,
+}
+
+impl FromStr for ZipEncryptionMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "aes" | "aes256" | "aes-256" => Ok(ZipEncryptionMethod::Aes256),
+            "zipcrypto" => Ok(ZipEncryptionMethod::ZipCrypto),
+            other => Err(anyhow!(
+                "unsupported zip encryption: {other} (expected aes256 or zipcrypto)"
+            )),
+        }
+    }
+}
+
+/// Password protection applied to every entry of a zip archive.
+#[derive(Clone)]
+pub struct ZipEncryption {
+    pub method: ZipEncryptionMethod,
+    pub password: String,
+}
+
+impl std::fmt::Debug for ZipEncryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ZipEncryption({:?})", self.method)
+    }
+}
+
+impl ZipEncryption {
+    /// Builds entry encryption settings from `zip_password` / `zip_encryption`.
+    pub fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(password) = config.zip_password.as_deref().filter(|p| !p.is_empty()) else {
+            return Ok(None);
+        };
+        let method = match config.zip_encryption.as_deref() {
+            Some(method) => method.parse()?,
+            None => ZipEncryptionMethod::default(),
+        };
+        Ok(Some(ZipEncryption {
+            method,
+            password: resolve_secret(password)?,
+        }))
+    }
+}
+
+/// Uncompressed size from which entries get Zip64 sizes up front. Deflate
+/// and encryption add a little to incompressible data, hence the margin.
+const ZIP64_THRESHOLD: u64 = u32::MAX as u64 - 1024 * 1024;
+
+/// Creates a zip packager, encrypting entries when `encryption` is set.
+/// With `preserve_permissions`, entries keep the Unix mode of their files.
+/// When compressing, entries `incompressible` recognizes are stored.
+///
+/// The `zip` crate is synchronous, so the archive is built on a blocking
+/// thread; streams are still written as they come. Entries of 4 GiB or
+/// more, or of unknown size, get Zip64 sizes, and archives past 4 GiB or
+/// 65535 entries a Zip64 end of central directory.
+pub fn zip_packager(
+    output: ArchiveOutput,
+    compress: bool,
+    incompressible: Incompressible,
+    encryption: Option<ZipEncryption>,
+    preserve_permissions: bool,
+) -> Box<dyn Packager> {
+    let incompressible = compress.then_some(incompressible);
+
+    Box::new(
+        BlockingPackager::spawn(output, move |bridge, entries| {
+            let mut zip = zip::ZipWriter::new_stream(bridge);
+
+            for (archive_name, mut entry) in entries {
+                let stored = match (&incompressible, &mut entry) {
+                    (None, _) => true,
+                    (Some(incompressible), SourceEntry::File(source)) => {
+                        incompressible.check(&archive_name, &mut source.file)?
+                    }
+                    (Some(incompressible), SourceEntry::Stream(_)) => {
+                        incompressible.by_name(&archive_name)
+                    }
+                    (Some(_), _) => false,
+                };
+                let method = if stored {
+                    zip::CompressionMethod::Stored
+                } else {
+                    zip::CompressionMethod::Deflated
+                };
+                // Streams have no metadata, and no size until they end
+                let (metadata, size) = match &entry {
+                    SourceEntry::File(source) => {
+                        (Some(&source.metadata), source.file.metadata()?.len())
+                    }
+                    SourceEntry::Symlink(link) => (Some(&link.metadata), 0),
+                    SourceEntry::Directory(metadata) => (Some(metadata), 0),
+                    SourceEntry::Stream(_) => (None, u64::MAX),
+                    SourceEntry::Hardlink { .. } => {
+                        anyhow::bail!("zip archives can't store hard links ({archive_name})")
+                    }
+                };
+                let modified = match metadata {
+                    Some(metadata) => zip_crate_modification_time(metadata),
+                    None => zip_crate_datetime(&chrono::Utc::now()),
+                };
+                let options = SimpleFileOptions::default()
+                    .compression_method(method)
+                    .last_modified_time(modified)
+                    .large_file(size >= ZIP64_THRESHOLD);
+                let options = match metadata
+                    .and_then(unix_mode)
+                    .filter(|_| preserve_permissions)
+                {
+                    Some(mode) => options.unix_permissions(mode),
+                    None => options,
+                };
+                let options = match &encryption {
+                    None => options,
+                    Some(ZipEncryption {
+                        method: ZipEncryptionMethod::Aes256,
+                        password,
+                    }) => options.with_aes_encryption(zip::AesMode::Aes256, password),
+                    Some(ZipEncryption {
+                        method: ZipEncryptionMethod::ZipCrypto,
+                        password,
+                    }) => options.with_deprecated_encryption(password.as_bytes())?,
+                };
+
+                match entry {
+                    SourceEntry::File(mut source) => {
+                        zip.start_file(&archive_name, options)?;
+                        std::io::copy(&mut source.file, &mut zip)
+                            .with_context(|| format!("adding {archive_name}"))?;
+                    }
+                    SourceEntry::Symlink(link) => {
+                        // Like Info-ZIP: a stored entry holding the target,
+                        // marked as a link (`S_IFLNK | 0o777`) in the Unix mode bits
+                        zip.add_symlink(
+                            &archive_name,
+                            link.target.to_string_lossy(),
+                            options.unix_permissions(0o777),
+                        )?;
+                    }
+                    SourceEntry::Stream(mut data) => {
+                        zip.start_file(&archive_name, options)?;
+                        std::io::copy(&mut data, &mut zip)
+                            .with_context(|| format!("adding {archive_name}"))?;
+                    }
+                    SourceEntry::Directory(_) => {
+                        zip.add_directory(&archive_name, options)?;
+                    }
+                    SourceEntry::Hardlink { .. } => unreachable!("rejected above"),
+                }
+            }
+
+            // Writes the central directory
+            zip.finish()?.into_inner().shutdown()?;
+            Ok(())
+        })
+        .with_streams(),
+    )
+}
+
+/// Alternative: Stream from async readers instead of file paths
+pub async fn stream_zip_from_readers<W, I, R, S>(
+    entries: I,
+    compression: Compression,
+    output: W,
+) -> anyhow::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    I: IntoIterator<Item = (S, R)>,
+    R: AsyncRead + Unpin,
+    S: AsRef<str>,
+{
+    let mut writer = ZipFileWriter::new(output.compat_write());
+
+    for (name, reader) in entries {
+        let builder = ZipEntryBuilder::new(name.as_ref().to_string().into(), compression);
+
+        let mut entry_writer = writer.write_entry_stream(builder).await?;
+        futures::io::copy(&mut reader.compat(), &mut entry_writer).await?;
+        entry_writer.close().await?;
+    }
+
+    writer.close().await?;
+    Ok(())
+}
+
+/// Reads the entry list from a zip archive's central directory.
+/// Uses the `zip` crate, since async_zip rejects AES-encrypted entries.
+pub fn read_zip_entries(path: &Path) -> anyhow::Result<Vec<super::ArchiveEntry>> {
+    let file = std::fs::File::open(path).with_context(|| format!("opening archive {path:?}"))?;
+    zip_entries(file).with_context(|| format!("reading zip archive {path:?}"))
+}
+
+/// Like [`read_zip_entries`], for a zip archive read from `reader`. Only the
+/// central directory is read.
+pub fn zip_entries<R: std::io::Read + std::io::Seek>(
+    reader: R,
+) -> anyhow::Result<Vec<super::ArchiveEntry>> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+
+    let mut result = Vec::with_capacity(archive.len());
+    for index in 0..archive.len() {
+        let entry = archive.by_index_raw(index)?;
+        result.push(super::ArchiveEntry {
+            name: entry.name().to_string(),
+            size: entry.size(),
+            compressed_size: Some(entry.compressed_size()),
+            modified: entry.last_modified().and_then(zip_datetime_to_chrono),
+            is_dir: entry.is_dir(),
+            encrypted: entry.encrypted(),
+            mode: entry.unix_mode().map(|mode| mode & 0o7777),
+            owner: None,
+            xattrs: Vec::new(),
+            sparse: false,
+        });
+    }
+    Ok(result)
+}
+
+/// Reads the entries of a zip archive, decrypting them with `password`.
+/// Symlinks are skipped with a warning, like in tar archives.
+pub fn visit_zip_entries(
+    path: &Path,
+    password: Option<&str>,
+    visitor: &mut dyn super::EntryVisitor,
+) -> anyhow::Result<()> {
+    let file = std::fs::File::open(path).with_context(|| format!("opening archive {path:?}"))?;
+    visit_zip(file, password, visitor).with_context(|| format!("reading zip archive {path:?}"))
+}
+
+/// Like [`visit_zip_entries`], for a zip archive read from `reader`. Only
+/// the central directory and the entries the visitor wants are read.
+pub fn visit_zip<R: std::io::Read + std::io::Seek>(
+    reader: R,
+    password: Option<&str>,
+    visitor: &mut dyn super::EntryVisitor,
+) -> anyhow::Result<()> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+
+    for index in 0..archive.len() {
+        // Before `by_index_raw`, which reads the local header of the entry
+        let Some(name) = archive.name_for_index(index).map(str::to_string) else {
+            continue;
+        };
+        if !visitor.wants(&name) {
+            continue;
+        }
+        let (encrypted, is_symlink) = {
+            let entry = archive.by_index_raw(index)?;
+            (entry.encrypted(), entry.is_symlink())
+        };
+        if is_symlink {
+            warn!("skipping {name}, Symlink entries are not supported");
+            continue;
+        }
+        let mut entry = match (encrypted, password) {
+            (false, _) => archive.by_index(index)?,
+            (true, Some(password)) => archive
+                .by_index_decrypt(index, password.as_bytes())
+                .with_context(|| format!("decrypting {name}"))?,
+            (true, None) => anyhow::bail!("{name} is encrypted, but no zip_password is set"),
+        };
+        let info = super::ArchiveEntry {
+            name,
+            size: entry.size(),
+            compressed_size: Some(entry.compressed_size()),
+            modified: entry.last_modified().and_then(zip_datetime_to_chrono),
+            is_dir: entry.is_dir(),
+            encrypted,
+            mode: entry.unix_mode().map(|mode| mode & 0o7777),
+            owner: None,
+            xattrs: Vec::new(),
+            sparse: false,
+        };
+        visitor.visit(&info, &mut entry)?;
+    }
+    Ok(())
+}
+
+fn zip_datetime_to_chrono(dt: zip::DateTime) -> Option<chrono::DateTime<chrono::Utc>> {
+    chrono::NaiveDate::from_ymd_opt(dt.year().into(), dt.month().into(), dt.day().into())?
+        .and_hms_opt(dt.hour().into(), dt.minute().into(), dt.second().into())
+        .map(|naive| naive.and_utc())
+}
+
+fn zip_crate_modification_time(metadata: &std::fs::Metadata) -> zip::DateTime {
+    use std::time::SystemTime;
+
+    metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .and_then(|d| chrono::DateTime::from_timestamp(d.as_secs() as i64, 0))
+        .map(|dt| zip_crate_datetime(&dt))
+        .unwrap_or_default()
+}
+
+fn zip_crate_datetime(dt: &chrono::DateTime<chrono::Utc>) -> zip::DateTime {
+    zip::DateTime::from_date_and_time(
+        dt.year() as u16,
+        dt.month() as u8,
+        dt.day() as u8,
+        dt.hour() as u8,
+        dt.minute() as u8,
+        dt.second() as u8,
+    )
+    .unwrap_or_default()
+}
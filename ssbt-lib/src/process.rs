@@ -0,0 +1,759 @@
+use crate::docker;
+use crate::error::SsbtError;
+use crate::file_reader::{ReadOptions, SourceReader};
+use crate::fs_utils::{FileWalk, encode_size};
+use crate::incremental::{load_manifest, select_changed};
+use crate::journal::{Journal, JournalHeader};
+use crate::naming::create_file_name;
+use crate::packaging::manifest::Manifest;
+use crate::packaging::{ArchiveFiles, ArchiveFormat, PackagerOptions};
+use crate::progress::Progress;
+use crate::sink::save_file::{
+    LocalFile, OverwritePolicy, is_local_output, is_windows_path, remove_stale_parts,
+};
+use crate::vss::ShadowVolumes;
+use anyhow::{Context, anyhow, bail};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use crate::{
+    Config,
+    sink::{
+        OutSink,
+        check::{check_destination, check_dir},
+        ftp::FtpUpload,
+        gcs::GcsUpload,
+        retry::RetryPolicy,
+        scp::ScpUpload,
+        send_net::HttpUpload,
+        sftp::SftpUpload,
+        ssh::SshTarget,
+        tee::{DeliveryOptions, stream_archive_to_sinks},
+        tus::TusUpload,
+        webdav::{WebDavUpload, is_webdav_url},
+    },
+};
+use tracing::{info, warn};
+
+/// Resolves every configured output; without any, the archive is saved in the
+/// current directory.
+fn get_output_sinks(config: &Config) -> anyhow::Result<Vec<OutSink>> {
+    match config.output.as_deref() {
+        Some(outputs) if outputs.iter().filter(|o| *o == "-").count() > 1 => {
+            Err(anyhow!("stdout (`-`) can only be used as an output once"))
+        }
+        Some(outputs) if !outputs.is_empty() => outputs
+            .iter()
+            .map(|output| get_output_sink(output, config))
+            .collect(),
+        _ => Ok(vec![get_output_sink(".", config)?]),
+    }
+}
+
+fn get_output_sink(output: &str, config: &Config) -> anyhow::Result<OutSink> {
+    let overwrite = match config.overwrite.as_deref() {
+        Some(policy) => policy.parse()?,
+        None => OverwritePolicy::default(),
+    };
+    let local = |output: &str| -> anyhow::Result<OutSink> {
+        Ok(OutSink::SaveToFile(LocalFile {
+            path: create_file_name(output, config)?,
+            overwrite,
+            fsync: config.fsync.unwrap_or(true),
+        }))
+    };
+
+    match output {
+        "-" => Ok(OutSink::Stdout),
+        // Not `host:path` for `protocol: scp`, even with a one-letter host
+        output if is_windows_path(output) => local(output),
+        output if is_webdav_url(output) => Ok(OutSink::UploadToWebDav(WebDavUpload::from_config(
+            output, config,
+        )?)),
+        output if output.starts_with("scp://") || config.protocol.as_deref() == Some("scp") => {
+            Ok(OutSink::Scp(ScpUpload {
+                target: SshTarget::from_config(output, config)?,
+            }))
+        }
+        output
+            if ["ftp://", "ftpes://", "ftps://"]
+                .iter()
+                .any(|scheme| output.starts_with(scheme)) =>
+        {
+            Ok(OutSink::UploadToFtp(FtpUpload::from_config(
+                output, config,
+            )?))
+        }
+        output if output.starts_with("gs://") => Ok(OutSink::UploadToGcs(GcsUpload::from_config(
+            output, config,
+        )?)),
+        output if output.starts_with("sftp://") || config.protocol.as_deref() == Some("sftp") => {
+            Ok(OutSink::Sftp(SftpUpload {
+                target: SshTarget::from_config(output, config)?,
+                retry: RetryPolicy::from_config(config)?,
+            }))
+        }
+        output => {
+            if (output.starts_with("http://") || output.starts_with("https://"))
+                && config.protocol.as_deref() == Some("tus")
+            {
+                Ok(OutSink::UploadToTus(TusUpload::from_config(
+                    output, config,
+                )?))
+            } else if output.starts_with("http://") || output.starts_with("https://") {
+                Ok(OutSink::UploadToUrl(HttpUpload::for_output(
+                    output, config,
+                )?))
+            } else {
+                local(output)
+            }
+        }
+    }
+}
+
+/// How files are named inside the archive. Each backed up path is a root:
+/// a single one holds the top of the archive, several get a top-level
+/// directory each, named after them (`/etc` and `/home/me/app` give `etc/...`
+/// and `app/...`), unless a `{path, prefix}` entry of `paths` names it. Files
+/// outside every root, such as those of `files_from`, are named relative to
+/// the deepest directory holding them. With `flatten`, only the file name is
+/// kept below the root's name.
+#[derive(Debug, Clone, Default)]
+pub struct EntryNaming {
+    /// Directory the names of files outside every root are relative to.
+    base: Option<PathBuf>,
+    /// The roots and their names in the archive, longest path first.
+    roots: Vec<(PathBuf, String)>,
+    flatten: bool,
+}
+
+impl EntryNaming {
+    /// Names of the roots of `config`, with names of other files relative
+    /// to `base`. Prefixes have to be relative and can't leave the archive
+    /// with `..`.
+    pub fn from_config(config: &Config, base: Option<PathBuf>) -> anyhow::Result<Self> {
+        let mut prefixed = Vec::new();
+        let mut plain = Vec::new();
+        for source in config.paths.iter().flatten() {
+            let path = PathBuf::from(source.path());
+            if docker::is_docker_path(source.path()) {
+                continue;
+            }
+            match source.prefix() {
+                Some(prefix) => {
+                    let prefix = prefix.replace('\\', "/");
+                    if prefix.starts_with('/') || prefix.split('/').any(|part| part == "..") {
+                        bail!(
+                            "prefix {prefix} of {} must be a relative path inside the archive",
+                            source.path()
+                        );
+                    }
+                    prefixed.push((path, prefix.trim_end_matches('/').to_string()));
+                }
+                None => plain.push(path),
+            }
+        }
+
+        let mut roots = Vec::new();
+        // Paths given with `files_from` are listed, not walked
+        if config.files_from.is_none() {
+            let all: Vec<&PathBuf> = prefixed
+                .iter()
+                .map(|(path, _)| path)
+                .chain(&plain)
+                .collect();
+            // A path inside another one is walked as part of it, and named so
+            // unless it has a prefix of its own
+            let outer = outer_paths(&all);
+            let roots_count = outer.iter().filter(|&&outer| outer).count();
+            let plain: Vec<PathBuf> = plain
+                .into_iter()
+                .zip(&outer[prefixed.len()..])
+                .filter_map(|(path, &outer)| outer.then_some(path))
+                .collect();
+            let single = roots_count == 1 && plain.len() == 1;
+            let names = match single {
+                true => vec![String::new()],
+                // A file keeps its own name, below what tells it apart
+                false => root_names(&plain)
+                    .into_iter()
+                    .zip(&plain)
+                    .map(|(name, path)| match path.is_file() {
+                        true => name
+                            .rsplit_once('/')
+                            .map_or("", |(dirs, _)| dirs)
+                            .to_string(),
+                        false => name,
+                    })
+                    .collect(),
+            };
+            roots.extend(plain.into_iter().zip(names));
+            roots.extend(prefixed);
+        }
+        roots.sort_by_key(|(path, _)| std::cmp::Reverse(path.components().count()));
+        Ok(EntryNaming {
+            base,
+            roots,
+            flatten: config.flatten.unwrap_or(false),
+        })
+    }
+
+    /// Naming for `files`, relative to the deepest directory holding those
+    /// outside every root.
+    pub fn for_files(config: &Config, files: &[PathBuf]) -> anyhow::Result<Self> {
+        let mut naming = EntryNaming::from_config(config, None)?;
+        naming.base = match naming.roots.is_empty() {
+            true => find_common_base(files),
+            false => find_common_base(
+                &files
+                    .iter()
+                    .filter(|file| naming.root_of(file).is_none())
+                    .cloned()
+                    .collect::<Vec<_>>(),
+            ),
+        };
+        Ok(naming)
+    }
+
+    fn root_of(&self, file: &Path) -> Option<&(PathBuf, String)> {
+        self.roots.iter().find(|(path, _)| file.starts_with(path))
+    }
+
+    /// The name of `file` inside the archive.
+    pub fn name(&self, file: &Path) -> String {
+        let file_name = || {
+            file.file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or_else(|| entry_name(file))
+        };
+        if let Some((path, prefix)) = self.root_of(file) {
+            let relative = match file.strip_prefix(path) {
+                Ok(relative) if !self.flatten && !relative.as_os_str().is_empty() => {
+                    entry_name(relative)
+                }
+                _ => file_name(),
+            };
+            return match prefix.is_empty() {
+                true => relative,
+                false => format!("{prefix}/{relative}"),
+            };
+        }
+        match &self.base {
+            Some(base) if !self.flatten => match file.strip_prefix(base) {
+                // An empty backed up directory is its own base
+                Ok(relative) if relative.as_os_str().is_empty() => file_name(),
+                Ok(relative) => entry_name(relative),
+                Err(_) => entry_name(file),
+            },
+            // Use just the filename if no base path
+            _ => file_name(),
+        }
+    }
+}
+
+/// `path` made absolute, with `.` and `..` resolved without touching the
+/// file system.
+fn normalized(path: &Path) -> PathBuf {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normal.pop();
+            }
+            other => normal.push(other),
+        }
+    }
+    normal
+}
+
+/// The paths of `paths` that aren't inside another one of them, each once.
+pub fn outermost(paths: &[&PathBuf]) -> Vec<PathBuf> {
+    paths
+        .iter()
+        .zip(outer_paths(paths))
+        .filter(|(_, outer)| *outer)
+        .map(|(path, _)| path.to_path_buf())
+        .collect()
+}
+
+/// Whether each of `paths` is outside all others, and the first of those
+/// that are the same.
+fn outer_paths(paths: &[&PathBuf]) -> Vec<bool> {
+    let normal: Vec<PathBuf> = paths.iter().map(|path| normalized(path)).collect();
+    (0..paths.len())
+        .map(|i| {
+            !normal.iter().enumerate().any(|(j, other)| {
+                j != i && normal[i].starts_with(other) && (normal[i] != *other || j < i)
+            })
+        })
+        .collect()
+}
+
+/// Top-level directory names for `roots`: their last component, or as many
+/// of their last components as it takes to tell them apart (`/etc` and
+/// `/opt/etc` give `etc` and `opt/etc`).
+pub fn root_names(roots: &[PathBuf]) -> Vec<String> {
+    let parts: Vec<Vec<String>> = roots
+        .iter()
+        .map(|root| {
+            normalized(root)
+                .components()
+                .filter_map(|component| match component {
+                    Component::Normal(part) => Some(part.to_string_lossy().into_owned()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .collect();
+    let name =
+        |parts: &[String], depth: usize| parts[parts.len() - depth.min(parts.len())..].join("/");
+    let mut depths = vec![1; roots.len()];
+    loop {
+        let names: Vec<String> = parts
+            .iter()
+            .zip(&depths)
+            .map(|(p, &d)| name(p, d))
+            .collect();
+        let mut extended = false;
+        for i in 0..names.len() {
+            let clashes = names
+                .iter()
+                .enumerate()
+                .any(|(j, other)| j != i && *other == names[i]);
+            if clashes && depths[i] < parts[i].len() {
+                depths[i] += 1;
+                extended = true;
+            }
+        }
+        if !extended {
+            return names;
+        }
+    }
+}
+
+pub fn prepare_entries(files: Vec<PathBuf>, naming: &EntryNaming) -> Vec<(String, PathBuf)> {
+    files
+        .into_iter()
+        .map(|file_path| prepare_entry(file_path, naming))
+        .collect()
+}
+
+/// Names `file_path` inside the archive (see [`EntryNaming`]).
+pub fn prepare_entry(file_path: PathBuf, naming: &EntryNaming) -> (String, PathBuf) {
+    (naming.name(&file_path), file_path)
+}
+
+/// Orders entries by archive name, directory by directory like the walk, so
+/// the same files always make the same archive whatever order they were
+/// listed in. Entries of the same name keep their order.
+pub fn sort_entries(entries: &mut [(String, PathBuf)]) {
+    entries.sort_by(|(a, _), (b, _)| a.split('/').cmp(b.split('/')));
+}
+
+/// `path` as the name of an archive entry, which separates directories with
+/// `/` on every platform.
+fn entry_name(path: &Path) -> String {
+    let name = path.to_string_lossy();
+    match std::path::MAIN_SEPARATOR {
+        '/' => name.into_owned(),
+        separator => name.replace(separator, "/"),
+    }
+}
+
+/// What a backup run wrote.
+#[derive(Debug, Default)]
+pub struct BackupOutcome {
+    /// The manifest written into the archive.
+    pub manifest: Option<Manifest>,
+    /// Where the archive was stored, for the outputs `ssbt prune` can clean up.
+    pub archives: Vec<String>,
+    /// Bytes of the archive; 0 for repository backups.
+    pub archive_size: u64,
+    /// Bytes of the files archived (or stored in the repository), by their
+    /// size when listed.
+    pub bytes_read: u64,
+    /// Files left out because of read errors, with the error message.
+    pub skipped: Vec<(PathBuf, String)>,
+    /// For `dry` runs, which write nothing: the entries the archive would
+    /// hold, as (archive name, path) pairs.
+    pub planned: Vec<(String, PathBuf)>,
+    /// For `dry` runs: the outputs the archive would be written to.
+    pub outputs: Vec<String>,
+}
+
+/// Builds the archive and delivers it to every output.
+pub fn process_files_within_tokio(
+    config: Config,
+    files: Vec<PathBuf>,
+    shadow_volumes: ShadowVolumes,
+) -> Result<BackupOutcome, SsbtError> {
+    within_tokio(process_files(config, files, shadow_volumes))
+}
+
+/// Builds the archive from the files `walk` finds while the archive is
+/// written (`stream_walk`), and delivers it to every output.
+pub fn process_walk_within_tokio(
+    config: Config,
+    walk: FileWalk,
+    shadow_volumes: ShadowVolumes,
+) -> Result<BackupOutcome, SsbtError> {
+    within_tokio(process_walk(config, walk, shadow_volumes))
+}
+
+/// Checks every output of `config`, or its local repository, without
+/// writing an archive (see [`check_destination`]), returning what was found
+/// for each destination, as named in status messages.
+pub fn check_destinations_within_tokio(
+    config: &Config,
+) -> Result<Vec<(String, anyhow::Result<String>)>, SsbtError> {
+    if let Some(repo) = &config.repo {
+        let checked = match repo.contains("://") {
+            true => Err(anyhow!("remote repositories are not supported: {repo}")),
+            false => check_dir(Path::new(repo)),
+        };
+        return Ok(vec![(repo.clone(), checked)]);
+    }
+    let sinks = get_output_sinks(config).map_err(SsbtError::Config)?;
+    within_tokio(async {
+        let mut checked = Vec::new();
+        for sink in sinks {
+            checked.push((sink.to_string(), check_destination(&sink).await));
+        }
+        Ok(checked)
+    })
+}
+
+fn within_tokio<T>(process: impl Future<Output = Result<T, SsbtError>>) -> Result<T, SsbtError> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all() // Enables both IO and time drivers
+        .build()
+        .map_err(|e| SsbtError::Packaging(e.into()))?;
+    // Run async function in runtime
+    runtime.block_on(process)
+}
+
+async fn process_files(
+    config: Config,
+    files: Vec<PathBuf>,
+    shadow_volumes: ShadowVolumes,
+) -> Result<BackupOutcome, SsbtError> {
+    // Determine output sinks
+    let sinks = get_output_sinks(&config).map_err(SsbtError::Config)?;
+
+    // Name files relative to their common directory or under their prefix
+    let naming = EntryNaming::for_files(&config, &files).map_err(SsbtError::Config)?;
+    let mut entries = prepare_entries(files, &naming);
+    if config.sort_files.unwrap_or(true) {
+        sort_entries(&mut entries);
+    }
+
+    // Only plan the archive on a dry run, the caller shows the plan
+    if config.dry == Some(true) {
+        info!("Dry run: the archive would hold {} files", entries.len());
+        return Ok(BackupOutcome {
+            planned: entries,
+            outputs: sinks.iter().map(ToString::to_string).collect(),
+            ..BackupOutcome::default()
+        });
+    }
+
+    let (format, mut options) = archive_options(&config, &sinks)?;
+
+    if let (Some(location), Some(manifest)) = (&config.since_manifest, &mut options.manifest) {
+        let password = options.encryption.as_ref().map(|e| e.password.as_str());
+        let previous = load_manifest(location, &config, password)
+            .await
+            .map_err(SsbtError::Config)?;
+        let total = entries.len();
+        // Files whose time changed but not their size are hashed
+        entries =
+            tokio::task::block_in_place(|| select_changed(entries, &previous, location, manifest))
+                .map_err(SsbtError::Walk)?;
+        info!(
+            "Incremental since {}: {} of {} files changed, {} deleted",
+            previous.created.format("%Y-%m-%d %H:%M:%S"),
+            entries.len(),
+            total,
+            manifest.deleted.len()
+        );
+    }
+
+    let total: u64 = entries
+        .iter()
+        .filter_map(|(_, path)| path.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let mut outcome = deliver(
+        &config,
+        format,
+        options,
+        ArchiveFiles::Listed(entries),
+        Some(total),
+        sinks,
+        shadow_volumes,
+    )
+    .await?;
+    outcome.bytes_read = total;
+    Ok(outcome)
+}
+
+async fn process_walk(
+    config: Config,
+    walk: FileWalk,
+    shadow_volumes: ShadowVolumes,
+) -> Result<BackupOutcome, SsbtError> {
+    let sinks = get_output_sinks(&config).map_err(SsbtError::Config)?;
+    let (format, options) = archive_options(&config, &sinks)?;
+    let files = ArchiveFiles::Walked(walk.clone());
+    let mut outcome = deliver(&config, format, options, files, None, sinks, shadow_volumes).await?;
+    // As found by the walk of the last archive built
+    outcome.bytes_read = walk.size();
+    Ok(outcome)
+}
+
+/// The format and packager options of `config`, logging what the archive
+/// will be like.
+fn archive_options(
+    config: &Config,
+    sinks: &[OutSink],
+) -> Result<(ArchiveFormat, PackagerOptions), SsbtError> {
+    for sink in sinks {
+        info!("Backup output: {:?}", sink);
+    }
+
+    let (format, options) = PackagerOptions::from_config(config).map_err(SsbtError::Config)?;
+
+    match (format, options.stream_compression) {
+        (_, Some(stream_compression)) => {
+            info!("Compressing tar stream with {:?}", stream_compression)
+        }
+        (ArchiveFormat::SevenZ, _) => info!("Using LZMA2 compression"),
+        (ArchiveFormat::Zip, _) if options.compress => info!("Using DEFLATE compression"),
+        (_, _) if options.compress => info!("Compression enabled"),
+        _ => info!("Compression disabled"),
+    }
+
+    if options.dedup && options.manifest.is_none() && format != ArchiveFormat::Tar {
+        warn!(
+            "dedup needs the manifest for {} archives, storing every file",
+            config.format.as_deref().unwrap_or("zip")
+        );
+    }
+
+    if let Some(encryption) = &options.encryption {
+        info!("Encrypting entries with {:?}", encryption.method);
+    }
+    if let Some(encryption) = &options.stream_encryption {
+        let recipients: Vec<String> = encryption
+            .recipients
+            .iter()
+            .map(|r| r.to_string())
+            .collect();
+        info!("Encrypting archive (age) to {}", recipients.join(", "));
+    }
+
+    Ok((format, options))
+}
+
+/// Writes the archive of `files` (of `total` bytes, if known) to every sink,
+/// reading them from `shadow_volumes`. `bytes_read` is left to the caller.
+async fn deliver(
+    config: &Config,
+    format: ArchiveFormat,
+    mut options: PackagerOptions,
+    files: ArchiveFiles,
+    total: Option<u64>,
+    sinks: Vec<OutSink>,
+    shadow_volumes: ShadowVolumes,
+) -> Result<BackupOutcome, SsbtError> {
+    let mut reader = ReadOptions::from_config(config).map_err(SsbtError::Config)?;
+    reader.shadow_volumes = shadow_volumes;
+    let reader = Arc::new(SourceReader::new(reader));
+    let delivery = DeliveryOptions::from_config(config).map_err(SsbtError::Config)?;
+    if delivery.split_size.is_some() && sinks.iter().any(|s| matches!(s, OutSink::Stdout)) {
+        return Err(SsbtError::Config(anyhow!(
+            "split_size can't be used when writing to stdout"
+        )));
+    }
+    let sinks = avoid_collisions(config, sinks, delivery.split_size.is_some())
+        .await
+        .map_err(|error| SsbtError::Upload {
+            status: None,
+            error,
+        })?;
+    if config.lock != Some(false) {
+        let outputs = match config.output.as_deref() {
+            Some(outputs) if !outputs.is_empty() => outputs.to_vec(),
+            _ => vec![".".to_string()],
+        };
+        for output in outputs.iter().filter(|o| is_local_output(o, config)) {
+            if let Err(e) = remove_stale_parts(output, config) {
+                warn!("{e:#}");
+            }
+        }
+    }
+    let locations: Vec<_> = sinks.iter().filter_map(OutSink::archive_location).collect();
+    options.progress = Progress::from_config(config, total);
+    if let Some(progress) = options.progress.clone() {
+        tokio::spawn(progress.run());
+    }
+    options.journal = start_journal(config, &options, &sinks, delivery.split_size.is_some())
+        .map_err(SsbtError::Packaging)?;
+    let result = stream_archive_to_sinks(format, &files, &options, &reader, sinks, &delivery).await;
+    // The archive was completed, or its partial files deleted
+    if let Some(journal) = &options.journal {
+        journal.remove();
+    }
+    if let Some(progress) = &options.progress {
+        progress.finish();
+    }
+    let streamed = result?;
+    info!("Archive created successfully!");
+    let archives = match delivery.split_size {
+        Some(split_size) => {
+            let volumes = streamed.size.div_ceil(split_size).max(1);
+            info!(
+                "Split into {volumes} volume(s) of up to {}",
+                encode_size(split_size)
+            );
+            locations
+                .iter()
+                .flat_map(|location| (1..=volumes).map(move |n| format!("{location}.{n:03}")))
+                .collect()
+        }
+        None => locations,
+    };
+
+    let report = reader.report();
+    if !report.skipped.is_empty() {
+        info!("Skipped {} unreadable files:", report.skipped.len());
+        for (path, reason) in &report.skipped {
+            info!("  {}: {}", path.display(), reason);
+        }
+    }
+    if !report.unstable.is_empty() {
+        info!(
+            "{} files changed while being read (their entries may be inconsistent):",
+            report.unstable.len()
+        );
+        for path in &report.unstable {
+            info!("  {}", path.display());
+        }
+    }
+
+    Ok(BackupOutcome {
+        manifest: streamed.manifest,
+        archives,
+        archive_size: streamed.size,
+        skipped: report.skipped,
+        ..BackupOutcome::default()
+    })
+}
+
+/// Most names tried for an output with `overwrite: auto-suffix`.
+const MAX_SUFFIX: u32 = 1000;
+
+/// Applies the overwrite policy to the archives already at the outputs
+/// (their first volume if `split`) before anything is written: `never`
+/// fails on one, and `auto-suffix` moves to a free name. Outputs that can't
+/// tell, like HTTP POST endpoints, are left alone.
+async fn avoid_collisions(
+    config: &Config,
+    sinks: Vec<OutSink>,
+    split: bool,
+) -> anyhow::Result<Vec<OutSink>> {
+    let policy = match config.overwrite.as_deref() {
+        Some(policy) => policy.parse()?,
+        None => OverwritePolicy::default(),
+    };
+    if !matches!(policy, OverwritePolicy::Never | OverwritePolicy::AutoSuffix) {
+        return Ok(sinks);
+    }
+    let exists = |sink: &OutSink| {
+        let checked = match split {
+            true => sink.volume(1),
+            false => Some(sink.clone()),
+        };
+        async move {
+            match checked {
+                Some(checked) => checked.exists().await,
+                None => Ok(None),
+            }
+        }
+    };
+
+    let mut resolved = Vec::new();
+    for sink in sinks {
+        let taken = exists(&sink)
+            .await
+            .with_context(|| format!("checking for an archive at {sink}"))?;
+        if taken != Some(true) {
+            resolved.push(sink);
+            continue;
+        }
+        if policy == OverwritePolicy::Never {
+            bail!("output {sink} already exists (overwrite policy: never)");
+        }
+        let mut number = 1;
+        let free = loop {
+            let Some(candidate) = sink.suffixed(number) else {
+                bail!("output {sink} already exists and can't be renamed");
+            };
+            if exists(&candidate).await? != Some(true) {
+                break candidate;
+            }
+            if number == MAX_SUFFIX {
+                bail!("output {sink} and {MAX_SUFFIX} numbered names next to it already exist");
+            }
+            number += 1;
+        };
+        info!("{sink} already exists, writing {free} instead");
+        resolved.push(free);
+    }
+    Ok(resolved)
+}
+
+/// Starts the resume journal next to the first local output, if there is
+/// one and the archive gets a manifest.
+fn start_journal(
+    config: &Config,
+    options: &PackagerOptions,
+    sinks: &[OutSink],
+    split: bool,
+) -> anyhow::Result<Option<Arc<Journal>>> {
+    let Some(manifest) = &options.manifest else {
+        return Ok(None);
+    };
+    let Some(archive) = sinks.iter().find_map(|sink| match sink {
+        OutSink::SaveToFile(file) => Some(file.path.clone()),
+        _ => None,
+    }) else {
+        return Ok(None);
+    };
+    let journal = Journal::create(&JournalHeader {
+        archive: std::path::absolute(archive)?,
+        split,
+        config: config.clone(),
+        manifest: manifest.clone(),
+    })?;
+    Ok(Some(Arc::new(journal)))
+}
+
+/// The deepest directory holding all `files`.
+pub fn find_common_base(files: &[PathBuf]) -> Option<PathBuf> {
+    let mut files = files.iter();
+
+    // Start with the parent of the first file
+    let mut base = files.next()?.parent()?.to_path_buf();
+
+    // Find common ancestor
+    for file in files {
+        while !file.starts_with(&base) {
+            base = base.parent()?.to_path_buf();
+        }
+    }
+
+    Some(base)
+}
@@ -0,0 +1,180 @@
+//! Progress bar on stderr while the archive is built and sent.
+
+use std::fs::File;
+use std::io::{IsTerminal, Seek, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::Config;
+use crate::fs_utils::encode_size;
+
+const BAR_WIDTH: usize = 24;
+/// Longest file name shown, keeping its end.
+const NAME_WIDTH: usize = 40;
+
+/// Whether a bar is on screen, so that status messages clear its line first.
+static VISIBLE: AtomicBool = AtomicBool::new(false);
+
+/// Clears the progress bar, if one is shown; the next redraw brings it back
+/// below whatever was printed meanwhile.
+pub fn clear_line() {
+    if VISIBLE.load(Ordering::Relaxed) {
+        eprint!("\r\x1b[2K");
+    }
+}
+
+/// Tracks how many source bytes went into the archive so far. Since the
+/// archive is streamed, packaging and upload advance together.
+#[derive(Debug)]
+pub struct Progress {
+    /// Unknown while the files are still being listed (`stream_walk`).
+    total: Option<u64>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    started: Instant,
+    /// Bytes of the files already archived.
+    done: u64,
+    /// The file being archived, with a handle sharing its read position.
+    current: Option<(String, File)>,
+    finished: bool,
+}
+
+impl Progress {
+    /// A bar for `total` bytes (just a counter without it), unless stderr
+    /// isn't a terminal or it was
+    /// turned off with `quiet` or `progress: false`.
+    pub fn from_config(config: &Config, total: Option<u64>) -> Option<Arc<Self>> {
+        let wanted = config.progress.unwrap_or(true) && !config.quiet.unwrap_or(false);
+        (wanted && std::io::stderr().is_terminal()).then(|| {
+            Arc::new(Progress {
+                total,
+                state: Mutex::new(State {
+                    started: Instant::now(),
+                    done: 0,
+                    current: None,
+                    finished: false,
+                }),
+            })
+        })
+    }
+
+    /// Starts over, for an archive that is rebuilt to retry an upload.
+    pub fn restart(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.started = Instant::now();
+        state.done = 0;
+        state.current = None;
+    }
+
+    /// Marks `name` as the file being read from `file`.
+    pub fn start_file(&self, name: &str, file: &File) {
+        // A cloned handle shares the offset, so its position is how far the
+        // packager has read
+        self.state.lock().unwrap().current = file.try_clone().ok().map(|f| (name.to_string(), f));
+    }
+
+    pub fn finish_file(&self, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.done += size;
+        state.current = None;
+    }
+
+    /// Redraws the bar until [`Progress::finish`] is called.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_millis(200));
+        loop {
+            interval.tick().await;
+            let mut state = self.state.lock().unwrap();
+            if state.finished {
+                break;
+            }
+            let line = self.render(&mut state);
+            VISIBLE.store(true, Ordering::Relaxed);
+            eprint!("\r\x1b[2K{line}");
+            let _ = std::io::stderr().flush();
+        }
+    }
+
+    /// Stops redrawing and clears the bar.
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !state.finished {
+            state.finished = true;
+            clear_line();
+            VISIBLE.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn render(&self, state: &mut State) -> String {
+        let reading = state
+            .current
+            .as_mut()
+            .and_then(|(_, file)| file.stream_position().ok())
+            .unwrap_or(0);
+        let processed = state.done + reading;
+        let elapsed = state.started.elapsed().as_secs_f64();
+        let speed = if elapsed > 0.0 {
+            processed as f64 / elapsed
+        } else {
+            0.0
+        };
+        let name = state
+            .current
+            .as_ref()
+            .map(|(name, _)| shorten(name))
+            .unwrap_or_default();
+
+        // Without a total, only how far it got
+        let Some(total) = self.total else {
+            return format!(
+                "{} {}/s {name}",
+                encode_size(processed),
+                encode_size(speed as u64),
+            );
+        };
+        let processed = processed.min(total);
+        let fraction = if total == 0 {
+            1.0
+        } else {
+            processed as f64 / total as f64
+        };
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+        let eta = if speed > 0.0 {
+            format_duration((total - processed) as f64 / speed)
+        } else {
+            "-".to_string()
+        };
+
+        format!(
+            "[{}{}] {:>3}% {}/{} {}/s ETA {eta} {name}",
+            "#".repeat(filled),
+            "-".repeat(BAR_WIDTH - filled),
+            (fraction * 100.0) as u32,
+            encode_size(processed),
+            encode_size(total),
+            encode_size(speed as u64),
+        )
+    }
+}
+
+/// `m:ss`, or `h:mm:ss` from an hour on.
+pub fn format_duration(secs: f64) -> String {
+    let secs = secs as u64;
+    match secs {
+        0..3600 => format!("{}:{:02}", secs / 60, secs % 60),
+        _ => format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60),
+    }
+}
+
+fn shorten(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    if chars.len() <= NAME_WIDTH {
+        return name.to_string();
+    }
+    let tail: String = chars[chars.len() - (NAME_WIDTH - 3)..].iter().collect();
+    format!("...{tail}")
+}
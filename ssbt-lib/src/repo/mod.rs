@@ -0,0 +1,377 @@
+//! Deduplicating, content-addressed backup repository.
+//!
+//! Layout of a repository directory:
+//!
+//! ```text
+//! config.json            format version and chunker
+//! blobs/ab/abcdef...     chunk contents, named by their blake3 hash
+//! snapshots/<id>.json    one index per backup run
+//! ```
+//!
+//! Files are split into chunks, each chunk is stored once no matter how many
+//! files or runs contain it, and a snapshot lists the chunks making up every file.
+
+pub mod snapshot;
+
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::Utc;
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::Config;
+use crate::file_reader::{ReadOptions, SourceReader};
+use crate::fs_utils::{encode_size, safe_join, write_atomic};
+use crate::process::{EntryNaming, prepare_entries, sort_entries};
+use crate::vss::ShadowVolumes;
+use snapshot::{Snapshot, SnapshotFile};
+use tracing::{info, warn};
+
+const REPO_VERSION: u32 = 1;
+const CDC_MIN_SIZE: u32 = 512 * 1024;
+const CDC_AVG_SIZE: u32 = 1024 * 1024;
+const CDC_MAX_SIZE: u32 = 8 * 1024 * 1024;
+const FIXED_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// How file contents are split into blobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Chunker {
+    /// Content-defined chunking (FastCDC): inserting bytes only changes nearby chunks.
+    #[default]
+    Cdc,
+    /// Fixed 1 MiB blocks: cheaper, but an insertion shifts every following chunk.
+    Fixed,
+}
+
+impl FromStr for Chunker {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "cdc" => Ok(Chunker::Cdc),
+            "fixed" => Ok(Chunker::Fixed),
+            other => Err(anyhow!(
+                "unsupported chunker: {other} (expected cdc or fixed)"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RepoConfig {
+    version: u32,
+    chunker: Chunker,
+}
+
+/// Totals of a single backup run.
+#[derive(Debug, Default)]
+pub struct BackupStats {
+    pub files: usize,
+    pub bytes: u64,
+    pub new_blobs: usize,
+    pub new_bytes: u64,
+}
+
+/// A repository on a local (or mounted) filesystem.
+#[derive(Debug)]
+pub struct Repository {
+    root: PathBuf,
+    chunker: Chunker,
+}
+
+impl Repository {
+    /// Opens the repository at `location`, initializing it if the directory is
+    /// missing or empty. `chunker` only applies to new repositories, since
+    /// changing it later would defeat deduplication against older snapshots.
+    pub fn open(location: &str, chunker: Option<Chunker>) -> Result<Self> {
+        let root = local_root(location)?;
+        let config_path = root.join("config.json");
+
+        if config_path.exists() {
+            let repo = Self::load(root)?;
+            if chunker.is_some_and(|c| c != repo.chunker) {
+                warn!(
+                    "repository uses the {:?} chunker, ignoring the configured one",
+                    repo.chunker
+                );
+            }
+            return Ok(repo);
+        }
+
+        if root.exists() && fs::read_dir(&root)?.next().is_some() {
+            bail!(
+                "{} is not empty and is not an ssbt repository",
+                root.display()
+            );
+        }
+
+        let chunker = chunker.unwrap_or_default();
+        fs::create_dir_all(root.join("blobs"))?;
+        fs::create_dir_all(root.join("snapshots"))?;
+        let config = RepoConfig {
+            version: REPO_VERSION,
+            chunker,
+        };
+        write_atomic(&config_path, &serde_json::to_vec_pretty(&config)?)?;
+        info!("Initialized repository at {}", root.display());
+
+        Ok(Repository { root, chunker })
+    }
+
+    /// Opens the repository at `location`, failing if there is none, for
+    /// commands that only read from it.
+    pub fn open_existing(location: &str) -> Result<Self> {
+        let root = local_root(location)?;
+        if !root.join("config.json").exists() {
+            bail!(
+                "{} is not an ssbt repository (no config.json); a backup with --repo creates one",
+                root.display()
+            );
+        }
+        Self::load(root)
+    }
+
+    fn load(root: PathBuf) -> Result<Self> {
+        let config_path = root.join("config.json");
+        let config: RepoConfig = serde_json::from_slice(&fs::read(&config_path)?)
+            .with_context(|| format!("parsing {}", config_path.display()))?;
+        if config.version != REPO_VERSION {
+            bail!(
+                "unsupported repository version {} in {}",
+                config.version,
+                root.display()
+            );
+        }
+        Ok(Repository {
+            root,
+            chunker: config.chunker,
+        })
+    }
+
+    fn snapshots_dir(&self) -> PathBuf {
+        self.root.join("snapshots")
+    }
+
+    fn blob_path(&self, hash: &str) -> PathBuf {
+        self.root.join("blobs").join(&hash[..2]).join(hash)
+    }
+
+    /// Stores `data` unless a blob with the same hash exists.
+    /// Returns the hash and whether the blob was new.
+    fn store_blob(&self, data: &[u8]) -> Result<(String, bool)> {
+        let hash = blake3::hash(data).to_hex().to_string();
+        let path = self.blob_path(&hash);
+        if path.exists() {
+            return Ok((hash, false));
+        }
+        fs::create_dir_all(path.parent().expect("blob path has a parent"))?;
+        write_atomic(&path, data)?;
+        Ok((hash, true))
+    }
+
+    /// Reads a blob and checks it against its hash.
+    fn read_blob(&self, hash: &str) -> Result<Vec<u8>> {
+        let path = self.blob_path(hash);
+        let data = fs::read(&path).with_context(|| format!("reading blob {hash}"))?;
+        if blake3::hash(&data).to_hex().as_str() != hash {
+            bail!("blob {hash} is corrupted");
+        }
+        Ok(data)
+    }
+
+    /// Splits `source` into chunks and stores each one, returning the chunk hashes.
+    fn store_file(&self, source: impl Read, stats: &mut BackupStats) -> Result<Vec<String>> {
+        let mut chunks = Vec::new();
+        let mut store = |data: &[u8]| -> Result<()> {
+            let (hash, new) = self.store_blob(data)?;
+            if new {
+                stats.new_blobs += 1;
+                stats.new_bytes += data.len() as u64;
+            }
+            chunks.push(hash);
+            Ok(())
+        };
+
+        match self.chunker {
+            Chunker::Cdc => {
+                let cdc = fastcdc::v2020::StreamCDC::new(
+                    source,
+                    CDC_MIN_SIZE,
+                    CDC_AVG_SIZE,
+                    CDC_MAX_SIZE,
+                );
+                for chunk in cdc {
+                    store(&chunk?.data)?;
+                }
+            }
+            Chunker::Fixed => {
+                let mut source = source;
+                let mut buffer = vec![0; FIXED_CHUNK_SIZE];
+                loop {
+                    let len = read_full(&mut source, &mut buffer)?;
+                    if len == 0 {
+                        break;
+                    }
+                    store(&buffer[..len])?;
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Backs up `files`, read from `shadow_volumes`, into a new snapshot.
+    pub fn backup(
+        &self,
+        config: &Config,
+        files: Vec<PathBuf>,
+        shadow_volumes: ShadowVolumes,
+    ) -> Result<(Snapshot, BackupStats)> {
+        let reader = SourceReader::new(ReadOptions {
+            shadow_volumes,
+            ..ReadOptions::from_config(config)?
+        });
+        let naming = EntryNaming::for_files(config, &files)?;
+        let mut entries = prepare_entries(files, &naming);
+        if config.sort_files.unwrap_or(true) {
+            sort_entries(&mut entries);
+        }
+
+        let mut stats = BackupStats::default();
+        let mut snapshot_files = Vec::with_capacity(entries.len());
+        for (name, path) in entries {
+            // Stored blobs are only referenced once the snapshot is written
+            crate::cancel::check()?;
+            let Some(source) = reader.open(&path)? else {
+                continue;
+            };
+            let chunks = self
+                .store_file(&source.file, &mut stats)
+                .with_context(|| format!("storing {}", path.display()))?;
+
+            stats.files += 1;
+            stats.bytes += source.metadata.len();
+            snapshot_files.push(SnapshotFile {
+                name,
+                size: source.metadata.len(),
+                modified: source.metadata.modified().ok().map(Into::into),
+                chunks,
+            });
+        }
+
+        for (path, reason) in reader.report().skipped {
+            info!("Skipped {}: {}", path.display(), reason);
+        }
+
+        let time = Utc::now();
+        let snapshot = Snapshot {
+            id: format!(
+                "{}-{:08x}",
+                time.format("%Y%m%d-%H%M%S"),
+                rand::random::<u32>()
+            ),
+            time,
+            paths: config
+                .paths
+                .iter()
+                .flatten()
+                .map(|source| source.path().to_string())
+                .collect(),
+            files: snapshot_files,
+        };
+        let path = self.snapshots_dir().join(format!("{}.json", snapshot.id));
+        write_atomic(&path, &serde_json::to_vec_pretty(&snapshot)?)?;
+
+        Ok((snapshot, stats))
+    }
+
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>> {
+        snapshot::list_snapshots(&self.snapshots_dir())
+    }
+
+    pub fn find_snapshot(&self, selector: &str) -> Result<Snapshot> {
+        snapshot::find_snapshot(&self.snapshots_dir(), selector)
+    }
+
+    /// Restores the files of `snapshot` matching `patterns` (all if empty) into `target`.
+    /// Returns the number of files restored.
+    pub fn restore(
+        &self,
+        snapshot: &Snapshot,
+        target: &Path,
+        patterns: &[Pattern],
+    ) -> Result<usize> {
+        let mut restored = 0;
+        for file in &snapshot.files {
+            if !patterns.is_empty() && !patterns.iter().any(|p| p.matches(&file.name)) {
+                continue;
+            }
+
+            let path = safe_join(target, &file.name)?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut out =
+                File::create(&path).with_context(|| format!("creating {}", path.display()))?;
+            for hash in &file.chunks {
+                out.write_all(&self.read_blob(hash)?)?;
+            }
+            if let Some(modified) = file.modified {
+                out.set_modified(modified.into())?;
+            }
+            restored += 1;
+        }
+        Ok(restored)
+    }
+}
+
+/// The directory of a repository; remote ones aren't supported.
+fn local_root(location: &str) -> Result<PathBuf> {
+    if location.contains("://") {
+        bail!(
+            "remote repositories are not supported yet, use a local or mounted directory: {location}"
+        );
+    }
+    Ok(PathBuf::from(location))
+}
+
+/// Runs a backup into the repository configured by `repo`.
+pub fn run_backup(
+    config: &Config,
+    files: Vec<PathBuf>,
+    shadow_volumes: ShadowVolumes,
+) -> Result<()> {
+    let location = config.repo.as_deref().context("no repository configured")?;
+    let chunker = config.chunker.as_deref().map(str::parse).transpose()?;
+    let repo = Repository::open(location, chunker)?;
+
+    info!("Backup repository: {location}");
+    let (snapshot, stats) = repo.backup(config, files, shadow_volumes)?;
+    info!(
+        "Snapshot {} saved: {} files, {} ({} new in {} blobs)",
+        snapshot.id,
+        stats.files,
+        encode_size(stats.bytes),
+        encode_size(stats.new_bytes),
+        stats.new_blobs
+    );
+    Ok(())
+}
+
+/// Joins an archived relative path onto `target`, refusing paths that escape it.
+/// Fills `buffer` as far as possible, returning fewer bytes only at end of input.
+fn read_full(source: &mut impl Read, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match source.read(&mut buffer[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
@@ -0,0 +1,67 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// The index written by every backup run into a repository.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: String,
+    pub time: DateTime<Utc>,
+    /// Source paths as configured for the run.
+    pub paths: Vec<String>,
+    pub files: Vec<SnapshotFile>,
+}
+
+/// A single file of a snapshot, stored as an ordered list of blobs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    /// Path relative to the common base of the backed up files.
+    pub name: String,
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+    /// blake3 hashes (hex) of the file's chunks, in order.
+    pub chunks: Vec<String>,
+}
+
+impl Snapshot {
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|f| f.size).sum()
+    }
+}
+
+/// Loads all snapshots in `dir`, oldest first.
+pub fn list_snapshots(dir: &Path) -> Result<Vec<Snapshot>> {
+    let mut snapshots = Vec::new();
+    for entry in std::fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "json") {
+            snapshots.push(read_snapshot(&path)?);
+        }
+    }
+    snapshots.sort_by_key(|s| s.time);
+    Ok(snapshots)
+}
+
+/// Finds a snapshot by `latest`, its full id or a unique id prefix.
+pub fn find_snapshot(dir: &Path, selector: &str) -> Result<Snapshot> {
+    let mut snapshots = list_snapshots(dir)?;
+    if selector == "latest" {
+        return snapshots.pop().context("repository has no snapshots");
+    }
+
+    let mut matches: Vec<_> = snapshots
+        .into_iter()
+        .filter(|s| s.id.starts_with(selector))
+        .collect();
+    match matches.len() {
+        0 => bail!("no snapshot matches {selector}"),
+        1 => Ok(matches.remove(0)),
+        n => bail!("{n} snapshots match {selector}, use a longer id"),
+    }
+}
+
+fn read_snapshot(path: &Path) -> Result<Snapshot> {
+    let content = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    serde_json::from_slice(&content).with_context(|| format!("parsing snapshot {}", path.display()))
+}
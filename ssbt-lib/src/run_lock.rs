@@ -0,0 +1,129 @@
+//! A lock per set of outputs, so overlapping runs (e.g. a slow nightly
+//! backup and the next cron invocation) don't write the same destination at
+//! the same time.
+
+use anyhow::{Context, Result, bail};
+use std::{
+    env,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::Write,
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+use crate::Config;
+use crate::fs_utils::parse_duration;
+use crate::packaging::manifest::sha256;
+
+/// Held for the duration of a run; dropping it releases the lock.
+pub struct RunLock {
+    _file: File,
+}
+
+/// Takes the lock of the run's outputs (or repository) and profile, waiting
+/// up to `wait_for_lock` for another run holding it. `None` with `lock: false`.
+pub fn acquire(config: &Config) -> Result<Option<RunLock>> {
+    if config.lock == Some(false) {
+        return Ok(None);
+    }
+    let wait = config
+        .wait_for_lock
+        .as_deref()
+        .map(|wait| parse_duration(wait).context("invalid wait_for_lock"))
+        .transpose()?
+        .unwrap_or_default();
+
+    let key = lock_key(config);
+    let dir = lock_dir();
+    fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+    let (_, digest) = sha256(&mut key.as_bytes())?;
+    let path = dir.join(format!("{}.lock", &digest[..16]));
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("opening lock file {}", path.display()))?;
+
+    let deadline = Instant::now() + wait;
+    let mut waiting = false;
+    loop {
+        crate::cancel::check()?;
+        match file.try_lock() {
+            Ok(()) => break,
+            Err(TryLockError::WouldBlock) => {
+                // The holder wrote its pid into the file
+                let holder = fs::read_to_string(&path)
+                    .ok()
+                    .map(|pid| pid.trim().to_string())
+                    .filter(|pid| !pid.is_empty())
+                    .map_or(String::new(), |pid| format!(" (pid {pid})"));
+                let now = Instant::now();
+                if now >= deadline {
+                    bail!(
+                        "another ssbt run{holder} is writing to {key}; use --wait-for-lock to wait for it, or --no-lock"
+                    );
+                }
+                if !waiting {
+                    info!("Waiting for another ssbt run{holder} writing to {key}");
+                    waiting = true;
+                }
+                thread::sleep(Duration::from_millis(250).min(deadline - now));
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("locking {}", path.display()));
+            }
+        }
+    }
+    file.set_len(0)?;
+    writeln!(file, "{}", std::process::id())?;
+    Ok(Some(RunLock { _file: file }))
+}
+
+/// What runs must not do at the same time: write to the same outputs, or
+/// the same repository, with the same profile.
+fn lock_key(config: &Config) -> String {
+    let mut targets: Vec<String> = match &config.repo {
+        Some(repo) => vec![absolute(repo)],
+        None => config
+            .output
+            .iter()
+            .flatten()
+            .filter(|o| !o.is_empty())
+            .map(|o| absolute(o))
+            .collect(),
+    };
+    targets.sort();
+    let targets = targets.join(", ");
+    match &config.profile {
+        Some(profile) => format!("{targets} (profile {profile})"),
+        None => targets,
+    }
+}
+
+/// Local paths made absolute, so runs from different directories agree.
+fn absolute(target: &str) -> String {
+    if target == "-" || target.contains("://") {
+        return target.to_string();
+    }
+    std::path::absolute(target).map_or(target.to_string(), |p| p.to_string_lossy().into_owned())
+}
+
+/// `$XDG_RUNTIME_DIR/ssbt`, else `~/.local/state/ssbt/locks`, else a
+/// directory in the system's temporary directory.
+fn lock_dir() -> PathBuf {
+    let var = |name| env::var_os(name).filter(|dir| !dir.is_empty());
+    if let Some(runtime) = var("XDG_RUNTIME_DIR") {
+        return PathBuf::from(runtime).join("ssbt");
+    }
+    var("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| var("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .map_or_else(
+            || env::temp_dir().join("ssbt-locks"),
+            |state| state.join("ssbt/locks"),
+        )
+}
@@ -0,0 +1,91 @@
+use crate::Config;
+use anyhow::{Context, Result, bail};
+use std::process::Stdio;
+use std::{env, fs};
+use tracing::warn;
+
+use crate::shell_exec::shell_command;
+
+/// Resolves a configuration value that may reference a secret stored elsewhere,
+/// so tokens don't have to be written into shell history or config files.
+///
+/// Supported forms:
+/// * `file:/run/secrets/token` - contents of the file, surrounding whitespace trimmed
+/// * `env:NAME` - value of the environment variable `NAME`
+/// * `cmd:pass show backup` - output of the command, run with `sh -c`, trimmed
+/// * `prompt:` - asked interactively on the terminal, optionally with a custom prompt (`prompt:Zip password`)
+/// * anything else is returned unchanged
+pub fn resolve_secret(value: &str) -> Result<String> {
+    if let Some(path) = value.strip_prefix("file:") {
+        let content =
+            fs::read_to_string(path).with_context(|| format!("reading secret file {path}"))?;
+        warn_if_readable_by_others(path);
+        Ok(content.trim().to_string())
+    } else if let Some(name) = value.strip_prefix("env:") {
+        env::var(name).with_context(|| format!("reading secret from environment variable {name}"))
+    } else if let Some(command) = value.strip_prefix("cmd:") {
+        run_secret_command(command)
+    } else if let Some(label) = value.strip_prefix("prompt:") {
+        let label = if label.is_empty() { "Password" } else { label };
+        rpassword::prompt_password(format!("{label}: ")).context("reading password from terminal")
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+/// Turns `authentication_file` or `authentication_cmd` into the equivalent
+/// `file:`/`cmd:` reference in `authentication`, so it is only read when an
+/// output needs it. Setting more than one of the three is an error.
+pub fn authentication_source(config: &mut Config) -> Result<()> {
+    let sources = [
+        config.authentication.is_some(),
+        config.authentication_file.is_some(),
+        config.authentication_cmd.is_some(),
+    ];
+    if sources.iter().filter(|set| **set).count() > 1 {
+        bail!("set only one of authentication, authentication_file and authentication_cmd");
+    }
+    if let Some(path) = config.authentication_file.take() {
+        config.authentication = Some(format!("file:{path}"));
+    } else if let Some(command) = config.authentication_cmd.take() {
+        config.authentication = Some(format!("cmd:{command}"));
+    }
+    Ok(())
+}
+
+/// Runs `command` with the shell and returns what it printed. Its stdin and
+/// stderr stay on the terminal, for tools like `pass` asking for a passphrase.
+fn run_secret_command(command: &str) -> Result<String> {
+    let output = shell_command(command)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("running secret command {command}"))?;
+    if !output.status.success() {
+        bail!("secret command {command} failed with {}", output.status);
+    }
+    let secret = String::from_utf8(output.stdout)
+        .with_context(|| format!("secret command {command} printed invalid UTF-8"))?;
+    let secret = secret.trim();
+    if secret.is_empty() {
+        bail!("secret command {command} printed nothing");
+    }
+    Ok(secret.to_string())
+}
+
+/// Secret files should be private to their owner (mode 0600 or stricter).
+#[cfg(unix)]
+fn warn_if_readable_by_others(path: &str) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(metadata) = fs::metadata(path) {
+        let mode = metadata.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            warn!(
+                "secret file {path} is accessible by other users (mode {mode:o}), consider chmod 600"
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_readable_by_others(_path: &str) {}
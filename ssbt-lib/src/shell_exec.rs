@@ -0,0 +1,83 @@
+use std::process::{Command, Stdio};
+use std::io::{BufReader, BufRead, Read};
+use std::thread;
+use anyhow::{Result, Context, anyhow};
+use tracing::{debug, error, info, warn};
+
+/// A `Command` running `command` through the platform shell: `sh -c` on Unix,
+/// `cmd /C` on Windows, so quoting, pipes, redirections and `&&` work as typed.
+pub fn shell_command(command: &str) -> Command {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    shell
+}
+
+/// Executes a shell command, logs its output in real-time,
+/// and returns an error if the command exits with a non-zero status code.
+///
+/// Arguments:
+/// * `command`: The command line to execute (e.g., "pg_dump mydb > /tmp/db.sql && echo done").
+/// * `env`: Extra environment variables for the command.
+///
+/// Returns:
+/// * `Ok(())` on successful execution (exit code 0).
+/// * `Err(anyhow::Error)` if the command fails to start, read output, or exits with a non-zero code.
+pub fn execute_and_stream_command(command: &str, env: &[(&str, String)]) -> Result<()> {
+    debug!("Running {command}");
+
+    // --- 1. Spawn the command, piping stdout and stderr ---
+    let mut child = shell_command(command)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context(format!("Failed to spawn command: '{}'", command))?;
+
+    // --- 2. Log stderr from a separate thread, so neither pipe fills up ---
+    let stderr = child.stderr.take()
+        .context("Child process did not have a stderr handle")?;
+    let stderr_logger = thread::spawn(move || log_lines(stderr, |l| warn!("{}", l)));
+
+    // --- 3. Log stdout line-by-line in real-time ---
+    let stdout = child.stdout.take()
+        .context("Child process did not have a stdout handle")?;
+    let read = log_lines(stdout, |l| info!("{}", l));
+    let read_stderr = stderr_logger
+        .join()
+        .map_err(|_| anyhow!("stderr reader of '{}' panicked", command))?;
+    read.and(read_stderr)
+        .context("Error reading output from child process")?;
+
+    // --- 4. Wait for the command to finish and check the exit status ---
+    let status = child.wait()
+        .context("Failed to wait on child process")?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        // Killed by a signal on Unix leaves no code
+        let code = status.code().unwrap_or(-1);
+        error!("Command failed with exit code: {}", code);
+        
+        Err(anyhow!("Command '{}' failed with exit code: {}", command, code))
+    }
+}
+
+/// Passes every line of `output` to `log`, tolerating invalid UTF-8.
+fn log_lines(output: impl Read, log: impl Fn(&str)) -> std::io::Result<()> {
+    let mut reader = BufReader::new(output);
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        log(String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']));
+        line.clear();
+    }
+    Ok(())
+}
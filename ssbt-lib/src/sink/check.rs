@@ -0,0 +1,124 @@
+//! `--check-destination`: whether an archive could be written to an output,
+//! found out without writing one.
+
+use anyhow::{Context, Result, bail};
+use reqwest::{Method, StatusCode};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+use super::send_net::HttpAuth;
+use super::{DEFAULT_BUFFER_SIZE, OutSink, deliver, ftp, gcs, scp, sftp, webdav};
+use crate::error::HttpStatusError;
+
+/// Extension of the test file written next to the archive of outputs that
+/// can delete it again.
+const CHECK_EXTENSION: &str = "ssbt-check";
+
+/// Checks that `sink` is reachable, takes the credentials and, where that
+/// can be told, can be written to, and says what was checked:
+///
+/// - local files: a temporary file is created in the archive's directory,
+///   or the closest existing one above it, which the run would create
+/// - WebDAV and GCS: a test file named like the archive plus
+///   `.ssbt-check` is uploaded and deleted
+/// - HTTP: HEAD (PUT uploads) or OPTIONS (POST endpoints and tus servers)
+///   with the credentials, which only fails on 401, 403 and server errors
+/// - SCP, SFTP and FTP: a login, looking for the archive
+pub async fn check_destination(sink: &OutSink) -> Result<String> {
+    match sink {
+        OutSink::Stdout => Ok("stdout".to_string()),
+        OutSink::SaveToFile(file) => check_dir(file.path.parent().unwrap_or(Path::new(""))),
+        OutSink::UploadToUrl(upload) => {
+            if let Some(HttpAuth::Digest { .. }) = upload.auth {
+                bail!("digest authentication is only supported for WebDAV outputs");
+            }
+            let method = match upload.method {
+                Method::PUT => Method::HEAD,
+                _ => Method::OPTIONS,
+            };
+            let response = upload
+                .authorize(upload.client.request(method.clone(), &upload.url))
+                .send()
+                .await?;
+            check_status(method, response.status())
+        }
+        OutSink::UploadToTus(upload) => {
+            if let Some(HttpAuth::Digest { .. }) = upload.http.auth {
+                bail!("digest authentication is only supported for WebDAV outputs");
+            }
+            let response = upload
+                .request(Method::OPTIONS, &upload.http.url)
+                .send()
+                .await?;
+            check_status(Method::OPTIONS, response.status())
+        }
+        OutSink::UploadToWebDav(_) | OutSink::UploadToGcs(_) => {
+            let Some((test, _)) = sink.sidecar(CHECK_EXTENSION) else {
+                bail!("{sink} has no name for a test file");
+            };
+            deliver(test.clone(), DEFAULT_BUFFER_SIZE, |mut output| async move {
+                output.write_all(b"ssbt destination check\n").await?;
+                output.shutdown().await?;
+                Ok(())
+            })
+            .await
+            .with_context(|| format!("writing the test file {test}"))?;
+            match &test {
+                OutSink::UploadToWebDav(upload) => webdav::delete(upload).await,
+                OutSink::UploadToGcs(upload) => gcs::delete(&upload.client, upload).await,
+                _ => unreachable!(),
+            }
+            .with_context(|| format!("deleting the test file {test}"))?;
+            Ok(format!("wrote and deleted {test}"))
+        }
+        OutSink::Scp(upload) => logged_in(scp::exists(upload).await?),
+        OutSink::Sftp(upload) => logged_in(sftp::exists(upload).await?),
+        OutSink::UploadToFtp(upload) => logged_in(ftp::exists(upload).await?),
+    }
+}
+
+/// Whether files can be created in `dir`, or in the closest existing
+/// directory above it.
+pub fn check_dir(dir: &Path) -> Result<String> {
+    let dir = std::path::absolute(dir)?;
+    let existing = dir
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .context("no directory of the path exists")?;
+    if !existing.is_dir() {
+        bail!("{} is not a directory", existing.display());
+    }
+    tempfile::NamedTempFile::new_in(existing)
+        .with_context(|| format!("creating a file in {}", existing.display()))?;
+    Ok(match existing == dir {
+        true => format!("{} is writable", dir.display()),
+        false => format!(
+            "{} is writable, {} will be created",
+            existing.display(),
+            dir.display()
+        ),
+    })
+}
+
+/// Fails on the statuses telling that an upload would fail too: rejected
+/// credentials and server errors, except for servers not implementing the
+/// method asked with.
+fn check_status(method: Method, status: StatusCode) -> Result<String> {
+    if matches!(status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN)
+        || (status.is_server_error() && status != StatusCode::NOT_IMPLEMENTED)
+    {
+        return Err(HttpStatusError {
+            what: method.to_string(),
+            status,
+        }
+        .into());
+    }
+    Ok(format!("{method} answered {status}"))
+}
+
+fn logged_in(archive_exists: bool) -> Result<String> {
+    Ok(match archive_exists {
+        true => "logged in, the archive already exists".to_string(),
+        false => "logged in".to_string(),
+    })
+}
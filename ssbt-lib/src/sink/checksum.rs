@@ -0,0 +1,132 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Result, anyhow};
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncWriteExt;
+
+use super::{DEFAULT_BUFFER_SIZE, OutSink, deliver};
+use crate::Config;
+use tracing::warn;
+
+/// Hash written next to every archive as `<archive>.<algorithm>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "sha256" | "sha-256" => Ok(ChecksumAlgorithm::Sha256),
+            "blake3" => Ok(ChecksumAlgorithm::Blake3),
+            other => Err(anyhow!(
+                "unsupported checksum: {other} (expected sha256, blake3 or none)"
+            )),
+        }
+    }
+}
+
+impl ChecksumAlgorithm {
+    pub const ALL: [ChecksumAlgorithm; 2] = [ChecksumAlgorithm::Sha256, ChecksumAlgorithm::Blake3];
+
+    /// Parses `checksum`, a comma separated list; `none` (the default) disables sidecars.
+    pub fn from_config(config: &Config) -> Result<Vec<Self>> {
+        match config.checksum.as_deref().map(str::trim) {
+            None | Some("" | "none") => Ok(Vec::new()),
+            Some(list) => list.split(',').map(str::parse).collect(),
+        }
+    }
+
+    /// Extension of the sidecar file, which is also the name of the tool
+    /// checking it (`sha256sum -c`, `b3sum -c`) without the `sum`.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Blake3 => "blake3",
+        }
+    }
+}
+
+/// Hashes the archive bytes as they are streamed to the sinks.
+pub struct ArchiveHasher {
+    sha256: Option<Sha256>,
+    blake3: Option<blake3::Hasher>,
+}
+
+impl ArchiveHasher {
+    pub fn new(algorithms: &[ChecksumAlgorithm]) -> Self {
+        ArchiveHasher {
+            sha256: algorithms
+                .contains(&ChecksumAlgorithm::Sha256)
+                .then(Sha256::new),
+            blake3: algorithms
+                .contains(&ChecksumAlgorithm::Blake3)
+                .then(blake3::Hasher::new),
+        }
+    }
+
+    pub fn update(&mut self, chunk: &[u8]) {
+        if let Some(hasher) = &mut self.sha256 {
+            hasher.update(chunk);
+        }
+        if let Some(hasher) = &mut self.blake3 {
+            hasher.update(chunk);
+        }
+    }
+
+    /// Hex encoded digests of everything hashed.
+    pub fn finish(self) -> Vec<(ChecksumAlgorithm, String)> {
+        let mut digests = Vec::new();
+        if let Some(hasher) = self.sha256 {
+            digests.push((
+                ChecksumAlgorithm::Sha256,
+                format!("{:x}", hasher.finalize()),
+            ));
+        }
+        if let Some(hasher) = self.blake3 {
+            digests.push((
+                ChecksumAlgorithm::Blake3,
+                hasher.finalize().to_hex().to_string(),
+            ));
+        }
+        digests
+    }
+}
+
+/// Stores `digest` next to the archive `sink` received, in the format of
+/// `sha256sum`/`b3sum`. Outputs that can't hold a second file (stdout, HTTP
+/// POST endpoints) are skipped, the latter with a warning.
+pub async fn write_sidecar(
+    sink: &OutSink,
+    algorithm: ChecksumAlgorithm,
+    digest: &str,
+) -> anyhow::Result<()> {
+    let Some((sidecar, archive_name)) = sink.sidecar(algorithm.extension()) else {
+        if !matches!(sink, OutSink::Stdout) {
+            warn!(
+                "{sink} can't store a .{} checksum file, skipping it",
+                algorithm.extension()
+            );
+        }
+        return Ok(());
+    };
+    let line = format!("{digest}  {archive_name}\n");
+    deliver(sidecar, DEFAULT_BUFFER_SIZE, |mut output| async move {
+        output.write_all(line.as_bytes()).await?;
+        output.shutdown().await?;
+        Ok(())
+    })
+    .await
+}
+
+/// The file name a sidecar refers to the archive by.
+pub(super) fn archive_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
@@ -0,0 +1,218 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use anyhow::{Context, Result, bail};
+use reqwest::{Method, Url, header};
+use sha2::{Digest as _, Sha256};
+
+/// HTTP Digest authentication (RFC 7616) against a challenge obtained once
+/// per upload. Streamed bodies can't be replayed after a 401, so the challenge
+/// is fetched up front and every request is signed with it.
+#[derive(Debug)]
+pub struct DigestAuth {
+    user: String,
+    password: String,
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: Algorithm,
+    /// Only `auth` is supported; `None` for old RFC 2069 servers.
+    qop: Option<&'static str>,
+    nonce_count: AtomicU32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    Md5,
+    Md5Sess,
+    Sha256,
+    Sha256Sess,
+}
+
+impl Algorithm {
+    fn hash(self, data: &str) -> String {
+        match self {
+            Algorithm::Md5 | Algorithm::Md5Sess => hex(&md5(data.as_bytes())),
+            Algorithm::Sha256 | Algorithm::Sha256Sess => hex(&Sha256::digest(data.as_bytes())),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Md5Sess => "MD5-sess",
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Sha256Sess => "SHA-256-sess",
+        }
+    }
+}
+
+impl DigestAuth {
+    /// Parses the `Digest` challenge among the `WWW-Authenticate` headers of a 401.
+    pub fn from_response(response: &reqwest::Response, user: &str, password: &str) -> Result<Self> {
+        let challenge = response
+            .headers()
+            .get_all(header::WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find_map(|value| {
+                value
+                    .get(..7)
+                    .filter(|scheme| scheme.eq_ignore_ascii_case("digest "))
+                    .map(|_| &value[7..])
+            })
+            .context("server did not offer Digest authentication")?;
+
+        let params = parse_params(challenge);
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone())
+        };
+
+        let algorithm = match param("algorithm").as_deref().map(str::to_ascii_uppercase) {
+            None => Algorithm::Md5,
+            Some(a) if a == "MD5" => Algorithm::Md5,
+            Some(a) if a == "MD5-SESS" => Algorithm::Md5Sess,
+            Some(a) if a == "SHA-256" => Algorithm::Sha256,
+            Some(a) if a == "SHA-256-SESS" => Algorithm::Sha256Sess,
+            Some(other) => bail!("unsupported Digest algorithm: {other}"),
+        };
+        let qop = match param("qop") {
+            None => None,
+            Some(qop) if qop.split(',').any(|q| q.trim() == "auth") => Some("auth"),
+            Some(qop) => bail!("unsupported Digest qop: {qop}"),
+        };
+
+        Ok(DigestAuth {
+            user: user.to_string(),
+            password: password.to_string(),
+            realm: param("realm").unwrap_or_default(),
+            nonce: param("nonce").context("Digest challenge without nonce")?,
+            opaque: param("opaque"),
+            algorithm,
+            qop,
+            nonce_count: AtomicU32::new(0),
+        })
+    }
+
+    /// Computes the `Authorization` header for one request.
+    pub fn authorization(&self, method: &Method, url: &Url) -> String {
+        let uri = match url.query() {
+            Some(query) => format!("{}?{query}", url.path()),
+            None => url.path().to_string(),
+        };
+        let nc = format!(
+            "{:08x}",
+            self.nonce_count.fetch_add(1, Ordering::Relaxed) + 1
+        );
+        let cnonce = hex(&rand::random::<[u8; 16]>());
+        let hash = |data: String| self.algorithm.hash(&data);
+
+        let mut ha1 = hash(format!("{}:{}:{}", self.user, self.realm, self.password));
+        if matches!(self.algorithm, Algorithm::Md5Sess | Algorithm::Sha256Sess) {
+            ha1 = hash(format!("{ha1}:{}:{cnonce}", self.nonce));
+        }
+        let ha2 = hash(format!("{method}:{uri}"));
+        let response = match self.qop {
+            Some(qop) => hash(format!("{ha1}:{}:{nc}:{cnonce}:{qop}:{ha2}", self.nonce)),
+            None => hash(format!("{ha1}:{}:{ha2}", self.nonce)),
+        };
+
+        let mut value = format!(
+            r#"Digest username="{}", realm="{}", nonce="{}", uri="{uri}", algorithm={}, response="{response}""#,
+            self.user,
+            self.realm,
+            self.nonce,
+            self.algorithm.name()
+        );
+        if let Some(qop) = self.qop {
+            value.push_str(&format!(r#", qop={qop}, nc={nc}, cnonce="{cnonce}""#));
+        }
+        if let Some(opaque) = &self.opaque {
+            value.push_str(&format!(r#", opaque="{opaque}""#));
+        }
+        value
+    }
+}
+
+/// Splits `key=value, key="quoted, value"` pairs.
+fn parse_params(input: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = input.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_string();
+        let after = after.trim_start();
+        let (value, remaining) = if let Some(quoted) = after.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            (
+                quoted[..end].to_string(),
+                quoted.get(end + 1..).unwrap_or(""),
+            )
+        } else {
+            let end = after.find(',').unwrap_or(after.len());
+            (after[..end].trim().to_string(), &after[end..])
+        };
+        params.push((key, value));
+        rest = remaining.trim_start_matches([',', ' ']);
+    }
+    params
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// MD5 (RFC 1321). Broken as a hash, but still what most Digest servers require.
+fn md5(input: &[u8]) -> [u8; 16] {
+    const S: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+    let k: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32)
+        .collect();
+
+    let mut message = input.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((input.len() as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(k[i])
+                .wrapping_add(words[g])
+                .rotate_left(S[i]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0; 16];
+    for (chunk, word) in digest.chunks_mut(4).zip(state) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
@@ -0,0 +1,508 @@
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use async_trait::async_trait;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned, pki_types::ServerName};
+use tokio::{io::AsyncRead, sync::oneshot};
+use tokio_util::io::SyncIoBridge;
+
+use super::resume::ResumeWindow;
+use super::retry::RetryPolicy;
+use super::{Sink, SinkWriter};
+use crate::Config;
+use crate::naming::create_file_name;
+use crate::secrets::resolve_secret;
+use tracing::warn;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Bytes kept in memory after they were sent, to replay what a dropped
+/// connection may have lost.
+const RESUME_WINDOW: usize = 8 * 1024 * 1024;
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How the FTP connection is secured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FtpSecurity {
+    /// Plain FTP, credentials are sent in the clear.
+    None,
+    /// `AUTH TLS` on the regular port (`ftpes://`).
+    Explicit,
+    /// TLS from the first byte, usually on port 990 (`ftps://`).
+    Implicit,
+}
+
+/// An archive upload to an FTP or FTPS server.
+#[derive(Clone)]
+pub struct FtpUpload {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    pub password: String,
+    /// Remote path, with naming templates already expanded.
+    pub path: String,
+    pub security: FtpSecurity,
+    /// Let the server connect back (PORT) instead of using passive mode.
+    pub active: bool,
+    pub retry: RetryPolicy,
+}
+
+// Secrets must never end up in logs, so the password is not printed.
+impl fmt::Debug for FtpUpload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FtpUpload")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("user", &self.user)
+            .field("path", &self.path)
+            .field("security", &self.security)
+            .field("active", &self.active)
+            .field("retry", &self.retry)
+            .finish()
+    }
+}
+
+impl FtpUpload {
+    /// Parses `ftp://`, `ftpes://` or `ftps://[user[:password]@]host[:port]/path`.
+    ///
+    /// `authentication` (`user:password`, or just the password) overrides the
+    /// credentials in the URL; without either, the login is anonymous.
+    pub fn from_config(output: &str, config: &Config) -> Result<Self> {
+        let (scheme, rest) = output
+            .split_once("://")
+            .with_context(|| format!("not an FTP URL: {output}"))?;
+        let (security, default_port) = match scheme {
+            "ftp" => (FtpSecurity::None, 21),
+            "ftpes" => (FtpSecurity::Explicit, 21),
+            "ftps" => (FtpSecurity::Implicit, 990),
+            other => bail!("unsupported FTP scheme: {other}"),
+        };
+
+        let url =
+            reqwest::Url::parse(output).with_context(|| format!("invalid FTP URL {output}"))?;
+        let host = url
+            .host_str()
+            .with_context(|| format!("missing host in {output}"))?
+            .to_string();
+        // Take the path verbatim: URL parsing would mangle `%date%` style templates
+        let path = rest.find('/').map_or("", |i| &rest[i + 1..]);
+        if path.is_empty() || path.ends_with('/') {
+            bail!("remote path in {output} must name a file");
+        }
+
+        let mut user = match url.username() {
+            "" => "anonymous".to_string(),
+            user => user.to_string(),
+        };
+        let mut password = url.password().unwrap_or_default().to_string();
+        if let Some(auth) = config.authentication.as_deref().filter(|a| !a.is_empty()) {
+            let auth = resolve_secret(auth)?;
+            match auth.split_once(':') {
+                Some((u, p)) if url.username().is_empty() => {
+                    user = u.to_string();
+                    password = p.to_string();
+                }
+                _ => password = auth,
+            }
+        }
+
+        Ok(FtpUpload {
+            port: url.port().unwrap_or(default_port),
+            host,
+            user,
+            password,
+            path: create_file_name(path, config)?
+                .to_string_lossy()
+                .to_string(),
+            security,
+            active: config.ftp_active.unwrap_or(false),
+            retry: RetryPolicy::from_config(config)?,
+        })
+    }
+}
+
+/// A failure reply from the server.
+#[derive(Debug)]
+struct FtpError {
+    code: u32,
+    message: String,
+}
+
+impl fmt::Display for FtpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FTP server replied {} {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for FtpError {}
+
+/// 4xx replies and network errors are worth retrying, 5xx replies are not.
+fn is_transient(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<FtpError>() {
+        Some(reply) => (400..500).contains(&reply.code),
+        None => true,
+    }
+}
+
+fn with_retries<T>(
+    retry: &RetryPolicy,
+    what: &str,
+    mut operation: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Err(e) if is_transient(&e) => {
+                match retry.next_delay(&format!("FTP {what}"), attempt, format!("{e:#}")) {
+                    Some(delay) => std::thread::sleep(delay),
+                    None => return Err(e).with_context(|| format!("FTP {what}")),
+                }
+                attempt += 1;
+            }
+            result => return result.with_context(|| format!("FTP {what}")),
+        }
+    }
+}
+
+/// A plain or TLS-wrapped TCP stream.
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(tcp) => tcp,
+            Stream::Tls(tls) => &tls.sock,
+        }
+    }
+
+    /// Closes the stream, sending a TLS close_notify first so the server
+    /// knows the file is complete.
+    fn close(self) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.shutdown(Shutdown::Write),
+            Stream::Tls(mut tls) => {
+                tls.conn.send_close_notify();
+                tls.flush()?;
+                tls.sock.shutdown(Shutdown::Write)
+            }
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.read(buf),
+            Stream::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.write(buf),
+            Stream::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.flush(),
+            Stream::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
+/// An authenticated control connection.
+struct Control<'a> {
+    upload: &'a FtpUpload,
+    stream: BufReader<Stream>,
+    tls: Option<Arc<ClientConfig>>,
+}
+
+impl<'a> Control<'a> {
+    fn connect(upload: &'a FtpUpload) -> Result<Self> {
+        let tcp = TcpStream::connect((upload.host.as_str(), upload.port))
+            .with_context(|| format!("connecting to {}:{}", upload.host, upload.port))?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+
+        let tls = (upload.security != FtpSecurity::None).then(tls_config);
+        let stream = match (&tls, upload.security) {
+            (Some(config), FtpSecurity::Implicit) => wrap_tls(config, &upload.host, tcp)?,
+            _ => Stream::Plain(tcp),
+        };
+        let mut control = Control {
+            upload,
+            stream: BufReader::new(stream),
+            tls,
+        };
+        control.expect(&[220])?;
+
+        if let (Some(config), FtpSecurity::Explicit) = (control.tls.clone(), upload.security) {
+            control.command("AUTH TLS", &[234])?;
+            let Stream::Plain(tcp) = control.stream.into_inner() else {
+                unreachable!("explicit TLS starts from a plain connection")
+            };
+            control.stream = BufReader::new(wrap_tls(&config, &upload.host, tcp)?);
+        }
+
+        let reply = control.command(&format!("USER {}", upload.user), &[230, 331])?;
+        if reply.0 == 331 {
+            control.command(&format!("PASS {}", upload.password), &[230, 202])?;
+        }
+        if control.tls.is_some() {
+            control.command("PBSZ 0", &[200])?;
+            control.command("PROT P", &[200])?;
+        }
+        control.command("TYPE I", &[200])?;
+        Ok(control)
+    }
+
+    fn command(&mut self, command: &str, expected: &[u32]) -> Result<(u32, String)> {
+        self.stream
+            .get_mut()
+            .write_all(format!("{command}\r\n").as_bytes())?;
+        self.expect(expected)
+    }
+
+    /// Reads a (possibly multi-line) reply and checks its code.
+    fn expect(&mut self, expected: &[u32]) -> Result<(u32, String)> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line)? == 0 {
+            bail!("FTP server closed the connection");
+        }
+        let code: u32 = line
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .with_context(|| format!("invalid FTP reply: {}", line.trim_end()))?;
+        if line.as_bytes().get(3) == Some(&b'-') {
+            let end = format!("{code} ");
+            loop {
+                let mut next = String::new();
+                if self.stream.read_line(&mut next)? == 0 || next.starts_with(&end) {
+                    break;
+                }
+            }
+        }
+
+        let message = line[3..].trim().to_string();
+        if !expected.contains(&code) {
+            return Err(FtpError { code, message }.into());
+        }
+        Ok((code, message))
+    }
+
+    /// Creates the directories leading to `path`, ignoring ones that exist.
+    fn create_dirs(&mut self, path: &str) -> Result<()> {
+        let Some((dir, _)) = path.rsplit_once('/') else {
+            return Ok(());
+        };
+        let mut current = String::new();
+        for part in dir.split('/') {
+            current.push_str(part);
+            if !part.is_empty() {
+                // 550 usually means the directory exists; the upload reports anything else
+                let _ = self.command(&format!("MKD {current}"), &[257, 250, 550, 521]);
+            }
+            current.push('/');
+        }
+        Ok(())
+    }
+
+    fn size(&mut self, path: &str) -> Result<u64> {
+        let (_, size) = self.command(&format!("SIZE {path}"), &[213])?;
+        size.parse()
+            .with_context(|| format!("invalid SIZE reply: {size}"))
+    }
+
+    /// Starts a transfer (`STOR`/`APPE`) and returns the data connection.
+    fn open_data(&mut self, command: &str) -> Result<Stream> {
+        let tcp = if self.upload.active {
+            let local = self.stream.get_ref().tcp().local_addr()?;
+            let listener = TcpListener::bind(SocketAddr::new(local.ip(), 0))?;
+            let port = listener.local_addr()?.port();
+            match local.ip() {
+                IpAddr::V4(ip) => {
+                    let [a, b, c, d] = ip.octets();
+                    self.command(
+                        &format!("PORT {a},{b},{c},{d},{},{}", port >> 8, port & 0xff),
+                        &[200],
+                    )?;
+                }
+                IpAddr::V6(ip) => {
+                    self.command(&format!("EPRT |2|{ip}|{port}|"), &[200])?;
+                }
+            }
+            self.command(command, &[125, 150])?;
+            let (tcp, _) = listener.accept()?;
+            tcp
+        } else {
+            let address = self.passive_address()?;
+            let tcp = TcpStream::connect(address)
+                .with_context(|| format!("opening passive data connection to {address}"))?;
+            self.command(command, &[125, 150])?;
+            tcp
+        };
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+
+        match &self.tls {
+            Some(config) => wrap_tls(config, &self.upload.host, tcp),
+            None => Ok(Stream::Plain(tcp)),
+        }
+    }
+
+    /// Asks for a passive data port with EPSV, falling back to PASV. The
+    /// address in a PASV reply is ignored in favour of the control peer,
+    /// since servers behind NAT often report their private address.
+    fn passive_address(&mut self) -> Result<SocketAddr> {
+        let peer = self.stream.get_ref().tcp().peer_addr()?;
+        let port = match self.command("EPSV", &[229]) {
+            Ok((_, message)) => message
+                .split('|')
+                .nth(3)
+                .and_then(|port| port.parse().ok())
+                .with_context(|| format!("invalid EPSV reply: {message}"))?,
+            Err(_) => {
+                let (_, message) = self.command("PASV", &[227])?;
+                let numbers: Vec<u16> = message
+                    .split(|c: char| !c.is_ascii_digit())
+                    .filter_map(|n| n.parse().ok())
+                    .collect();
+                match numbers[..] {
+                    [.., p1, p2] if numbers.len() >= 6 => p1 * 256 + p2,
+                    _ => bail!("invalid PASV reply: {message}"),
+                }
+            }
+        };
+        Ok(SocketAddr::new(peer.ip(), port))
+    }
+}
+
+fn tls_config() -> Arc<ClientConfig> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    // One shared config, so data connections can resume the control
+    // connection's TLS session as most servers require
+    Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
+
+fn wrap_tls(config: &Arc<ClientConfig>, host: &str, tcp: TcpStream) -> Result<Stream> {
+    let name = ServerName::try_from(host.to_string())?;
+    let connection = ClientConnection::new(config.clone(), name)?;
+    let mut stream = StreamOwned::new(connection, tcp);
+    // Complete the handshake now, so certificate errors surface here
+    while stream.conn.is_handshaking() {
+        stream.conn.complete_io(&mut stream.sock)?;
+    }
+    Ok(Stream::Tls(Box::new(stream)))
+}
+
+#[async_trait]
+impl Sink for FtpUpload {
+    async fn open_writer(&self, buffer_size: usize) -> Result<SinkWriter> {
+        let upload = self.clone();
+        Ok(SinkWriter::committed(buffer_size, |reader, committed| {
+            upload_stream(upload, reader, committed)
+        }))
+    }
+}
+
+/// Streams everything read from `reader` to `<path>.part` and renames it into
+/// place once `commit` fires. If `commit` is dropped because the archive
+/// couldn't be completed, the partial file is deleted instead.
+///
+/// Transient failures are retried; a transfer that breaks off is continued
+/// with `APPE` from the size the server has stored, as long as that is
+/// within the resume window.
+pub async fn upload_stream<R>(
+    upload: FtpUpload,
+    reader: R,
+    commit: oneshot::Receiver<()>,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let reader = SyncIoBridge::new(reader);
+    tokio::task::spawn_blocking(move || send(&upload, reader, commit))
+        .await
+        .map_err(|e| anyhow!(e))??;
+    Ok(())
+}
+
+/// Whether the file `upload` points at exists on the server, asking for its
+/// size.
+pub async fn exists(upload: &FtpUpload) -> Result<bool> {
+    let upload = upload.clone();
+    tokio::task::spawn_blocking(move || {
+        let mut control = with_retries(&upload.retry, "login", || Control::connect(&upload))?;
+        match control.size(&upload.path) {
+            Ok(_) => Ok(true),
+            Err(e)
+                if e.downcast_ref::<FtpError>()
+                    .is_some_and(|reply| reply.code == 550) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e).context("FTP SIZE"),
+        }
+    })
+    .await
+    .map_err(|e| anyhow!(e))?
+}
+
+fn send(upload: &FtpUpload, mut reader: impl Read, commit: oneshot::Receiver<()>) -> Result<()> {
+    let part = format!("{}.part", upload.path);
+    let mut control = with_retries(&upload.retry, "login", || Control::connect(upload))?;
+    control.create_dirs(&upload.path)?;
+    let mut data = with_retries(&upload.retry, "STOR", || {
+        control.open_data(&format!("STOR {part}"))
+    })?;
+
+    let mut window = ResumeWindow::new(RESUME_WINDOW);
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let read = reader.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        window.push(&chunk[..read]);
+        if let Err(e) = data.write_all(&chunk[..read]) {
+            warn!("FTP transfer failed ({e}), resuming");
+            (control, data) = with_retries(&upload.retry, "resume", || {
+                let mut control = Control::connect(upload)?;
+                let stored = control.size(&part)?;
+                let missing = window.since(stored)?;
+                let mut data = control.open_data(&format!("APPE {part}"))?;
+                data.write_all(missing)?;
+                Ok((control, data))
+            })?;
+        }
+    }
+    data.close()?;
+    control.expect(&[226, 250])?;
+
+    if commit.blocking_recv().is_err() {
+        // The archive writer reports why it stopped
+        control.command(&format!("DELE {part}"), &[250])?;
+        return Ok(());
+    }
+    control.command(&format!("RNFR {part}"), &[350])?;
+    control.command(&format!("RNTO {}", upload.path), &[250])?;
+    let _ = control.command("QUIT", &[221]);
+    Ok(())
+}
@@ -0,0 +1,592 @@
+use std::{fmt, path::PathBuf};
+
+use anyhow::{Context, Result, anyhow, bail};
+use async_trait::async_trait;
+use base64::{
+    Engine,
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+};
+use reqwest::{StatusCode, Url, header};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::oneshot,
+};
+
+use super::retry::RetryPolicy;
+use super::send_net::{archive_content_type, http_client};
+use super::verify::{HashAlgorithm, SentCounter};
+use super::{Sink, SinkWriter};
+use crate::Config;
+use crate::naming::create_file_name;
+use crate::secrets::resolve_secret;
+use tracing::info;
+
+const API: &str = "https://storage.googleapis.com";
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+const METADATA_HOST: &str = "metadata.google.internal";
+/// Upload chunk size; GCS requires a multiple of 256 KiB for all but the last chunk.
+const CHUNK_SIZE: usize = 32 * 256 * 1024;
+
+/// Where the OAuth access token for GCS comes from.
+#[derive(Clone)]
+pub enum GcsCredentials {
+    /// Service account key, signed into a JWT and exchanged for a token.
+    ServiceAccount(ServiceAccountKey),
+    /// `gcloud auth application-default login` credentials.
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+    },
+    /// The metadata server of GCE, GKE, Cloud Run, ...
+    Metadata,
+    /// No authentication, only used against `STORAGE_EMULATOR_HOST`.
+    Anonymous,
+}
+
+// Secrets must never end up in logs, so Debug only shows the credential kind.
+impl fmt::Debug for GcsCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GcsCredentials::ServiceAccount(key) => {
+                write!(f, "ServiceAccount({})", key.client_email)
+            }
+            GcsCredentials::AuthorizedUser { .. } => write!(f, "AuthorizedUser(***)"),
+            GcsCredentials::Metadata => write!(f, "Metadata"),
+            GcsCredentials::Anonymous => write!(f, "Anonymous"),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct ServiceAccountKey {
+    pub client_email: String,
+    pub private_key: String,
+    #[serde(default = "default_token_uri")]
+    pub token_uri: String,
+}
+
+fn default_token_uri() -> String {
+    "https://oauth2.googleapis.com/token".to_string()
+}
+
+/// Credentials file as written by the Cloud console or gcloud.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum CredentialsFile {
+    ServiceAccount(ServiceAccountKey),
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+    },
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+/// An archive upload to a Google Cloud Storage object.
+#[derive(Debug, Clone)]
+pub struct GcsUpload {
+    pub bucket: String,
+    /// Object name, with naming templates already expanded.
+    pub object: String,
+    pub credentials: GcsCredentials,
+    pub content_type: &'static str,
+    /// `STORAGE_EMULATOR_HOST` or the public API.
+    pub endpoint: String,
+    pub retry: RetryPolicy,
+    /// Client with the proxy and TLS settings of the config.
+    pub client: reqwest::Client,
+    /// Compare the stored object's size and MD5 against the bytes sent once
+    /// the upload finishes.
+    pub verify: bool,
+}
+
+impl GcsUpload {
+    /// Parses a `gs://bucket/object` output.
+    ///
+    /// `authentication` may hold a credentials JSON file (path, `file:` or
+    /// `env:` reference, or the JSON itself); otherwise Application Default
+    /// Credentials are used: `GOOGLE_APPLICATION_CREDENTIALS`, the gcloud ADC
+    /// file, then the metadata server.
+    pub fn from_config(output: &str, config: &Config) -> Result<Self> {
+        let rest = output
+            .strip_prefix("gs://")
+            .with_context(|| format!("not a GCS URL: {output}"))?;
+        let (bucket, object) = rest
+            .split_once('/')
+            .filter(|(bucket, object)| !bucket.is_empty() && !object.is_empty())
+            .with_context(|| format!("expected gs://bucket/object, got {output}"))?;
+        if object.ends_with('/') {
+            bail!("object name in {output} must name a file");
+        }
+
+        let emulator = std::env::var("STORAGE_EMULATOR_HOST").ok();
+        let credentials = match config.authentication.as_deref().filter(|a| !a.is_empty()) {
+            Some(value) => read_credentials(&resolve_secret(value)?)?,
+            None if emulator.is_some() => GcsCredentials::Anonymous,
+            None => application_default_credentials()?,
+        };
+        let endpoint = match emulator {
+            Some(host) if host.contains("://") => host,
+            Some(host) => format!("http://{host}"),
+            None => API.to_string(),
+        };
+
+        Ok(GcsUpload {
+            bucket: bucket.to_string(),
+            object: create_file_name(object, config)?
+                .to_string_lossy()
+                .to_string(),
+            credentials,
+            content_type: archive_content_type(config)?,
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            retry: RetryPolicy::from_config(config)?,
+            client: http_client(config)?,
+            verify: config.verify_upload.unwrap_or(false),
+        })
+    }
+}
+
+/// Parses credentials given either as JSON or as the path of a JSON file.
+fn read_credentials(value: &str) -> Result<GcsCredentials> {
+    let json = if value.trim_start().starts_with('{') {
+        value.to_string()
+    } else {
+        std::fs::read_to_string(value)
+            .with_context(|| format!("reading GCS credentials file {value}"))?
+    };
+    let file: CredentialsFile =
+        serde_json::from_str(&json).context("parsing GCS credentials JSON")?;
+    Ok(match file {
+        CredentialsFile::ServiceAccount(key) => GcsCredentials::ServiceAccount(key),
+        CredentialsFile::AuthorizedUser {
+            client_id,
+            client_secret,
+            refresh_token,
+        } => GcsCredentials::AuthorizedUser {
+            client_id,
+            client_secret,
+            refresh_token,
+        },
+    })
+}
+
+fn application_default_credentials() -> Result<GcsCredentials> {
+    if let Ok(path) = std::env::var("GOOGLE_APPLICATION_CREDENTIALS") {
+        return read_credentials(&path);
+    }
+    let gcloud = match std::env::var_os("APPDATA") {
+        Some(appdata) => PathBuf::from(appdata).join("gcloud"),
+        None => std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .unwrap_or_default()
+            .join(".config/gcloud"),
+    };
+    let adc = gcloud.join("application_default_credentials.json");
+    if adc.exists() {
+        return read_credentials(&adc.to_string_lossy());
+    }
+    Ok(GcsCredentials::Metadata)
+}
+
+impl GcsCredentials {
+    /// Fetches an access token, or `None` for anonymous access.
+    async fn access_token(&self, client: &reqwest::Client) -> Result<Option<String>> {
+        let request = match self {
+            GcsCredentials::ServiceAccount(key) => client.post(&key.token_uri).form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &signed_jwt(key)?),
+            ]),
+            GcsCredentials::AuthorizedUser {
+                client_id,
+                client_secret,
+                refresh_token,
+            } => client.post("https://oauth2.googleapis.com/token").form(&[
+                ("grant_type", "refresh_token"),
+                ("client_id", client_id),
+                ("client_secret", client_secret),
+                ("refresh_token", refresh_token),
+            ]),
+            GcsCredentials::Metadata => {
+                let host =
+                    std::env::var("GCE_METADATA_HOST").unwrap_or_else(|_| METADATA_HOST.into());
+                client
+                    .get(format!(
+                        "http://{host}/computeMetadata/v1/instance/service-accounts/default/token"
+                    ))
+                    .header("Metadata-Flavor", "Google")
+            }
+            GcsCredentials::Anonymous => return Ok(None),
+        };
+
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("requesting a GCS access token ({self:?})"))?;
+        if !response.status().is_success() {
+            bail!(
+                "GCS token request failed with status {}: {}",
+                response.status(),
+                response.text().await.unwrap_or_default()
+            );
+        }
+        let token: TokenResponse =
+            serde_json::from_str(&response.text().await?).context("parsing GCS token response")?;
+        Ok(Some(token.access_token))
+    }
+}
+
+/// Builds the RS256-signed JWT a service account exchanges for a token.
+fn signed_jwt(key: &ServiceAccountKey) -> Result<String> {
+    let now = chrono::Utc::now().timestamp();
+    let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256","typ":"JWT"}"#);
+    let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&serde_json::json!({
+        "iss": key.client_email,
+        "scope": SCOPE,
+        "aud": key.token_uri,
+        "iat": now,
+        "exp": now + 3600,
+    }))?);
+    let message = format!("{header}.{claims}");
+
+    let der = pem_to_der(&key.private_key)?;
+    let pair = ring::signature::RsaKeyPair::from_pkcs8(&der)
+        .map_err(|e| anyhow!("invalid service account private key: {e}"))?;
+    let mut signature = vec![0; pair.public().modulus_len()];
+    pair.sign(
+        &ring::signature::RSA_PKCS1_SHA256,
+        &ring::rand::SystemRandom::new(),
+        message.as_bytes(),
+        &mut signature,
+    )
+    .map_err(|_| anyhow!("signing the service account JWT failed"))?;
+    Ok(format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature)))
+}
+
+fn pem_to_der(pem: &str) -> Result<Vec<u8>> {
+    let body: String = pem
+        .lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    base64::engine::general_purpose::STANDARD
+        .decode(body.trim())
+        .context("decoding service account private key")
+}
+
+#[async_trait]
+impl Sink for GcsUpload {
+    async fn open_writer(&self, buffer_size: usize) -> Result<SinkWriter> {
+        let upload = self.clone();
+        Ok(SinkWriter::committed(buffer_size, |reader, committed| {
+            upload_stream(upload, reader, committed)
+        }))
+    }
+}
+
+/// Uploads everything read from `reader` through a resumable upload session.
+///
+/// Chunks that fail are retried from the offset the server confirms. The
+/// object is only finalized once `commit` fires; if it is dropped because the
+/// archive couldn't be completed, the session is cancelled instead.
+pub async fn upload_stream<R>(
+    upload: GcsUpload,
+    mut reader: R,
+    commit: oneshot::Receiver<()>,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let client = upload.client.clone();
+    let session = start_session(&client, &upload).await?;
+
+    let counter = upload.verify.then(SentCounter::default);
+    let mut offset = 0u64;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let last = loop {
+        let mut filled = 0;
+        while filled < CHUNK_SIZE {
+            match reader.read(&mut buffer[filled..]).await? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        if let Some(counter) = &counter {
+            counter.update(&buffer[..filled]);
+        }
+        // Only a short chunk is known to be the last one
+        if filled < CHUNK_SIZE {
+            break filled;
+        }
+        send_chunk(&client, &upload.retry, &session, &buffer, offset, None).await?;
+        offset += CHUNK_SIZE as u64;
+    };
+
+    if commit.await.is_err() {
+        // The archive writer reports why it stopped
+        let _ = client.delete(&session).send().await;
+        return Ok(());
+    }
+    let total = offset + last as u64;
+    send_chunk(
+        &client,
+        &upload.retry,
+        &session,
+        &buffer[..last],
+        offset,
+        Some(total),
+    )
+    .await?;
+    if let Some(counter) = counter {
+        verify(&client, &upload, &counter).await?;
+    }
+    Ok(())
+}
+
+/// Metadata of a stored object, as far as verification needs it.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectMetadata {
+    /// A decimal number, in a string.
+    size: String,
+    /// Base64; composite objects have none.
+    md5_hash: Option<String>,
+}
+
+/// Compares the size and MD5 GCS stored for the object with the bytes sent.
+async fn verify(client: &reqwest::Client, upload: &GcsUpload, counter: &SentCounter) -> Result<()> {
+    let mut url = Url::parse(&format!("{}/storage/v1/b", upload.endpoint))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid GCS endpoint {}", upload.endpoint))?
+        .extend([upload.bucket.as_str(), "o", upload.object.as_str()]);
+
+    let mut request = client.get(url);
+    if let Some(token) = upload.credentials.access_token(client).await? {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        bail!(
+            "GCS verification failed: reading gs://{}/{} returned status {}",
+            upload.bucket,
+            upload.object,
+            response.status()
+        );
+    }
+    let metadata: ObjectMetadata =
+        serde_json::from_str(&response.text().await?).context("parsing GCS object metadata")?;
+
+    let sent = counter.sent();
+    let size = metadata
+        .size
+        .parse()
+        .with_context(|| format!("invalid object size {}", metadata.size))?;
+    sent.check_size("GCS", size)?;
+    match metadata.md5_hash.map(|hash| STANDARD.decode(hash)) {
+        Some(hash) => {
+            sent.check_hash("GCS", "md5Hash", HashAlgorithm::Md5, &hash?)?;
+            info!("Verified remote object: {size} bytes, MD5");
+        }
+        None => info!("Verified remote object: {size} bytes"),
+    }
+    Ok(())
+}
+
+/// Requests the contents of the object named by `upload`, for restoring it.
+pub async fn download(client: &reqwest::Client, upload: &GcsUpload) -> Result<reqwest::Response> {
+    let mut url = Url::parse(&format!("{}/storage/v1/b", upload.endpoint))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid GCS endpoint {}", upload.endpoint))?
+        .extend([upload.bucket.as_str(), "o", upload.object.as_str()]);
+    url.set_query(Some("alt=media"));
+
+    let mut request = client.get(url);
+    if let Some(token) = upload.credentials.access_token(client).await? {
+        request = request.bearer_auth(token);
+    }
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        bail!(
+            "downloading gs://{}/{} failed with status {}",
+            upload.bucket,
+            upload.object,
+            response.status()
+        );
+    }
+    Ok(response)
+}
+
+/// Whether the object named by `upload` exists, reading its metadata.
+pub async fn exists(client: &reqwest::Client, upload: &GcsUpload) -> Result<bool> {
+    let mut url = Url::parse(&format!("{}/storage/v1/b", upload.endpoint))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid GCS endpoint {}", upload.endpoint))?
+        .extend([upload.bucket.as_str(), "o", upload.object.as_str()]);
+
+    let mut request = client.get(url);
+    if let Some(token) = upload.credentials.access_token(client).await? {
+        request = request.bearer_auth(token);
+    }
+    match request.send().await?.status() {
+        StatusCode::NOT_FOUND => Ok(false),
+        status if status.is_success() => Ok(true),
+        status => bail!(
+            "reading gs://{}/{} failed with status {status}",
+            upload.bucket,
+            upload.object
+        ),
+    }
+}
+
+/// Deletes the object named by `upload`; one that is already gone counts as deleted.
+pub async fn delete(client: &reqwest::Client, upload: &GcsUpload) -> Result<()> {
+    let mut url = Url::parse(&format!("{}/storage/v1/b", upload.endpoint))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid GCS endpoint {}", upload.endpoint))?
+        .extend([upload.bucket.as_str(), "o", upload.object.as_str()]);
+
+    let mut request = client.delete(url);
+    if let Some(token) = upload.credentials.access_token(client).await? {
+        request = request.bearer_auth(token);
+    }
+    let status = request.send().await?.status();
+    if !status.is_success() && status != StatusCode::NOT_FOUND {
+        bail!(
+            "deleting gs://{}/{} failed with status {status}",
+            upload.bucket,
+            upload.object
+        );
+    }
+    Ok(())
+}
+
+async fn start_session(client: &reqwest::Client, upload: &GcsUpload) -> Result<String> {
+    let mut request = client
+        .post(format!(
+            "{}/upload/storage/v1/b/{}/o",
+            upload.endpoint, upload.bucket
+        ))
+        .query(&[("uploadType", "resumable"), ("name", &upload.object)])
+        .header("X-Upload-Content-Type", upload.content_type)
+        .header(header::CONTENT_LENGTH, 0);
+    if let Some(token) = upload.credentials.access_token(client).await? {
+        request = request.bearer_auth(token);
+    }
+
+    let response = request.send().await?;
+    if !response.status().is_success() {
+        bail!(
+            "starting GCS upload to gs://{}/{} failed with status {}: {}",
+            upload.bucket,
+            upload.object,
+            response.status(),
+            response.text().await.unwrap_or_default()
+        );
+    }
+    Ok(response
+        .headers()
+        .get(header::LOCATION)
+        .context("GCS returned no upload session URL")?
+        .to_str()?
+        .to_string())
+}
+
+/// Sends `chunk`, which starts at `offset`, resending whatever the server did
+/// not persist. `total` is set for the final chunk.
+async fn send_chunk(
+    client: &reqwest::Client,
+    retry: &RetryPolicy,
+    session: &str,
+    chunk: &[u8],
+    offset: u64,
+    total: Option<u64>,
+) -> Result<()> {
+    let total_str = total.map_or("*".to_string(), |t| t.to_string());
+    let end = offset + chunk.len() as u64;
+    let mut sent = offset;
+    let mut attempt = 1;
+
+    loop {
+        let range = if sent == end {
+            format!("bytes */{total_str}")
+        } else {
+            format!("bytes {sent}-{}/{total_str}", end - 1)
+        };
+        let body = chunk[(sent - offset) as usize..].to_vec();
+        let result = client
+            .put(session)
+            .header(header::CONTENT_RANGE, range)
+            .body(body)
+            .send()
+            .await;
+
+        match result {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            Ok(response) if response.status() == StatusCode::PERMANENT_REDIRECT => {
+                let stored = persisted(&response).max(offset);
+                if stored >= end && total.is_none() {
+                    return Ok(());
+                }
+                if stored > sent {
+                    // A partially persisted chunk is progress, not a failure
+                    sent = stored;
+                    continue;
+                }
+                let error = format!("no bytes accepted from offset {sent}");
+                let Some(delay) = retry.next_delay("GCS chunk upload", attempt, &error) else {
+                    bail!("GCS upload failed after {attempt} attempts: {error}");
+                };
+                attempt += 1;
+                tokio::time::sleep(delay).await;
+            }
+            Ok(response) if !response.status().is_server_error() => {
+                bail!(
+                    "GCS upload failed with status {}: {}",
+                    response.status(),
+                    response.text().await.unwrap_or_default()
+                );
+            }
+            result => {
+                let reason = match result {
+                    Ok(response) => format!("status {}", response.status()),
+                    Err(e) => e.to_string(),
+                };
+                let Some(delay) = retry.next_delay("GCS chunk upload", attempt, &reason) else {
+                    bail!("GCS upload failed after {attempt} attempts: {reason}");
+                };
+                attempt += 1;
+                tokio::time::sleep(delay).await;
+                sent = query_offset(client, session, &total_str).await?.max(offset);
+            }
+        }
+    }
+}
+
+/// Asks the server how many bytes of the session it has persisted.
+async fn query_offset(client: &reqwest::Client, session: &str, total: &str) -> Result<u64> {
+    let response = client
+        .put(session)
+        .header(header::CONTENT_RANGE, format!("bytes */{total}"))
+        .header(header::CONTENT_LENGTH, 0)
+        .send()
+        .await?;
+    match response.status() {
+        StatusCode::PERMANENT_REDIRECT => Ok(persisted(&response)),
+        status => bail!("querying GCS upload status failed with status {status}"),
+    }
+}
+
+/// Reads the persisted size from the `Range: bytes=0-N` header of a 308.
+fn persisted(response: &reqwest::Response) -> u64 {
+    response
+        .headers()
+        .get(header::RANGE)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| range.rsplit_once('-'))
+        .and_then(|(_, last)| last.parse::<u64>().ok())
+        .map_or(0, |last| last + 1)
+}
@@ -0,0 +1,582 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, ready};
+
+use crate::file_reader::SourceReader;
+use crate::packaging::{ArchiveFormat, ArchiveOutput, PackagerOptions, write_archive};
+use async_trait::async_trait;
+use ftp::{FtpSecurity, FtpUpload};
+use gcs::GcsUpload;
+use reqwest::{Method, Url};
+use save_file::LocalFile;
+use scp::ScpUpload;
+use send_net::HttpUpload;
+use sftp::SftpUpload;
+use tokio::io::{AsyncRead, DuplexStream, ReadBuf};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tus::TusUpload;
+use webdav::WebDavUpload;
+
+pub mod check;
+pub mod checksum;
+pub mod digest;
+pub mod ftp;
+pub mod gcs;
+pub mod resume;
+pub mod retry;
+pub mod save_file;
+pub mod scp;
+pub mod send_net;
+pub mod sftp;
+pub mod ssh;
+pub mod tee;
+pub mod tus;
+pub mod verify;
+pub mod webdav;
+
+/// Bytes buffered between the archive writer and each sink unless
+/// `upload_buffer_size` says otherwise.
+pub const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Defines the destination for the generated backup archive.
+#[derive(Debug, Clone)]
+pub enum OutSink {
+    /// Save the archive to a local file, written atomically via a `.part` file.
+    SaveToFile(LocalFile),
+    /// Upload the archive to a remote URL via HTTP POST.
+    UploadToUrl(HttpUpload),
+    /// Upload the archive to a tus server in checksummed chunks.
+    UploadToTus(TusUpload),
+    /// Upload the archive to a WebDAV server via HTTP PUT.
+    UploadToWebDav(WebDavUpload),
+    /// Stream the archive to a remote host over SSH.
+    Scp(ScpUpload),
+    /// Upload the archive to an SFTP server, resuming after dropped connections.
+    Sftp(SftpUpload),
+    /// Upload the archive to Google Cloud Storage in a resumable session.
+    UploadToGcs(GcsUpload),
+    /// Upload the archive to an FTP or FTPS server.
+    UploadToFtp(FtpUpload),
+    /// Stream the archive to stdout for piping into other tools.
+    Stdout,
+}
+
+impl OutSink {
+    /// Whether the sink talks to another host, so a failure may be worth retrying.
+    pub fn is_remote(&self) -> bool {
+        !matches!(self, OutSink::SaveToFile(_) | OutSink::Stdout)
+    }
+
+    /// Where the written archive can be found again by `ssbt prune`: an
+    /// absolute path, a `webdav(s)://` or a `gs://` URL. `None` for stdout,
+    /// HTTP POST endpoints and the SSH/FTP outputs.
+    pub fn archive_location(&self) -> Option<String> {
+        match self {
+            OutSink::SaveToFile(file) => std::path::absolute(&file.path)
+                .ok()
+                .map(|path| path.display().to_string()),
+            OutSink::UploadToWebDav(upload) => Some(webdav::to_webdav_url(&upload.http.url)),
+            OutSink::UploadToGcs(upload) => {
+                Some(format!("gs://{}/{}", upload.bucket, upload.object))
+            }
+            _ => None,
+        }
+    }
+
+    /// The same destination with the archive's path changed by `renamed`.
+    /// `None` where the name isn't ours to choose (stdout, HTTP POST
+    /// endpoints).
+    fn renamed(&self, renamed: impl Fn(&str) -> String) -> Option<OutSink> {
+        Some(match self {
+            OutSink::Stdout => return None,
+            OutSink::UploadToUrl(upload) => OutSink::UploadToUrl(upload.renamed(renamed)?),
+            OutSink::SaveToFile(file) => OutSink::SaveToFile(LocalFile {
+                path: renamed(&file.path.to_string_lossy()).into(),
+                overwrite: file.overwrite,
+                fsync: file.fsync,
+            }),
+            OutSink::UploadToTus(upload) => {
+                let mut renamed_upload = upload.clone();
+                renamed_upload.filename = renamed(&upload.filename);
+                OutSink::UploadToTus(renamed_upload)
+            }
+            OutSink::UploadToWebDav(upload) => {
+                let mut renamed_upload = upload.clone();
+                renamed_upload.http.url = renamed(&upload.http.url);
+                OutSink::UploadToWebDav(renamed_upload)
+            }
+            OutSink::Scp(upload) => {
+                let mut renamed_upload = upload.clone();
+                renamed_upload.target.path = renamed(&upload.target.path);
+                OutSink::Scp(renamed_upload)
+            }
+            OutSink::Sftp(upload) => {
+                let mut renamed_upload = upload.clone();
+                renamed_upload.target.path = renamed(&upload.target.path);
+                OutSink::Sftp(renamed_upload)
+            }
+            OutSink::UploadToGcs(upload) => {
+                let mut renamed_upload = upload.clone();
+                renamed_upload.object = renamed(&upload.object);
+                OutSink::UploadToGcs(renamed_upload)
+            }
+            OutSink::UploadToFtp(upload) => {
+                let mut renamed_upload = upload.clone();
+                renamed_upload.path = renamed(&upload.path);
+                OutSink::UploadToFtp(renamed_upload)
+            }
+        })
+    }
+
+    /// Whether the archive already exists at this destination. `None` where
+    /// that can't be told (stdout, HTTP POST endpoints, tus servers).
+    pub async fn exists(&self) -> anyhow::Result<Option<bool>> {
+        Ok(Some(match self {
+            OutSink::Stdout | OutSink::UploadToTus(_) => return Ok(None),
+            OutSink::UploadToUrl(upload) => return upload.exists().await,
+            OutSink::SaveToFile(file) => tokio::fs::try_exists(&file.path).await?,
+            OutSink::UploadToWebDav(upload) => webdav::exists(upload).await?,
+            OutSink::Scp(upload) => scp::exists(upload).await?,
+            OutSink::Sftp(upload) => sftp::exists(upload).await?,
+            OutSink::UploadToGcs(upload) => gcs::exists(&upload.client, upload).await?,
+            OutSink::UploadToFtp(upload) => ftp::exists(upload).await?,
+        }))
+    }
+
+    /// The same destination with `-{number}` added to the archive's name,
+    /// before its extensions: `etc.tar.gz` becomes `etc-1.tar.gz`.
+    pub fn suffixed(&self, number: u32) -> Option<OutSink> {
+        self.renamed(|path| {
+            let stem = path.len() - archive_extensions(path).len();
+            format!("{}-{number}{}", &path[..stem], &path[stem..])
+        })
+    }
+
+    /// The archive's name as written to this sink.
+    fn archive_name(&self) -> Option<String> {
+        let path = match self {
+            OutSink::Stdout => return None,
+            OutSink::UploadToUrl(upload) if upload.method == Method::PUT => {
+                Url::parse(&upload.url).ok()?.path().to_string()
+            }
+            OutSink::UploadToUrl(_) => return None,
+            OutSink::UploadToTus(upload) => upload.filename.clone(),
+            OutSink::SaveToFile(file) => file.path.to_string_lossy().to_string(),
+            OutSink::UploadToWebDav(upload) => upload.http.url.clone(),
+            OutSink::Scp(ScpUpload { target }) | OutSink::Sftp(SftpUpload { target, .. }) => {
+                target.path.clone()
+            }
+            OutSink::UploadToGcs(upload) => upload.object.clone(),
+            OutSink::UploadToFtp(upload) => upload.path.clone(),
+        };
+        Some(checksum::archive_name(&path))
+    }
+
+    /// A sink storing a small file named like this sink's archive plus
+    /// `.{extension}`, together with the archive's file name. `None` where the
+    /// name isn't ours to choose (stdout, HTTP POST endpoints).
+    pub fn sidecar(&self, extension: &str) -> Option<(OutSink, String)> {
+        let mut sidecar = self.renamed(|path| format!("{path}.{extension}"))?;
+        match &mut sidecar {
+            OutSink::SaveToFile(file) => file.overwrite = save_file::OverwritePolicy::Always,
+            OutSink::UploadToWebDav(upload) => {
+                upload.http.content_type = "text/plain";
+                upload.verify = false;
+            }
+            OutSink::UploadToGcs(upload) => {
+                upload.content_type = "text/plain";
+                upload.verify = false;
+            }
+            OutSink::UploadToUrl(upload) => {
+                upload.content_type = "text/plain";
+                upload.verify = false;
+            }
+            OutSink::UploadToTus(upload) => upload.http.content_type = "text/plain",
+            _ => {}
+        }
+        Some((sidecar, self.archive_name()?))
+    }
+
+    /// The sink receiving volume `number` (from 1) of a split archive, named
+    /// `<archive>.001` and so on. HTTP POST endpoints get every volume posted
+    /// to the same URL, numbered in an `X-Ssbt-Volume` header. `None` for stdout.
+    pub fn volume(&self, number: u32) -> Option<OutSink> {
+        let extension = format!("{number:03}");
+        match self {
+            OutSink::UploadToUrl(upload) if upload.method != Method::PUT => {
+                let mut volume = upload.clone();
+                volume
+                    .headers
+                    .push(("X-Ssbt-Volume".to_string(), extension));
+                Some(OutSink::UploadToUrl(volume))
+            }
+            _ => self.renamed(|path| format!("{path}.{extension}")),
+        }
+    }
+}
+
+/// Extensions ending `path` that `suffixed` keeps together: those of
+/// archives and their compression and encryption, or else the last one.
+fn archive_extensions(path: &str) -> &str {
+    const KNOWN: [&str; 7] = [".age", ".gz", ".zst", ".tgz", ".tar", ".zip", ".7z"];
+    let name_start = path.rfind('/').map_or(0, |slash| slash + 1);
+    let name = &path[name_start..];
+    let mut stem = name;
+    while let Some(extension) = KNOWN.iter().find(|extension| {
+        stem.len() > extension.len() && stem.to_ascii_lowercase().ends_with(*extension)
+    }) {
+        stem = &stem[..stem.len() - extension.len()];
+    }
+    if stem.len() == name.len()
+        && let Some(dot) = name.rfind('.').filter(|&dot| dot > 0)
+    {
+        stem = &name[..dot];
+    }
+    &name[stem.len()..]
+}
+
+/// A short description of the destination for status and error messages.
+impl fmt::Display for OutSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutSink::SaveToFile(file) => write!(f, "{}", file.path.display()),
+            OutSink::UploadToUrl(upload) => write!(f, "{}", upload.url),
+            OutSink::UploadToTus(upload) => {
+                write!(f, "tus {} ({})", upload.http.url, upload.filename)
+            }
+            OutSink::UploadToWebDav(upload) => write!(f, "{}", upload.http.url),
+            OutSink::Scp(ScpUpload { target }) | OutSink::Sftp(SftpUpload { target, .. }) => {
+                let scheme = if matches!(self, OutSink::Scp(_)) {
+                    "scp"
+                } else {
+                    "sftp"
+                };
+                write!(
+                    f,
+                    "{scheme} {}@{}:{}",
+                    target.user, target.host, target.path
+                )
+            }
+            OutSink::UploadToGcs(upload) => write!(f, "gs://{}/{}", upload.bucket, upload.object),
+            OutSink::UploadToFtp(upload) => {
+                let scheme = match upload.security {
+                    FtpSecurity::None => "ftp",
+                    FtpSecurity::Explicit => "ftpes",
+                    FtpSecurity::Implicit => "ftps",
+                };
+                write!(
+                    f,
+                    "{scheme}://{}@{}:{}/{}",
+                    upload.user, upload.host, upload.port, upload.path
+                )
+            }
+            OutSink::Stdout => write!(f, "stdout"),
+        }
+    }
+}
+
+/// Streams an archive of the given format to the specified output sink.
+///
+/// # Example
+/// ```no_run
+/// use std::path::PathBuf;
+/// # use std::sync::Arc;
+/// # use ssbt_lib::Config;
+/// # use ssbt_lib::file_reader::SourceReader;
+/// # use ssbt_lib::packaging::{ArchiveFormat, PackagerOptions};
+/// # use ssbt_lib::sink::save_file::{LocalFile, OverwritePolicy};
+/// # use ssbt_lib::sink::send_net::HttpUpload;
+/// # use ssbt_lib::sink::{OutSink, stream_archive_to_sink};
+///
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let files = vec![
+///         ("readme.txt".to_string(), PathBuf::from("/tmp/readme.txt")),
+///         ("config.json".to_string(), PathBuf::from("/tmp/config.json")),
+///     ];
+///     let reader = Arc::new(SourceReader::default());
+///     let options = PackagerOptions {
+///         compress: true,
+///         ..Default::default()
+///     };
+///
+///     // Save to file
+///     let sink = OutSink::SaveToFile(LocalFile {
+///         path: PathBuf::from("backups/archive.zip"),
+///         overwrite: OverwritePolicy::Always,
+///         fsync: true,
+///     });
+///     stream_archive_to_sink(ArchiveFormat::Zip, files.clone(), &options, &reader, sink).await?;
+///
+///     // Upload via HTTP
+///     let upload = HttpUpload::from_config("https://api.example.com/upload", &Config::default())?;
+///     let sink = OutSink::UploadToUrl(upload);
+///     stream_archive_to_sink(ArchiveFormat::Zip, files, &options, &reader, sink).await?;
+///
+///     Ok(())
+/// }
+/// ```
+pub async fn stream_archive_to_sink(
+    format: ArchiveFormat,
+    files: Vec<(String, PathBuf)>,
+    options: &PackagerOptions,
+    reader: &Arc<SourceReader>,
+    sink: OutSink,
+) -> anyhow::Result<()> {
+    deliver(sink, DEFAULT_BUFFER_SIZE, |output| async move {
+        write_archive(format, files, options, reader, output)
+            .await
+            .map(|_| ())
+    })
+    .await
+}
+
+/// Opens `sink`, lets `produce` write the archive into it and completes the
+/// sink (rename, upload, ...) only if `produce` succeeded. Uploads read the
+/// archive from a pipe buffering up to `buffer_size` bytes.
+pub async fn deliver<F, Fut>(sink: OutSink, buffer_size: usize, produce: F) -> anyhow::Result<()>
+where
+    F: FnOnce(ArchiveOutput) -> Fut,
+    Fut: Future<Output = anyhow::Result<()>>,
+{
+    let SinkWriter { output, completion } = sink.open_writer(buffer_size).await?;
+    let result = produce(output).await;
+    completion.finish(result).await
+}
+
+/// A destination archives are written to. Each kind of output implements it
+/// once; [`deliver`] and the tee drive every sink the same way.
+#[async_trait]
+pub trait Sink {
+    /// Opens the destination for one archive. Uploads read it from a pipe
+    /// buffering up to `buffer_size` bytes. Nothing is kept unless the
+    /// writer's completion is finished with a successful result.
+    async fn open_writer(&self, buffer_size: usize) -> anyhow::Result<SinkWriter>;
+}
+
+#[async_trait]
+impl Sink for OutSink {
+    async fn open_writer(&self, buffer_size: usize) -> anyhow::Result<SinkWriter> {
+        match self {
+            OutSink::SaveToFile(target) => target.open_writer(buffer_size).await,
+            OutSink::UploadToUrl(upload) => upload.open_writer(buffer_size).await,
+            OutSink::UploadToTus(upload) => upload.open_writer(buffer_size).await,
+            OutSink::UploadToWebDav(upload) => upload.open_writer(buffer_size).await,
+            OutSink::Scp(upload) => upload.open_writer(buffer_size).await,
+            OutSink::Sftp(upload) => upload.open_writer(buffer_size).await,
+            OutSink::UploadToGcs(upload) => upload.open_writer(buffer_size).await,
+            OutSink::UploadToFtp(upload) => upload.open_writer(buffer_size).await,
+            OutSink::Stdout => Ok(SinkWriter::direct(Box::new(tokio::io::stdout()))),
+        }
+    }
+}
+
+/// An opened sink: where the archive goes, and how the sink is completed
+/// once it is written.
+pub struct SinkWriter {
+    pub output: ArchiveOutput,
+    pub completion: SinkCompletion,
+}
+
+impl SinkWriter {
+    /// Writes straight to `output`, with nothing to complete.
+    pub fn direct(output: ArchiveOutput) -> Self {
+        SinkWriter {
+            output,
+            completion: SinkCompletion(Finish::Direct),
+        }
+    }
+
+    /// Writes to the `.part` file of `target`, renamed into place once the
+    /// archive is complete and removed otherwise.
+    pub fn local(output: ArchiveOutput, target: LocalFile) -> Self {
+        SinkWriter {
+            output,
+            completion: SinkCompletion(Finish::Local(target)),
+        }
+    }
+
+    /// Streams the archive to an upload that stores whatever its body
+    /// receives. The [`UploadPipe`] it reads from fails at its end unless the
+    /// archive was completed, so a truncated archive is never stored.
+    pub fn streamed<F, Fut>(buffer_size: usize, upload: F) -> Self
+    where
+        F: FnOnce(UploadPipe) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let (writer, pipe, completion) = upload_pipe(buffer_size);
+        SinkWriter {
+            output: Box::new(writer),
+            completion: SinkCompletion(Finish::Streamed {
+                completion,
+                upload_task: tokio::spawn(upload(pipe)),
+            }),
+        }
+    }
+
+    /// Streams the archive to an upload with a commit step of its own (a
+    /// rename on the remote host, the final chunk, ...), which it only takes
+    /// once the receiver fires for a complete archive.
+    pub fn committed<F, Fut>(buffer_size: usize, upload: F) -> Self
+    where
+        F: FnOnce(DuplexStream, oneshot::Receiver<()>) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let (writer, pipe_reader) = tokio::io::duplex(buffer_size);
+        let (commit, committed) = oneshot::channel();
+        SinkWriter {
+            output: Box::new(writer),
+            completion: SinkCompletion(Finish::Committed {
+                commit,
+                upload_task: tokio::spawn(upload(pipe_reader, committed)),
+            }),
+        }
+    }
+}
+
+/// Completes a [`SinkWriter`] once its archive is written.
+pub struct SinkCompletion(Finish);
+
+enum Finish {
+    Direct,
+    Local(LocalFile),
+    Streamed {
+        completion: Completion,
+        upload_task: JoinHandle<anyhow::Result<()>>,
+    },
+    Committed {
+        commit: oneshot::Sender<()>,
+        upload_task: JoinHandle<anyhow::Result<()>>,
+    },
+}
+
+impl SinkCompletion {
+    /// Keeps the archive if `result` is a success and discards it otherwise,
+    /// returning the first error of either the archive or the sink.
+    pub async fn finish(self, result: anyhow::Result<()>) -> anyhow::Result<()> {
+        match self.0 {
+            Finish::Direct => result,
+            Finish::Local(target) => {
+                let result = match result {
+                    Ok(()) => target.commit().await,
+                    Err(e) => Err(e),
+                };
+                if result.is_err() {
+                    target.discard().await;
+                }
+                result
+            }
+            Finish::Streamed {
+                completion,
+                upload_task,
+            } => completion.finish(upload_task, result).await,
+            Finish::Committed {
+                commit,
+                upload_task,
+            } => {
+                if result.is_ok() {
+                    let _ = commit.send(());
+                } else {
+                    drop(commit);
+                }
+                // A failed connection also breaks the pipe, so report it first
+                upload_task.await??;
+                result
+            }
+        }
+    }
+}
+
+/// Creates the pipe an HTTP upload reads the archive from: the writer's end,
+/// the upload's end, and the [`Completion`] that ends the pipe.
+fn upload_pipe(buffer_size: usize) -> (DuplexStream, UploadPipe, Completion) {
+    let (writer, reader) = tokio::io::duplex(buffer_size);
+    let (completed, completion) = oneshot::channel();
+    let abandoned = Arc::new(AtomicBool::new(false));
+    let pipe = UploadPipe {
+        reader,
+        completion: Some(completion),
+        abandoned: abandoned.clone(),
+    };
+    (
+        writer,
+        pipe,
+        Completion {
+            completed,
+            abandoned,
+        },
+    )
+}
+
+/// The reading end of an HTTP upload's pipe. At the end of the archive it
+/// waits for the archive writer's verdict, and fails instead of ending if
+/// the archive wasn't completed, so a streamed request body is never
+/// finished with a truncated archive.
+pub struct UploadPipe {
+    reader: DuplexStream,
+    /// `None` once the verdict is in.
+    completion: Option<oneshot::Receiver<()>>,
+    /// Set once the pipe failed because the archive wasn't completed.
+    abandoned: Arc<AtomicBool>,
+}
+
+impl AsyncRead for UploadPipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.reader).poll_read(cx, buf))?;
+        if buf.filled().len() > filled || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(completion) = &mut self.completion {
+            let completed = ready!(Pin::new(completion).poll(cx)).is_ok();
+            self.completion = None;
+            if !completed {
+                self.abandoned.store(true, Ordering::Relaxed);
+            }
+        }
+        if self.abandoned.load(Ordering::Relaxed) {
+            return Poll::Ready(Err(io::Error::other("archive was not completed")));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The archive writer's end of an [`UploadPipe`].
+struct Completion {
+    completed: oneshot::Sender<()>,
+    abandoned: Arc<AtomicBool>,
+}
+
+impl Completion {
+    /// Ends the pipe according to the archive's `result` and waits for the
+    /// upload. Whichever side failed first is reported: a failed upload also
+    /// breaks the pipe, and an archive that failed also fails the upload.
+    async fn finish(
+        self,
+        upload_task: JoinHandle<anyhow::Result<()>>,
+        result: anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let Completion {
+            completed,
+            abandoned,
+        } = self;
+        if result.is_ok() {
+            let _ = completed.send(());
+        } else {
+            drop(completed);
+        }
+        match upload_task.await? {
+            Err(e) if !abandoned.load(Ordering::Relaxed) => Err(e),
+            _ => result,
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::VecDeque;
+
+use anyhow::{Result, bail};
+
+/// The most recently sent bytes of a sequential upload, kept in memory to
+/// replay whatever a dropped connection lost before the server stored it.
+pub struct ResumeWindow {
+    data: VecDeque<u8>,
+    /// Upload offset of the first byte in `data`.
+    start: u64,
+    capacity: usize,
+}
+
+impl ResumeWindow {
+    pub fn new(capacity: usize) -> Self {
+        ResumeWindow {
+            data: VecDeque::new(),
+            start: 0,
+            capacity,
+        }
+    }
+
+    /// Records bytes that are about to be sent.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.data.extend(bytes);
+        if self.data.len() > self.capacity {
+            let excess = self.data.len() - self.capacity;
+            self.data.drain(..excess);
+            self.start += excess as u64;
+        }
+    }
+
+    /// Offset just past the last byte sent.
+    pub fn end(&self) -> u64 {
+        self.start + self.data.len() as u64
+    }
+
+    /// The bytes to resend when the server has stored `stored` bytes.
+    pub fn since(&mut self, stored: u64) -> Result<&[u8]> {
+        if stored < self.start || stored > self.end() {
+            bail!(
+                "cannot resume: server has {stored} bytes, resumable range is {}..={}",
+                self.start,
+                self.end()
+            );
+        }
+        let skip = (stored - self.start) as usize;
+        Ok(&self.data.make_contiguous()[skip..])
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use anyhow::{Result, bail};
+
+use crate::Config;
+use tracing::warn;
+
+/// How often and how patiently failed network operations are retried.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one; 0 disables retrying.
+    pub retries: u32,
+    /// Delay before the first retry, doubled for every further one.
+    pub backoff: Duration,
+    /// Upper bound for a single delay.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 3,
+            backoff: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let defaults = RetryPolicy::default();
+        let seconds = |value: Option<f64>, name: &str, default: Duration| match value {
+            None => Ok(default),
+            // Fails for negative, infinite, NaN and too large values
+            Some(secs) => match Duration::try_from_secs_f64(secs) {
+                Ok(duration) => Ok(duration),
+                Err(e) => {
+                    bail!("{name} must be a non-negative number of seconds, got {secs:?} ({e})")
+                }
+            },
+        };
+        Ok(RetryPolicy {
+            retries: config.retries.unwrap_or(defaults.retries),
+            backoff: seconds(config.retry_backoff, "retry_backoff", defaults.backoff)?,
+            max_delay: seconds(
+                config.retry_max_delay,
+                "retry_max_delay",
+                defaults.max_delay,
+            )?,
+        })
+    }
+
+    /// Delay before retry number `attempt` (starting at 1): exponential, capped
+    /// at `max_delay`, and randomized into its upper half so that clients
+    /// failing together don't hammer the server in lockstep.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .backoff
+            .saturating_mul(1 << attempt.saturating_sub(1).min(31))
+            .min(self.max_delay);
+        exponential.mul_f64(0.5 + rand::random::<f64>() / 2.0)
+    }
+
+    /// Reports the failed `attempt` (starting at 1) of `what` and returns how
+    /// long to wait before the next one, or `None` once retries are exhausted.
+    pub fn next_delay(&self, what: &str, attempt: u32, error: impl Display) -> Option<Duration> {
+        if attempt > self.retries {
+            return None;
+        }
+        let delay = self.delay(attempt);
+        warn!(
+            "{what} failed ({error}), retrying in {:.1}s (attempt {}/{})",
+            delay.as_secs_f64(),
+            attempt + 1,
+            self.retries + 1
+        );
+        Some(delay)
+    }
+}
@@ -0,0 +1,270 @@
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use glob::MatchOptions;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::fs::File;
+use tracing::{info, warn};
+
+use super::{Sink, SinkWriter};
+use crate::Config;
+use crate::journal::journal_path;
+use crate::naming::{name_pattern, output_dir, volume_of};
+
+/// What to do when the destination file already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverwritePolicy {
+    /// Refuse to write, leaving the existing file untouched.
+    Never,
+    /// Replace the existing file.
+    #[default]
+    Always,
+    /// Replace the existing file only if the new archive is larger.
+    IfLarger,
+    /// Write the archive under the first free name with `-1`, `-2`, ...
+    /// added before its extensions.
+    AutoSuffix,
+}
+
+impl FromStr for OverwritePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" | "error" => Ok(OverwritePolicy::Never),
+            "always" | "overwrite" => Ok(OverwritePolicy::Always),
+            "if-larger" | "if_larger" => Ok(OverwritePolicy::IfLarger),
+            "auto-suffix" | "auto_suffix" => Ok(OverwritePolicy::AutoSuffix),
+            other => Err(anyhow!(
+                "unsupported overwrite policy: {other} (expected never, always, if-larger or auto-suffix)"
+            )),
+        }
+    }
+}
+
+/// A local archive destination.
+#[derive(Debug, Clone)]
+pub struct LocalFile {
+    pub path: PathBuf,
+    pub overwrite: OverwritePolicy,
+    /// Whether `commit` fsyncs the archive and its directory.
+    pub fsync: bool,
+}
+
+/// Whether `output` is written to the local file system: not stdout, no URL,
+/// and not the `host:path` of `protocol: scp` or `sftp`, except for Windows
+/// drive letter and UNC paths, which are never taken for a host.
+pub fn is_local_output(output: &str, config: &Config) -> bool {
+    if output.is_empty() || output == "-" || output.contains("://") {
+        return false;
+    }
+    is_windows_path(output) || !matches!(config.protocol.as_deref(), Some("scp" | "sftp"))
+}
+
+/// `C:\backups`, `C:/backups` or `\\server\share` on Windows.
+pub fn is_windows_path(output: &str) -> bool {
+    if !cfg!(windows) {
+        return false;
+    }
+    let bytes = output.as_bytes();
+    let drive = bytes.len() >= 3
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && matches!(bytes[2], b'\\' | b'/');
+    drive || output.starts_with(r"\\")
+}
+
+impl LocalFile {
+    /// Temporary path the archive is streamed into before being renamed into place.
+    pub fn part_path(&self) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(".part");
+        PathBuf::from(name)
+    }
+
+    /// Fails early if the destination exists and the policy forbids replacing it.
+    pub fn check_overwrite(&self) -> Result<()> {
+        if self.overwrite == OverwritePolicy::Never && self.path.exists() {
+            return Err(self.exists_error());
+        }
+        Ok(())
+    }
+
+    fn exists_error(&self) -> anyhow::Error {
+        anyhow!(
+            "output file {} already exists (overwrite policy: never)",
+            self.path.display()
+        )
+    }
+
+    /// Moves the `.part` file to the final path without replacing a file
+    /// created there since [`Self::check_overwrite`]: it is hard linked,
+    /// which fails if the path exists, then removed. File systems without
+    /// hard links get a rename after checking again.
+    async fn rename_no_replace(&self, part_path: &Path) -> Result<()> {
+        match tokio::fs::hard_link(part_path, &self.path).await {
+            Ok(()) => Ok(tokio::fs::remove_file(part_path).await?),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Err(self.exists_error()),
+            Err(e) => {
+                warn!(
+                    "Can't hard link {} ({e}), renaming it instead",
+                    part_path.display()
+                );
+                self.check_overwrite()?;
+                Ok(tokio::fs::rename(part_path, &self.path).await?)
+            }
+        }
+    }
+
+    /// Makes a fully written (and closed) `.part` file durable and moves it to
+    /// the final path.
+    ///
+    /// Unless `fsync` is off, the file and its parent directory are fsynced so
+    /// a power loss right after "Archive created successfully!" can't leave a
+    /// truncated or missing archive.
+    pub async fn commit(&self) -> Result<()> {
+        let part_path = self.part_path();
+
+        let part = tokio::fs::OpenOptions::new()
+            .write(true)
+            .open(&part_path)
+            .await?;
+        if self.fsync {
+            part.sync_all()
+                .await
+                .with_context(|| format!("syncing {}", part_path.display()))?;
+        }
+        let new_len = part.metadata().await?.len();
+        drop(part);
+
+        if self.overwrite == OverwritePolicy::IfLarger
+            && let Ok(existing) = tokio::fs::metadata(&self.path).await
+            && existing.len() >= new_len
+        {
+            tokio::fs::remove_file(&part_path).await?;
+            info!(
+                "Keeping existing {} ({} bytes), new archive is not larger ({} bytes)",
+                self.path.display(),
+                existing.len(),
+                new_len
+            );
+            return Ok(());
+        }
+
+        if self.overwrite == OverwritePolicy::Never {
+            self.rename_no_replace(&part_path).await?;
+        } else {
+            tokio::fs::rename(&part_path, &self.path).await?;
+        }
+        if self.fsync {
+            sync_parent_dir(&self.path)
+                .await
+                .with_context(|| format!("syncing the directory of {}", self.path.display()))?;
+        }
+        Ok(())
+    }
+
+    /// Removes the `.part` file left behind by a failed run.
+    pub async fn discard(&self) {
+        let _ = tokio::fs::remove_file(self.part_path()).await;
+    }
+}
+
+#[async_trait]
+impl Sink for LocalFile {
+    async fn open_writer(&self, _buffer_size: usize) -> Result<SinkWriter> {
+        self.check_overwrite()?;
+        let writer = create_file_writer(self.part_path()).await?;
+        Ok(SinkWriter::local(Box::new(writer), self.clone()))
+    }
+}
+
+/// Deletes the `.part` files that killed runs left next to the archives of
+/// the local `output`, i.e. those matching its naming template, unless a
+/// journal keeps them for `ssbt resume`. Another run writing the same output
+/// would lose its archive, so this is only done while holding the run lock.
+/// Returns how many were deleted.
+pub fn remove_stale_parts(output: &str, config: &Config) -> Result<usize> {
+    let output = std::path::absolute(output)?;
+    let output = output.to_string_lossy();
+    let pattern = name_pattern(&output, config)?;
+    let dir = output_dir(&output)?;
+    let listing = match std::fs::read_dir(&dir) {
+        Ok(listing) => listing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+    };
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+
+    let mut removed = 0;
+    for entry in listing {
+        let path = entry?.path();
+        let Some(archive) = path.to_str().and_then(|path| path.strip_suffix(".part")) else {
+            continue;
+        };
+        let archive = volume_of(archive).unwrap_or(archive);
+        if !pattern.matches_with(archive, options) || journal_path(Path::new(archive)).exists() {
+            continue;
+        }
+        std::fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+        info!("Removed {}, left by an interrupted run", path.display());
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Creates a file writer for streaming archive output.
+/// Automatically creates parent directories if they don't exist.
+///
+/// # Example
+/// ```no_run
+/// # use std::path::PathBuf;
+/// # use std::sync::Arc;
+/// # use ssbt_lib::file_reader::SourceReader;
+/// # use ssbt_lib::packaging::{ArchiveFormat, PackagerOptions, write_archive};
+/// # use ssbt_lib::sink::save_file::create_file_writer;
+/// #[tokio::main]
+/// async fn main() -> anyhow::Result<()> {
+///     let files = vec![("document.txt".to_string(), PathBuf::from("/path/to/file1.txt"))];
+///     let reader = Arc::new(SourceReader::default());
+///
+///     let writer = create_file_writer("output/archive.zip").await?;
+///     let options = PackagerOptions::default();
+///     write_archive(ArchiveFormat::Zip, files, &options, &reader, Box::new(writer)).await?;
+///     Ok(())
+/// }
+/// ```
+pub async fn create_file_writer<P: AsRef<Path>>(path: P) -> anyhow::Result<File> {
+    let path = path.as_ref();
+
+    // Create parent directories if they don't exist
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    // Create the file
+    let file = File::create(path).await?;
+
+    Ok(file)
+}
+
+/// Persists the directory entry of a freshly renamed file.
+#[cfg(unix)]
+async fn sync_parent_dir(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    tokio::task::spawn_blocking(move || std::fs::File::open(parent)?.sync_all()).await??;
+    Ok(())
+}
+
+/// Directories can't be opened for syncing on this platform; the rename is
+/// already durable once the file itself has been synced.
+#[cfg(not(unix))]
+async fn sync_parent_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use anyhow::{Context, Result, anyhow, bail};
+use async_trait::async_trait;
+use std::io::Read;
+use tokio::{io::AsyncRead, sync::oneshot};
+use tokio_util::io::SyncIoBridge;
+
+use super::ssh::SshTarget;
+use super::{Sink, SinkWriter};
+
+/// An archive streamed to a remote host over SSH.
+///
+/// The classic scp protocol announces the file size before the data, which a
+/// streamed archive doesn't have, so the data is piped into `cat` on the remote
+/// side instead. That needs a POSIX shell there, like scp itself does.
+#[derive(Debug, Clone)]
+pub struct ScpUpload {
+    pub target: SshTarget,
+}
+
+#[async_trait]
+impl Sink for ScpUpload {
+    async fn open_writer(&self, buffer_size: usize) -> Result<SinkWriter> {
+        let upload = self.clone();
+        Ok(SinkWriter::committed(buffer_size, |reader, committed| {
+            upload_stream(upload, reader, committed)
+        }))
+    }
+}
+
+/// Streams everything read from `reader` to `<path>.part` on the remote host
+/// and renames it into place once `commit` fires. If `commit` is dropped
+/// because the archive couldn't be completed, the partial file is removed
+/// instead.
+pub async fn upload_stream<R>(
+    upload: ScpUpload,
+    reader: R,
+    commit: oneshot::Receiver<()>,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let reader = SyncIoBridge::new(reader);
+    tokio::task::spawn_blocking(move || send(&upload.target, reader, commit))
+        .await
+        .map_err(|e| anyhow!(e))??;
+    Ok(())
+}
+
+fn send(target: &SshTarget, mut reader: impl Read, commit: oneshot::Receiver<()>) -> Result<()> {
+    let session = target.connect()?;
+    let path = quote(&target.path);
+    let part = quote(&format!("{}.part", target.path));
+
+    let mut command = format!("cat > {part}");
+    if let Some((dir, _)) = target.path.rsplit_once('/')
+        && !dir.is_empty()
+    {
+        command = format!("mkdir -p {} && {command}", quote(dir));
+    }
+
+    let mut channel = session.channel_session()?;
+    channel.exec(&command)?;
+    std::io::copy(&mut reader, &mut channel).context("streaming archive over SSH")?;
+    channel.send_eof()?;
+    finish(channel, &command)?;
+
+    if commit.blocking_recv().is_err() {
+        let mut cleanup = session.channel_session()?;
+        cleanup.exec(&format!("rm -f {part}"))?;
+        // The archive writer reports why it stopped
+        return finish(cleanup, "rm");
+    }
+
+    let mut rename = session.channel_session()?;
+    rename.exec(&format!("mv -f {part} {path}"))?;
+    finish(rename, "mv")
+}
+
+/// Whether the archive exists on the remote host.
+pub async fn exists(upload: &ScpUpload) -> Result<bool> {
+    let target = upload.target.clone();
+    tokio::task::spawn_blocking(move || {
+        let session = target.connect()?;
+        let command = format!("test -e {}", quote(&target.path));
+        let mut channel = session.channel_session()?;
+        channel.exec(&command)?;
+        channel.wait_eof()?;
+        channel.wait_close()?;
+        match channel.exit_status()? {
+            0 => Ok(true),
+            1 => Ok(false),
+            status => bail!("remote command `{command}` failed with status {status}"),
+        }
+    })
+    .await
+    .map_err(|e| anyhow!(e))?
+}
+
+/// Waits for a remote command to exit and turns a non-zero status into an error.
+fn finish(mut channel: ssh2::Channel, command: &str) -> Result<()> {
+    channel.wait_eof()?;
+    let mut stderr = String::new();
+    channel.stderr().read_to_string(&mut stderr)?;
+    channel.wait_close()?;
+    match channel.exit_status()? {
+        0 => Ok(()),
+        status => bail!(
+            "remote command `{command}` failed with status {status}: {}",
+            stderr.trim()
+        ),
+    }
+}
+
+/// Quotes a path for the remote POSIX shell.
+fn quote(path: &str) -> String {
+    format!("'{}'", path.replace('\'', r"'\''"))
+}
@@ -0,0 +1,472 @@
+use std::fmt;
+use std::fs;
+use std::sync::Once;
+use std::time::Duration;
+
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use futures::TryStreamExt;
+use reqwest::{Certificate, Identity, Method, NoProxy, Proxy, StatusCode, Url};
+use tokio::io::AsyncRead;
+
+use super::verify::SentCounter;
+use super::{Sink, SinkWriter};
+use crate::Config;
+use crate::error::HttpStatusError;
+use crate::naming::{create_file_name, expand_placeholders};
+use crate::packaging::ArchiveFormat;
+use crate::packaging::compress::StreamCompression;
+use crate::packaging::encrypt;
+use crate::secrets::resolve_secret;
+use tracing::{info, warn};
+
+/// Bytes read from the archive pipe per chunk of a streamed request body.
+pub const BODY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Authentication attached to HTTP uploads.
+#[derive(Clone)]
+pub enum HttpAuth {
+    /// `Authorization: Bearer <token>`
+    Bearer(String),
+    /// `Authorization: Basic base64(user:password)`
+    Basic {
+        user: String,
+        password: Option<String>,
+    },
+    /// HTTP Digest, answered per request once the server sent a challenge (WebDAV only)
+    Digest { user: String, password: String },
+    /// `Authorization: <value>`, sent verbatim (e.g. "Bearer abc" from older configs)
+    Raw(String),
+    /// `<name>: <value>`, for APIs taking the token in their own header (`auth_header`)
+    Header { name: String, value: String },
+}
+
+// Secrets must never end up in logs, so Debug only shows the auth kind.
+impl fmt::Debug for HttpAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpAuth::Bearer(_) => write!(f, "Bearer(***)"),
+            HttpAuth::Basic { user, .. } => write!(f, "Basic({user}:***)"),
+            HttpAuth::Digest { user, .. } => write!(f, "Digest({user}:***)"),
+            HttpAuth::Raw(_) => write!(f, "Raw(***)"),
+            HttpAuth::Header { name, .. } => write!(f, "Header({name}: ***)"),
+        }
+    }
+}
+
+/// Everything needed to upload an archive to an HTTP endpoint.
+#[derive(Clone)]
+pub struct HttpUpload {
+    pub url: String,
+    /// Client with the proxy and TLS settings of the config.
+    pub client: reqwest::Client,
+    /// POST (the default) or PUT.
+    pub method: Method,
+    pub auth: Option<HttpAuth>,
+    pub headers: Vec<(String, String)>,
+    /// Query parameters appended to the URL.
+    pub query: Vec<(String, String)>,
+    /// Content-Type of the uploaded archive.
+    pub content_type: &'static str,
+    /// Compare the stored object against the bytes sent once a PUT finishes.
+    pub verify: bool,
+}
+
+// Header values frequently carry API keys, so only their names are printed.
+impl fmt::Debug for HttpUpload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpUpload")
+            .field("url", &self.url)
+            .field("method", &self.method)
+            .field("auth", &self.auth)
+            .field(
+                "headers",
+                &self.headers.iter().map(|(n, _)| n).collect::<Vec<_>>(),
+            )
+            .field(
+                "query",
+                &self.query.iter().map(|(n, _)| n).collect::<Vec<_>>(),
+            )
+            .field("content_type", &self.content_type)
+            .field("verify", &self.verify)
+            .finish()
+    }
+}
+
+impl HttpUpload {
+    /// Builds an upload description from the merged config, resolving
+    /// `file:`/`env:` references in the token and header values.
+    pub fn from_config(url: &str, config: &Config) -> Result<Self> {
+        let auth = match config.authentication.as_deref().filter(|a| !a.is_empty()) {
+            Some(raw) => Some(parse_auth(
+                &resolve_secret(raw)?,
+                config.auth_type.as_deref(),
+                config.auth_header.as_deref(),
+            )?),
+            None => None,
+        };
+
+        let headers = config
+            .headers
+            .iter()
+            .flatten()
+            .map(|(name, value)| {
+                resolve_secret(value)
+                    .map(|v| (name.clone(), v))
+                    .with_context(|| format!("resolving value of header {name}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let query = config
+            .http_query
+            .iter()
+            .flatten()
+            .map(|(name, value)| {
+                resolve_secret(value)
+                    .map(|v| (name.clone(), v))
+                    .with_context(|| format!("resolving value of query parameter {name}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(HttpUpload {
+            url: url.to_string(),
+            client: http_client(config)?,
+            method: Method::POST,
+            auth,
+            headers,
+            query,
+            content_type: archive_content_type(config)?,
+            verify: false,
+        })
+    }
+
+    /// An upload to an `http(s)://` output, sent with `http_method`. Naming
+    /// templates in the URL path are expanded; with PUT, the archive's name
+    /// is ours to choose, so a URL ending in `/` gets the default name like a
+    /// local output directory.
+    pub fn for_output(output: &str, config: &Config) -> Result<Self> {
+        let method = parse_method(config.http_method.as_deref())?;
+        let mut url = Url::parse(output).with_context(|| format!("invalid URL {output}"))?;
+        let path = if method == Method::PUT {
+            create_file_name(url.path(), config)?
+                .to_string_lossy()
+                .to_string()
+        } else {
+            expand_placeholders(url.path(), config)?
+        };
+        url.set_path(&path);
+        Ok(HttpUpload {
+            verify: method == Method::PUT && config.verify_upload.unwrap_or(false),
+            method,
+            ..HttpUpload::from_config(url.as_str(), config)?
+        })
+    }
+
+    /// The same upload with the path of the URL changed by `rename`, for
+    /// PUT uploads only: a POST endpoint names archives itself.
+    pub fn renamed(&self, rename: impl Fn(&str) -> String) -> Option<Self> {
+        if self.method != Method::PUT {
+            return None;
+        }
+        let mut url = Url::parse(&self.url).ok()?;
+        url.set_path(&rename(url.path()));
+        Some(HttpUpload {
+            url: url.to_string(),
+            ..self.clone()
+        })
+    }
+
+    /// Whether something is stored at the URL of a PUT upload, asking with
+    /// HEAD. `None` for POST uploads, and servers that don't tell.
+    pub async fn exists(&self) -> Result<Option<bool>> {
+        if self.method != Method::PUT {
+            return Ok(None);
+        }
+        let response = self.authorize(self.client.head(&self.url)).send().await?;
+        Ok(match response.status() {
+            StatusCode::NOT_FOUND => Some(false),
+            status if status.is_success() => Some(true),
+            _ => None,
+        })
+    }
+
+    /// Creates the upload request with authentication and custom headers applied.
+    pub fn request(&self, client: &reqwest::Client) -> reqwest::RequestBuilder {
+        self.authorize(
+            client
+                .request(self.method.clone(), &self.url)
+                .header("Content-Type", self.content_type),
+        )
+    }
+
+    /// Applies authentication, custom headers and query parameters to an
+    /// arbitrary request, so downloads from the same endpoint use the same
+    /// credentials.
+    pub fn authorize(&self, mut request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        if !self.query.is_empty() {
+            request = request.query(&self.query);
+        }
+
+        match &self.auth {
+            Some(HttpAuth::Bearer(token)) => request.bearer_auth(token),
+            Some(HttpAuth::Basic { user, password }) => request.basic_auth(user, password.as_ref()),
+            Some(HttpAuth::Raw(value)) => request.header("Authorization", value),
+            Some(HttpAuth::Header { name, value }) => request.header(name, value),
+            // Needs the server's challenge first, see `sink::digest`
+            Some(HttpAuth::Digest { .. }) | None => request,
+        }
+    }
+}
+
+/// Proxy and TLS settings shared by every HTTP client.
+struct ClientSettings {
+    proxy: Option<Proxy>,
+    roots: Vec<Certificate>,
+    identity: Option<Identity>,
+    insecure: bool,
+}
+
+impl ClientSettings {
+    fn from_config(config: &Config) -> Result<Self> {
+        let proxy = match config.proxy.as_deref().filter(|p| !p.is_empty()) {
+            Some(url) => Some(
+                Proxy::all(url)
+                    .with_context(|| format!("invalid proxy {url}"))?
+                    .no_proxy(NoProxy::from_env()),
+            ),
+            None => None,
+        };
+        let roots = match config.ca_cert.as_deref().filter(|c| !c.is_empty()) {
+            Some(path) => Certificate::from_pem_bundle(&read_pem(path)?)
+                .with_context(|| format!("reading CA certificates from {path}"))?,
+            None => Vec::new(),
+        };
+        let identity = match config.client_cert.as_deref().filter(|c| !c.is_empty()) {
+            Some(path) => {
+                let mut pem = read_pem(path)?;
+                if let Some(key) = config.client_key.as_deref().filter(|k| !k.is_empty()) {
+                    pem.push(b'\n');
+                    pem.extend(read_pem(key)?);
+                }
+                Some(
+                    Identity::from_pem(&pem)
+                        .with_context(|| format!("reading client certificate {path}"))?,
+                )
+            }
+            None if config.client_key.is_some() => bail!("client_key needs client_cert"),
+            None => None,
+        };
+        let insecure = config.insecure.unwrap_or(false);
+        if insecure {
+            static WARNED: Once = Once::new();
+            WARNED.call_once(|| warn!("TLS certificate verification is disabled (insecure)"));
+        }
+        Ok(ClientSettings {
+            proxy,
+            roots,
+            identity,
+            insecure,
+        })
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("reading {path}"))
+}
+
+/// Applies [`ClientSettings`] to a `reqwest` or `reqwest::blocking` client builder.
+macro_rules! configure {
+    ($builder:expr, $settings:expr) => {{
+        let settings = $settings;
+        let mut builder = $builder.danger_accept_invalid_certs(settings.insecure);
+        if let Some(proxy) = settings.proxy {
+            builder = builder.proxy(proxy);
+        }
+        for root in settings.roots {
+            builder = builder.add_root_certificate(root);
+        }
+        if let Some(identity) = settings.identity {
+            builder = builder.identity(identity);
+        }
+        builder
+    }};
+}
+
+/// The client for HTTP(S) uploads and downloads, with `proxy`, `ca_cert`,
+/// `client_cert`/`client_key` and `insecure` applied.
+pub fn http_client(config: &Config) -> Result<reqwest::Client> {
+    let settings = ClientSettings::from_config(config)?;
+    Ok(configure!(reqwest::Client::builder(), settings).build()?)
+}
+
+/// Like [`http_client`], for blocking requests giving up after `timeout`.
+pub fn blocking_http_client(
+    config: &Config,
+    timeout: Duration,
+) -> Result<reqwest::blocking::Client> {
+    let settings = ClientSettings::from_config(config)?;
+    Ok(configure!(
+        reqwest::blocking::Client::builder().timeout(timeout),
+        settings
+    )
+    .build()?)
+}
+
+/// Parses `http_method` [post|put].
+pub fn parse_method(method: Option<&str>) -> Result<Method> {
+    match method.map(|m| m.to_ascii_lowercase()).as_deref() {
+        None | Some("post") => Ok(Method::POST),
+        Some("put") => Ok(Method::PUT),
+        Some(other) => bail!("unsupported http_method: {other} (expected post or put)"),
+    }
+}
+
+/// Content-Type of the archive the config produces.
+pub fn archive_content_type(config: &Config) -> Result<&'static str> {
+    if encrypt::is_configured(config) {
+        return Ok("application/octet-stream");
+    }
+    let format: ArchiveFormat = match config.format.as_deref() {
+        Some(format) => format.parse()?,
+        None => ArchiveFormat::Zip,
+    };
+    Ok(match StreamCompression::from_config(config, format)? {
+        Some(compression) => compression.content_type(),
+        None => format.content_type(),
+    })
+}
+
+/// Interprets the authentication value according to `auth_type`
+/// [bearer|basic|digest|raw]. Without an explicit type, values that already
+/// carry a scheme ("Bearer ...", "Basic ...") are sent verbatim and everything
+/// else is treated as a bearer token.
+///
+/// With `auth_header`, the value goes into that header instead of
+/// `Authorization`: verbatim unless `auth_type` asks for a scheme.
+pub fn parse_auth(value: &str, auth_type: Option<&str>, header: Option<&str>) -> Result<HttpAuth> {
+    let auth_type = auth_type.map(|t| t.to_ascii_lowercase());
+    if let Some(name) = header.filter(|h| !h.is_empty()) {
+        let value = match auth_type.as_deref() {
+            None | Some("raw") => value.to_string(),
+            Some("bearer") => format!("Bearer {value}"),
+            Some("basic") => format!("Basic {}", STANDARD.encode(value)),
+            Some(other) => bail!(
+                "auth_type {other} can't be combined with auth_header (expected bearer, basic or raw)"
+            ),
+        };
+        return Ok(HttpAuth::Header {
+            name: name.to_string(),
+            value,
+        });
+    }
+    match auth_type.as_deref() {
+        Some("bearer") => Ok(HttpAuth::Bearer(value.to_string())),
+        Some("raw") => Ok(HttpAuth::Raw(value.to_string())),
+        Some("basic") => {
+            let (user, password) = match value.split_once(':') {
+                Some((user, password)) => (user.to_string(), Some(password.to_string())),
+                None => (value.to_string(), None),
+            };
+            Ok(HttpAuth::Basic { user, password })
+        }
+        Some("digest") => {
+            let (user, password) = value
+                .split_once(':')
+                .context("digest authentication expects user:password")?;
+            Ok(HttpAuth::Digest {
+                user: user.to_string(),
+                password: password.to_string(),
+            })
+        }
+        Some(other) => {
+            bail!("unsupported auth_type: {other} (expected bearer, basic, digest or raw)")
+        }
+        None => {
+            let lower = value.to_ascii_lowercase();
+            if lower.starts_with("bearer ") || lower.starts_with("basic ") {
+                Ok(HttpAuth::Raw(value.to_string()))
+            } else {
+                Ok(HttpAuth::Bearer(value.to_string()))
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for HttpUpload {
+    async fn open_writer(&self, buffer_size: usize) -> Result<SinkWriter> {
+        let upload = self.clone();
+        Ok(SinkWriter::streamed(buffer_size, |pipe| {
+            upload_stream(upload, pipe)
+        }))
+    }
+}
+
+/// Uploads everything read from `reader` as the request body.
+pub async fn upload_stream<R>(upload: HttpUpload, reader: R) -> Result<()>
+where
+    R: AsyncRead + Send + 'static,
+{
+    if let Some(HttpAuth::Digest { .. }) = upload.auth {
+        bail!("digest authentication is only supported for WebDAV outputs");
+    }
+
+    let counter = upload.verify.then(SentCounter::default);
+    let body_counter = counter.clone();
+    let body = tokio_util::io::ReaderStream::with_capacity(reader, BODY_CHUNK_SIZE).inspect_ok(
+        move |chunk| {
+            if let Some(counter) = &body_counter {
+                counter.update(chunk);
+            }
+        },
+    );
+    let response = upload
+        .request(&upload.client)
+        .body(reqwest::Body::wrap_stream(body))
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(HttpStatusError {
+            what: "Upload".to_string(),
+            status: response.status(),
+        }
+        .into());
+    }
+
+    if let Some(counter) = counter {
+        verify(&upload, &counter).await?;
+    }
+    Ok(())
+}
+
+/// Asks for the stored object with HEAD and compares its size and whatever
+/// hashes the server reports with the bytes sent.
+async fn verify(upload: &HttpUpload, counter: &SentCounter) -> Result<()> {
+    let response = upload
+        .authorize(upload.client.head(&upload.url))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        bail!(
+            "upload verification failed: HEAD {} returned status {}",
+            upload.url,
+            response.status()
+        );
+    }
+    let checked = counter
+        .sent()
+        .check_headers("Upload", response.headers(), true)?;
+    if checked.is_empty() {
+        bail!(
+            "upload verification failed: HEAD {} reports neither size nor hash",
+            upload.url
+        );
+    }
+    info!("Verified upload: {}", checked.join(", "));
+    Ok(())
+}
@@ -0,0 +1,180 @@
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use ssh2::{ErrorCode, OpenFlags, OpenType, RenameFlags, Sftp};
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+use tokio::{io::AsyncRead, sync::oneshot};
+use tokio_util::io::SyncIoBridge;
+
+use super::resume::ResumeWindow;
+use super::retry::RetryPolicy;
+use super::ssh::SshTarget;
+use super::{Sink, SinkWriter};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Bytes kept in memory after they were sent, to replay what a dropped
+/// connection may have lost.
+const RESUME_WINDOW: usize = 8 * 1024 * 1024;
+/// SFTP status of a missing file.
+const LIBSSH2_FX_NO_SUCH_FILE: i32 = 2;
+
+/// An archive upload to an SFTP server.
+#[derive(Debug, Clone)]
+pub struct SftpUpload {
+    pub target: SshTarget,
+    pub retry: RetryPolicy,
+}
+
+#[async_trait]
+impl Sink for SftpUpload {
+    async fn open_writer(&self, buffer_size: usize) -> Result<SinkWriter> {
+        let upload = self.clone();
+        Ok(SinkWriter::committed(buffer_size, |reader, committed| {
+            upload_stream(upload, reader, committed)
+        }))
+    }
+}
+
+/// Streams everything read from `reader` to `<path>.part` on the server and
+/// renames it into place once `commit` fires. If `commit` is dropped because
+/// the archive couldn't be completed, the partial file is removed instead.
+///
+/// When the connection drops, the upload reconnects and continues from the
+/// size the server has stored, as long as that is within the resume window.
+pub async fn upload_stream<R>(
+    upload: SftpUpload,
+    reader: R,
+    commit: oneshot::Receiver<()>,
+) -> anyhow::Result<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let reader = SyncIoBridge::new(reader);
+    tokio::task::spawn_blocking(move || send(&upload, reader, commit))
+        .await
+        .map_err(|e| anyhow!(e))??;
+    Ok(())
+}
+
+/// Whether the archive exists on the server.
+pub async fn exists(upload: &SftpUpload) -> Result<bool> {
+    let target = upload.target.clone();
+    tokio::task::spawn_blocking(move || {
+        let path = Path::new(&target.path);
+        match target.connect()?.sftp()?.stat(path) {
+            Ok(_) => Ok(true),
+            Err(e) if e.code() == ErrorCode::SFTP(LIBSSH2_FX_NO_SUCH_FILE) => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("checking for {path:?}")),
+        }
+    })
+    .await
+    .map_err(|e| anyhow!(e))?
+}
+
+fn send(upload: &SftpUpload, mut reader: impl Read, commit: oneshot::Receiver<()>) -> Result<()> {
+    let target = &upload.target;
+    let path = Path::new(&target.path);
+    let part = format!("{}.part", target.path);
+    let part = Path::new(&part);
+
+    let sftp = target.connect()?.sftp()?;
+    if let Some(parent) = path.parent() {
+        create_dirs(&sftp, parent)?;
+    }
+    let mut file = ResumableFile {
+        target,
+        retry: &upload.retry,
+        part,
+        file: sftp
+            .create(part)
+            .with_context(|| format!("creating {part:?}"))?,
+        sftp,
+        window: ResumeWindow::new(RESUME_WINDOW),
+    };
+
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let read = reader.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        file.write(&chunk[..read])?;
+    }
+    let ResumableFile { sftp, mut file, .. } = file;
+    file.close().with_context(|| format!("closing {part:?}"))?;
+
+    if commit.blocking_recv().is_err() {
+        // The archive writer reports why it stopped
+        sftp.unlink(part)?;
+        return Ok(());
+    }
+    sftp.rename(
+        part,
+        path,
+        Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE),
+    )
+    .with_context(|| format!("renaming {part:?} to {path:?}"))?;
+    Ok(())
+}
+
+/// A remote file written sequentially that survives dropped connections.
+struct ResumableFile<'a> {
+    target: &'a SshTarget,
+    retry: &'a RetryPolicy,
+    part: &'a Path,
+    sftp: Sftp,
+    file: ssh2::File,
+    window: ResumeWindow,
+}
+
+impl ResumableFile<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.window.push(data);
+
+        let mut attempt = 1;
+        let mut result = self.file.write_all(data);
+        loop {
+            let Err(e) = result else {
+                return Ok(());
+            };
+            match self.retry.next_delay("SFTP write", attempt, &e) {
+                Some(delay) => std::thread::sleep(delay),
+                None => return Err(e).with_context(|| format!("writing {:?}", self.part)),
+            }
+            attempt += 1;
+            result = match self.reopen() {
+                // Replay everything the server hasn't stored
+                Ok(stored) => self.file.write_all(self.window.since(stored)?),
+                Err(e) => Err(std::io::Error::other(format!("{e:#}"))),
+            };
+        }
+    }
+
+    /// Reconnects and positions the file at the size the server has stored.
+    fn reopen(&mut self) -> Result<u64> {
+        let sftp = self.target.connect()?.sftp()?;
+        let mut file = sftp.open_mode(self.part, OpenFlags::WRITE, 0o644, OpenType::File)?;
+        let stored = file
+            .stat()?
+            .size
+            .context("server did not report the size of the partial file")?;
+        file.seek(SeekFrom::Start(stored))?;
+        self.sftp = sftp;
+        self.file = file;
+        Ok(stored)
+    }
+}
+
+/// Creates `dir` and its missing parents.
+fn create_dirs(sftp: &Sftp, dir: &Path) -> Result<()> {
+    if dir.as_os_str().is_empty() || sftp.stat(dir).is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        create_dirs(sftp, parent)?;
+    }
+    sftp.mkdir(dir, 0o755)
+        .with_context(|| format!("creating remote directory {dir:?}"))
+}
@@ -0,0 +1,203 @@
+use anyhow::{Context, Result, bail};
+use ssh2::{CheckResult, KnownHostFileKind, Session};
+use std::{fmt, net::TcpStream, path::PathBuf};
+
+use crate::Config;
+use crate::naming::create_file_name;
+use crate::secrets::resolve_secret;
+use tracing::warn;
+
+const DEFAULT_PORT: u16 = 22;
+/// A dead connection otherwise blocks forever instead of failing the upload.
+const TIMEOUT_MS: u32 = 60_000;
+
+/// How to authenticate against the SSH server.
+#[derive(Clone)]
+pub enum SshAuth {
+    /// Private key file, with an optional passphrase.
+    Key {
+        path: PathBuf,
+        passphrase: Option<String>,
+    },
+    Password(String),
+    /// ssh-agent, falling back to the default identity files in `~/.ssh`.
+    Agent,
+}
+
+// Secrets must never end up in logs, so Debug only shows the auth kind.
+impl fmt::Debug for SshAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SshAuth::Key { path, .. } => write!(f, "Key({})", path.display()),
+            SshAuth::Password(_) => write!(f, "Password(***)"),
+            SshAuth::Agent => write!(f, "Agent"),
+        }
+    }
+}
+
+/// A remote file reachable over SSH.
+#[derive(Debug, Clone)]
+pub struct SshTarget {
+    pub host: String,
+    pub port: u16,
+    pub user: String,
+    /// Remote path, with naming templates already expanded.
+    pub path: String,
+    pub auth: SshAuth,
+    /// Connect to hosts missing from `known_hosts` instead of failing.
+    pub accept_unknown_hosts: bool,
+}
+
+impl SshTarget {
+    /// Parses `scheme://[user@]host[:port]/path` or scp-style `[user@]host:path`.
+    ///
+    /// The password comes from `authentication`, a private key from `ssh_key`;
+    /// without either, ssh-agent and the default identities are tried.
+    pub fn from_config(output: &str, config: &Config) -> Result<Self> {
+        let (user, host, port, path) = match output.split_once("://") {
+            Some((_, rest)) => {
+                let url = reqwest::Url::parse(output)
+                    .with_context(|| format!("invalid SSH URL {output}"))?;
+                let host = url
+                    .host_str()
+                    .with_context(|| format!("missing host in {output}"))?
+                    .to_string();
+                let user = (!url.username().is_empty()).then(|| url.username().to_string());
+                // Take the path verbatim: URL parsing would mangle `%date%` style templates.
+                // scp://host/~/file is relative to the remote home directory.
+                let path = rest.find('/').map_or("", |i| &rest[i..]);
+                let path = path.strip_prefix("/~/").unwrap_or(path).to_string();
+                (user, host, url.port().unwrap_or(DEFAULT_PORT), path)
+            }
+            None => {
+                let (authority, path) = output
+                    .split_once(':')
+                    .with_context(|| format!("expected [user@]host:path, got {output}"))?;
+                let (user, host) = match authority.rsplit_once('@') {
+                    Some((user, host)) => (Some(user.to_string()), host.to_string()),
+                    None => (None, authority.to_string()),
+                };
+                (user, host, DEFAULT_PORT, path.to_string())
+            }
+        };
+
+        if path.is_empty() || path.ends_with('/') {
+            bail!("remote path in {output} must name a file");
+        }
+        let user = match user {
+            Some(user) => user,
+            None => std::env::var("USER")
+                .or_else(|_| std::env::var("USERNAME"))
+                .context("no SSH user in the output and USER is not set")?,
+        };
+
+        let auth = if let Some(key) = &config.ssh_key {
+            SshAuth::Key {
+                path: PathBuf::from(key),
+                passphrase: config
+                    .ssh_key_passphrase
+                    .as_deref()
+                    .map(resolve_secret)
+                    .transpose()?,
+            }
+        } else if let Some(password) = &config.authentication {
+            SshAuth::Password(resolve_secret(password)?)
+        } else {
+            SshAuth::Agent
+        };
+
+        Ok(SshTarget {
+            host,
+            port,
+            user,
+            path: create_file_name(&path, config)?
+                .to_string_lossy()
+                .to_string(),
+            auth,
+            accept_unknown_hosts: config.ssh_accept_unknown_hosts.unwrap_or(false),
+        })
+    }
+
+    /// Opens an authenticated session, verifying the host key against `~/.ssh/known_hosts`.
+    pub fn connect(&self) -> Result<Session> {
+        let tcp = TcpStream::connect((self.host.as_str(), self.port))
+            .with_context(|| format!("connecting to {}:{}", self.host, self.port))?;
+        let mut session = Session::new()?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(TIMEOUT_MS);
+        session.handshake()?;
+
+        self.check_host_key(&session)?;
+        self.authenticate(&session)?;
+        Ok(session)
+    }
+
+    fn check_host_key(&self, session: &Session) -> Result<()> {
+        let (key, _) = session.host_key().context("server sent no host key")?;
+        let mut known_hosts = session.known_hosts()?;
+        if let Some(file) = home_dir().map(|h| h.join(".ssh/known_hosts"))
+            && file.exists()
+        {
+            known_hosts.read_file(&file, KnownHostFileKind::OpenSSH)?;
+        }
+
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound if self.accept_unknown_hosts => {
+                warn!(
+                    "{} is not in known_hosts, accepting its host key",
+                    self.host
+                );
+                Ok(())
+            }
+            CheckResult::NotFound => bail!(
+                "{} is not in ~/.ssh/known_hosts (connect once with ssh, or set ssh_accept_unknown_hosts)",
+                self.host
+            ),
+            CheckResult::Mismatch => bail!(
+                "host key of {} does not match ~/.ssh/known_hosts, refusing to connect",
+                self.host
+            ),
+            CheckResult::Failure => bail!("could not check the host key of {}", self.host),
+        }
+    }
+
+    fn authenticate(&self, session: &Session) -> Result<()> {
+        match &self.auth {
+            SshAuth::Key { path, passphrase } => session
+                .userauth_pubkey_file(&self.user, None, path, passphrase.as_deref())
+                .with_context(|| format!("authenticating with key {}", path.display()))?,
+            SshAuth::Password(password) => session
+                .userauth_password(&self.user, password)
+                .context("authenticating with password")?,
+            SshAuth::Agent => {
+                if session.userauth_agent(&self.user).is_err() {
+                    let identities = ["id_ed25519", "id_ecdsa", "id_rsa"];
+                    for name in identities {
+                        let Some(key) = home_dir().map(|h| h.join(".ssh").join(name)) else {
+                            break;
+                        };
+                        if key.exists()
+                            && session
+                                .userauth_pubkey_file(&self.user, None, &key, None)
+                                .is_ok()
+                        {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        if !session.authenticated() {
+            bail!("SSH authentication as {} failed", self.user);
+        }
+        Ok(())
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+}
@@ -0,0 +1,439 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::{Context, anyhow, bail};
+use futures::future::join_all;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::sync::watch;
+
+use super::checksum::{ArchiveHasher, ChecksumAlgorithm, write_sidecar};
+use super::retry::RetryPolicy;
+use super::{DEFAULT_BUFFER_SIZE, OutSink, deliver};
+use crate::Config;
+use crate::cancel::{self, Interrupted};
+use crate::error::{HttpStatusError, SsbtError};
+use crate::file_reader::SourceReader;
+use crate::fs_utils::{SizeLimitExceeded, parse_size};
+use crate::packaging::manifest::Manifest;
+use crate::packaging::{
+    ArchiveFiles, ArchiveFormat, ArchiveOutput, PackagerOptions, write_archive_from,
+};
+use tracing::info;
+
+/// How the archive is handed to the sinks.
+#[derive(Debug, Clone, Default)]
+pub struct DeliveryOptions {
+    pub retry: RetryPolicy,
+    /// Checksum sidecars written next to the archive.
+    pub checksums: Vec<ChecksumAlgorithm>,
+    /// Splits the archive into volumes of at most this many bytes.
+    pub split_size: Option<u64>,
+    /// Aborts the archive once it grows past this many bytes (`max_size`).
+    pub max_size: Option<u64>,
+    /// Bytes buffered between the archive writer and each sink.
+    pub buffer_size: usize,
+}
+
+impl DeliveryOptions {
+    pub fn from_config(config: &Config) -> anyhow::Result<Self> {
+        let split_size = match config.split_size.as_deref().map(str::trim) {
+            None | Some("" | "0") => None,
+            Some(size) => Some(parse_size(size)?),
+        };
+        if split_size == Some(0) {
+            bail!("split_size must be at least 1 byte");
+        }
+        let buffer_size = match config.upload_buffer_size.as_deref().map(str::trim) {
+            None | Some("") => DEFAULT_BUFFER_SIZE,
+            Some(size) => usize::try_from(parse_size(size)?)?,
+        };
+        if buffer_size == 0 {
+            bail!("upload_buffer_size must be at least 1 byte");
+        }
+        Ok(DeliveryOptions {
+            retry: RetryPolicy::from_config(config)?,
+            checksums: ChecksumAlgorithm::from_config(config)?,
+            split_size,
+            max_size: config.max_size.filter(|size| *size > 0),
+            buffer_size,
+        })
+    }
+}
+
+/// Result of streaming one archive to several sinks.
+struct DeliveryOutcome {
+    delivered: Vec<OutSink>,
+    failures: Vec<(OutSink, anyhow::Error)>,
+    manifest: Option<Manifest>,
+    /// Bytes of the archive.
+    size: u64,
+    /// Digests of the archive bytes, for the checksum sidecars.
+    checksums: Vec<(ChecksumAlgorithm, String)>,
+}
+
+/// The archive that reached every sink.
+#[derive(Debug)]
+pub struct StreamedArchive {
+    pub manifest: Option<Manifest>,
+    /// Bytes of the last archive built.
+    pub size: u64,
+}
+
+/// Builds the archive once and streams it to all `sinks` concurrently.
+///
+/// The slowest sink sets the pace. A sink that fails is dropped while the
+/// others continue, so e.g. the local copy survives a failed upload. Failed
+/// remote sinks are then retried according to `delivery.retry`, rebuilding
+/// the archive for them only, as a streamed upload can't be replayed (walked
+/// `files` are walked again). Sinks
+/// only complete if the archive did; with `split_size`, every full volume is
+/// stored as soon as it is written, and only the last one waits for the
+/// archive. Every sink that received the archive then gets a sidecar per
+/// `checksums` algorithm, hashed from the bytes it was sent.
+///
+/// An archive growing past `delivery.max_size` is aborted, none of the sinks
+/// keep it, and [`SsbtError::SizeLimitExceeded`] is returned. Outputs still
+/// failing after the retries make it [`SsbtError::Upload`], with the HTTP
+/// status the first of them got, if any.
+///
+/// Returns the manifest and size of the last archive built.
+pub async fn stream_archive_to_sinks(
+    format: ArchiveFormat,
+    files: &ArchiveFiles,
+    options: &PackagerOptions,
+    reader: &Arc<SourceReader>,
+    sinks: Vec<OutSink>,
+    delivery: &DeliveryOptions,
+) -> Result<StreamedArchive, SsbtError> {
+    let mut pending = sinks;
+    let mut failed = Vec::new();
+    let mut status = None;
+    let mut attempt = 1;
+    let mut streamed;
+    loop {
+        let outcome = deliver_all(format, files, options, reader, pending, delivery)
+            .await
+            .map_err(|e| SsbtError::classify(e, SsbtError::Packaging))?;
+        streamed = StreamedArchive {
+            manifest: outcome.manifest,
+            size: outcome.size,
+        };
+        for (algorithm, digest) in &outcome.checksums {
+            info!("{}: {digest}", algorithm.extension().to_uppercase());
+            for sink in &outcome.delivered {
+                if let Err(e) = write_sidecar(sink, *algorithm, digest).await {
+                    failed.push(format!("{sink} (.{}): {e}", algorithm.extension()));
+                }
+            }
+        }
+        status = status.or_else(|| {
+            outcome
+                .failures
+                .iter()
+                .find_map(|(_, e)| e.downcast_ref::<HttpStatusError>())
+                .map(|e| e.status.as_u16())
+        });
+        let (retryable, permanent): (Vec<_>, Vec<_>) = outcome
+            .failures
+            .into_iter()
+            .partition(|(sink, _)| sink.is_remote());
+        failed.extend(
+            permanent
+                .into_iter()
+                .map(|(sink, e)| format!("{sink}: {e}")),
+        );
+        if retryable.is_empty() {
+            break;
+        }
+
+        let what = retryable
+            .iter()
+            .map(|(sink, _)| format!("upload to {sink}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let errors = retryable
+            .iter()
+            .map(|(_, e)| e.to_string())
+            .collect::<Vec<_>>()
+            .join("; ");
+        match delivery.retry.next_delay(&what, attempt, errors) {
+            Some(delay) => {
+                tokio::select! {
+                    () = tokio::time::sleep(delay) => {}
+                    () = cancel::cancelled() => return Err(SsbtError::Interrupted),
+                }
+            }
+            None => {
+                failed.extend(
+                    retryable
+                        .into_iter()
+                        .map(|(sink, e)| format!("{sink}: {e}")),
+                );
+                break;
+            }
+        }
+        pending = retryable.into_iter().map(|(sink, _)| sink).collect();
+        attempt += 1;
+    }
+
+    if !failed.is_empty() {
+        return Err(SsbtError::Upload {
+            status,
+            error: anyhow!(
+                "{} output(s) failed:\n  {}",
+                failed.len(),
+                failed.join("\n  ")
+            ),
+        });
+    }
+    Ok(streamed)
+}
+
+/// Streams one archive to `sinks` and sorts them into delivered and failed
+/// ones. Fails as a whole only if the archive itself couldn't be built.
+async fn deliver_all(
+    format: ArchiveFormat,
+    files: &ArchiveFiles,
+    options: &PackagerOptions,
+    reader: &Arc<SourceReader>,
+    sinks: Vec<OutSink>,
+    delivery: &DeliveryOptions,
+) -> anyhow::Result<DeliveryOutcome> {
+    let buffer_size = delivery.buffer_size;
+    let (archive_writer, archive_reader) = tokio::io::duplex(buffer_size);
+    let (archive_done, archive_result) = watch::channel(None);
+
+    let mut pipes = Vec::new();
+    let mut deliveries = Vec::new();
+    for sink in sinks {
+        let (pipe_writer, pipe_reader) = tokio::io::duplex(buffer_size);
+        pipes.push(pipe_writer);
+        let done = archive_result.clone();
+        let split_size = delivery.split_size;
+        deliveries.push(async move {
+            let result = match split_size {
+                Some(split_size) => {
+                    relay_volumes(pipe_reader, &sink, split_size, buffer_size, done).await
+                }
+                None => {
+                    deliver(sink.clone(), buffer_size, |output| {
+                        relay(pipe_reader, output, done)
+                    })
+                    .await
+                }
+            };
+            match result {
+                Ok(()) => Ok(sink),
+                Err(e) => Err((sink, e)),
+            }
+        });
+    }
+
+    let written = Arc::new(AtomicU64::new(0));
+    let output: ArchiveOutput = match delivery.max_size {
+        Some(limit) => Box::new(SizeGuard {
+            inner: archive_writer,
+            limit,
+            written: written.clone(),
+        }),
+        None => Box::new(archive_writer),
+    };
+    let archive = async {
+        // Dropping the packager closes the pipes like a failed archive,
+        // which makes every sink discard what it got
+        let result = tokio::select! {
+            result = write_archive_from(format, files.entries(), options, reader, output) => result,
+            () = cancel::cancelled() => Err(Interrupted.into()),
+        };
+        let _ = archive_done.send(Some(result.is_ok()));
+        result
+    };
+    let mut hasher = ArchiveHasher::new(&delivery.checksums);
+    let (archive, size, results) = tokio::join!(
+        archive,
+        fan_out(archive_reader, pipes, buffer_size, &mut hasher),
+        join_all(deliveries)
+    );
+    if cancel::is_cancelled() {
+        return Err(Interrupted.into());
+    }
+    // Once every sink is gone the archive writer only sees a closed pipe;
+    // the sink errors say what actually went wrong.
+    let manifest = match archive {
+        Ok(manifest) => manifest,
+        Err(_)
+            if let Some(limit) = delivery.max_size
+                && written.load(Ordering::Relaxed) > limit =>
+        {
+            return Err(SizeLimitExceeded {
+                total: written.load(Ordering::Relaxed),
+                limit,
+                archive: true,
+            }
+            .into());
+        }
+        Err(_) if size.is_none() => None,
+        Err(e) => return Err(e),
+    };
+    let (delivered, failures): (Vec<_>, Vec<_>) = results.into_iter().partition(Result::is_ok);
+    Ok(DeliveryOutcome {
+        delivered: delivered.into_iter().filter_map(Result::ok).collect(),
+        failures: failures.into_iter().filter_map(Result::err).collect(),
+        manifest,
+        size: size.unwrap_or(0),
+        checksums: hasher.finish(),
+    })
+}
+
+/// Counts the bytes written to the archive pipe and fails the write that
+/// would take it past `limit`, which makes the packager give up.
+struct SizeGuard {
+    inner: DuplexStream,
+    limit: u64,
+    /// Bytes written, plus those of the rejected write.
+    written: Arc<AtomicU64>,
+}
+
+impl AsyncWrite for SizeGuard {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let written = self.written.load(Ordering::Relaxed);
+        if written + buf.len() as u64 > self.limit {
+            self.written
+                .store(written + buf.len() as u64, Ordering::Relaxed);
+            return Poll::Ready(Err(io::Error::other(format!(
+                "archive is larger than max_size ({} bytes)",
+                self.limit
+            ))));
+        }
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(count)) = result {
+            self.written.fetch_add(count as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Copies every chunk of `input` to all outputs, dropping outputs that fail,
+/// and feeds it to `hasher`. Returns the bytes copied, or `None` if all
+/// outputs were dropped.
+async fn fan_out(
+    mut input: DuplexStream,
+    outputs: Vec<DuplexStream>,
+    buffer_size: usize,
+    hasher: &mut ArchiveHasher,
+) -> Option<u64> {
+    let mut size = 0;
+    let mut outputs: Vec<Option<DuplexStream>> = outputs.into_iter().map(Some).collect();
+    let mut buffer = vec![0; buffer_size];
+    loop {
+        let read = match input.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        let chunk = &buffer[..read];
+        size += read as u64;
+        hasher.update(chunk);
+        join_all(outputs.iter_mut().map(|slot| async move {
+            if let Some(output) = slot
+                && output.write_all(chunk).await.is_err()
+            {
+                *slot = None;
+            }
+        }))
+        .await;
+        // Without any sink left, stop the archive early
+        if outputs.iter().all(Option::is_none) {
+            return None;
+        }
+    }
+    for output in outputs.iter_mut().flatten() {
+        let _ = output.shutdown().await;
+    }
+    Some(size)
+}
+
+/// Feeds one sink from its pipe, then reports whether the whole archive succeeded.
+async fn relay(
+    mut input: DuplexStream,
+    mut output: ArchiveOutput,
+    mut archive_result: watch::Receiver<Option<bool>>,
+) -> anyhow::Result<()> {
+    tokio::io::copy(&mut input, &mut output).await?;
+    let succeeded = archive_result
+        .wait_for(Option::is_some)
+        .await
+        .context("archive writer vanished")?
+        .unwrap_or(false);
+    if !succeeded {
+        return Err(anyhow!("archive was not completed"));
+    }
+    output.shutdown().await?;
+    Ok(())
+}
+
+/// Feeds one sink from its pipe in volumes of `split_size` bytes, each stored
+/// on its own as `<archive>.001`, `.002`, ... The last volume is only
+/// completed if the whole archive succeeded.
+async fn relay_volumes(
+    mut input: DuplexStream,
+    sink: &OutSink,
+    split_size: u64,
+    buffer_size: usize,
+    mut archive_result: watch::Receiver<Option<bool>>,
+) -> anyhow::Result<()> {
+    // Bytes read to find out whether another volume follows
+    let mut next = Vec::new();
+    let mut number = 1;
+    loop {
+        let volume = sink
+            .volume(number)
+            .ok_or_else(|| anyhow!("{sink} can't be split into volumes"))?;
+        let (input, next, archive_result) = (&mut input, &mut next, &mut archive_result);
+        let mut last = false;
+        let last_ref = &mut last;
+        deliver(volume, buffer_size, |mut output| async move {
+            output.write_all(next).await?;
+            let rest = split_size - next.len() as u64;
+            next.clear();
+            tokio::io::copy(&mut (&mut *input).take(rest), &mut output).await?;
+
+            let mut buffer = vec![0; buffer_size.min(split_size as usize)];
+            let read = input.read(&mut buffer).await?;
+            if read == 0 {
+                *last_ref = true;
+                let succeeded = archive_result
+                    .wait_for(Option::is_some)
+                    .await
+                    .context("archive writer vanished")?
+                    .unwrap_or(false);
+                if !succeeded {
+                    return Err(anyhow!("archive was not completed"));
+                }
+            } else {
+                next.extend_from_slice(&buffer[..read]);
+            }
+            output.shutdown().await?;
+            Ok(())
+        })
+        .await?;
+        if last {
+            return Ok(());
+        }
+        number += 1;
+    }
+}
@@ -0,0 +1,353 @@
+//! Uploads to [tus](https://tus.io) servers (`protocol: tus`). The archive is
+//! sent in chunks of `upload_chunk_size`, one PATCH request each, with a
+//! checksum the server checks before storing it. A chunk that fails is
+//! retried on its own from the offset the server confirms, so a dropped
+//! connection costs at most a chunk instead of the whole upload.
+
+use anyhow::{Context, Result, anyhow, bail};
+use async_trait::async_trait;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use md5::Md5;
+use reqwest::{Method, StatusCode, Url, header};
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::oneshot,
+};
+use tracing::{info, warn};
+
+use super::retry::RetryPolicy;
+use super::send_net::{HttpAuth, HttpUpload};
+use super::{Sink, SinkWriter};
+use crate::Config;
+use crate::fs_utils::parse_size;
+use crate::naming::{create_file_name, expand_placeholders};
+
+const TUS_VERSION: &str = "1.0.0";
+/// Bytes per PATCH request unless `upload_chunk_size` says otherwise.
+pub const DEFAULT_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+/// The server rejected a chunk whose checksum didn't match its contents.
+const CHECKSUM_MISMATCH: u16 = 460;
+
+/// An archive upload to a tus server.
+#[derive(Debug, Clone)]
+pub struct TusUpload {
+    /// Creation endpoint, with the authentication, headers and TLS settings
+    /// every request of the upload uses.
+    pub http: HttpUpload,
+    /// Name of the archive, sent as `filename` in the upload's metadata.
+    pub filename: String,
+    /// Bytes per PATCH request.
+    pub chunk_size: usize,
+    pub retry: RetryPolicy,
+}
+
+impl TusUpload {
+    /// Builds an upload from an `http(s)://` output. A last path segment
+    /// with an extension names the archive (`/files/%date%.zip` uploads
+    /// `2024-01-31.zip` to the endpoint `/files/`); otherwise the URL is the
+    /// endpoint and the archive gets the default name. Naming templates are
+    /// expanded in both.
+    pub fn from_config(output: &str, config: &Config) -> Result<Self> {
+        let mut url = Url::parse(output).with_context(|| format!("invalid URL {output}"))?;
+        let path = url.path().to_string();
+        let (endpoint, filename) = match path.rsplit_once('/') {
+            Some((dir, name))
+                if name
+                    .rsplit_once('.')
+                    .is_some_and(|(_, ext)| !ext.is_empty()) =>
+            {
+                (format!("{dir}/"), create_file_name(name, config)?)
+            }
+            _ => (path.clone(), create_file_name("", config)?),
+        };
+        url.set_path(&expand_placeholders(&endpoint, config)?);
+
+        let chunk_size = match config.upload_chunk_size.as_deref().map(str::trim) {
+            None | Some("") => DEFAULT_CHUNK_SIZE,
+            Some(size) => usize::try_from(parse_size(size)?)?,
+        };
+        if chunk_size == 0 {
+            bail!("upload_chunk_size must be at least 1 byte");
+        }
+
+        Ok(TusUpload {
+            http: HttpUpload::from_config(url.as_str(), config)?,
+            filename: filename.to_string_lossy().to_string(),
+            chunk_size,
+            retry: RetryPolicy::from_config(config)?,
+        })
+    }
+
+    pub(super) fn request(&self, method: Method, url: &str) -> reqwest::RequestBuilder {
+        self.http
+            .authorize(self.http.client.request(method, url))
+            .header("Tus-Resumable", TUS_VERSION)
+    }
+}
+
+/// Checksum algorithms of the tus checksum extension that ssbt computes, in
+/// order of preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkChecksum {
+    Sha256,
+    Md5,
+}
+
+impl ChunkChecksum {
+    /// The `Upload-Checksum` value for `chunk`.
+    fn header(self, chunk: &[u8]) -> String {
+        match self {
+            ChunkChecksum::Sha256 => format!("sha256 {}", STANDARD.encode(Sha256::digest(chunk))),
+            ChunkChecksum::Md5 => format!("md5 {}", STANDARD.encode(Md5::digest(chunk))),
+        }
+    }
+}
+
+#[async_trait]
+impl Sink for TusUpload {
+    async fn open_writer(&self, buffer_size: usize) -> Result<SinkWriter> {
+        let upload = self.clone();
+        Ok(SinkWriter::committed(buffer_size, |reader, committed| {
+            upload_stream(upload, reader, committed)
+        }))
+    }
+}
+
+/// Creates the upload and sends everything read from `reader` in chunks.
+///
+/// The last chunk, which declares the archive's length, is only sent once
+/// `commit` fires; if it is dropped because the archive couldn't be
+/// completed, the upload is terminated instead.
+pub async fn upload_stream<R>(
+    upload: TusUpload,
+    mut reader: R,
+    commit: oneshot::Receiver<()>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    if let Some(HttpAuth::Digest { .. }) = upload.http.auth {
+        bail!("digest authentication is only supported for WebDAV outputs");
+    }
+    let checksum = discover(&upload).await?;
+    let location = create(&upload).await?;
+
+    let mut offset = 0u64;
+    let mut buffer = vec![0; upload.chunk_size];
+    let last = loop {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            match reader.read(&mut buffer[filled..]).await? {
+                0 => break,
+                read => filled += read,
+            }
+        }
+        // Only a short chunk is known to be the last one
+        if filled < buffer.len() {
+            break filled;
+        }
+        send_chunk(&upload, &location, checksum, &buffer, offset, None).await?;
+        offset += buffer.len() as u64;
+    };
+
+    if commit.await.is_err() {
+        // The archive writer reports why it stopped
+        let _ = upload.request(Method::DELETE, &location).send().await;
+        return Ok(());
+    }
+    let total = offset + last as u64;
+    send_chunk(
+        &upload,
+        &location,
+        checksum,
+        &buffer[..last],
+        offset,
+        Some(total),
+    )
+    .await?;
+    info!("Uploaded {total} bytes to {location}");
+    Ok(())
+}
+
+/// Asks the server with OPTIONS which extensions it supports, and picks the
+/// checksum algorithm for the chunks. Servers that don't answer OPTIONS are
+/// assumed to support deferring the length, and get no checksums.
+async fn discover(upload: &TusUpload) -> Result<Option<ChunkChecksum>> {
+    let response = upload
+        .request(Method::OPTIONS, &upload.http.url)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        warn!(
+            "tus OPTIONS {} returned status {}, uploading without chunk checksums",
+            upload.http.url,
+            response.status()
+        );
+        return Ok(None);
+    }
+    let list = |name: &str| -> Vec<String> {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(|item| item.trim().to_ascii_lowercase())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    let extensions = list("Tus-Extension");
+    if !extensions.is_empty() && !extensions.iter().any(|e| e == "creation-defer-length") {
+        bail!(
+            "tus server {} doesn't support the creation-defer-length extension, which streamed archives need",
+            upload.http.url
+        );
+    }
+    let algorithms = list("Tus-Checksum-Algorithm");
+    let checksum = if algorithms.iter().any(|a| a == "sha256") {
+        Some(ChunkChecksum::Sha256)
+    } else if algorithms.iter().any(|a| a == "md5") {
+        Some(ChunkChecksum::Md5)
+    } else {
+        None
+    };
+    if checksum.is_none() {
+        warn!(
+            "tus server {} offers no sha256 or md5 checksums, uploading chunks without them",
+            upload.http.url
+        );
+    }
+    Ok(checksum)
+}
+
+/// Creates an upload of yet unknown length and returns its URL.
+async fn create(upload: &TusUpload) -> Result<String> {
+    let metadata = format!(
+        "filename {},filetype {}",
+        STANDARD.encode(&upload.filename),
+        STANDARD.encode(upload.http.content_type)
+    );
+    let response = upload
+        .request(Method::POST, &upload.http.url)
+        .header("Upload-Defer-Length", "1")
+        .header("Upload-Metadata", metadata)
+        .header(header::CONTENT_LENGTH, 0)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        bail!(
+            "creating tus upload at {} failed with status {}: {}",
+            upload.http.url,
+            response.status(),
+            response.text().await.unwrap_or_default()
+        );
+    }
+    let location = response
+        .headers()
+        .get(header::LOCATION)
+        .context("tus server returned no upload URL")?
+        .to_str()?;
+    // The location may be relative to the endpoint
+    Ok(Url::parse(&upload.http.url)?.join(location)?.to_string())
+}
+
+/// Sends `chunk`, which starts at `offset`, resending whatever the server did
+/// not store. `total` is set for the final chunk.
+async fn send_chunk(
+    upload: &TusUpload,
+    location: &str,
+    checksum: Option<ChunkChecksum>,
+    chunk: &[u8],
+    offset: u64,
+    total: Option<u64>,
+) -> Result<()> {
+    let end = offset + chunk.len() as u64;
+    let mut sent = offset;
+    let mut attempt = 1;
+
+    loop {
+        let body = &chunk[(sent - offset) as usize..];
+        let mut request = upload
+            .request(Method::PATCH, location)
+            .header(header::CONTENT_TYPE, "application/offset+octet-stream")
+            .header("Upload-Offset", sent);
+        if let Some(checksum) = checksum {
+            request = request.header("Upload-Checksum", checksum.header(body));
+        }
+        if let Some(total) = total {
+            request = request.header("Upload-Length", total);
+        }
+
+        let error = match request.body(body.to_vec()).send().await {
+            Ok(response) if response.status().is_success() => {
+                let stored = upload_offset(&response)?;
+                if stored >= end {
+                    return Ok(());
+                }
+                if stored > sent {
+                    // A partially stored chunk is progress, not a failure
+                    sent = stored;
+                    continue;
+                }
+                format!("no bytes accepted from offset {sent}")
+            }
+            Ok(response) if response.status().as_u16() == CHECKSUM_MISMATCH => {
+                "the server got a corrupted chunk (checksum mismatch)".to_string()
+            }
+            Ok(response)
+                if response.status().is_client_error()
+                    && !matches!(
+                        response.status(),
+                        StatusCode::CONFLICT
+                            | StatusCode::REQUEST_TIMEOUT
+                            | StatusCode::TOO_MANY_REQUESTS
+                    ) =>
+            {
+                bail!(
+                    "tus upload failed with status {}: {}",
+                    response.status(),
+                    response.text().await.unwrap_or_default()
+                );
+            }
+            Ok(response) => format!("status {}", response.status()),
+            Err(e) => e.to_string(),
+        };
+
+        let Some(delay) = upload.retry.next_delay("tus chunk upload", attempt, &error) else {
+            bail!("tus upload failed after {attempt} attempts: {error}");
+        };
+        attempt += 1;
+        tokio::time::sleep(delay).await;
+        sent = query_offset(upload, location).await?;
+        if sent < offset || sent > end {
+            bail!(
+                "cannot resume tus upload: server has {sent} bytes, the chunk covers {offset}..{end}"
+            );
+        }
+    }
+}
+
+/// Asks the server with HEAD how many bytes of the upload it has stored.
+async fn query_offset(upload: &TusUpload, location: &str) -> Result<u64> {
+    let response = upload.request(Method::HEAD, location).send().await?;
+    if !response.status().is_success() {
+        bail!(
+            "querying tus upload offset failed with status {}",
+            response.status()
+        );
+    }
+    upload_offset(&response)
+}
+
+fn upload_offset(response: &reqwest::Response) -> Result<u64> {
+    response
+        .headers()
+        .get("Upload-Offset")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .ok_or_else(|| anyhow!("tus server returned no valid Upload-Offset"))
+}
@@ -0,0 +1,206 @@
+//! Checks an uploaded object against the bytes that were sent (`verify_upload`),
+//! so an archive truncated by a proxy or the server fails the run instead of
+//! being reported as a success.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result, bail};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use md5::Md5;
+use reqwest::header::{self, HeaderMap};
+use sha2::{Digest, Sha256};
+
+/// Size and hashes of an upload, updated as its chunks go out. Clones share
+/// the counters, so one can move into the body stream.
+#[derive(Clone, Default)]
+pub struct SentCounter(Arc<Mutex<Hashes>>);
+
+#[derive(Default)]
+struct Hashes {
+    size: u64,
+    md5: Md5,
+    sha256: Sha256,
+}
+
+impl SentCounter {
+    pub fn update(&self, chunk: &[u8]) {
+        let mut hashes = self.0.lock().unwrap();
+        hashes.size += chunk.len() as u64;
+        hashes.md5.update(chunk);
+        hashes.sha256.update(chunk);
+    }
+
+    /// What has been sent so far.
+    pub fn sent(&self) -> Sent {
+        let hashes = self.0.lock().unwrap();
+        Sent {
+            size: hashes.size,
+            md5: hashes.md5.clone().finalize().into(),
+            sha256: hashes.sha256.clone().finalize().into(),
+        }
+    }
+}
+
+/// Hash algorithms servers report stored objects with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "MD5",
+            HashAlgorithm::Sha256 => "SHA-256",
+        }
+    }
+}
+
+/// The size and hashes of an uploaded object, as sent.
+#[derive(Debug, Clone)]
+pub struct Sent {
+    pub size: u64,
+    pub md5: [u8; 16],
+    pub sha256: [u8; 32],
+}
+
+impl Sent {
+    /// Fails unless the server stored as many bytes as were sent.
+    pub fn check_size(&self, what: &str, stored: u64) -> Result<()> {
+        if stored != self.size {
+            bail!(
+                "{what} verification failed: sent {} bytes, server reports {stored} bytes",
+                self.size
+            );
+        }
+        Ok(())
+    }
+
+    /// Fails unless `stored`, reported in `source`, is the hash of the bytes sent.
+    pub fn check_hash(
+        &self,
+        what: &str,
+        source: &str,
+        algorithm: HashAlgorithm,
+        stored: &[u8],
+    ) -> Result<()> {
+        let sent = match algorithm {
+            HashAlgorithm::Md5 => &self.md5[..],
+            HashAlgorithm::Sha256 => &self.sha256[..],
+        };
+        if stored != sent {
+            bail!(
+                "{what} verification failed: {source} reports {} {}, the bytes sent hash to {}",
+                algorithm.name(),
+                hex(stored),
+                hex(sent)
+            );
+        }
+        Ok(())
+    }
+
+    /// Compares the headers of a HEAD (or PROPFIND) response for the stored
+    /// object with what was sent: `Content-Length`, and the hashes in
+    /// `Content-MD5`, `Digest`, `Repr-Digest`, `x-goog-hash` and
+    /// `x-amz-checksum-sha256`. With `etag`, an ETag of 32 hex digits is taken
+    /// for the MD5 of the object, as S3 and compatible stores return for
+    /// uploads in one part. Returns the names of the headers checked.
+    pub fn check_headers(
+        &self,
+        what: &str,
+        headers: &HeaderMap,
+        etag: bool,
+    ) -> Result<Vec<String>> {
+        let mut checked = Vec::new();
+        if let Some(length) = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok())
+        {
+            let stored = length
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid Content-Length {length}"))?;
+            self.check_size(what, stored)?;
+            checked.push("Content-Length".to_string());
+        }
+        for (name, algorithm, stored) in reported_hashes(headers, etag) {
+            self.check_hash(what, &name, algorithm, &stored)?;
+            checked.push(name);
+        }
+        Ok(checked)
+    }
+}
+
+/// The hashes `headers` report, skipping values that don't decode.
+fn reported_hashes(headers: &HeaderMap, etag: bool) -> Vec<(String, HashAlgorithm, Vec<u8>)> {
+    let mut hashes = Vec::new();
+    let values = |name: &'static str| {
+        headers
+            .get_all(name)
+            .into_iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+    };
+    let algorithm = |name: &str| match name.trim().to_ascii_lowercase().as_str() {
+        "md5" => Some(HashAlgorithm::Md5),
+        "sha-256" | "sha256" => Some(HashAlgorithm::Sha256),
+        _ => None,
+    };
+
+    for value in values("content-md5") {
+        if let Ok(hash) = STANDARD.decode(value) {
+            hashes.push(("Content-MD5".to_string(), HashAlgorithm::Md5, hash));
+        }
+    }
+    for value in values("x-amz-checksum-sha256") {
+        if let Ok(hash) = STANDARD.decode(value) {
+            hashes.push((
+                "x-amz-checksum-sha256".to_string(),
+                HashAlgorithm::Sha256,
+                hash,
+            ));
+        }
+    }
+    // `md5=<base64>, crc32c=<base64>`, `sha-256=<base64>` (RFC 3230) and
+    // `sha-256=:<base64>:` (RFC 9530)
+    for name in ["x-goog-hash", "digest", "repr-digest"] {
+        for value in values(name) {
+            let Some((label, hash)) = value.split_once('=') else {
+                continue;
+            };
+            let Some(algorithm) = algorithm(label) else {
+                continue;
+            };
+            if let Ok(hash) = STANDARD.decode(hash.trim().trim_matches(':')) {
+                hashes.push((format!("{name} {}", label.trim()), algorithm, hash));
+            }
+        }
+    }
+    if etag
+        && let Some(hash) = headers
+            .get(header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| md5_etag(value.trim()))
+    {
+        hashes.push(("ETag".to_string(), HashAlgorithm::Md5, hash));
+    }
+    hashes
+}
+
+/// The MD5 a strong ETag like `"9e107d9d372bb6826bd81d3542a419d6"` stands for.
+fn md5_etag(etag: &str) -> Option<Vec<u8>> {
+    let hex = etag.strip_prefix('"')?.strip_suffix('"')?;
+    if hex.len() != 32 {
+        return None;
+    }
+    (0..32)
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
@@ -0,0 +1,299 @@
+use anyhow::{Context, Result, bail};
+use async_trait::async_trait;
+use futures::TryStreamExt;
+use reqwest::{Method, StatusCode, Url};
+use tokio::io::AsyncRead;
+
+use super::digest::DigestAuth;
+use super::send_net::{BODY_CHUNK_SIZE, HttpAuth, HttpUpload};
+use super::verify::SentCounter;
+use super::{Sink, SinkWriter};
+use crate::Config;
+use crate::error::HttpStatusError;
+use crate::naming::create_file_name;
+use tracing::{info, warn};
+
+const PROPFIND_SIZE: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:getcontentlength/></d:prop></d:propfind>"#;
+
+/// An archive upload to a WebDAV server (Nextcloud, ownCloud, Apache mod_dav, ...).
+#[derive(Debug, Clone)]
+pub struct WebDavUpload {
+    /// Target file URL, already translated to http(s) and with the file name expanded.
+    pub http: HttpUpload,
+    /// Compare the stored size (and hashes the server reports) against the
+    /// bytes sent once the upload finishes.
+    pub verify: bool,
+}
+
+impl WebDavUpload {
+    /// Builds an upload from a `webdav://`/`dav+http://` (plain HTTP) or
+    /// `webdavs://`/`dav+https://` (HTTPS) output, expanding naming templates
+    /// in the file name.
+    pub fn from_config(output: &str, config: &Config) -> Result<Self> {
+        let url = to_http_url(output)?;
+        let mut url = Url::parse(&url).with_context(|| format!("invalid WebDAV URL {output}"))?;
+        let path = create_file_name(url.path(), config)?;
+        url.set_path(&path.to_string_lossy());
+
+        Ok(WebDavUpload {
+            http: HttpUpload::from_config(url.as_str(), config)?,
+            verify: config.verify_upload.unwrap_or(false),
+        })
+    }
+
+    fn url(&self) -> Result<Url> {
+        Ok(Url::parse(&self.http.url)?)
+    }
+}
+
+/// Translates a WebDAV output to the http(s) URL it is uploaded to.
+pub fn to_http_url(output: &str) -> Result<String> {
+    if let Some(rest) = output
+        .strip_prefix("webdavs://")
+        .or_else(|| output.strip_prefix("dav+https://"))
+    {
+        Ok(format!("https://{rest}"))
+    } else if let Some(rest) = output
+        .strip_prefix("webdav://")
+        .or_else(|| output.strip_prefix("dav+http://"))
+    {
+        Ok(format!("http://{rest}"))
+    } else {
+        bail!("not a WebDAV URL: {output}");
+    }
+}
+
+/// The reverse of [`to_http_url`], giving the `webdav://`/`webdavs://` form.
+pub fn to_webdav_url(url: &str) -> String {
+    if let Some(rest) = url.strip_prefix("https://") {
+        format!("webdavs://{rest}")
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        format!("webdav://{rest}")
+    } else {
+        url.to_string()
+    }
+}
+
+/// Returns true for outputs handled by [`WebDavUpload`].
+pub fn is_webdav_url(output: &str) -> bool {
+    ["webdav://", "webdavs://", "dav+http://", "dav+https://"]
+        .iter()
+        .any(|scheme| output.starts_with(scheme))
+}
+
+/// A client for one upload, applying the configured authentication to every request.
+struct Dav<'a> {
+    client: reqwest::Client,
+    upload: &'a WebDavUpload,
+    digest: Option<DigestAuth>,
+}
+
+impl<'a> Dav<'a> {
+    /// For Digest authentication, fetches the server's challenge with an
+    /// unauthenticated PROPFIND first.
+    async fn connect(upload: &'a WebDavUpload) -> Result<Self> {
+        let mut dav = Dav {
+            client: upload.http.client.clone(),
+            upload,
+            digest: None,
+        };
+        if let Some(HttpAuth::Digest { user, password }) = &upload.http.auth {
+            let response = dav.propfind(upload.url()?).await?;
+            if response.status() == StatusCode::UNAUTHORIZED {
+                dav.digest = Some(DigestAuth::from_response(&response, user, password)?);
+            }
+        }
+        Ok(dav)
+    }
+
+    fn request(&self, method: Method, url: Url) -> reqwest::RequestBuilder {
+        let digest = self
+            .digest
+            .as_ref()
+            .map(|digest| digest.authorization(&method, &url));
+        let request = self.upload.http.authorize(self.client.request(method, url));
+        match digest {
+            Some(value) => request.header("Authorization", value),
+            None => request,
+        }
+    }
+
+    /// Walks up from the target's parent until an existing collection is found,
+    /// then creates the missing ones top-down with MKCOL.
+    async fn ensure_collections(&self) -> Result<()> {
+        let mut missing = Vec::new();
+        let mut dir = parent_collection(&self.upload.url()?);
+
+        while let Some(current) = dir {
+            let response = self.propfind(current.clone()).await?;
+            match response.status() {
+                StatusCode::NOT_FOUND => {
+                    dir = parent_collection(&current);
+                    missing.push(current);
+                }
+                status if status.is_success() => break,
+                status => bail!("WebDAV PROPFIND {current} failed with status: {status}"),
+            }
+        }
+
+        for collection in missing.into_iter().rev() {
+            let response = self
+                .request(Method::from_bytes(b"MKCOL")?, collection.clone())
+                .send()
+                .await?;
+            // 405 means the collection already exists (e.g. created concurrently)
+            if !response.status().is_success()
+                && response.status() != StatusCode::METHOD_NOT_ALLOWED
+            {
+                bail!(
+                    "WebDAV MKCOL {collection} failed with status: {}",
+                    response.status()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn propfind(&self, url: Url) -> Result<reqwest::Response> {
+        Ok(self
+            .request(Method::from_bytes(b"PROPFIND")?, url)
+            .header("Depth", "0")
+            .header("Content-Type", "application/xml")
+            .body(PROPFIND_SIZE)
+            .send()
+            .await?)
+    }
+
+    /// Reads `getcontentlength` of a stored file via PROPFIND.
+    async fn remote_size(&self, url: Url) -> Result<Option<u64>> {
+        let response = self.propfind(url).await?;
+        if !response.status().is_success() {
+            bail!("WebDAV PROPFIND failed with status: {}", response.status());
+        }
+        Ok(parse_content_length(&response.text().await?))
+    }
+}
+
+#[async_trait]
+impl Sink for WebDavUpload {
+    async fn open_writer(&self, buffer_size: usize) -> Result<SinkWriter> {
+        let upload = self.clone();
+        Ok(SinkWriter::streamed(buffer_size, |pipe| {
+            upload_stream(upload, pipe)
+        }))
+    }
+}
+
+/// Creates missing parent collections, then PUTs everything read from `reader`.
+pub async fn upload_stream<R>(upload: WebDavUpload, reader: R) -> Result<()>
+where
+    R: AsyncRead + Send + 'static,
+{
+    let dav = Dav::connect(&upload).await?;
+    dav.ensure_collections().await?;
+
+    let counter = upload.verify.then(SentCounter::default);
+    let body_counter = counter.clone();
+    let body = tokio_util::io::ReaderStream::with_capacity(reader, BODY_CHUNK_SIZE).inspect_ok(
+        move |chunk| {
+            if let Some(counter) = &body_counter {
+                counter.update(chunk);
+            }
+        },
+    );
+
+    let response = dav
+        .request(Method::PUT, upload.url()?)
+        .header("Content-Type", upload.http.content_type)
+        .body(reqwest::Body::wrap_stream(body))
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(HttpStatusError {
+            what: "WebDAV upload".to_string(),
+            status: response.status(),
+        }
+        .into());
+    }
+
+    if let Some(counter) = counter {
+        let sent = counter.sent();
+        let stored = dav.remote_size(upload.url()?).await?;
+        if stored != Some(sent.size) {
+            bail!(
+                "WebDAV verification failed: sent {} bytes, server reports {}",
+                sent.size,
+                stored.map_or("no size".to_string(), |s| format!("{s} bytes"))
+            );
+        }
+        info!("Verified remote size: {} bytes", sent.size);
+
+        // ETags of WebDAV servers (Nextcloud's among them) aren't content hashes
+        let response = dav.request(Method::HEAD, upload.url()?).send().await?;
+        if response.status().is_success() {
+            let checked = sent.check_headers("WebDAV", response.headers(), false)?;
+            if checked.iter().any(|name| name != "Content-Length") {
+                info!("Verified remote file: {}", checked.join(", "));
+            }
+        } else {
+            warn!(
+                "WebDAV HEAD {} returned status {}, only the size was verified",
+                upload.http.url,
+                response.status()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the file `upload` points at exists.
+pub async fn exists(upload: &WebDavUpload) -> Result<bool> {
+    let dav = Dav::connect(upload).await?;
+    let response = dav.propfind(upload.url()?).await?;
+    match response.status() {
+        StatusCode::NOT_FOUND => Ok(false),
+        status if status.is_success() => Ok(true),
+        status => bail!(
+            "WebDAV PROPFIND {} failed with status: {status}",
+            upload.http.url
+        ),
+    }
+}
+
+/// Deletes the file `upload` points at; one that is already gone counts as deleted.
+pub async fn delete(upload: &WebDavUpload) -> Result<()> {
+    let dav = Dav::connect(upload).await?;
+    let response = dav.request(Method::DELETE, upload.url()?).send().await?;
+    let status = response.status();
+    if !status.is_success() && status != StatusCode::NOT_FOUND {
+        bail!(
+            "WebDAV DELETE {} failed with status: {status}",
+            upload.http.url
+        );
+    }
+    Ok(())
+}
+
+/// Extracts the `getcontentlength` value from a multistatus body, whatever
+/// namespace prefix the server uses.
+fn parse_content_length(xml: &str) -> Option<u64> {
+    let start = xml.find("getcontentlength")?;
+    let value_start = start + xml[start..].find('>')? + 1;
+    let value_end = value_start + xml[value_start..].find('<')?;
+    xml[value_start..value_end].trim().parse().ok()
+}
+
+/// Returns the collection URL containing `url`, or `None` at the server root.
+fn parent_collection(url: &Url) -> Option<Url> {
+    let path = url.path().trim_end_matches('/');
+    let (parent, _) = path.rsplit_once('/')?;
+    if parent.is_empty() {
+        return None;
+    }
+    let mut parent_url = url.clone();
+    parent_url.set_path(&format!("{parent}/"));
+    Some(parent_url)
+}
@@ -0,0 +1,120 @@
+//! Databases in `sources`, dumped with `pg_dump` or `mysqldump` straight
+//! into an archive entry (see [`crate::packaging::streamed`]), so no
+//! temporary file as large as the database is needed, and the output of
+//! the commands in `virtual_files`. The Kubernetes resources of `k8s` are
+//! added by [`crate::k8s`].
+
+use anyhow::{Context, Result, anyhow, bail};
+use reqwest::Url;
+
+use crate::k8s;
+use crate::packaging::streamed::{StreamSource, StreamedEntry};
+use crate::secrets::resolve_secret;
+use crate::{Config, SourceConfig};
+
+/// The streamed entries of `config.sources`, `config.virtual_files` and
+/// `config.k8s`.
+pub fn streamed_entries(config: &Config) -> Result<Vec<StreamedEntry>> {
+    let mut entries = config
+        .sources
+        .iter()
+        .flatten()
+        .enumerate()
+        .map(|(number, source)| dump_entry(source).with_context(|| format!("sources[{number}]")))
+        .collect::<Result<Vec<_>>>()?;
+    for (number, file) in config.virtual_files.iter().flatten().enumerate() {
+        let (Some(name), Some(command)) = (&file.name, &file.command) else {
+            bail!("virtual_files[{number}]: name and command are required");
+        };
+        entries.push(StreamedEntry {
+            name: name.clone(),
+            source: StreamSource::Shell(command.clone()),
+        });
+    }
+    entries.extend(k8s::streamed_entries(config)?);
+    Ok(entries)
+}
+
+fn dump_entry(source: &SourceConfig) -> Result<StreamedEntry> {
+    let kind = source
+        .kind
+        .as_deref()
+        .ok_or_else(|| anyhow!("type is required (postgres or mysql)"))?;
+    let url = source
+        .url
+        .as_deref()
+        .ok_or_else(|| anyhow!("url is required"))?;
+    // `env:DATABASE_URL` and the like keep the password out of the config
+    let url = resolve_secret(url)?;
+    let mut url = Url::parse(&url).context("invalid url")?;
+    let database = percent_decode(url.path().trim_start_matches('/'))?;
+    if database.is_empty() {
+        bail!("the url names no database");
+    }
+    let password = url.password().map(percent_decode).transpose()?;
+    let _ = url.set_password(None);
+    let extra = source.args.clone().unwrap_or_default();
+
+    let (program, args, env) = match kind {
+        "postgres" | "postgresql" => {
+            // pg_dump takes the URL itself; the password goes through the
+            // environment so it doesn't show in the process list
+            let mut args = vec![format!("--dbname={url}")];
+            args.extend(extra);
+            let env = password.map(|p| ("PGPASSWORD".to_string(), p));
+            ("pg_dump", args, env)
+        }
+        "mysql" | "mariadb" => {
+            let mut args = vec![
+                "--single-transaction".to_string(),
+                "--routines".to_string(),
+                "--events".to_string(),
+            ];
+            if let Some(host) = url.host_str() {
+                args.push(format!("--host={host}"));
+            }
+            if let Some(port) = url.port() {
+                args.push(format!("--port={port}"));
+            }
+            if !url.username().is_empty() {
+                args.push(format!("--user={}", percent_decode(url.username())?));
+            }
+            args.extend(extra);
+            args.push(database.clone());
+            let env = password.map(|p| ("MYSQL_PWD".to_string(), p));
+            ("mysqldump", args, env)
+        }
+        kind => bail!("unknown type {kind} (expected postgres or mysql)"),
+    };
+    Ok(StreamedEntry {
+        name: source
+            .name_in_archive
+            .clone()
+            .unwrap_or_else(|| format!("{database}.sql")),
+        source: StreamSource::Program {
+            program: program.to_string(),
+            args,
+            env: env.into_iter().collect(),
+        },
+    })
+}
+
+fn percent_decode(value: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        match (byte, tail) {
+            (b'%', [high, low, tail @ ..]) => {
+                let pair = [*high, *low];
+                let hex = std::str::from_utf8(&pair)?;
+                bytes.push(u8::from_str_radix(hex, 16).context("invalid percent escape")?);
+                rest = tail;
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
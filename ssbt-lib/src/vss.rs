@@ -0,0 +1,157 @@
+//! `vss: true` on Windows: the files are read from a Volume Shadow Copy of
+//! their volumes, made after the `before` hook, instead of the live files.
+//! Files other programs hold locked (Outlook PSTs, databases) can be read
+//! that way, and all of them as they were at one moment. Archive entries
+//! keep the names of the original paths.
+//!
+//! The copies are made and deleted through the `Win32_ShadowCopy` WMI class
+//! in PowerShell, which needs administrator rights.
+
+use anyhow::{Context, Result, anyhow, bail};
+use std::borrow::Cow;
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::process::Command;
+use tracing::{info, warn};
+
+/// Volume roots (`C:\`) and the device of their shadow copy, for reading
+/// the files of a run from its [`ShadowCopies`]. Empty without `vss`.
+#[derive(Debug, Clone, Default)]
+pub struct ShadowVolumes(Vec<(PathBuf, PathBuf)>);
+
+impl ShadowVolumes {
+    /// Where to read `path` from: in its volume's shadow copy, if there is one.
+    pub fn source_path<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        for (volume, device) in &self.0 {
+            if let Ok(rest) = path.strip_prefix(volume) {
+                return Cow::Owned(device.join(rest));
+            }
+        }
+        Cow::Borrowed(path)
+    }
+}
+
+/// Shadow copies of the volumes of a run, deleted when dropped.
+pub struct ShadowCopies {
+    ids: Vec<String>,
+    volumes: ShadowVolumes,
+}
+
+impl ShadowCopies {
+    /// Makes a shadow copy of every volume holding one of `files`; their
+    /// [`Self::volumes`] tell where to read the files from.
+    pub fn create(files: &[PathBuf]) -> Result<Self> {
+        if !cfg!(windows) {
+            bail!("vss is only available on Windows");
+        }
+        let mut volumes: Vec<PathBuf> = Vec::new();
+        for file in files {
+            let volume = volume_of(file)?;
+            if !volumes.contains(&volume) {
+                volumes.push(volume);
+            }
+        }
+
+        let mut copies = ShadowCopies {
+            ids: Vec::new(),
+            volumes: ShadowVolumes::default(),
+        };
+        for volume in volumes {
+            let (id, device) = create_shadow(&volume)
+                .with_context(|| format!("creating a shadow copy of {}", volume.display()))?;
+            info!("Reading {} from shadow copy {id}", volume.display());
+            copies.ids.push(id);
+            copies.volumes.0.push((volume, device));
+        }
+        Ok(copies)
+    }
+
+    /// Where the copies are, valid until they are dropped.
+    pub fn volumes(&self) -> &ShadowVolumes {
+        &self.volumes
+    }
+}
+
+impl Drop for ShadowCopies {
+    fn drop(&mut self) {
+        for id in &self.ids {
+            if let Err(e) = delete_shadow(id) {
+                warn!("deleting shadow copy {id} failed: {e:#}");
+            }
+        }
+    }
+}
+
+/// The root of the drive `path` is on, e.g. `C:\`.
+fn volume_of(path: &Path) -> Result<PathBuf> {
+    let absolute = std::path::absolute(path)?;
+    match absolute.components().next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => {
+                Ok(PathBuf::from(format!("{}:\\", letter as char)))
+            }
+            _ => bail!(
+                "{} isn't on a local drive, shadow copies can't be made of it",
+                path.display()
+            ),
+        },
+        _ => bail!("{} has no drive letter", path.display()),
+    }
+}
+
+/// Returns the id and the device path of the new shadow copy of `volume`.
+fn create_shadow(volume: &Path) -> Result<(String, PathBuf)> {
+    let script = format!(
+        "$r = (Get-WmiObject -List Win32_ShadowCopy).Create('{}', 'ClientAccessible'); \
+         if ($r.ReturnValue -ne 0) {{ exit $r.ReturnValue }}; \
+         $s = Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq $r.ShadowID }}; \
+         Write-Output $s.ID; Write-Output $s.DeviceObject",
+        volume.display()
+    );
+    let output = powershell(&script)?;
+    if !output.status.success() {
+        let code = output.status.code().unwrap_or(-1);
+        return Err(anyhow!("{}", create_error(code)));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines().map(str::trim).filter(|l| !l.is_empty());
+    match (lines.next(), lines.next()) {
+        (Some(id), Some(device)) => Ok((id.to_string(), PathBuf::from(device))),
+        _ => bail!("unexpected output of PowerShell: {stdout}"),
+    }
+}
+
+fn delete_shadow(id: &str) -> Result<()> {
+    let script = format!(
+        "Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq '{id}' }} | \
+         ForEach-Object {{ $_.Delete() }}"
+    );
+    let output = powershell(&script)?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(())
+}
+
+fn powershell(script: &str) -> Result<std::process::Output> {
+    Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()
+        .context("running PowerShell")
+}
+
+/// The meaning of the return values of `Win32_ShadowCopy.Create`.
+fn create_error(code: i32) -> String {
+    match code {
+        1 => "access denied (run ssbt as administrator)".to_string(),
+        2 => "invalid argument".to_string(),
+        3 => "the volume wasn't found".to_string(),
+        4 => "the volume doesn't support shadow copies".to_string(),
+        6 => "not enough storage for the shadow copy".to_string(),
+        7 => "the volume is in use".to_string(),
+        8 => "the volume has the maximum number of shadow copies".to_string(),
+        9 => "another shadow copy is being made".to_string(),
+        10 => "the shadow copy provider vetoed the operation".to_string(),
+        12 => "the shadow copy provider failed".to_string(),
+        code => format!("PowerShell failed with exit code {code}"),
+    }
+}
@@ -0,0 +1,57 @@
+//! `--check-destination` on local outputs: the directory the archive goes
+//! to has to take files, or the closest one above it if it doesn't exist.
+//! Archives written with `overwrite: never` don't replace files either.
+
+use ssbt_lib::sink::OutSink;
+use ssbt_lib::sink::check::check_destination;
+use ssbt_lib::sink::save_file::{LocalFile, OverwritePolicy};
+
+fn local(path: std::path::PathBuf) -> OutSink {
+    OutSink::SaveToFile(LocalFile {
+        path,
+        overwrite: OverwritePolicy::Always,
+        fsync: false,
+    })
+}
+
+#[tokio::test]
+async fn checks_the_archive_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let found = check_destination(&local(dir.path().join("new/site.zip")))
+        .await
+        .unwrap();
+    assert!(found.ends_with("will be created"), "{found}");
+    // Nothing is left behind
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+    let file = dir.path().join("file");
+    std::fs::write(&file, "").unwrap();
+    assert!(
+        check_destination(&local(file.join("site.zip")))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn never_replaces_a_file_created_while_writing() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = LocalFile {
+        path: dir.path().join("site.zip"),
+        overwrite: OverwritePolicy::Never,
+        fsync: false,
+    };
+    target.check_overwrite().unwrap();
+    std::fs::write(target.part_path(), "new").unwrap();
+    // Another run finishes first
+    std::fs::write(&target.path, "other").unwrap();
+
+    let error = target.commit().await.unwrap_err();
+    assert!(error.to_string().contains("already exists"), "{error}");
+    assert_eq!(std::fs::read_to_string(&target.path).unwrap(), "other");
+
+    std::fs::remove_file(&target.path).unwrap();
+    target.commit().await.unwrap();
+    assert_eq!(std::fs::read_to_string(&target.path).unwrap(), "new");
+    assert!(!target.part_path().exists());
+}
@@ -0,0 +1,98 @@
+//! age stream encryption: archives written through `encrypt_writer` decrypt
+//! again, and files made by the reference age implementation can be read.
+
+use sha2::{Digest, Sha256};
+use ssbt_lib::age::{self, secrecy::ExposeSecret};
+use ssbt_lib::packaging::encrypt::{self, StreamEncryption};
+use ssbt_lib::{Config, EncryptConfig};
+use std::io::{BufReader, Read};
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+const DATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/age");
+
+fn passphrase_config(passphrase: &str) -> Config {
+    Config {
+        encrypt: Some(EncryptConfig {
+            passphrase: Some(passphrase.to_string()),
+            ..EncryptConfig::default()
+        }),
+        ..Config::default()
+    }
+}
+
+fn decrypt(path: &Path, identity_files: &[String], config: &Config) -> anyhow::Result<Vec<u8>> {
+    let identities = encrypt::identities(identity_files, config)?;
+    let file = std::fs::File::open(path)?;
+    let mut plaintext = Vec::new();
+    encrypt::decrypt_reader(&identities, BufReader::new(file))?.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+async fn encrypt_to(path: &Path, config: &Config, plaintext: &[u8]) {
+    let encryption = StreamEncryption::from_config(config).unwrap().unwrap();
+    let file = tokio::fs::File::create(path).await.unwrap();
+    let mut writer = encrypt::encrypt_writer(&encryption.recipients, Box::new(file)).unwrap();
+    writer.write_all(plaintext).await.unwrap();
+    writer.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn round_trips_through_recipients_and_passphrases() {
+    let dir = tempfile::tempdir().unwrap();
+    let identity = age::x25519::Identity::generate();
+    let key_file = dir.path().join("key.txt");
+    std::fs::write(
+        &key_file,
+        format!("# a comment\n{}\n", identity.to_string().expose_secret()),
+    )
+    .unwrap();
+    let key_file = key_file.to_string_lossy().into_owned();
+    // More than one 64 KiB chunk, ending in a partial one
+    let plaintext: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+
+    let config = Config {
+        encrypt: Some(EncryptConfig {
+            recipients: Some(vec![identity.to_public().to_string()]),
+            ..EncryptConfig::default()
+        }),
+        ..Config::default()
+    };
+    let archive = dir.path().join("keys.zip.age");
+    encrypt_to(&archive, &config, &plaintext).await;
+    let decrypted = decrypt(&archive, std::slice::from_ref(&key_file), &config).unwrap();
+    assert_eq!(decrypted, plaintext);
+    let other = dir.path().join("other.txt");
+    let other_identity = age::x25519::Identity::generate();
+    std::fs::write(&other, other_identity.to_string().expose_secret()).unwrap();
+    assert!(decrypt(&archive, &[other.to_string_lossy().into_owned()], &config).is_err());
+
+    let archive = dir.path().join("passphrase.zip.age");
+    encrypt_to(&archive, &passphrase_config("hunter2"), b"").await;
+    let decrypted = decrypt(&archive, &[], &passphrase_config("hunter2")).unwrap();
+    assert!(decrypted.is_empty());
+    assert!(decrypt(&archive, &[], &passphrase_config("hunter3")).is_err());
+}
+
+#[test]
+fn reads_files_from_the_reference_implementation() {
+    // Test vectors of the age reference implementation (C2SP age testkit)
+    let payload = "013f54400c82da08037759ada907a8b864e97de81c088a182062c4b5622fd2ab";
+
+    let key_file = format!("{DATA}/x25519_key.txt");
+    let plaintext = decrypt(
+        &Path::new(DATA).join("x25519.age"),
+        &[key_file],
+        &Config::default(),
+    )
+    .unwrap();
+    assert_eq!(format!("{:x}", Sha256::digest(&plaintext)), payload);
+
+    let plaintext = decrypt(
+        &Path::new(DATA).join("scrypt.age"),
+        &[],
+        &passphrase_config("password"),
+    )
+    .unwrap();
+    assert_eq!(format!("{:x}", Sha256::digest(&plaintext)), payload);
+}
@@ -0,0 +1,62 @@
+//! The compression a dry run predicts from samples of the files.
+
+use std::fs;
+use std::path::PathBuf;
+
+use ssbt_lib::Config;
+use ssbt_lib::packaging::{ArchiveFormat, estimate_compression_ratio};
+
+#[test]
+fn predicts_compression_from_samples() {
+    let dir = tempfile::tempdir().unwrap();
+    let text = dir.path().join("log.txt");
+    fs::write(&text, "GET /index.html 200\n".repeat(10_000)).unwrap();
+    let photo = dir.path().join("photo.jpg");
+    fs::write(&photo, "not really a jpeg\n".repeat(10_000)).unwrap();
+    let entries = |files: &[&PathBuf]| -> Vec<(String, PathBuf)> {
+        files
+            .iter()
+            .map(|file| {
+                let name = file.file_name().unwrap().to_string_lossy().to_string();
+                (name, file.to_path_buf())
+            })
+            .collect()
+    };
+    let compressed = Config {
+        compress: Some(true),
+        ..Config::default()
+    };
+
+    let ratio = |config: &Config, format, files: &[&PathBuf]| {
+        estimate_compression_ratio(config, format, &entries(files)).unwrap()
+    };
+    assert_eq!(ratio(&Config::default(), ArchiveFormat::Zip, &[&text]), 1.0);
+    assert!(ratio(&compressed, ArchiveFormat::Zip, &[&text]) < 0.1);
+    assert!(ratio(&compressed, ArchiveFormat::Tar, &[&text]) < 0.1);
+    assert!(ratio(&Config::default(), ArchiveFormat::SevenZ, &[&text]) < 0.1);
+    // Zips store photos as they are
+    assert_eq!(ratio(&compressed, ArchiveFormat::Zip, &[&photo]), 1.0);
+    assert_eq!(ratio(&compressed, ArchiveFormat::Zip, &[]), 1.0);
+}
+
+#[test]
+fn weighs_samples_by_file_size() {
+    let dir = tempfile::tempdir().unwrap();
+    // Sparse, so it takes no room; zips store videos as they are
+    let video = dir.path().join("video.mp4");
+    fs::File::create(&video).unwrap().set_len(10 << 30).unwrap();
+    let mut entries = vec![("video.mp4".to_string(), video)];
+    for i in 0..31 {
+        let name = format!("app-{i}.log");
+        let log = dir.path().join(&name);
+        fs::write(&log, "GET /index.html 200\n".repeat(10_000)).unwrap();
+        entries.push((name, log));
+    }
+    let config = Config {
+        compress: Some(true),
+        ..Config::default()
+    };
+
+    let ratio = estimate_compression_ratio(&config, ArchiveFormat::Zip, &entries).unwrap();
+    assert!(ratio > 0.99, "{ratio}");
+}
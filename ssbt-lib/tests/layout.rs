@@ -0,0 +1,112 @@
+//! Entry names of backed up files: under a top-level directory per backed up
+//! path, under the prefix of their `paths` entry, or flattened.
+
+use std::path::PathBuf;
+
+use ssbt_lib::Config;
+use ssbt_lib::process::{EntryNaming, prepare_entries};
+
+fn names(config: &str, files: &[&str]) -> Vec<String> {
+    let config: Config = serde_yaml::from_str(config).unwrap();
+    let files: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+    let naming = EntryNaming::for_files(&config, &files).unwrap();
+    prepare_entries(files, &naming)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect()
+}
+
+#[test]
+fn each_path_gets_a_top_level_directory() {
+    assert_eq!(
+        names(
+            "paths: [/etc, /home/me/app]",
+            &["/etc/hosts", "/home/me/app/src/main.rs"]
+        ),
+        ["etc/hosts", "app/src/main.rs"]
+    );
+    // A single path is the top of the archive
+    assert_eq!(
+        names("paths: [/home/me/app]", &["/home/me/app/src/main.rs"]),
+        ["src/main.rs"]
+    );
+    // Paths of the same name take more of their directories
+    assert_eq!(
+        names(
+            "paths: [/etc, /opt/etc, /srv/a/etc]",
+            &["/etc/hosts", "/opt/etc/o.conf", "/srv/a/etc/a.conf"]
+        ),
+        ["etc/hosts", "opt/etc/o.conf", "a/etc/a.conf"]
+    );
+}
+
+#[test]
+fn nested_and_overlapping_paths() {
+    // The inner path is part of the outer one, which is then the only root
+    assert_eq!(
+        names(
+            "paths: [/home/me/app, /home/me, /home/me/]",
+            &["/home/me/notes.txt", "/home/me/app/src/main.rs"]
+        ),
+        ["notes.txt", "app/src/main.rs"]
+    );
+    assert_eq!(
+        names(
+            "paths: [/etc, /home/me, /home/me/app]",
+            &["/etc/hosts", "/home/me/app/src/main.rs"]
+        ),
+        ["etc/hosts", "me/app/src/main.rs"]
+    );
+    // A prefix still applies inside another path
+    assert_eq!(
+        names(
+            "paths: [/etc, {path: /etc/nginx, prefix: web}]",
+            &["/etc/hosts", "/etc/nginx/nginx.conf"]
+        ),
+        ["hosts", "web/nginx.conf"]
+    );
+}
+
+#[test]
+fn prefixed_paths_and_flatten() {
+    let config = "
+paths:
+  - {path: /etc, prefix: config/}
+  - path: /var/www
+    prefix: site
+  - /home/user/notes
+";
+    let files = [
+        "/etc/hosts",
+        "/etc/nginx/nginx.conf",
+        "/var/www/css/site.css",
+        "/home/user/notes/todo.txt",
+        "/home/user/notes/old/done.txt",
+    ];
+    assert_eq!(
+        names(config, &files),
+        [
+            "config/hosts",
+            "config/nginx/nginx.conf",
+            "site/css/site.css",
+            "notes/todo.txt",
+            "notes/old/done.txt",
+        ]
+    );
+
+    let flat = format!("{config}flatten: true\n");
+    assert_eq!(
+        names(&flat, &files),
+        [
+            "config/hosts",
+            "config/nginx.conf",
+            "site/site.css",
+            "notes/todo.txt",
+            "notes/done.txt"
+        ]
+    );
+
+    let escaping = "paths: [{path: /etc, prefix: ../etc}]";
+    let config: Config = serde_yaml::from_str(escaping).unwrap();
+    assert!(EntryNaming::from_config(&config, None).is_err());
+}
@@ -0,0 +1,50 @@
+//! How the configuration reaches the packager: the format, compression and
+//! encryption settings `PackagerOptions::from_config` derives from `Config`.
+
+use ssbt_lib::Config;
+use ssbt_lib::packaging::compress::StreamCompression;
+use ssbt_lib::packaging::zip::ZipEncryptionMethod;
+use ssbt_lib::packaging::{ArchiveFormat, PackagerOptions};
+
+#[test]
+fn defaults_to_uncompressed_zip() {
+    let (format, options) = PackagerOptions::from_config(&Config::default()).unwrap();
+    assert_eq!(format, ArchiveFormat::Zip);
+    assert!(!options.compress);
+    assert!(options.stream_compression.is_none());
+    assert!(options.encryption.is_none());
+    assert!(options.stream_encryption.is_none());
+    assert!(options.manifest.is_some());
+}
+
+#[test]
+fn compression_and_encryption_follow_the_format() {
+    let config = Config {
+        format: Some("tar".to_string()),
+        compress: Some(true),
+        compression_algorithm: Some("zstd".to_string()),
+        ..Config::default()
+    };
+    let (format, options) = PackagerOptions::from_config(&config).unwrap();
+    assert_eq!(format, ArchiveFormat::Tar);
+    assert_eq!(options.stream_compression, Some(StreamCompression::Zstd));
+
+    let config = Config {
+        compress: Some(true),
+        zip_password: Some("secret".to_string()),
+        zip_encryption: Some("zipcrypto".to_string()),
+        ..Config::default()
+    };
+    let (_, options) = PackagerOptions::from_config(&config).unwrap();
+    assert!(options.compress);
+    let encryption = options.encryption.unwrap();
+    assert_eq!(encryption.method, ZipEncryptionMethod::ZipCrypto);
+    assert_eq!(encryption.password, "secret");
+
+    let config = Config {
+        format: Some("tar".to_string()),
+        zip_password: Some("secret".to_string()),
+        ..Config::default()
+    };
+    assert!(PackagerOptions::from_config(&config).is_err());
+}
@@ -0,0 +1,18 @@
+//! Opening repositories: backups create them, reading commands don't.
+
+use ssbt_lib::repo::Repository;
+
+#[test]
+fn only_backups_initialize_a_repository() {
+    let dir = tempfile::tempdir().unwrap();
+    let location = dir.path().join("repo");
+    let location = location.to_str().unwrap();
+
+    let error = Repository::open_existing(location).unwrap_err();
+    assert!(error.to_string().contains("not an ssbt repository"));
+    assert!(!dir.path().join("repo").exists());
+
+    Repository::open(location, None).unwrap();
+    assert!(dir.path().join("repo/config.json").is_file());
+    Repository::open_existing(location).unwrap();
+}
@@ -0,0 +1,67 @@
+//! Restoring some entries of an archive: files stored once with `dedup`
+//! come back even if only their duplicate is asked for.
+
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use glob::Pattern;
+use ssbt_lib::Config;
+use ssbt_lib::file_reader::SourceReader;
+use ssbt_lib::packaging::manifest::Manifest;
+use ssbt_lib::packaging::{
+    ArchiveFormat, Extraction, PackagerOptions, extract_entries, write_archive,
+};
+
+async fn write_deduplicated(format: ArchiveFormat, source: &Path, archive: &Path) {
+    let options = PackagerOptions {
+        dedup: true,
+        manifest: Some(Manifest::new(&Config::default())),
+        ..PackagerOptions::default()
+    };
+    let files = ["a-original.txt", "b-copy.txt"]
+        .map(|name| (name.to_string(), source.join(name)))
+        .to_vec();
+    let output = tokio::fs::File::create(archive).await.unwrap();
+    write_archive(
+        format,
+        files,
+        &options,
+        &Arc::new(SourceReader::default()),
+        Box::new(output),
+    )
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn restores_duplicates_without_their_original() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("source");
+    fs::create_dir(&source).unwrap();
+    for name in ["a-original.txt", "b-copy.txt"] {
+        fs::write(source.join(name), "same contents").unwrap();
+    }
+
+    for (format, name) in [
+        (ArchiveFormat::Zip, "backup.zip"),
+        (ArchiveFormat::Tar, "backup.tar"),
+    ] {
+        let archive = dir.path().join(name);
+        write_deduplicated(format, &source, &archive).await;
+        let target = dir.path().join(format!("restored-{name}"));
+        let extraction = Extraction::new(
+            target.clone(),
+            vec![Pattern::new("b-copy.txt").unwrap()],
+            true,
+        );
+
+        let restored = extract_entries(&archive, extraction, None).await.unwrap();
+        assert_eq!(restored, 1, "{name}");
+        assert_eq!(
+            fs::read_to_string(target.join("b-copy.txt")).unwrap(),
+            "same contents"
+        );
+        assert!(!target.join("a-original.txt").exists(), "{name}");
+    }
+}
@@ -0,0 +1,63 @@
+//! Sparse files in tar archives: only their data is stored, and restoring
+//! them brings the holes back.
+
+#![cfg(target_os = "linux")]
+
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use ssbt_lib::file_reader::SourceReader;
+use ssbt_lib::packaging::tar::read_tar_entries;
+use ssbt_lib::packaging::{
+    ArchiveFormat, Extraction, PackagerOptions, extract_entries, write_archive,
+};
+
+const MIB: u64 = 1024 * 1024;
+
+/// A 256 MiB file with a little data every 16 MiB, and a hole at its end.
+fn sparse_file(path: &PathBuf) {
+    let mut file = File::create(path).unwrap();
+    file.set_len(256 * MIB).unwrap();
+    for block in 0..15 {
+        file.seek(SeekFrom::Start(block * 16 * MIB)).unwrap();
+        writeln!(file, "block {block}").unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tar_stores_only_data() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("disk.img");
+    sparse_file(&source);
+    if fs::metadata(&source).unwrap().blocks() * 512 >= 256 * MIB {
+        // The file system doesn't do holes
+        return;
+    }
+    let archive = dir.path().join("disk.tar");
+
+    let output = tokio::fs::File::create(&archive).await.unwrap();
+    let files = vec![("disk.img".to_string(), source.clone())];
+    write_archive(
+        ArchiveFormat::Tar,
+        files,
+        &PackagerOptions::default(),
+        &Arc::new(SourceReader::default()),
+        Box::new(output),
+    )
+    .await
+    .unwrap();
+
+    assert!(fs::metadata(&archive).unwrap().len() < MIB);
+    let entries = read_tar_entries(&archive, None).unwrap();
+    assert_eq!(entries[0].size, 256 * MIB);
+
+    let target = dir.path().join("restored");
+    let extraction = Extraction::new(target.clone(), Vec::new(), true);
+    extract_entries(&archive, extraction, None).await.unwrap();
+    let restored = target.join("disk.img");
+    assert_eq!(fs::read(&restored).unwrap(), fs::read(&source).unwrap());
+    assert!(fs::metadata(&restored).unwrap().blocks() * 512 < 16 * MIB);
+}
@@ -0,0 +1,95 @@
+//! What a walk of `paths` leaves out on its own: the files the run writes.
+//! Lists given with `files_from` are read as they are, names need not be UTF-8.
+
+use std::fs;
+
+use ssbt_lib::Config;
+use ssbt_lib::fs_utils::list_total_files;
+
+#[test]
+fn leaves_out_its_own_archives() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("out");
+    fs::create_dir(&out).unwrap();
+    for name in [
+        "notes.txt",
+        "out/backup_2024-01-01_00-00-00_abcde.zip",
+        "out/backup_2024-01-02_00-00-00_fghij.zip.part",
+        "out/backup_2024-01-02_00-00-00_fghij.zip.journal",
+        "out/other.zip",
+        "catalog.json",
+    ] {
+        fs::write(dir.path().join(name), "data").unwrap();
+    }
+    let config = Config {
+        paths: Some(vec![dir.path().to_string_lossy().to_string().into()]),
+        output: Some(vec![out.to_string_lossy().to_string()]),
+        catalog: Some(
+            dir.path()
+                .join("catalog.json")
+                .to_string_lossy()
+                .to_string(),
+        ),
+        ..Config::default()
+    };
+
+    let files = list_total_files(&config).unwrap();
+    let names: Vec<_> = files
+        .iter()
+        .map(|file| file.strip_prefix(dir.path()).unwrap().to_string_lossy())
+        .collect();
+    assert_eq!(names, ["notes.txt", "out/other.zip"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn compares_resolved_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::create_dir_all(dir.path().join("data/out")).unwrap();
+    fs::write(dir.path().join("data/notes.txt"), "data").unwrap();
+    fs::write(dir.path().join("data/out/backup.zip"), "data").unwrap();
+    std::os::unix::fs::symlink(dir.path().join("data"), dir.path().join("link")).unwrap();
+    let link = dir.path().join("link");
+    let config = Config {
+        paths: Some(vec![link.to_string_lossy().to_string().into()]),
+        // Spelled through a directory that doesn't exist yet
+        output: Some(vec![
+            dir.path()
+                .join("data/missing/../out/backup.zip")
+                .to_string_lossy()
+                .to_string(),
+        ]),
+        ..Config::default()
+    };
+
+    // The emptied directory stays, as an empty one
+    let files = list_total_files(&config).unwrap();
+    assert_eq!(files, [link.join("notes.txt"), link.join("out")]);
+}
+
+#[cfg(unix)]
+#[test]
+fn reads_file_lists_with_non_utf8_names() {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let latin1 = dir.path().join(OsStr::from_bytes(b"caf\xe9.txt"));
+    let newline = dir.path().join("two\nlines.txt");
+    for file in [&latin1, &newline] {
+        fs::write(file, "data").unwrap();
+    }
+    let mut list = Vec::new();
+    for file in [&latin1, &newline] {
+        list.extend_from_slice(file.as_os_str().as_bytes());
+        list.push(0);
+    }
+    let list_file = dir.path().join("list");
+    fs::write(&list_file, list).unwrap();
+    let config = Config {
+        files_from: Some(list_file.to_string_lossy().to_string()),
+        ..Config::default()
+    };
+
+    assert_eq!(list_total_files(&config).unwrap(), [latin1, newline]);
+}
@@ -0,0 +1,152 @@
+//! Zip archives past the limits of the original zip format: more than 65535
+//! entries, entries of 4 GiB or more, and entries starting past 4 GiB.
+//!
+//! The tests over 4 GiB write large (if sparse) files and take minutes, so
+//! they are ignored by default:
+//!
+//! ```text
+//! cargo test --release -p ssbt-lib --test zip64 -- --ignored
+//! ```
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ssbt_lib::file_reader::SourceReader;
+use ssbt_lib::packaging::zip::read_zip_entries;
+use ssbt_lib::packaging::{
+    ArchiveEntry, ArchiveFormat, EntryVisitor, PackagerOptions, visit_entries, write_archive,
+};
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// Signature of the Zip64 end of central directory record.
+const ZIP64_END: &[u8] = b"PK\x06\x06";
+
+async fn write_zip(files: Vec<(String, PathBuf)>, compress: bool, archive: &Path) {
+    let options = PackagerOptions {
+        compress,
+        ..PackagerOptions::default()
+    };
+    let output = tokio::fs::File::create(archive).await.unwrap();
+    write_archive(
+        ArchiveFormat::Zip,
+        files,
+        &options,
+        &Arc::new(SourceReader::default()),
+        Box::new(output),
+    )
+    .await
+    .unwrap();
+}
+
+/// Whether the archive ends with Zip64 end records.
+fn has_zip64_end(archive: &Path) -> bool {
+    let mut file = File::open(archive).unwrap();
+    let len = file.metadata().unwrap().len();
+    file.seek(SeekFrom::Start(len.saturating_sub(1024)))
+        .unwrap();
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail).unwrap();
+    tail.windows(ZIP64_END.len())
+        .any(|window| window == ZIP64_END)
+}
+
+/// Counts the bytes of the entries it reads.
+#[derive(Default)]
+struct Sizes(Vec<(String, u64)>);
+
+impl EntryVisitor for Sizes {
+    fn visit(&mut self, entry: &ArchiveEntry, data: &mut dyn Read) -> anyhow::Result<()> {
+        let size = io::copy(data, &mut io::sink())?;
+        self.0.push((entry.name.clone(), size));
+        Ok(())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn more_than_65535_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("file.txt");
+    std::fs::write(&source, "contents\n").unwrap();
+    let files = (0..70_000)
+        .map(|number| (format!("dir/{number}.txt"), source.clone()))
+        .collect();
+    let archive = dir.path().join("many.zip");
+
+    write_zip(files, true, &archive).await;
+
+    assert!(has_zip64_end(&archive));
+    let entries = read_zip_entries(&archive).unwrap();
+    assert_eq!(entries.len(), 70_000);
+    assert_eq!(entries[69_999].name, "dir/69999.txt");
+    assert!(entries.iter().all(|entry| entry.size == 9));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn small_archives_stay_classic() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("file.txt");
+    std::fs::write(&source, "contents\n").unwrap();
+    let archive = dir.path().join("small.zip");
+
+    write_zip(vec![("file.txt".to_string(), source)], true, &archive).await;
+
+    assert!(!has_zip64_end(&archive));
+    let mut sizes = Sizes::default();
+    visit_entries(&archive, None, &mut sizes).unwrap();
+    assert_eq!(sizes.0, [("file.txt".to_string(), 9)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "reads a 4.5 GiB sparse file"]
+async fn entry_larger_than_4_gib() {
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("sparse.img");
+    File::create(&source)
+        .unwrap()
+        .set_len(4 * GIB + GIB / 2)
+        .unwrap();
+    let archive = dir.path().join("large.zip");
+
+    // Zeros deflate to almost nothing, so only the sizes need Zip64
+    write_zip(vec![("sparse.img".to_string(), source)], true, &archive).await;
+
+    let entries = read_zip_entries(&archive).unwrap();
+    assert_eq!(entries[0].size, 4 * GIB + GIB / 2);
+    let mut sizes = Sizes::default();
+    visit_entries(&archive, None, &mut sizes).unwrap();
+    assert_eq!(sizes.0, [("sparse.img".to_string(), 4 * GIB + GIB / 2)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "writes a 4.5 GiB archive"]
+async fn entries_past_4_gib() {
+    let dir = tempfile::tempdir().unwrap();
+    let sparse = dir.path().join("sparse.img");
+    File::create(&sparse)
+        .unwrap()
+        .set_len(4 * GIB + GIB / 2)
+        .unwrap();
+    let small = dir.path().join("after.txt");
+    std::fs::write(&small, "after\n").unwrap();
+    let archive = dir.path().join("large.zip");
+
+    // Stored, so the entry after the sparse file starts past 4 GiB
+    let files = vec![
+        ("sparse.img".to_string(), sparse),
+        ("after.txt".to_string(), small),
+    ];
+    write_zip(files, false, &archive).await;
+
+    assert!(std::fs::metadata(&archive).unwrap().len() > 4 * GIB);
+    assert!(has_zip64_end(&archive));
+    let mut zip = zip::ZipArchive::new(File::open(&archive).unwrap()).unwrap();
+    let mut after = String::new();
+    zip.by_name("after.txt")
+        .unwrap()
+        .read_to_string(&mut after)
+        .unwrap();
+    assert_eq!(after, "after\n");
+}
@@ -0,0 +1,134 @@
+//! Local record of every backup run, browsed with `ssbt list` and `ssbt show`.
+
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::Config;
+use crate::fs_utils::{catalog_path, write_atomic};
+use crate::notify::RunSummary;
+use crate::packaging::manifest::Manifest;
+use crate::process::BackupOutcome;
+
+/// One backup run.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub id: String,
+    pub time: DateTime<Utc>,
+    pub job: String,
+    pub success: bool,
+    pub error: Option<String>,
+    /// Outputs, or the repository, the backup went to.
+    pub destination: Option<String>,
+    pub files: usize,
+    /// Total size of the backed up files.
+    pub size: u64,
+    pub duration_secs: f64,
+    /// SHA-256 of the archive's `ssbt-manifest.json`.
+    pub manifest_sha256: Option<String>,
+    /// Manifest an incremental backup was based on.
+    pub incremental_base: Option<String>,
+    /// Archives the run stored where `ssbt prune` can find them again.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub archives: Vec<String>,
+}
+
+impl CatalogEntry {
+    pub fn new(summary: &RunSummary, outcome: Option<&BackupOutcome>) -> Result<Self> {
+        let manifest = outcome.and_then(|o| o.manifest.as_ref());
+        Ok(CatalogEntry {
+            id: format!(
+                "{}-{:08x}",
+                summary.started_at.format("%Y%m%d-%H%M%S"),
+                rand::random::<u32>()
+            ),
+            time: summary.started_at,
+            job: summary.job.clone(),
+            success: summary.success,
+            error: summary.error.clone(),
+            destination: summary.output.clone(),
+            files: summary.files,
+            size: summary.size,
+            duration_secs: summary.duration_secs,
+            manifest_sha256: manifest.map(Manifest::digest).transpose()?,
+            incremental_base: manifest
+                .and_then(|m| m.base.as_ref())
+                .map(|base| base.location.clone()),
+            archives: outcome.map(|o| o.archives.clone()).unwrap_or_default(),
+        })
+    }
+}
+
+/// The catalog file: `catalog` from the config, else
+/// `$XDG_DATA_HOME/ssbt/catalog.json` or `~/.local/share/ssbt/catalog.json`.
+#[derive(Debug, Clone)]
+pub struct Catalog {
+    path: PathBuf,
+}
+
+impl Catalog {
+    /// `None` if the catalog is turned off (`catalog: off`) or there is no
+    /// home directory to keep it in.
+    pub fn from_config(config: &Config) -> Option<Self> {
+        Some(Catalog {
+            path: catalog_path(config)?,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// All recorded runs, oldest first.
+    pub fn entries(&self) -> Result<Vec<CatalogEntry>> {
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", self.path.display())),
+        };
+        serde_json::from_slice(&content)
+            .with_context(|| format!("parsing catalog {}", self.path.display()))
+    }
+
+    /// How many runs of `job` are recorded.
+    pub fn runs_of(&self, job: &str) -> Result<usize> {
+        Ok(self.entries()?.iter().filter(|e| e.job == job).count())
+    }
+
+    pub fn append(&self, entry: CatalogEntry) -> Result<()> {
+        let mut entries = self.entries()?;
+        entries.push(entry);
+        self.save(&entries)
+    }
+
+    /// Replaces all recorded runs.
+    pub fn save(&self, entries: &[CatalogEntry]) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomic(&self.path, &serde_json::to_vec_pretty(entries)?)
+            .with_context(|| format!("writing {}", self.path.display()))
+    }
+
+    /// Finds a run by `latest`, its full id or a unique id prefix.
+    pub fn find(&self, selector: &str) -> Result<CatalogEntry> {
+        let mut entries = self.entries()?;
+        if selector == "latest" {
+            return entries.pop().context("the catalog is empty");
+        }
+
+        let mut matches: Vec<_> = entries
+            .into_iter()
+            .filter(|e| e.id.starts_with(selector))
+            .collect();
+        match matches.len() {
+            0 => bail!("no backup run matches {selector}"),
+            1 => Ok(matches.remove(0)),
+            n => bail!("{n} backup runs match {selector}, use a longer id"),
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use anyhow::{Result, bail};
+use clap::Args;
+use std::io::{self, Read, Write};
+
+use crate::Config;
+use crate::fetch::{decrypt_archive, fetch_archive};
+use crate::incremental::manifest_of;
+use crate::packaging::{ArchiveEntry, EntryVisitor, visit_entries};
+use crate::secrets::resolve_secret;
+
+#[derive(Args, Debug)]
+pub struct CatArgs {
+    /// Archive path, or file://, http(s):// or gs:// URL
+    pub archive: String,
+
+    /// Name of the entry, as `ssbt list` shows it
+    pub entry: String,
+
+    /// age identity file for encrypted archives (default: `encrypt.identities`)
+    #[arg(short, long)]
+    pub identity: Vec<String>,
+}
+
+/// Writes one entry of a local or remote archive to stdout, for getting a
+/// single file back without extracting the archive. Encrypted zip entries
+/// use `zip_password`.
+pub fn run(args: &CatArgs, config: &Config) -> Result<()> {
+    let password = config
+        .zip_password
+        .as_deref()
+        .filter(|p| !p.is_empty())
+        .map(resolve_secret)
+        .transpose()?;
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let archive = runtime.block_on(async {
+        let archive = fetch_archive(&args.archive, config).await?;
+        decrypt_archive(archive, &args.identity, config)
+    })?;
+
+    let name = args.entry.trim_start_matches("./");
+    let mut printer = Printer {
+        name: name.to_string(),
+        found: false,
+    };
+    visit_entries(archive.path(), password.as_deref(), &mut printer)?;
+    if printer.found {
+        return Ok(());
+    }
+    // With `dedup`, the contents of a duplicate are stored once, under the
+    // name the manifest gives
+    let original = manifest_of(archive.path(), password.as_deref())?.and_then(|manifest| {
+        manifest
+            .files
+            .into_iter()
+            .find(|file| file.name == name)
+            .and_then(|file| file.duplicate_of)
+    });
+    if let Some(original) = original {
+        printer.name = original;
+        visit_entries(archive.path(), password.as_deref(), &mut printer)?;
+    }
+    if !printer.found {
+        bail!("{} has no entry {name}", args.archive);
+    }
+    Ok(())
+}
+
+/// Copies the entry `name` to stdout.
+struct Printer {
+    name: String,
+    found: bool,
+}
+
+impl EntryVisitor for Printer {
+    fn wants(&self, name: &str) -> bool {
+        !self.found && name.trim_start_matches("./") == self.name
+    }
+
+    fn visit(&mut self, entry: &ArchiveEntry, data: &mut dyn Read) -> Result<()> {
+        if entry.is_dir {
+            bail!("{} is a directory", self.name);
+        }
+        self.found = true;
+        let mut stdout = io::stdout().lock();
+        match io::copy(data, &mut stdout).and_then(|_| stdout.flush()) {
+            // The reader (`| head`) has seen enough
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            result => Ok(result?),
+        }
+    }
+}
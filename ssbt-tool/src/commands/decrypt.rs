@@ -0,0 +1,123 @@
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::Config;
+use crate::age::{self, secrecy::ExposeSecret};
+use crate::fetch::fetch_archive;
+use crate::packaging::encrypt;
+
+#[derive(Args, Debug)]
+pub struct DecryptArgs {
+    /// Encrypted archive path or http(s) URL
+    pub archive: String,
+
+    /// Where to write the decrypted archive, `-` for stdout (default: the name without `.age`)
+    #[arg(short, long)]
+    pub output: Option<String>,
+
+    /// age identity file (can be repeated; default: `encrypt.identities`, then a passphrase prompt)
+    #[arg(short, long)]
+    pub identity: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct KeygenArgs {
+    /// Write the identity to this file instead of stdout
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+/// Decrypts a local or remote archive written with `encrypt`.
+pub fn run(args: &DecryptArgs, config: &Config) -> Result<()> {
+    let output = match &args.output {
+        Some(output) => output.clone(),
+        None => {
+            let name = args.archive.rsplit('/').next().unwrap_or_default();
+            match name.strip_suffix(".age") {
+                Some(stem) if !stem.is_empty() => stem.to_string(),
+                _ => bail!(
+                    "can't derive the output name from {}, pass --output",
+                    args.archive
+                ),
+            }
+        }
+    };
+    if output == "-" && io::stdout().is_terminal() {
+        bail!("refusing to write the archive to a terminal; redirect stdout or pass --output FILE");
+    }
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let archive = runtime.block_on(fetch_archive(&args.archive, config))?;
+    let identities = encrypt::identities(&args.identity, config)?;
+    let mut reader =
+        encrypt::decrypt_reader(&identities, BufReader::new(File::open(archive.path())?))?;
+
+    if output == "-" {
+        io::copy(&mut reader, &mut io::stdout().lock()).context("decrypting archive")?;
+        return Ok(());
+    }
+
+    // Only a fully decrypted and authenticated archive gets the final name
+    let path = Path::new(&output);
+    let part = PathBuf::from(format!("{output}.part"));
+    let result = File::create(&part)
+        .and_then(|mut file| io::copy(&mut reader, &mut file).and(file.sync_all()))
+        .with_context(|| format!("decrypting into {}", part.display()))
+        .and_then(|_| fs::rename(&part, path).context("renaming decrypted archive"));
+    if result.is_err() {
+        let _ = fs::remove_file(&part);
+    }
+    result?;
+    eprintln!("Decrypted {} to {}", args.archive, path.display());
+    Ok(())
+}
+
+/// Prints a new identity like `age-keygen`, with its public key for `--recipient`.
+pub fn keygen(args: &KeygenArgs) -> Result<()> {
+    let identity = age::x25519::Identity::generate();
+    let recipient = identity.to_public();
+    let content = format!(
+        "# created: {}\n# public key: {recipient}\n{}\n",
+        chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        identity.to_string().expose_secret(),
+    );
+
+    match &args.output {
+        Some(path) => {
+            if path.exists() {
+                bail!("{} already exists", path.display());
+            }
+            write_private(path, &content).with_context(|| format!("writing {}", path.display()))?;
+            eprintln!("Public key: {recipient}");
+        }
+        None => io::stdout().write_all(content.as_bytes())?,
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, content: &str) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(content.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, content: &str) -> io::Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?
+        .write_all(content.as_bytes())
+}
@@ -0,0 +1,198 @@
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use glob::Pattern;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::Config;
+use crate::fetch::{decrypt_archive, fetch_archive};
+use crate::fs_utils::encode_size;
+use crate::incremental::manifest_of;
+use crate::packaging::manifest::is_manifest;
+use crate::packaging::read_entries;
+use crate::secrets::resolve_secret;
+
+#[derive(Args, Debug)]
+pub struct DiffArgs {
+    /// The older archive or manifest: a path, or file://, http(s):// or gs:// URL
+    pub old: String,
+
+    /// The newer archive or manifest
+    pub new: String,
+
+    /// Only compare files matching these glob patterns
+    pub patterns: Vec<String>,
+
+    /// Output format [table|json]
+    #[arg(long, default_value = "table")]
+    pub format: String,
+
+    /// age identity file for encrypted archives (default: `encrypt.identities`)
+    #[arg(short, long)]
+    pub identity: Vec<String>,
+}
+
+/// A file as far as the comparison goes.
+struct FileState {
+    size: u64,
+    /// SHA-256 of the contents, known from a manifest.
+    sha256: Option<String>,
+    modified: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+#[derive(Debug, Serialize)]
+struct Difference {
+    name: String,
+    change: Change,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_size: Option<u64>,
+    size_delta: i64,
+}
+
+/// Prints the files added, removed and changed between two backups. Files
+/// are compared by the SHA-256 of their manifests; archives without a
+/// manifest are compared by the size and modification time of their
+/// entries. The files of an incremental archive include the ones its
+/// manifest records as unchanged since its base.
+pub fn run(args: &DiffArgs, config: &Config) -> Result<()> {
+    let patterns = args
+        .patterns
+        .iter()
+        .map(|p| Pattern::new(p))
+        .collect::<Result<Vec<_>, _>>()?;
+    let password = config
+        .zip_password
+        .as_deref()
+        .filter(|p| !p.is_empty())
+        .map(resolve_secret)
+        .transpose()?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let [old, new] = [&args.old, &args.new].map(|location| {
+        runtime
+            .block_on(files_of(location, args, config, password.as_deref()))
+            .with_context(|| format!("reading {location}"))
+    });
+    let (old, new) = (old?, new?);
+
+    let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+    let differences: Vec<Difference> = names
+        .into_iter()
+        .filter(|name| patterns.is_empty() || patterns.iter().any(|p| p.matches(name)))
+        .filter_map(|name| {
+            let (old, new) = (old.get(name), new.get(name));
+            let change = match (old, new) {
+                (None, Some(_)) => Change::Added,
+                (Some(_), None) => Change::Removed,
+                (Some(old), Some(new)) if differs(old, new) => Change::Changed,
+                _ => return None,
+            };
+            let (old_size, new_size) = (old.map(|f| f.size), new.map(|f| f.size));
+            Some(Difference {
+                name: name.clone(),
+                change,
+                old_size,
+                new_size,
+                size_delta: new_size.unwrap_or(0) as i64 - old_size.unwrap_or(0) as i64,
+            })
+        })
+        .collect();
+
+    match args.format.as_str() {
+        "json" => println!("{}", serde_json::to_string_pretty(&differences)?),
+        "table" => {
+            let mut counts = [0; 3];
+            for difference in &differences {
+                let (sign, size) = match difference.change {
+                    Change::Added => ('+', difference.new_size),
+                    Change::Removed => ('-', difference.old_size),
+                    Change::Changed => ('~', difference.new_size),
+                };
+                counts[difference.change as usize] += 1;
+                println!(
+                    "{sign}  {:>10}  {:>11}  {}",
+                    encode_size(size.unwrap_or(0)),
+                    signed_size(difference.size_delta),
+                    difference.name
+                );
+            }
+            let delta: i64 = differences.iter().map(|d| d.size_delta).sum();
+            println!(
+                "{} added, {} removed, {} changed, {}",
+                counts[0],
+                counts[1],
+                counts[2],
+                signed_size(delta)
+            );
+        }
+        other => bail!("unsupported diff format: {other} (expected table or json)"),
+    }
+    Ok(())
+}
+
+/// The files a backup holds, by entry name.
+async fn files_of(
+    location: &str,
+    args: &DiffArgs,
+    config: &Config,
+    password: Option<&str>,
+) -> Result<BTreeMap<String, FileState>> {
+    let archive = fetch_archive(location, config).await?;
+    let archive = decrypt_archive(archive, &args.identity, config)?;
+    if let Some(manifest) = manifest_of(archive.path(), password)? {
+        return Ok(manifest
+            .files
+            .into_iter()
+            .chain(manifest.unchanged)
+            .map(|file| {
+                let state = FileState {
+                    size: file.size,
+                    sha256: Some(file.sha256),
+                    modified: file.modified,
+                };
+                (file.name, state)
+            })
+            .collect());
+    }
+    Ok(read_entries(archive.path())
+        .await?
+        .into_iter()
+        .filter(|entry| !entry.is_dir && !is_manifest(&entry.name))
+        .map(|entry| {
+            let state = FileState {
+                size: entry.size,
+                sha256: None,
+                modified: entry.modified,
+            };
+            (entry.name.trim_start_matches("./").to_string(), state)
+        })
+        .collect())
+}
+
+fn differs(old: &FileState, new: &FileState) -> bool {
+    match (&old.sha256, &new.sha256) {
+        (Some(old), Some(new)) => old != new,
+        _ => old.size != new.size || old.modified != new.modified,
+    }
+}
+
+/// `+1.2 MiB`, `-300 B` or `0 B`.
+fn signed_size(delta: i64) -> String {
+    match delta {
+        0 => encode_size(0),
+        1.. => format!("+{}", encode_size(delta as u64)),
+        _ => format!("-{}", encode_size(delta.unsigned_abs())),
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use glob::Pattern;
+
+use crate::Config;
+use crate::catalog::Catalog;
+use crate::fetch::{RangeReader, decrypt_archive, fetch_archive};
+use crate::fs_utils::encode_size;
+use crate::packaging::{read_entries, read_zip_entries};
+
+#[derive(Args, Debug)]
+pub struct ListArgs {
+    /// Archive path, or file://, http(s):// or gs:// URL (default: list past backup runs)
+    pub archive: Option<String>,
+
+    /// Only show entries matching these glob patterns
+    pub patterns: Vec<String>,
+
+    /// Output format [table|json]
+    #[arg(long, default_value = "table")]
+    pub format: String,
+
+    /// age identity file for encrypted archives (default: `encrypt.identities`)
+    #[arg(short, long)]
+    pub identity: Vec<String>,
+}
+
+/// Prints the entries of a local or remote zip/tar archive, or without one,
+/// the backup runs recorded in the catalog. Of a zip archive on an HTTP
+/// server that supports Range requests, only the central directory is
+/// downloaded.
+pub fn run(args: &ListArgs, config: &Config) -> Result<()> {
+    let Some(archive) = &args.archive else {
+        return list_runs(args, config);
+    };
+    let patterns = args
+        .patterns
+        .iter()
+        .map(|p| Pattern::new(p))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let entries = runtime.block_on(async {
+        if let Some(reader) = RangeReader::open_zip(archive, config).await? {
+            return read_zip_entries(reader).await;
+        }
+        let archive = fetch_archive(archive, config).await?;
+        let archive = decrypt_archive(archive, &args.identity, config)?;
+        read_entries(archive.path()).await
+    })?;
+
+    let entries: Vec<_> = entries
+        .into_iter()
+        .filter(|e| patterns.is_empty() || patterns.iter().any(|p| p.matches(&e.name)))
+        .collect();
+
+    match args.format.as_str() {
+        "json" => println!("{}", serde_json::to_string_pretty(&entries)?),
+        "table" => {
+            for entry in &entries {
+                let modified = entry
+                    .modified
+                    .map(|m| m.format("%Y-%m-%d %H:%M:%S").to_string())
+                    .unwrap_or_else(|| "-".to_string());
+                let compressed = entry
+                    .compressed_size
+                    .map(encode_size)
+                    .unwrap_or_else(|| "-".to_string());
+                println!(
+                    "{:>10}  {:>10}  {}  {}{}",
+                    encode_size(entry.size),
+                    compressed,
+                    modified,
+                    entry.name,
+                    if entry.encrypted { "  [encrypted]" } else { "" }
+                );
+            }
+            let total: u64 = entries.iter().map(|e| e.size).sum();
+            println!("{} entries, {}", entries.len(), encode_size(total));
+        }
+        other => bail!("unsupported list format: {other} (expected table or json)"),
+    }
+
+    Ok(())
+}
+
+fn list_runs(args: &ListArgs, config: &Config) -> Result<()> {
+    let catalog = Catalog::from_config(config).context("the catalog is turned off")?;
+    let patterns = args
+        .patterns
+        .iter()
+        .map(|p| Pattern::new(p))
+        .collect::<Result<Vec<_>, _>>()?;
+    let runs: Vec<_> = catalog
+        .entries()?
+        .into_iter()
+        .filter(|run| patterns.is_empty() || patterns.iter().any(|p| p.matches(&run.job)))
+        .collect();
+
+    match args.format.as_str() {
+        "json" => println!("{}", serde_json::to_string_pretty(&runs)?),
+        "table" => {
+            for run in &runs {
+                println!(
+                    "{}  {}  {:<7}  {:>6} files  {:>10}  {}",
+                    run.id,
+                    run.time.format("%Y-%m-%d %H:%M:%S"),
+                    if run.success { "ok" } else { "FAILED" },
+                    run.files,
+                    encode_size(run.size),
+                    run.destination.as_deref().unwrap_or("-")
+                );
+            }
+            println!("{} backup runs", runs.len());
+        }
+        other => bail!("unsupported list format: {other} (expected table or json)"),
+    }
+    Ok(())
+}
@@ -0,0 +1,12 @@
+pub mod cat;
+pub mod decrypt;
+pub mod diff;
+pub mod list;
+pub mod prune;
+pub mod repo;
+pub mod restore;
+pub mod resume;
+pub mod serve;
+pub mod show;
+pub mod verify;
+pub mod watch;
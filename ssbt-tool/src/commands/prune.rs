@@ -0,0 +1,333 @@
+use anyhow::{Context, Result, bail};
+use chrono::{DateTime, Datelike, Local, Utc};
+use clap::Args;
+use glob::{MatchOptions, Pattern};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::Config;
+use crate::catalog::Catalog;
+use crate::naming::{name_pattern, output_dir, volume_of};
+use crate::sink::checksum::ChecksumAlgorithm;
+use crate::sink::gcs::{self, GcsUpload};
+use crate::sink::save_file::is_local_output;
+use crate::sink::send_net::http_client;
+use crate::sink::webdav::{self, WebDavUpload, is_webdav_url, to_http_url, to_webdav_url};
+use tracing::warn;
+
+#[derive(Args, Debug)]
+pub struct PruneArgs {
+    /// Only print which archives would be deleted
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Which archives survive: the newest `last` ones, plus the newest archive of
+/// each of the last `daily` days, `weekly` ISO weeks and `monthly` months.
+/// Periods without an archive don't count.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetentionPolicy {
+    last: u32,
+    daily: u32,
+    weekly: u32,
+    monthly: u32,
+}
+
+impl RetentionPolicy {
+    pub(crate) fn from_config(config: &Config) -> Result<Self> {
+        let policy = [
+            config.keep_last,
+            config.keep_daily,
+            config.keep_weekly,
+            config.keep_monthly,
+        ];
+        if policy.iter().all(Option::is_none) {
+            bail!(
+                "no retention policy, set keep_last, keep_daily, keep_weekly or keep_monthly (--keep-last, ...)"
+            );
+        }
+        let [last, daily, weekly, monthly] = policy.map(|keep| keep.unwrap_or(0));
+        Ok(RetentionPolicy {
+            last,
+            daily,
+            weekly,
+            monthly,
+        })
+    }
+
+    /// For archive times sorted newest first, whether each one is kept.
+    pub(crate) fn keep(&self, times: &[DateTime<Utc>]) -> Vec<bool> {
+        let mut keep: Vec<bool> = (0..times.len()).map(|i| i < self.last as usize).collect();
+        let periods: [(u32, PeriodOf); 3] = [
+            (self.daily, |t| (t.year(), t.ordinal())),
+            (self.weekly, |t| (t.iso_week().year(), t.iso_week().week())),
+            (self.monthly, |t| (t.year(), t.month())),
+        ];
+        for (count, period) in periods {
+            let mut kept = 0;
+            let mut last_period = None;
+            for (i, time) in times.iter().enumerate() {
+                if kept == count {
+                    break;
+                }
+                let current = Some(period(time.with_timezone(&Local)));
+                if current != last_period {
+                    keep[i] = true;
+                    kept += 1;
+                    last_period = current;
+                }
+            }
+        }
+        keep
+    }
+}
+
+/// Identifies the day, week or month a local time falls into.
+type PeriodOf = fn(DateTime<Local>) -> (i32, u32);
+
+/// An archive found in an output: a single file, or the volumes of a split
+/// archive (`<archive>.001`, ...), listed under the archive's name.
+#[derive(Debug)]
+struct Archive {
+    time: DateTime<Utc>,
+    files: BTreeSet<String>,
+}
+
+/// Where an output's archives are stored, and how to delete them.
+enum Store {
+    Local,
+    WebDav(WebDavUpload),
+    Gcs(GcsUpload),
+}
+
+/// Applies the retention policy to the archives of every configured output.
+///
+/// Archives are the ones the catalog recorded for an output's naming
+/// template; for local outputs, files in the output directory matching the
+/// template are included too, dated by their modification time.
+pub fn run(args: &PruneArgs, config: &Config) -> Result<()> {
+    let policy = RetentionPolicy::from_config(config)?;
+    let outputs = config.output.clone().unwrap_or_default();
+    if outputs.iter().all(|o| o.is_empty()) {
+        bail!("prune needs the outputs to clean up (--output, config:output or SSBT_OUTPUT)");
+    }
+    let dry_run = args.dry_run || config.dry == Some(true);
+
+    let catalog = Catalog::from_config(config);
+    let mut entries = match &catalog {
+        Some(catalog) => catalog.entries()?,
+        None => Vec::new(),
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let client = http_client(config)?;
+    // Deleted files, for the catalog update
+    let mut deleted = HashSet::new();
+    let mut removed = 0;
+    let mut failures = 0;
+    let mut total = 0;
+
+    for output in outputs.iter().filter(|o| !o.is_empty()) {
+        let Some((store, pattern)) = store(output, config)? else {
+            warn!("skipping {output}, only local, WebDAV and gs:// outputs can be pruned");
+            continue;
+        };
+
+        let mut archives = BTreeMap::new();
+        for entry in entries.iter().filter(|e| e.success) {
+            for location in &entry.archives {
+                let exists = !matches!(store, Store::Local) || Path::new(location).is_file();
+                if exists {
+                    add_archive(&mut archives, &pattern, location, entry.time);
+                }
+            }
+        }
+        if matches!(store, Store::Local) {
+            scan_directory(output, &pattern, &mut archives)?;
+        }
+
+        let mut archives: Vec<_> = archives.into_iter().collect();
+        archives.sort_by_key(|(_, archive)| std::cmp::Reverse(archive.time));
+        let times: Vec<_> = archives.iter().map(|(_, archive)| archive.time).collect();
+        total += archives.len();
+
+        println!("{output}: {} archives", archives.len());
+        for ((location, archive), keep) in archives.into_iter().zip(policy.keep(&times)) {
+            let time = archive
+                .time
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S");
+            let volumes = match archive.files.len() {
+                1 if archive.files.contains(&location) => String::new(),
+                n => format!(" ({n} volumes)"),
+            };
+            let shown = format!("{location}{volumes}");
+            if keep {
+                println!("  keep    {time}  {shown}");
+                continue;
+            }
+            if dry_run {
+                println!("  delete  {time}  {shown} (dry run)");
+                continue;
+            }
+            match runtime.block_on(delete(&store, &location, &archive, &client)) {
+                Ok(()) => {
+                    println!("  delete  {time}  {shown}");
+                    deleted.extend(archive.files);
+                    removed += 1;
+                }
+                Err(e) => {
+                    eprintln!("  FAILED  {time}  {shown}: {e:#}");
+                    failures += 1;
+                }
+            }
+        }
+    }
+
+    if let Some(catalog) = &catalog
+        && !deleted.is_empty()
+    {
+        for entry in &mut entries {
+            entry
+                .archives
+                .retain(|location| !deleted.contains(location));
+        }
+        catalog.save(&entries)?;
+    }
+
+    if dry_run {
+        println!("Dry run, nothing deleted");
+    } else {
+        println!("Deleted {removed} of {total} archives");
+    }
+    if failures > 0 {
+        bail!("{failures} archive(s) could not be deleted");
+    }
+    Ok(())
+}
+
+/// Resolves an output to its store and the pattern its archive locations
+/// match, or `None` for outputs that can't be pruned.
+fn store(output: &str, config: &Config) -> Result<Option<(Store, Pattern)>> {
+    if is_webdav_url(output) {
+        let template = to_webdav_url(&to_http_url(output)?);
+        let upload = WebDavUpload::from_config(output, config)?;
+        return Ok(Some((
+            Store::WebDav(upload),
+            name_pattern(&template, config)?,
+        )));
+    }
+    if output.starts_with("gs://") {
+        let upload = GcsUpload::from_config(output, config)?;
+        return Ok(Some((Store::Gcs(upload), name_pattern(output, config)?)));
+    }
+    if !is_local_output(output, config) {
+        return Ok(None);
+    }
+    let output = std::path::absolute(output)?;
+    Ok(Some((
+        Store::Local,
+        name_pattern(&output.to_string_lossy(), config)?,
+    )))
+}
+
+/// Adds `location` if it, or the archive it is a volume of, matches `pattern`.
+fn add_archive(
+    archives: &mut BTreeMap<String, Archive>,
+    pattern: &Pattern,
+    location: &str,
+    time: DateTime<Utc>,
+) {
+    let name = volume_of(location)
+        .filter(|archive| matches(pattern, archive))
+        .unwrap_or(location);
+    if matches(pattern, name) {
+        archives
+            .entry(name.to_string())
+            .or_insert(Archive {
+                time,
+                files: BTreeSet::new(),
+            })
+            .files
+            .insert(location.to_string());
+    }
+}
+
+/// Adds the files in a local output's directory that match its naming template.
+fn scan_directory(
+    output: &str,
+    pattern: &Pattern,
+    archives: &mut BTreeMap<String, Archive>,
+) -> Result<()> {
+    let dir = output_dir(&std::path::absolute(output)?.to_string_lossy())?;
+    let listing = match fs::read_dir(&dir) {
+        Ok(listing) => listing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+    };
+    for entry in listing {
+        let entry = entry?;
+        let location = entry.path().display().to_string();
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() {
+            continue;
+        }
+        if let Ok(modified) = metadata.modified() {
+            add_archive(archives, pattern, &location, modified.into());
+        }
+    }
+    Ok(())
+}
+
+fn matches(pattern: &Pattern, location: &str) -> bool {
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+    pattern.matches_with(location, options)
+}
+
+/// Deletes an archive, or all of its volumes, together with its checksum sidecars.
+async fn delete(
+    store: &Store,
+    location: &str,
+    archive: &Archive,
+    client: &reqwest::Client,
+) -> Result<()> {
+    for file in &archive.files {
+        delete_file(store, file, client).await?;
+    }
+    for algorithm in ChecksumAlgorithm::ALL {
+        let sidecar = format!("{location}.{}", algorithm.extension());
+        delete_file(store, &sidecar, client).await?;
+    }
+    Ok(())
+}
+
+/// Deletes one file; one that is already gone counts as deleted.
+async fn delete_file(store: &Store, location: &str, client: &reqwest::Client) -> Result<()> {
+    match store {
+        Store::Local => match fs::remove_file(location) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        },
+        Store::WebDav(upload) => {
+            let mut upload = upload.clone();
+            upload.http.url = to_http_url(location)?;
+            webdav::delete(&upload).await
+        }
+        Store::Gcs(upload) => {
+            let object = location
+                .strip_prefix(&format!("gs://{}/", upload.bucket))
+                .with_context(|| format!("{location} is not in bucket {}", upload.bucket))?;
+            let upload = GcsUpload {
+                object: object.to_string(),
+                ..upload.clone()
+            };
+            gcs::delete(client, &upload).await
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use anyhow::{Context, Result};
+use clap::{Args, Subcommand};
+use glob::Pattern;
+use std::path::PathBuf;
+
+use crate::Config;
+use crate::fs_utils::encode_size;
+use crate::repo::Repository;
+
+#[derive(Args, Debug)]
+pub struct RepoArgs {
+    #[command(subcommand)]
+    pub command: RepoCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RepoCommand {
+    /// List the snapshots stored in the repository
+    Snapshots,
+    /// Restore files from a snapshot
+    Restore {
+        /// Snapshot id (or unique prefix), or `latest`
+        snapshot: String,
+
+        /// Directory to restore into
+        target: PathBuf,
+
+        /// Only restore files matching these glob patterns
+        patterns: Vec<String>,
+    },
+}
+
+/// Runs a repository subcommand against the repository configured by `--repo`.
+pub fn run(args: &RepoArgs, config: &Config) -> Result<()> {
+    let location = config
+        .repo
+        .as_deref()
+        .context("repository path is required (--repo or config:repo or SSBT_REPO)")?;
+    let repo = Repository::open_existing(location)?;
+
+    match &args.command {
+        RepoCommand::Snapshots => {
+            let snapshots = repo.snapshots()?;
+            for snapshot in &snapshots {
+                println!(
+                    "{}  {}  {:>6} files  {:>10}  {}",
+                    snapshot.id,
+                    snapshot.time.format("%Y-%m-%d %H:%M:%S"),
+                    snapshot.files.len(),
+                    encode_size(snapshot.total_size()),
+                    snapshot.paths.join(", ")
+                );
+            }
+            println!("{} snapshots", snapshots.len());
+        }
+        RepoCommand::Restore {
+            snapshot,
+            target,
+            patterns,
+        } => {
+            let patterns = patterns
+                .iter()
+                .map(|p| Pattern::new(p))
+                .collect::<Result<Vec<_>, _>>()?;
+            let snapshot = repo.find_snapshot(snapshot)?;
+            let restored = repo.restore(&snapshot, target, &patterns)?;
+            println!(
+                "Restored {} files from snapshot {} into {}",
+                restored,
+                snapshot.id,
+                target.display()
+            );
+        }
+    }
+    Ok(())
+}
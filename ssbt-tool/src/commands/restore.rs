@@ -0,0 +1,72 @@
+use anyhow::Result;
+use clap::Args;
+use glob::Pattern;
+use std::path::PathBuf;
+
+use crate::Config;
+use crate::fetch::{RangeReader, decrypt_archive, fetch_archive};
+use crate::packaging::{Extraction, extract_entries, extract_zip_entries};
+use crate::secrets::resolve_secret;
+
+#[derive(Args, Debug)]
+pub struct RestoreArgs {
+    /// Archive path, or file://, http(s):// or gs:// URL
+    pub archive: String,
+
+    /// Directory to extract into
+    #[arg(long, default_value = ".")]
+    pub to: PathBuf,
+
+    /// Only restore entries matching this glob pattern (can be repeated)
+    #[arg(long, visible_alias = "only", value_name = "PATTERN")]
+    pub include: Vec<String>,
+
+    /// age identity file for encrypted archives (default: `encrypt.identities`)
+    #[arg(short, long)]
+    pub identity: Vec<String>,
+}
+
+/// Downloads (if remote), decrypts and extracts a backup archive.
+/// Encrypted zip entries use `zip_password`. Restoring some entries of a zip
+/// archive on an HTTP server that supports Range requests downloads only
+/// those entries and the central directory.
+pub fn run(args: &RestoreArgs, config: &Config) -> Result<()> {
+    let patterns = args
+        .include
+        .iter()
+        .map(|p| Pattern::new(p))
+        .collect::<Result<Vec<_>, _>>()?;
+    let password = config
+        .zip_password
+        .as_deref()
+        .filter(|p| !p.is_empty())
+        .map(resolve_secret)
+        .transpose()?;
+    let extraction = Extraction::new(
+        args.to.clone(),
+        patterns,
+        config.preserve_permissions.unwrap_or(true),
+    );
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    let restored = runtime.block_on(async {
+        if !args.include.is_empty()
+            && let Some(reader) = RangeReader::open_zip(&args.archive, config).await?
+        {
+            return extract_zip_entries(reader, extraction, password).await;
+        }
+        let archive = fetch_archive(&args.archive, config).await?;
+        let archive = decrypt_archive(archive, &args.identity, config)?;
+        extract_entries(archive.path(), extraction, password).await
+    })?;
+
+    println!(
+        "Restored {} files from {} into {}",
+        restored,
+        args.archive,
+        args.to.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,249 @@
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::Config;
+use crate::journal::{self, JournalHeader};
+use crate::packaging::ArchiveFormat;
+use crate::packaging::compress::StreamCompression;
+use crate::packaging::encrypt;
+use crate::packaging::manifest::{MANIFEST_NAME, Manifest};
+use crate::packaging::tar::{complete_entries, finish_interrupted};
+use crate::run_lock;
+use crate::sink::save_file::is_local_output;
+
+#[derive(Args, Debug)]
+pub struct ResumeArgs {
+    /// The interrupted archive or its `.journal` file (default: every journal
+    /// next to the configured local outputs)
+    pub archive: Option<String>,
+
+    /// Delete archives that can't be resumed and run their backup again
+    #[arg(long)]
+    pub restart: bool,
+}
+
+/// Picks up backups that were killed while writing a local archive, found
+/// through the journal they left behind. An uncompressed, unencrypted tar
+/// archive keeps the files it completely holds: it is cut after them and
+/// finished, and an incremental backup next to it stores the rest. Other
+/// archives fail, unless `--restart` deletes them and runs their backup again.
+pub fn run(args: &ResumeArgs, config: &Config) -> Result<()> {
+    let journals = match &args.archive {
+        Some(archive) if archive.ends_with(&format!(".{}", journal::EXTENSION)) => {
+            vec![PathBuf::from(archive)]
+        }
+        Some(archive) => {
+            let path = journal::journal_path(Path::new(archive));
+            if !path.is_file() {
+                bail!("{archive} has no journal, there's nothing to resume");
+            }
+            vec![path]
+        }
+        None => find_journals(config)?,
+    };
+    if journals.is_empty() {
+        info!("No interrupted backups found, nothing to resume");
+        return Ok(());
+    }
+
+    crate::cancel::install()?;
+    for path in journals {
+        resume(&path, args.restart).with_context(|| format!("resuming from {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Journals in the directories of the local outputs.
+fn find_journals(config: &Config) -> Result<Vec<PathBuf>> {
+    let mut dirs = HashSet::new();
+    for output in config.output.iter().flatten() {
+        if !is_local_output(output, config) {
+            continue;
+        }
+        let dir = Path::new(output)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        dirs.insert(dir.to_path_buf());
+    }
+
+    let mut journals = Vec::new();
+    for dir in dirs {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|ext| ext == journal::EXTENSION)
+            {
+                journals.push(path);
+            }
+        }
+    }
+    journals.sort();
+    Ok(journals)
+}
+
+fn resume(path: &Path, restart: bool) -> Result<()> {
+    let (header, entries) = journal::read(path)?;
+    // A journal is also left by a run still in progress, which holds the lock
+    let mut locked = header.config.clone();
+    locked.wait_for_lock = None;
+    let lock = run_lock::acquire(&locked)?;
+
+    let pieces = pieces(&header);
+    if pieces.is_empty() && header.archive.is_file() {
+        info!("{} was completed", header.archive.display());
+        fs::remove_file(path)?;
+        return Ok(());
+    }
+
+    let salvageable = is_salvageable(&header.config)?;
+    if !salvageable && !pieces.is_empty() && !restart {
+        bail!(
+            "{} can't be resumed, only uncompressed, unencrypted tar archives can \
+             (--restart deletes it and runs its backup again)",
+            header.archive.display()
+        );
+    }
+    if salvageable && !pieces.is_empty() {
+        let hashes: HashMap<String, String> = entries
+            .iter()
+            .map(|entry| (entry.name.clone(), entry.sha256.clone()))
+            .collect();
+        let (names, end) = complete_entries(&pieces, &hashes)?;
+        if names.iter().any(|name| name == MANIFEST_NAME) {
+            // Only the renaming of the last file was missed
+            for piece in &pieces {
+                rename_part(piece)?;
+            }
+            info!("{} was completed", header.archive.display());
+            fs::remove_file(path)?;
+            return Ok(());
+        }
+        if !names.is_empty() {
+            let names: HashSet<&String> = names.iter().collect();
+            let mut manifest: Manifest = header.manifest.clone();
+            manifest.files = entries
+                .into_iter()
+                .filter(|entry| names.contains(&entry.name))
+                .collect();
+            let preserve_permissions = header.config.preserve_permissions.unwrap_or(true);
+            let last = finish_interrupted(&pieces, end, &manifest, preserve_permissions)?;
+            for piece in &pieces[..=last] {
+                rename_part(piece)?;
+            }
+            info!(
+                "Kept {} file(s) in {}",
+                manifest.files.len(),
+                header.archive.display()
+            );
+            fs::remove_file(path)?;
+            drop(lock);
+            return backup_rest(&header);
+        }
+    }
+
+    for piece in &pieces {
+        fs::remove_file(piece).with_context(|| format!("removing {}", piece.display()))?;
+    }
+    match salvageable {
+        true => warn!(
+            "{} holds no complete file, running its backup again",
+            header.archive.display()
+        ),
+        false if pieces.is_empty() => warn!(
+            "{} left nothing behind, running its backup again",
+            header.archive.display()
+        ),
+        false => warn!(
+            "{} can't be resumed, deleting it and running its backup again",
+            header.archive.display()
+        ),
+    }
+    fs::remove_file(path)?;
+    drop(lock);
+    crate::backup(header.config).map(drop)
+}
+
+/// The files of the interrupted archive in order: its `.part` file, or the
+/// volumes stored so far and the one being written.
+fn pieces(header: &JournalHeader) -> Vec<PathBuf> {
+    let with_suffix = |path: &Path, suffix: &str| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    if !header.split {
+        let part = with_suffix(&header.archive, ".part");
+        return if part.is_file() { vec![part] } else { vec![] };
+    }
+    let mut pieces = Vec::new();
+    for number in 1.. {
+        let volume = with_suffix(&header.archive, &format!(".{number:03}"));
+        let part = with_suffix(&volume, ".part");
+        if volume.is_file() {
+            pieces.push(volume);
+        } else {
+            if part.is_file() {
+                pieces.push(part);
+            }
+            break;
+        }
+    }
+    pieces
+}
+
+/// Only plain tar archives can be cut after a file and extended.
+fn is_salvageable(config: &Config) -> Result<bool> {
+    let format: ArchiveFormat = config.format.as_deref().unwrap_or("zip").parse()?;
+    Ok(format == ArchiveFormat::Tar
+        && StreamCompression::from_config(config, format)?.is_none()
+        && !encrypt::is_configured(config))
+}
+
+fn rename_part(piece: &Path) -> Result<()> {
+    if let Some(done) = piece.to_str().and_then(|p| p.strip_suffix(".part")) {
+        fs::rename(piece, done).with_context(|| format!("renaming {}", piece.display()))?;
+    }
+    Ok(())
+}
+
+/// Backs up what the salvaged archive is missing, as an incremental archive
+/// named `<name>.resumed.<ext>` next to it.
+fn backup_rest(header: &JournalHeader) -> Result<()> {
+    let archive = &header.archive;
+    let name = match (archive.file_stem(), archive.extension()) {
+        (Some(stem), Some(ext)) => format!(
+            "{}.resumed.{}",
+            stem.to_string_lossy(),
+            ext.to_string_lossy()
+        ),
+        _ => format!("{}.resumed", archive.to_string_lossy()),
+    };
+    let resumed = archive.with_file_name(name);
+
+    let mut config = header.config.clone();
+    // The journal was kept for the first local output
+    let mut others: Vec<&str> = config.output.iter().flatten().map(String::as_str).collect();
+    if let Some(local) = others.iter().position(|o| is_local_output(o, &config)) {
+        others.remove(local);
+    }
+    if !others.is_empty() {
+        warn!(
+            "The other outputs of the interrupted run ({}) don't get the resumed archive",
+            others.join(", ")
+        );
+    }
+    config.output = Some(vec![resumed.to_string_lossy().into_owned()]);
+    config.incremental = Some(true);
+    config.since_manifest = Some(archive.to_string_lossy().into_owned());
+    info!("Backing up the remaining files to {}", resumed.display());
+    crate::backup(config).map(drop)
+}
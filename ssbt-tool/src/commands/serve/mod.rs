@@ -0,0 +1,483 @@
+use anyhow::{Context, Result, anyhow, bail};
+use axum::Router;
+use axum::body::{Body, Bytes};
+use axum::extract::{DefaultBodyLimit, FromRequest, Multipart, Request, State};
+use axum::http::{HeaderMap, Method, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use chrono::{DateTime, Utc};
+use clap::Args;
+use futures::{Stream, StreamExt};
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+use tracing::{info, warn};
+
+mod tus;
+
+use crate::Config;
+use crate::commands::prune::RetentionPolicy;
+use crate::fs_utils::encode_size;
+use crate::naming::{expand_placeholders, volume_of};
+use crate::secrets::resolve_secret;
+use crate::sink::checksum::ChecksumAlgorithm;
+
+/// Directory of the uploads accepted without a token.
+const ANONYMOUS: &str = "anonymous";
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// Address and port to listen on
+    #[arg(long, value_name = "ADDRESS", default_value = "0.0.0.0:8080")]
+    pub listen: String,
+
+    /// Directory the archives are stored in, in a subdirectory per client
+    #[arg(long, value_name = "DIR")]
+    pub store: PathBuf,
+
+    /// A client allowed to upload, as `name=token`; the token may be a
+    /// `file:`/`env:`/`cmd:` reference. Repeat for several clients
+    #[arg(long = "client", value_name = "NAME=TOKEN")]
+    pub clients: Vec<String>,
+
+    /// Accept uploads without a token, stored in `anonymous`
+    #[arg(long)]
+    pub no_auth: bool,
+}
+
+/// Receives the archives of ssbt's `http(s)://` outputs: POSTed archives
+/// (raw or as `multipart/form-data`) are named by the server, PUT ones keep
+/// the path of their URL, tus uploads the name in their metadata, and GET
+/// downloads them again for `ssbt list`,
+/// `verify` and `restore`. Each client, known by its token, gets its own
+/// directory under `--store`; with a `keep_*` policy, older archives in the
+/// directory of each new one are deleted like `ssbt prune` does.
+pub fn run(args: &ServeArgs, config: &Config) -> Result<()> {
+    let clients = args
+        .clients
+        .iter()
+        .map(|client| parse_client(client))
+        .collect::<Result<Vec<_>>>()?;
+    if clients.is_empty() && !args.no_auth {
+        bail!("serve needs at least one --client name=token, or --no-auth to accept anyone");
+    }
+    if !clients.is_empty() && args.no_auth {
+        bail!("--no-auth can't be combined with --client");
+    }
+    let retention = [
+        config.keep_last,
+        config.keep_daily,
+        config.keep_weekly,
+        config.keep_monthly,
+    ]
+    .iter()
+    .any(Option::is_some)
+    .then(|| RetentionPolicy::from_config(config))
+    .transpose()?;
+    fs::create_dir_all(&args.store)
+        .with_context(|| format!("creating {}", args.store.display()))?;
+
+    let server = Arc::new(Server {
+        store: std::path::absolute(&args.store)?,
+        clients,
+        retention,
+        volumes: Mutex::new(HashMap::new()),
+        tus: Mutex::new(HashMap::new()),
+    });
+    let app = Router::new()
+        .fallback(handle)
+        // Archives are streamed to disk, however large they are
+        .layer(DefaultBodyLimit::disable())
+        .with_state(server.clone());
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let listener = tokio::net::TcpListener::bind(&args.listen)
+            .await
+            .with_context(|| format!("listening on {}", args.listen))?;
+        info!(
+            "Receiving archives on {} into {}",
+            listener.local_addr()?,
+            server.store.display()
+        );
+        axum::serve(listener, app).await?;
+        Ok(())
+    })
+}
+
+/// `name=token`, resolving a secret reference in the token.
+fn parse_client(client: &str) -> Result<(String, String)> {
+    let (name, token) = client
+        .split_once('=')
+        .ok_or_else(|| anyhow!("invalid --client {client} (expected name=token)"))?;
+    if safe_name(name).is_none() {
+        bail!("invalid client name {name}");
+    }
+    let token = resolve_secret(token).with_context(|| format!("resolving the token of {name}"))?;
+    if token.is_empty() {
+        bail!("client {name} has an empty token");
+    }
+    Ok((name.to_string(), token))
+}
+
+struct Server {
+    store: PathBuf,
+    /// Client names and their tokens.
+    clients: Vec<(String, String)>,
+    retention: Option<RetentionPolicy>,
+    /// For each client directory, the archive the volumes POSTed since the
+    /// last `X-Ssbt-Volume: 001` belong to.
+    volumes: Mutex<HashMap<PathBuf, String>>,
+    tus: tus::Uploads,
+}
+
+async fn handle(State(server): State<Arc<Server>>, request: Request) -> Response {
+    let Some(client) = server.client(request.headers()) else {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            "a valid token is required\n",
+        )
+            .into_response();
+    };
+    let dir = server.store.join(client);
+    if tus::is_tus(&request) {
+        return tus::handle(&server, client, &dir, request).await;
+    }
+    let method = request.method().clone();
+    let result = match method {
+        Method::POST => server.post(&dir, request).await,
+        Method::PUT => match url_path(request.uri().path()) {
+            Some(path) => {
+                let body = request.into_body().into_data_stream();
+                server.store_file(&dir, &path, body).await
+            }
+            None => Err(bad_request("invalid path")),
+        },
+        Method::GET | Method::HEAD => {
+            return download(&dir, request.uri().path(), method == Method::HEAD).await;
+        }
+        _ => return StatusCode::METHOD_NOT_ALLOWED.into_response(),
+    };
+    match result {
+        Ok((path, size)) => {
+            let name = server.stored(client, &dir, &path, size);
+            let body = json!({ "name": name, "size": size });
+            (
+                StatusCode::CREATED,
+                [(header::CONTENT_TYPE, "application/json")],
+                body.to_string(),
+            )
+                .into_response()
+        }
+        Err(rejection) => rejection.into_response(client),
+    }
+}
+
+/// Why an upload wasn't stored.
+struct Rejection(StatusCode, String);
+
+impl Rejection {
+    fn into_response(self, client: &str) -> Response {
+        let Rejection(status, message) = self;
+        if status.is_server_error() {
+            warn!("{client}: {message}");
+        }
+        (status, format!("{message}\n")).into_response()
+    }
+}
+
+fn bad_request(message: &str) -> Rejection {
+    Rejection(StatusCode::BAD_REQUEST, message.to_string())
+}
+
+impl From<anyhow::Error> for Rejection {
+    fn from(e: anyhow::Error) -> Self {
+        Rejection(StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))
+    }
+}
+
+impl Server {
+    /// Logs an archive stored in `dir` and applies the retention policy
+    /// next to it. Returns its name relative to `dir`.
+    fn stored(&self, client: &str, dir: &Path, path: &Path, size: u64) -> String {
+        let name = path.strip_prefix(dir).unwrap_or(path).to_string_lossy();
+        info!("{client}: stored {name} ({})", encode_size(size));
+        if let Some(policy) = self.retention
+            && let Err(e) = apply_retention(policy, path)
+        {
+            warn!("{client}: applying the retention policy failed: {e:#}");
+        }
+        name.into_owned()
+    }
+
+    /// The client the request's token belongs to.
+    fn client(&self, headers: &HeaderMap) -> Option<&str> {
+        if self.clients.is_empty() {
+            return Some(ANONYMOUS);
+        }
+        let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+        let (scheme, credentials) = value.split_once(' ')?;
+        let token = match scheme.to_ascii_lowercase().as_str() {
+            "bearer" => credentials.trim().to_string(),
+            // `user:token`, or the token alone
+            "basic" => {
+                let decoded = STANDARD.decode(credentials.trim()).ok()?;
+                let decoded = String::from_utf8(decoded).ok()?;
+                match decoded.split_once(':') {
+                    Some((_, token)) => token.to_string(),
+                    None => decoded,
+                }
+            }
+            _ => return None,
+        };
+        self.clients
+            .iter()
+            .find(|(_, expected)| same_token(expected, &token))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Stores a POSTed archive under a name of our own, or under the file
+    /// name of a `multipart/form-data` upload.
+    async fn post(&self, dir: &Path, request: Request) -> Result<(PathBuf, u64), Rejection> {
+        let content_type = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        if content_type.starts_with("multipart/form-data") {
+            let mut form = Multipart::from_request(request, &())
+                .await
+                .map_err(|e| bad_request(&e.body_text()))?;
+            while let Some(field) = form
+                .next_field()
+                .await
+                .map_err(|e| bad_request(&e.body_text()))?
+            {
+                let Some(file_name) = field.file_name().map(str::to_string) else {
+                    continue;
+                };
+                let name = Path::new(&file_name)
+                    .file_name()
+                    .and_then(|name| safe_name(&name.to_string_lossy()))
+                    .unwrap_or_else(|| generated_name(""));
+                let stream = futures::stream::unfold(field, |mut field| async move {
+                    field.chunk().await.transpose().map(|chunk| (chunk, field))
+                });
+                return self.store_file(dir, Path::new(&name), stream).await;
+            }
+            return Err(bad_request("the form holds no file"));
+        }
+
+        let volume = request
+            .headers()
+            .get("X-Ssbt-Volume")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let name = match volume {
+            Some(volume) if volume_of(&format!("archive.{volume}")).is_some() => {
+                let mut volumes = self.volumes.lock().unwrap();
+                let archive = match volumes.get(dir) {
+                    Some(archive) if volume != "001" => archive.clone(),
+                    _ => {
+                        let archive = generated_name(&content_type);
+                        volumes.insert(dir.to_path_buf(), archive.clone());
+                        archive
+                    }
+                };
+                format!("{archive}.{volume}")
+            }
+            Some(volume) => return Err(bad_request(&format!("invalid volume {volume}"))),
+            None => generated_name(&content_type),
+        };
+        let body = request.into_body().into_data_stream();
+        self.store_file(dir, Path::new(&name), body).await
+    }
+
+    /// Streams `body` into `dir/path` through a `.part` file of its own,
+    /// renamed once the whole body arrived.
+    async fn store_file<S, E>(
+        &self,
+        dir: &Path,
+        path: &Path,
+        body: S,
+    ) -> Result<(PathBuf, u64), Rejection>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+        E: std::fmt::Display,
+    {
+        let target = dir.join(path);
+        let (file, part) = part_file(&target)?.into_parts();
+        // Dropping `part` on the way out removes the file
+        let size = write_body(tokio::fs::File::from_std(file), &part, body).await?;
+        part.persist(&target)
+            .map_err(|e| e.error)
+            .with_context(|| format!("renaming to {}", target.display()))?;
+        Ok((target, size))
+    }
+}
+
+/// A `.part` file next to `target`, named uniquely so that concurrent
+/// uploads of the same name don't write into each other.
+fn part_file(target: &Path) -> Result<NamedTempFile, Rejection> {
+    let parent = target.parent().context("the upload has no directory")?;
+    fs::create_dir_all(parent).with_context(|| format!("creating {}", parent.display()))?;
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    let part = tempfile::Builder::new()
+        .prefix(&format!(".{name}."))
+        .suffix(".part")
+        .tempfile_in(parent)
+        .with_context(|| format!("creating a file in {}", parent.display()))?;
+    Ok(part)
+}
+
+async fn write_body<S, E>(mut file: tokio::fs::File, part: &Path, body: S) -> Result<u64, Rejection>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+    E: std::fmt::Display,
+{
+    let mut body = std::pin::pin!(body);
+    let mut size = 0;
+    while let Some(chunk) = body.next().await {
+        // The client went away or sent a broken body
+        let chunk = chunk.map_err(|e| bad_request(&format!("receiving the upload failed: {e}")))?;
+        file.write_all(&chunk)
+            .await
+            .with_context(|| format!("writing {}", part.display()))?;
+        size += chunk.len() as u64;
+    }
+    file.sync_all().await.context("syncing the archive")?;
+    Ok(size)
+}
+
+/// Sends back a stored archive.
+async fn download(dir: &Path, uri_path: &str, head: bool) -> Response {
+    let Some(path) = url_path(uri_path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let path = dir.join(path);
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(_) => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let size = match file.metadata().await {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let body = match head {
+        true => Body::empty(),
+        false => Body::from_stream(tokio_util::io::ReaderStream::new(file)),
+    };
+    (
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_LENGTH, size.to_string()),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// The relative file path a URL path names, or `None` if it would leave
+/// the client's directory.
+fn url_path(path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        let segment = percent_decode_str(segment).decode_utf8().ok()?;
+        relative.push(safe_name(&segment)?);
+    }
+    (relative.components().count() > 0).then_some(relative)
+}
+
+/// `name` if it is a plain file name, which can't climb out of a directory
+/// or hide as a `.part` file.
+fn safe_name(name: &str) -> Option<String> {
+    let mut components = Path::new(name).components();
+    let plain = matches!(components.next(), Some(Component::Normal(_)))
+        && components.next().is_none()
+        && !name.contains(['\\', '\0'])
+        && !name.ends_with(".part");
+    plain.then(|| name.to_string())
+}
+
+/// `backup_<datetime>_<random>` with the extension of the content type.
+fn generated_name(content_type: &str) -> String {
+    let extension = match content_type.split(';').next().unwrap_or_default().trim() {
+        "application/zip" => ".zip",
+        "application/x-tar" => ".tar",
+        "application/gzip" => ".tar.gz",
+        "application/zstd" => ".tar.zst",
+        "application/x-7z-compressed" => ".7z",
+        _ => "",
+    };
+    expand_placeholders(
+        &format!("backup_%datetime%_%rand%{extension}"),
+        &Config::default(),
+    )
+    .expect("the template has no time formats")
+}
+
+/// Compares tokens in constant time, so timing doesn't tell how much of a
+/// guess was right.
+fn same_token(expected: &str, given: &str) -> bool {
+    expected.len() == given.len()
+        && expected
+            .bytes()
+            .zip(given.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Deletes the archives next to `stored` that the retention policy doesn't
+/// keep, with their volumes and checksum files.
+fn apply_retention(policy: RetentionPolicy, stored: &Path) -> Result<()> {
+    let dir = stored.parent().context("the archive has no directory")?;
+    let sidecars: Vec<String> = ChecksumAlgorithm::ALL
+        .iter()
+        .map(|algorithm| format!(".{}", algorithm.extension()))
+        .collect();
+    let mut archives: BTreeMap<String, (DateTime<Utc>, Vec<PathBuf>)> = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !metadata.is_file()
+            || name.ends_with(".part")
+            || sidecars.iter().any(|sidecar| name.ends_with(sidecar))
+        {
+            continue;
+        }
+        let archive = volume_of(&name).unwrap_or(&name).to_string();
+        let time: DateTime<Utc> = metadata.modified()?.into();
+        let (newest, files) = archives
+            .entry(archive)
+            .or_insert_with(|| (time, Vec::new()));
+        *newest = (*newest).max(time);
+        files.push(entry.path());
+    }
+
+    let mut archives: Vec<_> = archives.into_iter().collect();
+    archives.sort_by_key(|(_, (time, _))| std::cmp::Reverse(*time));
+    let times: Vec<_> = archives.iter().map(|(_, (time, _))| *time).collect();
+    for ((name, (_, files)), keep) in archives.into_iter().zip(policy.keep(&times)) {
+        if keep {
+            continue;
+        }
+        for file in files {
+            fs::remove_file(&file).with_context(|| format!("deleting {}", file.display()))?;
+        }
+        for sidecar in &sidecars {
+            let _ = fs::remove_file(dir.join(format!("{name}{sidecar}")));
+        }
+        info!("Deleted {} (retention policy)", dir.join(&name).display());
+    }
+    Ok(())
+}
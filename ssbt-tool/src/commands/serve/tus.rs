@@ -0,0 +1,383 @@
+//! The [tus](https://tus.io) 1.0 side of `ssbt serve`, for `--protocol tus`
+//! outputs: OPTIONS, creation (with a deferred length), PATCH with chunk
+//! checksums, HEAD to resume and DELETE to terminate. An upload is written
+//! to a `.part` file next to its final name until its last byte arrives.
+
+use axum::extract::Request;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use futures::StreamExt;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tempfile::TempPath;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+use super::{Rejection, Server, bad_request, generated_name, part_file, safe_name, url_path};
+
+const VERSION: &str = "1.0.0";
+const EXTENSIONS: &str = "creation,creation-defer-length,checksum,termination";
+const CHECKSUM_ALGORITHMS: &str = "sha256,md5";
+/// Status of a chunk whose checksum doesn't match its contents.
+const CHECKSUM_MISMATCH: u16 = 460;
+
+/// An upload that was created and hasn't received all its bytes yet.
+pub(super) struct Upload {
+    target: PathBuf,
+    /// `None` once the upload is complete.
+    part: Option<TempPath>,
+    offset: u64,
+    /// `None` until a PATCH declares it.
+    length: Option<u64>,
+}
+
+/// Uploads in progress by their ID, with the directory of the client that
+/// created them. Each is locked while a request works on it, so a HEAD
+/// after a broken PATCH answers once that PATCH is done.
+pub(super) type Uploads = std::sync::Mutex<HashMap<String, (PathBuf, Arc<Mutex<Upload>>)>>;
+
+/// Whether `request` is for the tus protocol: OPTIONS, or a request sending
+/// `Tus-Resumable` with a method tus uses.
+pub(super) fn is_tus(request: &Request) -> bool {
+    let method = request.method();
+    *method == Method::OPTIONS
+        || (request.headers().contains_key("Tus-Resumable")
+            && [Method::POST, Method::PATCH, Method::HEAD, Method::DELETE].contains(method))
+}
+
+pub(super) async fn handle(
+    server: &Server,
+    client: &str,
+    dir: &Path,
+    request: Request,
+) -> Response {
+    let result = if *request.method() != Method::OPTIONS
+        && header_str(request.headers(), "Tus-Resumable") != Some(VERSION)
+    {
+        Ok((
+            StatusCode::PRECONDITION_FAILED,
+            [("Tus-Version", VERSION)],
+            "unsupported tus version\n",
+        )
+            .into_response())
+    } else {
+        match request.method().clone() {
+            Method::OPTIONS => Ok(options()),
+            Method::POST => create(server, client, dir, &request),
+            Method::HEAD => head(server, dir, request.uri().path()).await,
+            Method::PATCH => patch(server, client, dir, request).await,
+            Method::DELETE => terminate(server, dir, request.uri().path()),
+            _ => Ok(StatusCode::METHOD_NOT_ALLOWED.into_response()),
+        }
+    };
+    let mut response = result.unwrap_or_else(|rejection| rejection.into_response(client));
+    response
+        .headers_mut()
+        .insert("Tus-Resumable", HeaderValue::from_static(VERSION));
+    response
+}
+
+fn options() -> Response {
+    (
+        StatusCode::NO_CONTENT,
+        [
+            ("Tus-Version", VERSION),
+            ("Tus-Extension", EXTENSIONS),
+            ("Tus-Checksum-Algorithm", CHECKSUM_ALGORITHMS),
+        ],
+    )
+        .into_response()
+}
+
+/// Creates an upload, stored under the endpoint's path with the `filename`
+/// of its metadata, or else a name of our own.
+fn create(
+    server: &Server,
+    client: &str,
+    dir: &Path,
+    request: &Request,
+) -> Result<Response, Rejection> {
+    let headers = request.headers();
+    let length = match (
+        header_str(headers, "Upload-Length"),
+        header_str(headers, "Upload-Defer-Length"),
+    ) {
+        (Some(length), None) => Some(
+            length
+                .parse()
+                .map_err(|_| bad_request("invalid Upload-Length"))?,
+        ),
+        (None, Some("1")) => None,
+        _ => {
+            return Err(bad_request(
+                "Upload-Length or Upload-Defer-Length: 1 is required",
+            ));
+        }
+    };
+    let metadata = metadata(header_str(headers, "Upload-Metadata").unwrap_or_default())?;
+    let name = metadata
+        .get("filename")
+        .and_then(|name| Path::new(name).file_name())
+        .and_then(|name| safe_name(&name.to_string_lossy()))
+        .unwrap_or_else(|| generated_name(metadata.get("filetype").map_or("", String::as_str)));
+    let endpoint = request.uri().path();
+    let target = dir.join(url_path(endpoint).unwrap_or_default()).join(name);
+
+    let (_, part) = part_file(&target)?.into_parts();
+    let id = format!("{:032x}", rand::random::<u128>());
+    let mut upload = Upload {
+        target,
+        part: Some(part),
+        offset: 0,
+        length,
+    };
+    if length == Some(0) {
+        upload.complete(server, client, dir)?;
+    } else {
+        let upload = Arc::new(Mutex::new(upload));
+        server
+            .tus
+            .lock()
+            .unwrap()
+            .insert(id.clone(), (dir.to_path_buf(), upload));
+    }
+    let location = format!("{}/{id}", endpoint.trim_end_matches('/'));
+    Ok((StatusCode::CREATED, [(header::LOCATION, location)]).into_response())
+}
+
+/// How much of an upload is stored.
+async fn head(server: &Server, dir: &Path, path: &str) -> Result<Response, Rejection> {
+    let (_, upload) = server.tus_upload(dir, path)?;
+    let upload = upload.lock().await;
+    let length = match upload.length {
+        Some(length) => ("Upload-Length", length.to_string()),
+        None => ("Upload-Defer-Length", "1".to_string()),
+    };
+    Ok((
+        StatusCode::OK,
+        [
+            (header::CACHE_CONTROL.as_str(), "no-store".to_string()),
+            ("Upload-Offset", upload.offset.to_string()),
+            length,
+        ],
+    )
+        .into_response())
+}
+
+/// Appends a chunk at the offset the upload is at. A chunk whose checksum
+/// doesn't match is dropped; without a checksum, what arrived of a broken
+/// chunk is kept for the client to resume from.
+async fn patch(
+    server: &Server,
+    client: &str,
+    dir: &Path,
+    request: Request,
+) -> Result<Response, Rejection> {
+    let headers = request.headers();
+    if header_str(headers, header::CONTENT_TYPE.as_str()) != Some("application/offset+octet-stream")
+    {
+        return Err(Rejection(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "expected Content-Type: application/offset+octet-stream".to_string(),
+        ));
+    }
+    let offset: u64 = header_str(headers, "Upload-Offset")
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| bad_request("invalid Upload-Offset"))?;
+    let declared = header_str(headers, "Upload-Length")
+        .map(|value| value.parse::<u64>())
+        .transpose()
+        .map_err(|_| bad_request("invalid Upload-Length"))?;
+    let mut checksum = header_str(headers, "Upload-Checksum")
+        .map(ChunkChecksum::parse)
+        .transpose()?;
+
+    let (id, entry) = server.tus_upload(dir, request.uri().path())?;
+    let mut upload = entry.lock().await;
+    let Some(part) = &upload.part else {
+        return Err(Rejection(
+            StatusCode::NOT_FOUND,
+            "no such upload".to_string(),
+        ));
+    };
+    if offset != upload.offset {
+        return Err(Rejection(
+            StatusCode::CONFLICT,
+            format!("the upload is at offset {}", upload.offset),
+        ));
+    }
+    let length = match (declared, upload.length) {
+        (Some(declared), Some(length)) if declared != length => {
+            return Err(bad_request("Upload-Length can't be changed"));
+        }
+        (Some(declared), _) if declared < offset => {
+            return Err(bad_request("Upload-Length is below the upload's offset"));
+        }
+        (declared, length) => declared.or(length),
+    };
+
+    // Bytes past the offset are left over from a chunk that was dropped
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(part)
+        .await
+        .map_err(anyhow::Error::from)?;
+    file.set_len(offset).await.map_err(anyhow::Error::from)?;
+    file.seek(SeekFrom::Start(offset))
+        .await
+        .map_err(anyhow::Error::from)?;
+    let checked = checksum.is_some();
+    let mut body = request.into_body().into_data_stream();
+    let mut written = 0u64;
+    let mut received = Ok(());
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                received = Err(bad_request(&format!("receiving the chunk failed: {e}")));
+                break;
+            }
+        };
+        if length.is_some_and(|length| offset + written + chunk.len() as u64 > length) {
+            return Err(bad_request("the chunk goes past Upload-Length"));
+        }
+        if let Some(checksum) = &mut checksum {
+            checksum.update(&chunk);
+        }
+        file.write_all(&chunk).await.map_err(anyhow::Error::from)?;
+        written += chunk.len() as u64;
+    }
+    if let Some(checksum) = checksum
+        && received.is_ok()
+        && !checksum.matches()
+    {
+        received = Err(Rejection(
+            StatusCode::from_u16(CHECKSUM_MISMATCH).expect("valid status"),
+            "checksum mismatch".to_string(),
+        ));
+    }
+    // A chunk with a checksum is only kept whole
+    let received = match received {
+        Err(rejection) if checked => return Err(rejection),
+        received => received,
+    };
+    file.sync_all().await.map_err(anyhow::Error::from)?;
+    upload.offset = offset + written;
+    upload.length = length;
+    received?;
+
+    if upload.length == Some(upload.offset) {
+        server.tus.lock().unwrap().remove(&id);
+        upload.complete(server, client, dir)?;
+    }
+    Ok((
+        StatusCode::NO_CONTENT,
+        [("Upload-Offset", upload.offset.to_string())],
+    )
+        .into_response())
+}
+
+/// Cancels an upload and deletes what it stored.
+fn terminate(server: &Server, dir: &Path, path: &str) -> Result<Response, Rejection> {
+    let (id, _) = server.tus_upload(dir, path)?;
+    // The part file goes once no request uses the upload anymore
+    server.tus.lock().unwrap().remove(&id);
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+impl Upload {
+    /// Gives the finished upload its final name.
+    fn complete(&mut self, server: &Server, client: &str, dir: &Path) -> Result<(), Rejection> {
+        if let Some(part) = self.part.take() {
+            part.persist(&self.target)
+                .map_err(|e| anyhow::Error::from(e.error))?;
+            server.stored(client, dir, &self.target, self.offset);
+        }
+        Ok(())
+    }
+}
+
+impl Server {
+    /// The ID and upload a tus URL names, if the client in `dir` created it.
+    fn tus_upload(
+        &self,
+        dir: &Path,
+        path: &str,
+    ) -> Result<(String, Arc<Mutex<Upload>>), Rejection> {
+        let id = path.rsplit('/').next().unwrap_or_default();
+        match self.tus.lock().unwrap().get(id) {
+            Some((owner, upload)) if owner == dir => Ok((id.to_string(), upload.clone())),
+            _ => Err(Rejection(
+                StatusCode::NOT_FOUND,
+                "no such upload".to_string(),
+            )),
+        }
+    }
+}
+
+/// The `Upload-Metadata` pairs, `key base64-value` separated by commas.
+fn metadata(value: &str) -> Result<HashMap<String, String>, Rejection> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once(' ').unwrap_or((pair, ""));
+            let value = STANDARD
+                .decode(value.trim())
+                .map_err(|_| bad_request("invalid Upload-Metadata"))?;
+            Ok((
+                key.to_string(),
+                String::from_utf8_lossy(&value).into_owned(),
+            ))
+        })
+        .collect()
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// An `Upload-Checksum` being checked against the chunk it came with.
+enum ChunkChecksum {
+    Sha256(Sha256, Vec<u8>),
+    Md5(Md5, Vec<u8>),
+}
+
+impl ChunkChecksum {
+    /// `<algorithm> <base64 digest>`
+    fn parse(value: &str) -> Result<Self, Rejection> {
+        let (algorithm, digest) = value
+            .split_once(' ')
+            .ok_or_else(|| bad_request("invalid Upload-Checksum"))?;
+        let digest = STANDARD
+            .decode(digest.trim())
+            .map_err(|_| bad_request("invalid Upload-Checksum"))?;
+        match algorithm {
+            "sha256" => Ok(ChunkChecksum::Sha256(Sha256::new(), digest)),
+            "md5" => Ok(ChunkChecksum::Md5(Md5::new(), digest)),
+            _ => Err(bad_request(&format!(
+                "unsupported checksum algorithm {algorithm}"
+            ))),
+        }
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        match self {
+            ChunkChecksum::Sha256(hasher, _) => hasher.update(chunk),
+            ChunkChecksum::Md5(hasher, _) => hasher.update(chunk),
+        }
+    }
+
+    fn matches(self) -> bool {
+        match self {
+            ChunkChecksum::Sha256(hasher, expected) => hasher.finalize()[..] == expected[..],
+            ChunkChecksum::Md5(hasher, expected) => hasher.finalize()[..] == expected[..],
+        }
+    }
+}
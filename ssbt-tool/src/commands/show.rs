@@ -0,0 +1,53 @@
+use anyhow::{Context, Result, bail};
+use clap::Args;
+
+use crate::Config;
+use crate::catalog::Catalog;
+use crate::fs_utils::encode_size;
+
+#[derive(Args, Debug)]
+pub struct ShowArgs {
+    /// Run id (or unique prefix) from `ssbt list`, or `latest`
+    pub id: String,
+
+    /// Output format [text|json]
+    #[arg(long, default_value = "text")]
+    pub format: String,
+}
+
+/// Prints the details of one backup run from the catalog.
+pub fn run(args: &ShowArgs, config: &Config) -> Result<()> {
+    let catalog = Catalog::from_config(config).context("the catalog is turned off")?;
+    let run = catalog.find(&args.id)?;
+
+    match args.format.as_str() {
+        "json" => println!("{}", serde_json::to_string_pretty(&run)?),
+        "text" => {
+            println!("Run:         {}", run.id);
+            println!("Job:         {}", run.job);
+            println!("Started:     {}", run.time.format("%Y-%m-%d %H:%M:%S UTC"));
+            println!("Duration:    {:.1}s", run.duration_secs);
+            println!(
+                "Status:      {}",
+                if run.success { "success" } else { "FAILED" }
+            );
+            if let Some(error) = &run.error {
+                println!("Error:       {error}");
+            }
+            println!("Destination: {}", run.destination.as_deref().unwrap_or("-"));
+            println!("Files:       {}", run.files);
+            println!("Size:        {}", encode_size(run.size));
+            if let Some(digest) = &run.manifest_sha256 {
+                println!("Manifest:    sha256:{digest}");
+            }
+            if let Some(base) = &run.incremental_base {
+                println!("Incremental: since {base}");
+            }
+            for archive in &run.archives {
+                println!("Archive:     {archive}");
+            }
+        }
+        other => bail!("unsupported show format: {other} (expected text or json)"),
+    }
+    Ok(())
+}
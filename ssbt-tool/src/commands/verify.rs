@@ -0,0 +1,156 @@
+use anyhow::{Result, bail};
+use clap::Args;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
+
+use crate::Config;
+use crate::fetch::{decrypt_archive, fetch_archive};
+use crate::fs_utils::encode_size;
+use crate::packaging::manifest::{Manifest, is_manifest, read_manifest, sha256};
+use crate::packaging::{ArchiveEntry, EntryVisitor, read_entries, visit_entries};
+use crate::secrets::resolve_secret;
+
+#[derive(Args, Debug)]
+pub struct VerifyArgs {
+    /// Archive path, or file://, http(s):// or gs:// URL
+    pub archive: String,
+
+    /// age identity file for encrypted archives (default: `encrypt.identities`)
+    #[arg(short, long)]
+    pub identity: Vec<String>,
+}
+
+/// Reads every entry to the end, letting the format's checksums (zip and 7z
+/// CRCs, gzip/zstd trailers, zip AES authentication codes) catch corruption,
+/// and hashes it for the comparison with the archive's manifest.
+#[derive(Default)]
+struct Verification {
+    files: usize,
+    bytes: u64,
+    /// SHA-256 of every file entry, `None` if it couldn't be read.
+    hashes: BTreeMap<String, Option<String>>,
+    manifest: Option<Manifest>,
+    failures: Vec<String>,
+}
+
+impl EntryVisitor for Verification {
+    fn visit(&mut self, entry: &ArchiveEntry, data: &mut dyn Read) -> Result<()> {
+        if entry.is_dir {
+            return Ok(());
+        }
+        if is_manifest(&entry.name) {
+            match read_manifest(data) {
+                Ok(manifest) => self.manifest = Some(manifest),
+                Err(e) => self.failures.push(format!("{}: {e:#}", entry.name)),
+            }
+            return Ok(());
+        }
+        self.files += 1;
+        let hash = match sha256(data) {
+            Ok((read, hash)) if read == entry.size => {
+                self.bytes += read;
+                Some(hash)
+            }
+            Ok((read, _)) => {
+                self.failures.push(format!(
+                    "{}: expected {} bytes, read {}",
+                    entry.name, entry.size, read
+                ));
+                None
+            }
+            Err(e) => {
+                self.failures.push(format!("{}: {}", entry.name, e));
+                None
+            }
+        };
+        self.hashes.insert(entry.name.clone(), hash);
+        Ok(())
+    }
+}
+
+impl Verification {
+    /// Compares the hashed entries with the manifest, if there is one.
+    fn check_manifest(&mut self) {
+        let Some(manifest) = &self.manifest else {
+            return;
+        };
+        let mut unlisted: BTreeSet<&String> = self.hashes.keys().collect();
+        for file in &manifest.files {
+            unlisted.remove(&file.name);
+            // Deduplicated files are checked against the entry holding their contents
+            let stored = file.duplicate_of.as_ref().unwrap_or(&file.name);
+            match self.hashes.get(stored) {
+                Some(Some(hash)) if *hash == file.sha256 => {}
+                Some(Some(_)) => self
+                    .failures
+                    .push(format!("{}: SHA-256 differs from the manifest", file.name)),
+                // Already reported as unreadable
+                Some(None) => {}
+                None => self.failures.push(format!(
+                    "{}: listed in the manifest, but missing",
+                    file.name
+                )),
+            }
+        }
+        for name in unlisted {
+            self.failures
+                .push(format!("{name}: not listed in the manifest"));
+        }
+    }
+}
+
+/// Checks a local or remote archive for corruption, failing if any is found.
+pub fn run(args: &VerifyArgs, config: &Config) -> Result<()> {
+    let password = config
+        .zip_password
+        .as_deref()
+        .filter(|p| !p.is_empty())
+        .map(resolve_secret)
+        .transpose()?;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    // Decrypting checks the age authentication tags of the whole stream
+    let archive = runtime.block_on(async {
+        let archive = fetch_archive(&args.archive, config).await?;
+        decrypt_archive(archive, &args.identity, config)
+    })?;
+
+    let entries = runtime.block_on(read_entries(archive.path()))?;
+    if password.is_none() && entries.iter().any(|e| e.encrypted) {
+        bail!(
+            "{} has encrypted entries, set zip_password to verify them",
+            args.archive
+        );
+    }
+
+    let mut verification = Verification::default();
+    if let Err(e) = visit_entries(archive.path(), password.as_deref(), &mut verification) {
+        verification.failures.push(format!("{e:#}"));
+    }
+    verification.check_manifest();
+
+    for failure in &verification.failures {
+        println!("FAILED  {failure}");
+    }
+    if !verification.failures.is_empty() {
+        bail!(
+            "{} is corrupt ({} problems found)",
+            args.archive,
+            verification.failures.len()
+        );
+    }
+    println!(
+        "Verified {} files ({}) in {}{}: OK",
+        verification.files,
+        encode_size(verification.bytes),
+        args.archive,
+        if verification.manifest.is_some() {
+            " against its manifest"
+        } else {
+            ""
+        }
+    );
+    Ok(())
+}
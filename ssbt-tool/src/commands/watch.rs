@@ -0,0 +1,177 @@
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use glob::Pattern;
+// `::notify` is the file watching crate, `crate::notify` the run notifications
+use ::notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+use crate::Config;
+use crate::cancel;
+use crate::catalog::Catalog;
+use crate::fs_utils::{PATTERN_OPTIONS, parse_duration};
+use crate::naming::name_pattern;
+use crate::sink::save_file::is_local_output;
+
+#[derive(Args, Debug)]
+pub struct WatchArgs {
+    /// How long the paths must stay unchanged before a backup starts (e.g. 30s, 5m)
+    #[arg(long, value_name = "DURATION", default_value = "10s")]
+    pub debounce: String,
+
+    /// Files or directories to back up and watch, instead of the configured paths
+    pub paths: Vec<String>,
+}
+
+/// Backs up the configured paths, then again whenever files in them change
+/// and `--debounce` passes without further changes. With `incremental`,
+/// every backup after the first only holds what changed since the previous
+/// one. Runs until interrupted; failed backups are reported and retried on
+/// the next change.
+pub fn run(args: &WatchArgs, mut config: Config) -> Result<()> {
+    let debounce = parse_duration(&args.debounce).context("invalid --debounce")?;
+    if config.files_from.is_some() {
+        bail!("watch needs paths to watch, it can't be combined with files_from");
+    }
+    let paths = config
+        .paths
+        .iter()
+        .flatten()
+        .map(|source| {
+            std::path::absolute(source.path()).with_context(|| format!("resolving {source}"))
+        })
+        .collect::<Result<Vec<PathBuf>>>()?;
+    let ignored = Ignored::from_config(&config)?;
+
+    let (sender, events) = std::sync::mpsc::channel();
+    let mut watcher = ::notify::recommended_watcher(sender).context("starting the file watcher")?;
+    for path in &paths {
+        watcher
+            .watch(path, RecursiveMode::Recursive)
+            .with_context(|| format!("watching {}", path.display()))?;
+    }
+
+    let incremental = config.incremental.unwrap_or(false);
+    loop {
+        match crate::backup(config.clone()) {
+            Err(e) if cancel::is_cancelled() => return Err(e),
+            Ok(outcome) if incremental => {
+                // The next backup only stores what changed since this one
+                if let Some(archive) = outcome.archives.first() {
+                    config.since_manifest = Some(archive.clone());
+                }
+            }
+            Ok(_) => {}
+            Err(e) => error!("{e:#}"),
+        }
+        info!(
+            "Watching {} path(s) for changes (debounce {})",
+            paths.len(),
+            args.debounce
+        );
+        wait_for_changes(&events, debounce, &ignored)?;
+        info!("Changes detected, starting a backup");
+    }
+}
+
+/// Blocks until a change that isn't `ignored`, then until `debounce` passes
+/// without another one.
+fn wait_for_changes(
+    events: &Receiver<::notify::Result<Event>>,
+    debounce: Duration,
+    ignored: &Ignored,
+) -> Result<()> {
+    let is_change = |event: &::notify::Result<Event>| match event {
+        Ok(event) => {
+            matches!(
+                event.kind,
+                EventKind::Any | EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+            ) && event.paths.iter().any(|path| !ignored.contains(path))
+        }
+        Err(e) => {
+            // E.g. a full event queue: changes may have been missed
+            warn!("file watcher: {e}");
+            true
+        }
+    };
+
+    // Woken up regularly to notice Ctrl-C
+    let tick = Duration::from_millis(250);
+    loop {
+        cancel::check()?;
+        match events.recv_timeout(tick) {
+            Ok(event) if is_change(&event) => break,
+            Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => bail!("the file watcher stopped"),
+        }
+    }
+    let mut quiet_since = Instant::now();
+    loop {
+        cancel::check()?;
+        match events.recv_timeout(tick) {
+            Ok(_) => quiet_since = Instant::now(),
+            Err(RecvTimeoutError::Timeout) if quiet_since.elapsed() >= debounce => return Ok(()),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => bail!("the file watcher stopped"),
+        }
+    }
+}
+
+/// Paths whose changes don't call for a backup: those matching `skip`, and
+/// what ssbt writes itself, in case it lies within a watched directory.
+struct Ignored {
+    skip: Vec<Pattern>,
+    /// Archives (and their checksum files and volumes) of the local outputs.
+    archives: Vec<Pattern>,
+    /// The repository, the catalog and the log file.
+    own_files: Vec<PathBuf>,
+}
+
+impl Ignored {
+    fn from_config(config: &Config) -> Result<Self> {
+        let skip = config
+            .skip
+            .iter()
+            .flatten()
+            .map(|p| Pattern::new(p).with_context(|| format!("invalid skip pattern: {p}")))
+            .collect::<Result<_>>()?;
+
+        let mut archives = Vec::new();
+        for output in config.output.iter().flatten() {
+            if !is_local_output(output, config) {
+                continue;
+            }
+            let output = std::path::absolute(output)?;
+            let pattern = name_pattern(&output.to_string_lossy(), config)?;
+            archives.push(Pattern::new(&format!("{}*", pattern.as_str()))?);
+        }
+
+        let mut own_files = Vec::new();
+        if let Some(repo) = config.repo.as_deref().filter(|r| !r.contains("://")) {
+            own_files.push(std::path::absolute(repo)?);
+        }
+        if let Some(catalog) = Catalog::from_config(config) {
+            // Written through a temporary file next to it
+            own_files.extend(catalog.path().parent().map(Path::to_path_buf));
+        }
+        if let Some(log_file) = &config.log_file {
+            own_files.push(std::path::absolute(log_file)?);
+        }
+        Ok(Ignored {
+            skip,
+            archives,
+            own_files,
+        })
+    }
+
+    fn contains(&self, path: &Path) -> bool {
+        let path_str = path.to_string_lossy();
+        self.skip
+            .iter()
+            .any(|p| p.matches_with(&path_str, PATTERN_OPTIONS))
+            || self.archives.iter().any(|p| p.matches(&path_str))
+            || self.own_files.iter().any(|own| path.starts_with(own))
+    }
+}
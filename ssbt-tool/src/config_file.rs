@@ -0,0 +1,570 @@
+//! Configuration files: reading YAML, JSON or TOML, and `--check-config`,
+//! which reports unknown keys and option values ssbt would only reject
+//! halfway through a backup, with the line they are on.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{Context, Result, anyhow, bail};
+use glob::Pattern;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use ssbt_lib::{
+    Config, DockerConfig, EmailConfig, EncryptConfig, KubernetesConfig, MetricsConfig,
+    NotifyConfig, SourceConfig, VirtualFileConfig,
+};
+
+use crate::file_reader::{ChangePolicy, ReadErrorPolicy};
+use crate::fs_utils::{SizeExceededPolicy, SymlinkPolicy, parse_duration, parse_size, parse_time};
+use crate::naming::expand_placeholders;
+use crate::notify::NotifyOn;
+use crate::packaging::ArchiveFormat;
+use crate::packaging::compress::StreamCompression;
+use crate::packaging::zip::ZipEncryptionMethod;
+use crate::process::EntryNaming;
+use crate::repo::Chunker;
+use crate::sink::checksum::ChecksumAlgorithm;
+use crate::sink::retry::RetryPolicy;
+use crate::sink::save_file::OverwritePolicy;
+use crate::sink::send_net::{parse_auth, parse_method};
+
+/// Keys serde accepts in place of an option's own name.
+const ALIASES: [&str; 1] = ["http_headers"];
+
+const PROTOCOLS: [&str; 6] = ["http", "https", "multipart", "scp", "sftp", "tus"];
+
+/// Syntax of a configuration file.
+#[derive(Debug, Clone, Copy)]
+enum Syntax {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl Syntax {
+    /// By extension or, for other names, by the look of the content.
+    fn detect(path: &str, content: &str) -> Self {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".json") {
+            Syntax::Json
+        } else if lower.ends_with(".toml") {
+            Syntax::Toml
+        } else if lower.ends_with(".yaml") || lower.ends_with(".yml") {
+            Syntax::Yaml
+        } else if content.trim_start().starts_with('{') {
+            Syntax::Json
+        } else if looks_like_toml(content) {
+            Syntax::Toml
+        } else {
+            Syntax::Yaml
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(self, content: &str) -> Result<T> {
+        Ok(match self {
+            Syntax::Yaml => serde_yaml::from_str(content)?,
+            Syntax::Json => serde_json::from_str(content)?,
+            Syntax::Toml => toml::from_str(content)?,
+        })
+    }
+}
+
+/// Whether the first line that isn't blank or a comment is a TOML table
+/// header (`[profiles.nightly]`) or a `key = value` assignment.
+fn looks_like_toml(content: &str) -> bool {
+    let Some(line) = content
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+    else {
+        return false;
+    };
+    if line.starts_with('[') {
+        return line.ends_with(']') && !line.contains(',');
+    }
+    line.split_once('=').is_some_and(|(key, _)| {
+        let key = key.trim();
+        !key.is_empty()
+            && key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '"' | '\''))
+    })
+}
+
+/// Where a config file is looked for when neither `--config` nor
+/// `SSBT_CONFIG` names one, in order.
+pub fn default_locations() -> Vec<PathBuf> {
+    let mut locations = vec![PathBuf::from("ssbt.yaml")];
+    let config_home = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+    if let Some(dir) = config_home {
+        locations.push(dir.join("ssbt").join("config.yaml"));
+    }
+    if let Some(appdata) = env::var_os("APPDATA").filter(|dir| !dir.is_empty()) {
+        locations.push(PathBuf::from(appdata).join("ssbt").join("config.yaml"));
+    }
+    locations.push(PathBuf::from("/etc/ssbt/config.yaml"));
+    locations
+}
+
+/// The first of the [`default_locations`] that exists.
+pub fn find_default_config() -> Option<String> {
+    default_locations()
+        .into_iter()
+        .find(|path| path.is_file())
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+/// Reads YAML, JSON or TOML config from file. Unknown keys are refused,
+/// rather than silently ignored like a misspelled option would be.
+pub fn read_config_file(path: &str) -> Result<Config> {
+    let content = fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+    let syntax = Syntax::detect(path, &content);
+    let value: Value = syntax
+        .parse(&content)
+        .with_context(|| format!("parsing {path}"))?;
+    let unknown = unknown_keys(&content, &value);
+    if !unknown.is_empty() {
+        let problems: Vec<String> = unknown.iter().map(ToString::to_string).collect();
+        bail!("{path}: {}", problems.join("; "));
+    }
+    syntax
+        .parse(&content)
+        .with_context(|| format!("parsing {path}"))
+}
+
+/// A mistake found in the configuration.
+#[derive(Debug)]
+pub struct Problem {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {line}: {}", self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// `--check-config`: checks the config file, if any, then the options from
+/// `SSBT_*` variables and the command line, and prints every problem found.
+pub fn check(path: Option<&str>, env: Config, cli: Config) -> Result<()> {
+    let mut found = 0;
+    if let Some(path) = path {
+        let problems = check_file(path)?;
+        for problem in &problems {
+            match problem.line {
+                Some(line) => println!("{path}:{line}: {}", problem.message),
+                None => println!("{path}: {}", problem.message),
+            }
+        }
+        found += problems.len();
+    }
+    let overrides = crate::merge_configs(env, Config::default(), cli);
+    for (key, _, error) in invalid_values(&overrides) {
+        println!("{key} (environment or command line): {error:#}");
+        found += 1;
+    }
+    if found > 0 {
+        bail!("{found} problem(s) in the configuration");
+    }
+    match path {
+        Some(path) => println!("{path}: OK"),
+        None => println!("Configuration OK"),
+    }
+    Ok(())
+}
+
+/// Problems in a config file: syntax errors, unknown keys, values of the
+/// wrong type and values ssbt would reject, in the shared options and in
+/// every profile merged over them.
+fn check_file(path: &str) -> Result<Vec<Problem>> {
+    let content = fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+    let syntax = Syntax::detect(path, &content);
+    let value: Value = match syntax.parse(&content) {
+        Ok(value) => value,
+        Err(e) => {
+            return Ok(vec![Problem {
+                line: None,
+                message: format!("{e:#}"),
+            }]);
+        }
+    };
+    let mut problems = unknown_keys(&content, &value);
+    if !problems.is_empty() {
+        return Ok(problems);
+    }
+    let mut config: Config = match syntax.parse(&content) {
+        Ok(config) => config,
+        Err(e) => {
+            problems.push(Problem {
+                line: None,
+                message: format!("{e:#}"),
+            });
+            return Ok(problems);
+        }
+    };
+
+    let profiles = config.profiles.take().unwrap_or_default();
+    let shared = invalid_values(&config);
+    for (key, value, error) in &shared {
+        problems.push(Problem {
+            line: find_line(&content, key, value.as_deref()),
+            message: format!("{error:#}"),
+        });
+    }
+    for (name, profile) in profiles {
+        let merged = crate::merge_configs(Config::default(), config.clone(), profile);
+        // Values inherited from the shared options are reported once
+        for (key, value, error) in invalid_values(&merged)
+            .into_iter()
+            .filter(|(key, value, _)| !shared.iter().any(|(k, v, _)| k == key && v == value))
+        {
+            problems.push(Problem {
+                line: find_line(&content, key, value.as_deref()),
+                message: format!("profile {name}: {error:#}"),
+            });
+        }
+    }
+    problems.sort_by_key(|problem| problem.line);
+    Ok(problems)
+}
+
+fn unknown_keys(content: &str, value: &Value) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    unknown_config_keys(content, value, "", &mut problems);
+    problems
+}
+
+fn unknown_config_keys(content: &str, value: &Value, prefix: &str, problems: &mut Vec<Problem>) {
+    unknown_object_keys::<Config>(content, value, prefix, problems);
+    if let Some(notify) = value.get("notify") {
+        let prefix = format!("{prefix}notify.");
+        unknown_object_keys::<NotifyConfig>(content, notify, &prefix, problems);
+        if let Some(email) = notify.get("email") {
+            let prefix = format!("{prefix}email.");
+            unknown_object_keys::<EmailConfig>(content, email, &prefix, problems);
+        }
+    }
+    if let Some(metrics) = value.get("metrics") {
+        let prefix = format!("{prefix}metrics.");
+        unknown_object_keys::<MetricsConfig>(content, metrics, &prefix, problems);
+    }
+    if let Some(docker) = value.get("docker") {
+        let prefix = format!("{prefix}docker.");
+        unknown_object_keys::<DockerConfig>(content, docker, &prefix, problems);
+    }
+    if let Some(paths) = value.get("paths").and_then(Value::as_array) {
+        for (number, path) in paths.iter().enumerate() {
+            let prefix = format!("{prefix}paths[{number}].");
+            unknown_object_keys::<PrefixedPath>(content, path, &prefix, problems);
+        }
+    }
+    if let Some(sources) = value.get("sources").and_then(Value::as_array) {
+        for (number, source) in sources.iter().enumerate() {
+            let prefix = format!("{prefix}sources[{number}].");
+            unknown_object_keys::<SourceConfig>(content, source, &prefix, problems);
+        }
+    }
+    if let Some(files) = value.get("virtual_files").and_then(Value::as_array) {
+        for (number, file) in files.iter().enumerate() {
+            let prefix = format!("{prefix}virtual_files[{number}].");
+            unknown_object_keys::<VirtualFileConfig>(content, file, &prefix, problems);
+        }
+    }
+    if let Some(k8s) = value.get("k8s") {
+        let prefix = format!("{prefix}k8s.");
+        unknown_object_keys::<KubernetesConfig>(content, k8s, &prefix, problems);
+    }
+    if let Some(encrypt) = value.get("encrypt") {
+        let prefix = format!("{prefix}encrypt.");
+        unknown_object_keys::<EncryptConfig>(content, encrypt, &prefix, problems);
+    }
+    if let Some(profiles) = value.get("profiles").and_then(Value::as_object) {
+        for (name, profile) in profiles {
+            let prefix = format!("{prefix}profiles.{name}.");
+            unknown_config_keys(content, profile, &prefix, problems);
+        }
+    }
+}
+
+/// The keys of a `{path, prefix}` entry of `paths`.
+#[derive(Serialize, Default)]
+struct PrefixedPath {
+    path: String,
+    prefix: String,
+}
+
+/// Reports the keys of `value` that `T` has no field for, suggesting the
+/// closest one. Values that aren't maps are left to the typed parse.
+fn unknown_object_keys<T: Serialize + Default>(
+    content: &str,
+    value: &Value,
+    prefix: &str,
+    problems: &mut Vec<Problem>,
+) {
+    let Some(object) = value.as_object() else {
+        return;
+    };
+    // Options are never skipped when serialized, so the default value lists them all
+    let known: Vec<String> = match serde_json::to_value(T::default()) {
+        Ok(Value::Object(fields)) => fields.keys().cloned().collect(),
+        _ => return,
+    };
+    let unknown = |key: &&String| !known.contains(key) && !ALIASES.contains(&key.as_str());
+    for key in object.keys().filter(unknown) {
+        let mut message = format!("unknown key `{prefix}{key}`");
+        let closest = known
+            .iter()
+            .map(|candidate| (strsim::jaro_winkler(key, candidate), candidate))
+            .max_by(|a, b| a.0.total_cmp(&b.0));
+        if let Some((score, candidate)) = closest
+            && score > 0.8
+        {
+            message.push_str(&format!(", did you mean `{candidate}`?"));
+        }
+        problems.push(Problem {
+            line: find_line(content, key, None),
+            message,
+        });
+    }
+}
+
+/// Option values ssbt would reject, as the key, the offending value where
+/// there is a single one, and the error.
+fn invalid_values(config: &Config) -> Vec<(&'static str, Option<String>, anyhow::Error)> {
+    fn parsed<T: FromStr>(value: &Option<String>) -> Result<()>
+    where
+        T::Err: Into<anyhow::Error>,
+    {
+        match value {
+            Some(value) => value.parse::<T>().map(drop).map_err(Into::into),
+            None => Ok(()),
+        }
+    }
+    fn with<T>(value: &Option<String>, check: impl Fn(&str) -> Result<T>) -> Result<()> {
+        value.as_deref().map(check).transpose().map(drop)
+    }
+
+    let format = parsed::<ArchiveFormat>(&config.format);
+    let mut checks: Vec<(&'static str, Option<String>, Result<()>)> = vec![
+        ("format", config.format.clone(), format),
+        (
+            "protocol",
+            config.protocol.clone(),
+            match config.protocol.as_deref() {
+                Some(protocol) if !PROTOCOLS.contains(&protocol) => Err(anyhow!(
+                    "unsupported protocol: {protocol} (expected {})",
+                    PROTOCOLS.join(", ")
+                )),
+                _ => Ok(()),
+            },
+        ),
+        (
+            "skip_larger_than",
+            config.skip_larger_than.clone(),
+            with(&config.skip_larger_than, parse_size).context("invalid skip_larger_than"),
+        ),
+        (
+            "split_size",
+            config.split_size.clone(),
+            with(&config.split_size, parse_size).context("invalid split_size"),
+        ),
+        (
+            "upload_chunk_size",
+            config.upload_chunk_size.clone(),
+            with(&config.upload_chunk_size, parse_size).context("invalid upload_chunk_size"),
+        ),
+        (
+            "upload_buffer_size",
+            config.upload_buffer_size.clone(),
+            with(&config.upload_buffer_size, parse_size).context("invalid upload_buffer_size"),
+        ),
+        (
+            "estimate_bandwidth",
+            config.estimate_bandwidth.clone(),
+            with(&config.estimate_bandwidth, parse_size).context("invalid estimate_bandwidth"),
+        ),
+        (
+            "modified_within",
+            config.modified_within.clone(),
+            with(&config.modified_within, parse_time).context("invalid modified_within"),
+        ),
+        (
+            "modified_before",
+            config.modified_before.clone(),
+            with(&config.modified_before, parse_time).context("invalid modified_before"),
+        ),
+        (
+            "symlinks",
+            config.symlinks.clone(),
+            SymlinkPolicy::from_config(config).map(drop),
+        ),
+        (
+            "on_size_exceeded",
+            config.on_size_exceeded.clone(),
+            parsed::<SizeExceededPolicy>(&config.on_size_exceeded),
+        ),
+        (
+            "on_read_error",
+            config.on_read_error.clone(),
+            parsed::<ReadErrorPolicy>(&config.on_read_error),
+        ),
+        (
+            "on_change",
+            config.on_change.clone(),
+            parsed::<ChangePolicy>(&config.on_change),
+        ),
+        (
+            "overwrite",
+            config.overwrite.clone(),
+            parsed::<OverwritePolicy>(&config.overwrite),
+        ),
+        (
+            "checksum",
+            config.checksum.clone(),
+            ChecksumAlgorithm::from_config(config).map(drop),
+        ),
+        (
+            "zip_encryption",
+            config.zip_encryption.clone(),
+            parsed::<ZipEncryptionMethod>(&config.zip_encryption),
+        ),
+        (
+            "chunker",
+            config.chunker.clone(),
+            parsed::<Chunker>(&config.chunker),
+        ),
+        (
+            "on",
+            config.notify.as_ref().and_then(|n| n.on.clone()),
+            parsed::<NotifyOn>(&config.notify.as_ref().and_then(|n| n.on.clone())),
+        ),
+        (
+            "log_level",
+            config.log_level.clone(),
+            with(&config.log_level, |level| {
+                tracing::Level::from_str(level).map_err(|_| {
+                    anyhow!("unsupported log level: {level} (expected error, warn, info, debug or trace)")
+                })
+            }),
+        ),
+        (
+            "auth_type",
+            config.auth_type.clone(),
+            with(&config.auth_type, |auth_type| {
+                parse_auth(
+                    "user:password",
+                    Some(auth_type),
+                    config.auth_header.as_deref(),
+                )
+            }),
+        ),
+        (
+            "http_method",
+            config.http_method.clone(),
+            parse_method(config.http_method.as_deref()).map(drop),
+        ),
+        (
+            "proxy",
+            config.proxy.clone(),
+            with(&config.proxy, |proxy| {
+                reqwest::Proxy::all(proxy).with_context(|| format!("invalid proxy {proxy}"))
+            }),
+        ),
+        ("retries", None, RetryPolicy::from_config(config).map(drop)),
+        (
+            "paths",
+            None,
+            EntryNaming::from_config(config, None).map(drop),
+        ),
+        (
+            "wait_for_lock",
+            config.wait_for_lock.clone(),
+            with(&config.wait_for_lock, parse_duration),
+        ),
+        (
+            "healthcheck_timeout",
+            config.healthcheck_timeout.clone(),
+            with(&config.healthcheck_timeout, parse_duration),
+        ),
+    ];
+    if let Some(format) = config.format.as_deref().and_then(|f| f.parse().ok()) {
+        checks.push((
+            "compression_algorithm",
+            config.compression_algorithm.clone(),
+            StreamCompression::from_config(config, format).map(drop),
+        ));
+    }
+    for (key, patterns) in [("skip", &config.skip), ("include", &config.include)] {
+        for pattern in patterns.iter().flatten() {
+            checks.push((
+                key,
+                Some(pattern.clone()),
+                Pattern::new(pattern)
+                    .map(drop)
+                    .with_context(|| format!("invalid {key} pattern: {pattern}")),
+            ));
+        }
+    }
+    for output in config.output.iter().flatten() {
+        checks.push((
+            "output",
+            Some(output.clone()),
+            expand_placeholders(output, config).map(drop),
+        ));
+    }
+    for name in config.name_vars.iter().flat_map(|vars| vars.keys()) {
+        if name.is_empty() || name.contains('%') {
+            checks.push((
+                "name_vars",
+                Some(name.clone()),
+                Err(anyhow!(
+                    "invalid variable name {name:?}, it is empty or has a %"
+                )),
+            ));
+        }
+    }
+    checks
+        .into_iter()
+        .filter_map(|(key, value, result)| result.err().map(|e| (key, value, e)))
+        .collect()
+}
+
+/// Line number of `key` in the file; given a `value`, the first line
+/// mentioning it from there on, for values in lists. Best effort, as the
+/// parsers don't keep positions.
+fn find_line(content: &str, key: &str, value: Option<&str>) -> Option<usize> {
+    let lines: Vec<&str> = content.lines().collect();
+    let is_key = |line: &&str| {
+        line.trim_start()
+            .trim_start_matches(['"', '\''])
+            .strip_prefix(key)
+            .is_some_and(|rest| {
+                rest.trim_start_matches(['"', '\''])
+                    .trim_start()
+                    .starts_with([':', '='])
+            })
+    };
+    let start = lines
+        .iter()
+        .position(is_key)
+        .or_else(|| lines.iter().position(|line| line.contains(key)))?;
+    let line = match value.filter(|value| !value.is_empty()) {
+        Some(value) => lines[start..]
+            .iter()
+            .position(|line| line.contains(value))
+            .map_or(start, |offset| start + offset),
+        None => start,
+    };
+    Some(line + 1)
+}
@@ -0,0 +1,94 @@
+//! `--json`: the dry run or the result of a backup as a single JSON document
+//! on stdout, for scripts driving ssbt.
+
+use serde::Serialize;
+use serde_json::json;
+use ssbt_lib::Config;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::notify::RunSummary;
+use crate::process::BackupOutcome;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Set once the document is out, so a failed run isn't reported twice.
+static PRINTED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// What a dry run would back up.
+#[derive(Debug, Serialize)]
+pub struct DryRun<'a> {
+    pub dry_run: bool,
+    pub config: &'a Config,
+    pub files: usize,
+    /// Total size of the files.
+    pub size: u64,
+    /// Archive size, with compression predicted from samples of the files;
+    /// absent for repository backups.
+    pub estimated_archive_size: Option<u64>,
+    /// What compression leaves of the data, 1.0 for none.
+    pub estimated_compression_ratio: Option<f64>,
+    /// Upload time of the archive at `estimate_bandwidth`, if set.
+    pub estimated_upload_secs: Option<f64>,
+    pub destinations: &'a [DestinationCheck],
+    pub paths: &'a [PathBuf],
+}
+
+/// Whether an archive could be written to an output (`--check-destination`).
+#[derive(Debug, Serialize)]
+pub struct DestinationCheck {
+    pub destination: String,
+    pub ok: bool,
+    /// What was checked, or why the check failed.
+    pub message: String,
+}
+
+/// The outcome of a backup run, successful or not.
+#[derive(Debug, Serialize)]
+pub struct RunResult<'a> {
+    /// Catalog id of the run, if it was recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<&'a str>,
+    #[serde(flatten)]
+    pub summary: &'a RunSummary,
+    /// Where the archive was stored, as paths or URLs.
+    pub archives: &'a [String],
+    pub archive_size: Option<u64>,
+}
+
+impl<'a> RunResult<'a> {
+    pub fn new(
+        id: Option<&'a str>,
+        summary: &'a RunSummary,
+        outcome: Option<&'a BackupOutcome>,
+    ) -> Self {
+        RunResult {
+            id,
+            summary,
+            archives: outcome.map_or(&[], |o| o.archives.as_slice()),
+            archive_size: outcome.map(|o| o.archive_size).filter(|size| *size > 0),
+        }
+    }
+}
+
+/// Prints `document` as the output of the run.
+pub fn print<T: Serialize>(document: &T) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(document)?);
+    PRINTED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Prints an error that ended the run before it had a result, in `--json`
+/// mode only.
+pub fn print_error(message: &str) {
+    if enabled() && !PRINTED.load(Ordering::Relaxed) {
+        let _ = print(&json!({ "success": false, "error": message }));
+    }
+}
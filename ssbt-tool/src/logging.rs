@@ -0,0 +1,215 @@
+//! Log output: `tracing` events printed as plain status lines on the
+//! terminal, or as timestamped text or JSON lines for cron and systemd.
+
+use std::fmt::{self, Write as _};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+
+use anyhow::{Context, Result, anyhow};
+use chrono::{SecondsFormat, Utc};
+use serde_json::{Map, Value};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Subscriber};
+
+use crate::Config;
+
+/// Formats events and writes them to the terminal or the log file.
+struct Logger {
+    level: Level,
+    json: bool,
+    file: Option<Mutex<File>>,
+}
+
+/// Installs the logger for the rest of the process, according to
+/// `log_level` (or `quiet`), `log_file` and `log_json`.
+pub fn init(config: &Config) -> Result<()> {
+    let level = match config.log_level.as_deref() {
+        Some(level) => Level::from_str(level).map_err(|_| {
+            anyhow!("unsupported log level: {level} (expected error, warn, info, debug or trace)")
+        })?,
+        None if config.quiet.unwrap_or(false) => Level::WARN,
+        None => Level::INFO,
+    };
+    let file = match config.log_file.as_deref().filter(|f| !f.is_empty()) {
+        Some(path) => Some(Mutex::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("opening log file {path}"))?,
+        )),
+        None => None,
+    };
+    let logger = Logger {
+        level,
+        json: config.log_json.unwrap_or(false),
+        file,
+    };
+    tracing::subscriber::set_global_default(logger).context("installing the logger")
+}
+
+impl Logger {
+    /// A line as seen on the terminal: status messages without decoration,
+    /// warnings and errors with a prefix.
+    fn plain(&self, level: Level, message: &str, fields: &str) -> String {
+        let prefix = match level {
+            Level::ERROR => "Error: ",
+            Level::WARN => "Warning: ",
+            Level::INFO => "",
+            Level::DEBUG => "debug: ",
+            Level::TRACE => "trace: ",
+        };
+        format!("{prefix}{message}{fields}")
+    }
+
+    fn text(&self, level: Level, message: &str, fields: &str) -> String {
+        let time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        format!("{time} {level:>5} {message}{fields}")
+    }
+
+    fn json(&self, event: &Event<'_>, visitor: JsonVisitor) -> String {
+        let metadata = event.metadata();
+        let mut record = Map::new();
+        record.insert(
+            "timestamp".into(),
+            Utc::now()
+                .to_rfc3339_opts(SecondsFormat::Millis, true)
+                .into(),
+        );
+        record.insert("level".into(), metadata.level().as_str().into());
+        record.insert("target".into(), metadata.target().into());
+        record.insert("message".into(), visitor.message.into());
+        record.extend(visitor.fields);
+        Value::Object(record).to_string()
+    }
+
+    fn write(&self, level: Level, line: &str) {
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            let _ = writeln!(file, "{line}");
+            return;
+        }
+        crate::progress::clear_line();
+        // Status goes to stdout unless stdout carries the archive
+        if level == Level::INFO && !crate::STATUS_TO_STDERR.load(Ordering::Relaxed) {
+            println!("{line}");
+        } else {
+            eprintln!("{line}");
+        }
+    }
+}
+
+impl Subscriber for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        // Dependencies log through tracing as well; only our own events are shown
+        *metadata.level() <= self.level && metadata.target().starts_with("ssbt")
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::from_level(self.level))
+    }
+
+    fn new_span(&self, _span: &Attributes<'_>) -> Id {
+        Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let level = *event.metadata().level();
+        let line = if self.json {
+            let mut visitor = JsonVisitor::default();
+            event.record(&mut visitor);
+            self.json(event, visitor)
+        } else {
+            let mut visitor = TextVisitor::default();
+            event.record(&mut visitor);
+            if self.file.is_some() {
+                self.text(level, &visitor.message, &visitor.fields)
+            } else {
+                self.plain(level, &visitor.message, &visitor.fields)
+            }
+        };
+        self.write(level, &line);
+    }
+
+    fn enter(&self, _span: &Id) {}
+
+    fn exit(&self, _span: &Id) {}
+}
+
+/// Collects the message and appends other fields as ` key=value`.
+#[derive(Default)]
+struct TextVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for TextVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+        } else {
+            let _ = write!(self.fields, " {}={value:?}", field.name());
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            let _ = write!(self.fields, " {}={value}", field.name());
+        }
+    }
+}
+
+/// Collects fields as JSON values, keeping numbers and booleans typed.
+#[derive(Default)]
+struct JsonVisitor {
+    message: String,
+    fields: Map<String, Value>,
+}
+
+impl Visit for JsonVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        let value = format!("{value:?}");
+        match field.name() {
+            "message" => self.message = value,
+            name => {
+                self.fields.insert(name.into(), value.into());
+            }
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.message = value.to_string(),
+            name => {
+                self.fields.insert(name.into(), value.into());
+            }
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.fields.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.fields.insert(field.name().into(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.fields.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.fields.insert(field.name().into(), value.into());
+    }
+}
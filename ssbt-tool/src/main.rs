@@ -1,37 +1,127 @@
-pub mod fs_utils;
-pub mod naming;
-pub mod packaging;
-pub mod process;
-pub mod shell_exec;
-pub mod sink;
+pub mod catalog;
+pub mod commands;
+pub mod config_file;
+pub mod json_output;
+pub mod logging;
+pub mod metrics;
+pub mod notify;
+pub mod report;
 
-use anyhow::anyhow;
-use clap::Parser;
-use fs_utils::{list_total_files, total_size};
-use ssbt_lib::Config;
-use std::{collections::HashMap, env, fs};
+// The engine lives in ssbt-lib
+pub use ssbt_lib::{
+    age, cancel, docker, fetch, file_reader, fs_utils, incremental, journal, naming, packaging,
+    process, progress, repo, run_lock, secrets, shell_exec, sink,
+};
 
-use crate::{fs_utils::encode_size, process::process_files_within_tokio};
+use anyhow::{Context, anyhow};
+use catalog::{Catalog, CatalogEntry};
+use clap::{Parser, Subcommand};
+use config_file::read_config_file;
+use fs_utils::{apply_size_limit, list_total_files, parse_size};
+use notify::RunSummary;
+use packaging::{ArchiveFormat, estimate_archive_size, estimate_compression_ratio};
+use ssbt_lib::{
+    Backup, Config, DockerConfig, EncryptConfig, KubernetesConfig, MetricsConfig, NotifyConfig,
+    SourcePath, SsbtError, Totals,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    io::IsTerminal,
+    process::ExitCode,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+use tracing::{debug, error, warn};
+
+use crate::{fs_utils::encode_size, process::BackupOutcome};
+
+/// Set when the archive itself is streamed to stdout, so status messages
+/// have to go to stderr instead of corrupting it.
+pub static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
 
 #[derive(Parser, Debug)]
 #[command(author, version, about = "SSBT CLI Backup Tool", long_about = None)]
 pub struct Cli {
-    /// Output path (can be defined via config/env)
+    /// Output path, `-` for stdout (can be defined via config/env); repeat to
+    /// write the same archive to several outputs
     #[arg(short, long)]
-    pub output: Option<String>,
+    pub output: Vec<String>,
 
-    /// Configuration file (YAML or JSON)
+    /// Variable `name=value` of the naming templates, expanded for `%name%`
+    /// (can be specified multiple times)
+    #[arg(long = "name-var", value_name = "NAME=VALUE", value_parser = parse_name_var)]
+    pub name_vars: Vec<(String, String)>,
+
+    /// Configuration file (YAML, JSON or TOML)
     #[arg(short, long)]
     pub config: Option<String>,
 
+    /// Profile of the configuration file to apply
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    /// Don't look for ssbt.yaml, ~/.config/ssbt/config.yaml or
+    /// /etc/ssbt/config.yaml when no configuration file is given
+    #[arg(long)]
+    pub no_default_config: bool,
+
     /// Output format [zip|7z|tar]
     #[arg(short, long)]
     pub format: Option<String>,
 
-    /// Authentication token
+    /// Authentication token (or `file:PATH` / `env:NAME` / `cmd:COMMAND` reference)
     #[arg(long)]
     pub authentication: Option<String>,
 
+    /// File holding the authentication token
+    #[arg(long, value_name = "PATH")]
+    pub authentication_file: Option<String>,
+
+    /// Command printing the authentication token (run with `sh -c`)
+    #[arg(long, value_name = "COMMAND")]
+    pub authentication_cmd: Option<String>,
+
+    /// Authentication type [bearer|basic|digest|raw] (basic and digest expect `user:password`)
+    #[arg(long)]
+    pub auth_type: Option<String>,
+
+    /// Send the authentication token in this header instead of `Authorization`
+    #[arg(long, value_name = "NAME")]
+    pub auth_header: Option<String>,
+
+    /// Extra HTTP header `Name:Value` (can be specified multiple times)
+    #[arg(short = 'H', long = "header", value_parser = parse_header)]
+    pub headers: Vec<(String, String)>,
+
+    /// Query parameter `name=value` added to HTTP upload URLs (can be specified multiple times)
+    #[arg(long = "query", value_parser = parse_query)]
+    pub http_query: Vec<(String, String)>,
+
+    /// Method of HTTP uploads [post|put] (default: post)
+    #[arg(long)]
+    pub http_method: Option<String>,
+
+    /// Proxy for HTTP(S) requests (default: HTTP_PROXY/HTTPS_PROXY)
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<String>,
+
+    /// PEM file with extra CA certificates to trust
+    #[arg(long, value_name = "PATH")]
+    pub ca_cert: Option<String>,
+
+    /// PEM client certificate for mutual TLS (with the key, or see --client-key)
+    #[arg(long, value_name = "PATH")]
+    pub client_cert: Option<String>,
+
+    /// PEM private key of --client-cert
+    #[arg(long, value_name = "PATH")]
+    pub client_key: Option<String>,
+
+    /// Skip TLS certificate verification (lab environments only)
+    #[arg(long)]
+    pub insecure: bool,
+
     /// Protocol [http|https|multipart|scp|tus]
     #[arg(long)]
     pub protocol: Option<String>,
@@ -44,6 +134,10 @@ pub struct Cli {
     #[arg(short, long, default_value_t = 0)]
     pub max_size: u64,
 
+    /// When the files exceed --max-size: fail, skip-largest or oldest-first
+    #[arg(long, value_name = "POLICY")]
+    pub on_size_exceeded: Option<String>,
+
     /// Command to execute before backup
     #[arg(short, long)]
     pub before: Option<String>,
@@ -52,14 +146,294 @@ pub struct Cli {
     #[arg(short, long)]
     pub after: Option<String>,
 
+    /// Run the --after command even when the backup fails
+    #[arg(long)]
+    pub after_on_failure: bool,
+
+    /// Command to execute when the backup succeeded (can be specified multiple times)
+    #[arg(long, value_name = "COMMAND")]
+    pub on_success: Vec<String>,
+
+    /// Command to execute when the backup failed (can be specified multiple times)
+    #[arg(long, value_name = "COMMAND")]
+    pub on_failure: Vec<String>,
+
     /// Patterns to skip (can be specified multiple times)
     #[arg(short = 's', long)]
     pub skip: Vec<String>,
 
+    /// Only back up files matching these patterns (can be specified multiple times)
+    #[arg(short = 'I', long)]
+    pub include: Vec<String>,
+
+    /// Exclude files matching the gitignore-style rules in FILE (can be specified multiple times)
+    #[arg(long, value_name = "FILE")]
+    pub exclude_from: Vec<String>,
+
+    /// Don't read .ssbtignore files in the backed up directories
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub no_ssbtignore: bool,
+
+    /// Keep the order directories list their files in instead of sorting them by name
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub no_sort: bool,
+
+    /// Name archive entries by their file name alone, dropping their directories
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub flatten: bool,
+
+    /// Directories to read at once while listing files (default: number of CPUs)
+    #[arg(long, value_name = "N")]
+    pub walk_threads: Option<usize>,
+
+    /// Start the archive while directories are still being walked, in walk order
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub stream_walk: bool,
+
+    /// Symlinks in backed up directories: follow, skip or store (as links, tar and zip)
+    #[arg(long, value_name = "POLICY")]
+    pub symlinks: Option<String>,
+
+    /// Don't store permissions, ownership and xattrs, nor apply them on restore
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub no_preserve_permissions: bool,
+
+    /// Leave out directories with nothing to back up in them instead of storing them empty
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub no_empty_dirs: bool,
+
+    /// Skip files larger than SIZE (e.g. 500MB, 1GiB)
+    #[arg(long, value_name = "SIZE")]
+    pub skip_larger_than: Option<String>,
+
+    /// Only back up files modified within AGE (e.g. 7d, 12h) or since a date (2024-01-31)
+    #[arg(long, value_name = "AGE")]
+    pub modified_within: Option<String>,
+
+    /// Only back up files modified before AGE ago (e.g. 30d) or before a date
+    #[arg(long, value_name = "AGE")]
+    pub modified_before: Option<String>,
+
+    /// Store hard-linked and identical files only once
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub dedup: bool,
+
+    /// Store hard-linked files in full under every name (tar)
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub no_hard_links: bool,
+
     /// Enable compression
     #[arg(long, action = clap::ArgAction::SetTrue)]
     pub compress: bool,
 
+    /// Extensions stored without compression in zip archives, comma separated
+    /// (default: common image, media and archive formats)
+    #[arg(long, value_name = "EXTS")]
+    pub no_compress_extensions: Option<String>,
+
+    /// Compression for tar archives when `--compress` is set [gzip|zstd]
+    #[arg(long)]
+    pub compression_algorithm: Option<String>,
+
+    /// What to do if the output file exists [never|always|if-larger|auto-suffix]
+    #[arg(long)]
+    pub overwrite: Option<String>,
+
+    /// Don't fsync local archives before reporting success (faster, but a power
+    /// loss may truncate them)
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub no_fsync: bool,
+
+    /// Read the list of files to back up from FILE (`-` for stdin), one per line
+    /// or NUL-delimited, instead of walking the configured paths
+    #[arg(long, value_name = "FILE")]
+    pub files_from: Option<String>,
+
+    /// Store what is piped to stdin as an archive entry of this name, next to the paths
+    #[arg(long, value_name = "NAME")]
+    pub stdin_name: Option<String>,
+
+    /// Encrypt zip entries with this password (or `file:PATH` / `env:NAME` /
+    /// `prompt:` reference)
+    #[arg(long, value_name = "PASSWORD")]
+    pub zip_password: Option<String>,
+
+    /// Zip entry encryption when `zip_password` is set [aes256|zipcrypto]
+    #[arg(long)]
+    pub zip_encryption: Option<String>,
+
+    /// What to do when a file can't be read [fail|skip|warn|retry]
+    #[arg(long, visible_alias = "on-file-error")]
+    pub on_read_error: Option<String>,
+
+    /// Read attempts for `retry` and for re-reading files that changed while being read
+    #[arg(long)]
+    pub read_retries: Option<u32>,
+
+    /// Copy files aside and re-read them if they change while being archived
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub stabilize: bool,
+
+    /// What to do when a file changes while being read [flag|reread|fail]
+    #[arg(long, value_name = "POLICY")]
+    pub on_change: Option<String>,
+
+    /// Read the files from Volume Shadow Copies of their volumes (Windows, as administrator)
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub vss: bool,
+
+    /// Files to read and hash in parallel while archiving (default: number of CPUs)
+    #[arg(short, long, value_name = "N")]
+    pub jobs: Option<usize>,
+
+    /// Check the stored size and hashes after uploading (WebDAV, HTTP PUT, GCS)
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub verify_upload: bool,
+
+    /// Private key for SSH outputs (default: ssh-agent, then ~/.ssh/id_*)
+    #[arg(long, value_name = "FILE")]
+    pub ssh_key: Option<String>,
+
+    /// Connect to SSH hosts that are not in ~/.ssh/known_hosts
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub ssh_accept_unknown_hosts: bool,
+
+    /// Use active FTP (the server connects back) instead of passive mode
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub ftp_active: bool,
+
+    /// Encrypt the archive to this age recipient (`age1...` or a file of them; can be repeated)
+    #[arg(long, value_name = "RECIPIENT")]
+    pub recipient: Vec<String>,
+
+    /// Retries for failed network uploads (default: 3)
+    #[arg(long, value_name = "N")]
+    pub retries: Option<u32>,
+
+    /// Seconds before the first upload retry, doubled for every further one (default: 1)
+    #[arg(long, value_name = "SECS")]
+    pub retry_backoff: Option<f64>,
+
+    /// Upper bound in seconds for the delay between upload retries (default: 60)
+    #[arg(long, value_name = "SECS")]
+    pub retry_max_delay: Option<f64>,
+
+    /// Don't add `ssbt-manifest.json` (file list with SHA-256 hashes) to the archive
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub no_manifest: bool,
+
+    /// Log verbosity [error|warn|info|debug|trace] (default: info)
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Append log records to this file instead of printing them
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<String>,
+
+    /// Log one JSON object per line, for log collectors
+    #[arg(long, global = true, action = clap::ArgAction::SetTrue)]
+    pub log_json: bool,
+
+    /// Print the dry run or the run's result (or error) as JSON on stdout
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub json: bool,
+
+    /// Write a JSON report of the run (timings, sizes, skipped files) to PATH
+    #[arg(long, value_name = "PATH")]
+    pub report: Option<String>,
+
+    /// Ping URL/start, URL on success and URL/fail on failure (healthchecks.io style)
+    #[arg(long, value_name = "URL")]
+    pub healthcheck_url: Option<String>,
+
+    /// How long a healthcheck ping may take (default: 10s)
+    #[arg(long, value_name = "DURATION")]
+    pub healthcheck_timeout: Option<String>,
+
+    /// Retries of a failed healthcheck ping (default: 2)
+    #[arg(long, value_name = "N")]
+    pub healthcheck_retries: Option<u32>,
+
+    /// Only print warnings and errors, no status messages or progress bar
+    #[arg(short, long, global = true, action = clap::ArgAction::SetTrue)]
+    pub quiet: bool,
+
+    /// Don't show the progress bar (shown by default when stderr is a terminal)
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub no_progress: bool,
+
+    /// Write a checksum file next to every archive [sha256|blake3|none], comma separated for both
+    #[arg(long, value_name = "ALG")]
+    pub checksum: Option<String>,
+
+    /// Split the archive into volumes of at most SIZE (e.g. 2GiB), stored as <archive>.001, .002, ...
+    #[arg(long, value_name = "SIZE")]
+    pub split_size: Option<String>,
+
+    /// Bytes per checksummed chunk of tus uploads (default: 8MiB)
+    #[arg(long, value_name = "SIZE")]
+    pub upload_chunk_size: Option<String>,
+
+    /// Bytes buffered between the archive writer and each output (default: 256KiB)
+    #[arg(long, value_name = "SIZE")]
+    pub upload_buffer_size: Option<String>,
+
+    /// Upload bandwidth per second to assume for the upload time estimate of --dry (e.g. 10MB); nothing is throttled
+    #[arg(long, value_name = "SIZE")]
+    pub estimate_bandwidth: Option<String>,
+
+    /// Only archive files changed since the run described by `--since-manifest`
+    #[arg(long, action = clap::ArgAction::SetTrue)]
+    pub incremental: bool,
+
+    /// Manifest of the previous run, or an archive containing one (path or URL)
+    #[arg(long, value_name = "PATH|URL")]
+    pub since_manifest: Option<String>,
+
+    /// Back up into a deduplicating repository directory instead of an archive
+    #[arg(long, global = true)]
+    pub repo: Option<String>,
+
+    /// Catalog file recording every run, `off` to disable (default: ~/.local/share/ssbt/catalog.json)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub catalog: Option<String>,
+
+    /// Wait up to DURATION (e.g. 30m) for another run writing to the same outputs
+    #[arg(long, global = true, value_name = "DURATION")]
+    pub wait_for_lock: Option<String>,
+
+    /// Don't lock the outputs against concurrent runs
+    #[arg(long, global = true)]
+    pub no_lock: bool,
+
+    /// `prune`: keep this many of the newest archives
+    #[arg(long, global = true, value_name = "N")]
+    pub keep_last: Option<u32>,
+
+    /// `prune`: keep the newest archive of each of the last N days
+    #[arg(long, global = true, value_name = "N")]
+    pub keep_daily: Option<u32>,
+
+    /// `prune`: keep the newest archive of each of the last N weeks
+    #[arg(long, global = true, value_name = "N")]
+    pub keep_weekly: Option<u32>,
+
+    /// `prune`: keep the newest archive of each of the last N months
+    #[arg(long, global = true, value_name = "N")]
+    pub keep_monthly: Option<u32>,
+
+    /// How a new repository splits files into blobs [cdc|fixed]
+    #[arg(long)]
+    pub chunker: Option<String>,
+
+    /// Check the configuration for unknown keys and invalid values, then exit
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Check that every output is reachable and writable without writing an archive, then exit (also part of --dry)
+    #[arg(long)]
+    pub check_destination: bool,
+
     /// Generate YAML config to stdout
     #[arg(long, action = clap::ArgAction::SetTrue)]
     pub generate_yaml_config: bool,
@@ -67,22 +441,126 @@ pub struct Cli {
     /// Files or directories to backup
     #[arg()]
     pub paths: Vec<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// List entries of a local or remote archive, or past backup runs
+    #[command(visible_alias = "ls")]
+    List(commands::list::ListArgs),
+    /// Write one entry of a local or remote archive to stdout
+    Cat(commands::cat::CatArgs),
+    /// Show a backup run recorded in the catalog
+    Show(commands::show::ShowArgs),
+    /// Extract a local or remote archive
+    Restore(commands::restore::RestoreArgs),
+    /// Check a local or remote archive for corruption
+    Verify(commands::verify::VerifyArgs),
+    /// Show the files added, removed and changed between two backups
+    Diff(commands::diff::DiffArgs),
+    /// Inspect and restore snapshots of a `--repo` repository
+    Repo(commands::repo::RepoArgs),
+    /// Decrypt an archive encrypted with `encrypt` or `--recipient`
+    Decrypt(commands::decrypt::DecryptArgs),
+    /// Generate an age key pair for `--recipient`
+    Keygen(commands::decrypt::KeygenArgs),
+    /// Delete old archives from the outputs according to the keep_* policy
+    Prune(commands::prune::PruneArgs),
+    /// Back up, then again whenever files in the backed up paths change
+    Watch(commands::watch::WatchArgs),
+    /// Pick up backups that were killed while writing a local archive
+    Resume(commands::resume::ResumeArgs),
+    /// Receive archives uploaded by ssbt over HTTP
+    Serve(commands::serve::ServeArgs),
 }
 
-fn main() -> anyhow::Result<()> {
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            // Errors before the logger is set up (e.g. a broken config file)
+            if tracing::dispatcher::has_been_set() {
+                error!("{e:#}");
+            } else {
+                eprintln!("Error: {e:#}");
+            }
+            json_output::print_error(&format!("{e:#}"));
+            match e.downcast_ref::<SsbtError>() {
+                Some(SsbtError::Config(_)) => ExitCode::from(2),
+                Some(SsbtError::Walk(_)) => ExitCode::from(3),
+                Some(SsbtError::Packaging(_)) => ExitCode::from(4),
+                Some(SsbtError::Upload { .. }) => ExitCode::from(5),
+                Some(SsbtError::Hook(_)) => ExitCode::from(6),
+                Some(SsbtError::SizeLimitExceeded(_)) => ExitCode::from(42),
+                Some(SsbtError::Interrupted) => ExitCode::from(cancel::EXIT_CODE),
+                None if e.is::<cancel::Interrupted>() => ExitCode::from(cancel::EXIT_CODE),
+                None => ExitCode::FAILURE,
+            }
+        }
+    }
+}
+
+fn run() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
     // Step 1: Read environment
-    let env_config = read_env();
+    let env_config = read_env()?;
+
+    let mut discovered = None;
+    let config_path = match cli.config.clone().or(env_config.config.clone()) {
+        Some(path) => Some(path),
+        None if cli.no_default_config => None,
+        None => {
+            discovered = config_file::find_default_config();
+            discovered.clone()
+        }
+    };
+    if cli.check_config {
+        return config_file::check(config_path.as_deref(), env_config, cli_to_config(&cli));
+    }
 
     // Step 2: Read config file (if exists)
     let mut file_config = Config::default();
-    if let Some(path) = cli.config.clone().or(env_config.config.clone()) {
-        file_config = read_config_file(&path)?;
+    if let Some(path) = config_path {
+        file_config = read_config_file(&path).map_err(SsbtError::Config)?;
     }
+    let profile = cli.profile.clone().or(env_config.profile.clone());
+    let file_config = apply_profile(file_config, profile).map_err(SsbtError::Config)?;
 
     // Step 3: Merge configs: env < file < CLI
     let mut merged = merge_configs(env_config, file_config, cli_to_config(&cli));
+    logging::init(&merged)?;
+    secrets::authentication_source(&mut merged)?;
+    if let Some(path) = &discovered {
+        debug!("Using configuration file {path}");
+    }
+
+    // Subcommands only need the merged config, not backup paths or output;
+    // `watch` runs backups, so it goes through the checks below
+    let watch = match &cli.command {
+        None => None,
+        Some(Command::Watch(args)) => {
+            if !args.paths.is_empty() {
+                merged.paths = Some(args.paths.iter().cloned().map(SourcePath::from).collect());
+            }
+            Some(args)
+        }
+        Some(Command::List(args)) => return commands::list::run(args, &merged),
+        Some(Command::Show(args)) => return commands::show::run(args, &merged),
+        Some(Command::Cat(args)) => return commands::cat::run(args, &merged),
+        Some(Command::Restore(args)) => return commands::restore::run(args, &merged),
+        Some(Command::Verify(args)) => return commands::verify::run(args, &merged),
+        Some(Command::Diff(args)) => return commands::diff::run(args, &merged),
+        Some(Command::Repo(args)) => return commands::repo::run(args, &merged),
+        Some(Command::Decrypt(args)) => return commands::decrypt::run(args, &merged),
+        Some(Command::Keygen(args)) => return commands::decrypt::keygen(args),
+        Some(Command::Prune(args)) => return commands::prune::run(args, &merged),
+        Some(Command::Resume(args)) => return commands::resume::run(args, &merged),
+        Some(Command::Serve(args)) => return commands::serve::run(args, &merged),
+    };
 
     // Apply defaults for optional parameters
     if merged.format.is_none() {
@@ -95,17 +573,66 @@ fn main() -> anyhow::Result<()> {
         merged.compress = Some(false);
     }
 
+    let outputs = merged.output.clone().unwrap_or_default();
+    if merged.json.unwrap_or(false) {
+        if outputs.iter().any(|o| o == "-") {
+            return Err(anyhow!(
+                "--json prints the result on stdout, it can't be combined with --output -"
+            ));
+        }
+        // Keep stdout for the JSON document
+        STATUS_TO_STDERR.store(true, Ordering::Relaxed);
+        json_output::enable();
+    }
+
     // Validate required fields (after merging all sources)
-    if merged.output.as_deref().unwrap_or("").is_empty() {
-        eprintln!("Error: output path (--output or config:output or SSBT_OUTPUT) is required");
-        std::process::exit(2);
+    if merged.repo.is_none() && outputs.iter().all(|o| o.is_empty()) {
+        return Err(SsbtError::Config(anyhow!(
+            "output path (--output or config:output or SSBT_OUTPUT) or --repo is required"
+        ))
+        .into());
+    }
+
+    if cli.check_destination {
+        let checks = check_destinations(&merged)?;
+        if json_output::enabled() {
+            json_output::print(&serde_json::json!({ "destinations": checks }))?;
+        }
+        return report_destinations(&checks);
+    }
+
+    if merged.files_from.is_none()
+        && merged.paths.as_ref().map(|p| p.is_empty()).unwrap_or(true)
+        && merged
+            .sources
+            .as_ref()
+            .map(|s| s.is_empty())
+            .unwrap_or(true)
+        && merged.stdin_name.is_none()
+        && merged
+            .virtual_files
+            .as_ref()
+            .map(|v| v.is_empty())
+            .unwrap_or(true)
+        && merged.k8s.is_none()
+    {
+        return Err(SsbtError::Config(anyhow!(
+            "at least one path must be provided (CLI argument, config:paths, SSBT_PATHS, --files-from, --stdin-name, config:sources, config:virtual_files or config:k8s)"
+        ))
+        .into());
     }
 
-    if merged.paths.as_ref().map(|p| p.is_empty()).unwrap_or(true) {
-        eprintln!(
-            "Error: at least one path must be provided (CLI argument, config:paths, or SSBT_PATHS)"
-        );
-        std::process::exit(3);
+    incremental::validate(&merged, watch.is_some()).map_err(SsbtError::Config)?;
+
+    if outputs.iter().any(|o| o == "-") {
+        STATUS_TO_STDERR.store(true, Ordering::Relaxed);
+        // A binary archive would only garble the terminal
+        let writes_archive = !cli.generate_yaml_config && !merged.dry.unwrap_or(false);
+        if writes_archive && std::io::stdout().is_terminal() {
+            return Err(anyhow!(
+                "refusing to write the archive to a terminal; redirect or pipe stdout when using --output -"
+            ));
+        }
     }
 
     // Apply defaults only if not defined anywhere
@@ -125,35 +652,213 @@ fn main() -> anyhow::Result<()> {
 
     // Dry run: just list parameters
     if merged.dry.unwrap_or(false) {
+        let mut files = list_total_files(&merged)?;
+        let total = apply_size_limit(&merged, &mut files)?;
+        let format: ArchiveFormat = merged.format.as_deref().unwrap_or("zip").parse()?;
+        let naming = process::EntryNaming::for_files(&merged, &files)?;
+        let estimated = match merged.repo {
+            Some(_) => None,
+            None => {
+                let entries = process::prepare_entries(files.clone(), &naming);
+                let sized: Vec<_> = entries
+                    .iter()
+                    .map(|(name, path)| {
+                        let size = fs::metadata(path).ok().filter(|m| m.is_file());
+                        (name.clone(), size.map_or(0, |m| m.len()))
+                    })
+                    .collect();
+                let ratio = estimate_compression_ratio(&merged, format, &entries)?;
+                let size = estimate_archive_size(format, &sized) as f64 * ratio;
+                Some((size as u64, ratio))
+            }
+        };
+        let bandwidth = merged
+            .estimate_bandwidth
+            .as_deref()
+            .map(parse_size)
+            .transpose()
+            .context("invalid estimate_bandwidth")?
+            .filter(|rate| *rate > 0);
+        let upload_secs = estimated
+            .zip(bandwidth)
+            .map(|((size, _), rate)| size as f64 / rate as f64);
+        let destinations = check_destinations(&merged)?;
+        if json_output::enabled() {
+            json_output::print(&json_output::DryRun {
+                dry_run: true,
+                config: &merged,
+                files: files.len(),
+                size: total,
+                estimated_archive_size: estimated.map(|(size, _)| size),
+                estimated_compression_ratio: estimated.map(|(_, ratio)| ratio),
+                estimated_upload_secs: upload_secs,
+                destinations: &destinations,
+                paths: &files,
+            })?;
+            return report_destinations(&destinations);
+        }
+
         println!("--- DRY RUN ---");
         println!("{}", serde_yaml::to_string(&merged)?);
-        let files = list_total_files(&merged)?;
-        let total = total_size(&merged, &files)?;
         println!("Total files: {}", files.len());
         println!("Total size: {}", encode_size(total));
+        if let Some((estimated, ratio)) = estimated {
+            println!(
+                "Estimated {:?} archive size: {}{}",
+                format,
+                encode_size(estimated),
+                if ratio < 1.0 {
+                    format!(
+                        " (data compressed to about {:.0}%, from samples)",
+                        ratio * 100.0
+                    )
+                } else {
+                    String::new()
+                }
+            );
+        }
+        if let (Some(secs), Some(rate)) = (upload_secs, bandwidth) {
+            println!(
+                "Estimated upload time at {}/s: {}",
+                encode_size(rate),
+                progress::format_duration(secs)
+            );
+        }
+        report_destinations(&destinations)?;
         for f in files {
             println!("{}", f.display());
         }
         return Ok(());
     }
 
-    let files = list_total_files(&merged)?;
-    let total = total_size(&merged, &files)?;
-    println!("Total files: {}", files.len());
-    println!("Total size: {}", encode_size(total));
-    if merged.before.as_ref().is_some_and(|x| !x.is_empty()) {
-        shell_exec::execute_and_stream_command(merged.before.as_ref().unwrap())?;
+    cancel::install()?;
+    match watch {
+        Some(args) => commands::watch::run(args, merged),
+        None => backup(merged).map(drop),
     }
-    let after = merged.after.clone();
-    process_files_within_tokio(merged, files).map_err(|e| anyhow!("{}", e))?;
-    if after.as_ref().is_some_and(|x| !x.is_empty()) {
-        shell_exec::execute_and_stream_command(after.as_ref().unwrap())?;
+}
+
+/// Checks every output, or the repository, without writing an archive.
+fn check_destinations(config: &Config) -> Result<Vec<json_output::DestinationCheck>, SsbtError> {
+    let checked = process::check_destinations_within_tokio(config)?;
+    Ok(checked
+        .into_iter()
+        .map(|(destination, result)| {
+            let (ok, message) = match result {
+                Ok(found) => (true, found),
+                Err(e) => (false, format!("{e:#}")),
+            };
+            json_output::DestinationCheck {
+                destination,
+                ok,
+                message,
+            }
+        })
+        .collect())
+}
+
+/// Prints the destination checks, unless they went into the JSON document,
+/// and fails if any destination can't be written.
+fn report_destinations(checks: &[json_output::DestinationCheck]) -> anyhow::Result<()> {
+    if !json_output::enabled() {
+        for check in checks {
+            let status = if check.ok { "OK" } else { "FAILED" };
+            println!(
+                "Destination {}: {status} ({})",
+                check.destination, check.message
+            );
+        }
+    }
+    let failed = checks.iter().filter(|check| !check.ok).count();
+    if failed > 0 {
+        return Err(SsbtError::Upload {
+            status: None,
+            error: anyhow!(
+                "{failed} of {} destination(s) can't be written",
+                checks.len()
+            ),
+        }
+        .into());
     }
     Ok(())
 }
 
+/// Runs one backup, with notifications, metrics, the catalog entry, the
+/// report and the JSON result.
+fn backup(mut merged: Config) -> anyhow::Result<BackupOutcome> {
+    let _lock = run_lock::acquire(&merged)?;
+    let notifier = notify::Notifier::from_config(&merged)?;
+    let metrics = metrics::Metrics::from_config(&merged)?;
+    let job = notifier
+        .as_ref()
+        .map_or("ssbt".to_string(), |n| n.job_name());
+    let catalog = Catalog::from_config(&merged);
+    if merged.seq.is_none()
+        && let Some(catalog) = &catalog
+    {
+        match catalog.runs_of(&job) {
+            Ok(runs) => merged.seq = Some(runs as u64 + 1),
+            Err(e) => warn!("{e:#}, numbering the run 1"),
+        }
+    }
+    let mut summary = RunSummary::new(
+        job,
+        merged
+            .output
+            .as_ref()
+            .map(|outputs| outputs.join(", "))
+            .or(merged.repo.clone()),
+    );
+    let started = Instant::now();
+    if let Some(notifier) = &notifier {
+        notifier.start();
+    }
+
+    let report = merged.report.clone().filter(|path| !path.is_empty());
+    let mut totals = Totals::default();
+    let result = Backup::run_with_totals(merged, &mut totals);
+    summary.files = totals.files;
+    summary.size = totals.size;
+
+    summary.duration_secs = started.elapsed().as_secs_f64();
+    summary.success = result.is_ok();
+    summary.error = result.as_ref().err().map(|e| format!("{e:#}"));
+    let outcome = result.as_ref().ok();
+    if let Some(notifier) = &notifier {
+        notifier.finish(&summary, outcome);
+    }
+    if let Some(metrics) = &metrics {
+        metrics.record(&summary, outcome);
+    }
+    let mut id = None;
+    if let Some(catalog) = catalog {
+        let recorded = CatalogEntry::new(&summary, outcome).and_then(|entry| {
+            let id = entry.id.clone();
+            catalog.append(entry).map(|()| id)
+        });
+        match recorded {
+            Ok(recorded) => id = Some(recorded),
+            Err(e) => warn!("failed to record the run in the catalog: {e:#}"),
+        }
+    }
+    if let Some(path) = report {
+        let report = report::Report::new(id.as_deref(), &summary, &totals, outcome);
+        if let Err(e) = report.write(&path) {
+            warn!("{e:#}");
+        }
+    }
+    if json_output::enabled() {
+        json_output::print(&json_output::RunResult::new(
+            id.as_deref(),
+            &summary,
+            outcome,
+        ))?;
+    }
+    Ok(result?)
+}
+
 /// Reads environment variables prefixed with SSBT_
-fn read_env() -> Config {
+fn read_env() -> anyhow::Result<Config> {
     let mut cfg = Config::default();
     let vars: HashMap<String, String> = env::vars().collect();
 
@@ -163,14 +868,45 @@ fn read_env() -> Config {
         };
     }
 
-    cfg.output = get_env!("OUTPUT");
+    cfg.output = get_env!("OUTPUT").map(|v| split_list(&v));
     cfg.config = get_env!("CONFIG");
+    cfg.profile = get_env!("PROFILE");
     cfg.format = get_env!("FORMAT");
     cfg.authentication = get_env!("AUTHENTICATION");
+    cfg.authentication_file = get_env!("AUTHENTICATION_FILE");
+    cfg.authentication_cmd = get_env!("AUTHENTICATION_CMD");
+    cfg.auth_type = get_env!("AUTH_TYPE");
+    cfg.auth_header = get_env!("AUTH_HEADER");
+    // One header per line, as values may contain commas
+    cfg.headers = get_env!("HEADERS")
+        .map(|v| {
+            v.lines()
+                .filter(|h| !h.trim().is_empty())
+                .map(parse_header)
+                .collect::<Result<_, _>>()
+        })
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("SSBT_HEADERS: {e}"))?;
+    cfg.http_query = get_env!("HTTP_QUERY")
+        .map(|v| split_list(&v).iter().map(|q| parse_query(q)).collect())
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("SSBT_HTTP_QUERY: {e}"))?;
+    cfg.http_method = get_env!("HTTP_METHOD");
+    cfg.proxy = get_env!("PROXY");
+    cfg.ca_cert = get_env!("CA_CERT");
+    cfg.client_cert = get_env!("CLIENT_CERT");
+    cfg.client_key = get_env!("CLIENT_KEY");
+    cfg.insecure =
+        get_env!("INSECURE").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
     cfg.protocol = get_env!("PROTOCOL");
     cfg.before = get_env!("BEFORE");
     cfg.after = get_env!("AFTER");
+    cfg.on_success = get_env!("ON_SUCCESS").map(|v| vec![v]);
+    cfg.on_failure = get_env!("ON_FAILURE").map(|v| vec![v]);
+    cfg.after_on_failure = get_env!("AFTER_ON_FAILURE")
+        .map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
     cfg.max_size = get_env!("MAX_SIZE").and_then(|v| v.parse().ok());
+    cfg.on_size_exceeded = get_env!("ON_SIZE_EXCEEDED");
     cfg.dry = get_env!("DRY").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
     cfg.skip = get_env!("SKIP").map(|v| {
         v.split(',')
@@ -178,46 +914,365 @@ fn read_env() -> Config {
             .filter(|s| !s.is_empty())
             .collect()
     });
+    cfg.include = get_env!("INCLUDE").map(|v| {
+        v.split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    });
+    cfg.exclude_from = get_env!("EXCLUDE_FROM").map(|v| split_list(&v));
+    cfg.ssbtignore =
+        get_env!("SSBTIGNORE").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.sort_files =
+        get_env!("SORT_FILES").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.flatten =
+        get_env!("FLATTEN").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.walk_threads = get_env!("WALK_THREADS").and_then(|v| v.parse().ok());
+    cfg.stream_walk =
+        get_env!("STREAM_WALK").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.symlinks = get_env!("SYMLINKS");
+    cfg.preserve_permissions = get_env!("PRESERVE_PERMISSIONS")
+        .map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.preserve_empty_dirs = get_env!("PRESERVE_EMPTY_DIRS")
+        .map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.skip_larger_than = get_env!("SKIP_LARGER_THAN");
+    cfg.modified_within = get_env!("MODIFIED_WITHIN");
+    cfg.modified_before = get_env!("MODIFIED_BEFORE");
+    cfg.dedup = get_env!("DEDUP").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.hard_links =
+        get_env!("HARD_LINKS").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
     cfg.compress =
         get_env!("COMPRESS").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
-    cfg
+    cfg.no_compress_extensions = get_env!("NO_COMPRESS_EXTENSIONS").map(|v| split_list(&v));
+    cfg.compression_algorithm = get_env!("COMPRESSION_ALGORITHM");
+    cfg.overwrite = get_env!("OVERWRITE");
+    cfg.fsync = get_env!("FSYNC").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.files_from = get_env!("FILES_FROM");
+    cfg.stdin_name = get_env!("STDIN_NAME");
+    cfg.zip_password = get_env!("ZIP_PASSWORD");
+    cfg.zip_encryption = get_env!("ZIP_ENCRYPTION");
+    cfg.on_read_error = get_env!("ON_READ_ERROR").or_else(|| get_env!("ON_FILE_ERROR"));
+    cfg.read_retries = get_env!("READ_RETRIES").and_then(|v| v.parse().ok());
+    cfg.jobs = get_env!("JOBS").and_then(|v| v.parse().ok());
+    cfg.stabilize =
+        get_env!("STABILIZE").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.on_change = get_env!("ON_CHANGE");
+    cfg.vss = get_env!("VSS").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.verify_upload =
+        get_env!("VERIFY_UPLOAD").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.ssh_key = get_env!("SSH_KEY");
+    cfg.ssh_key_passphrase = get_env!("SSH_KEY_PASSPHRASE");
+    cfg.ssh_accept_unknown_hosts = get_env!("SSH_ACCEPT_UNKNOWN_HOSTS")
+        .map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.ftp_active =
+        get_env!("FTP_ACTIVE").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.retries = get_env!("RETRIES").and_then(|v| v.parse().ok());
+    cfg.retry_backoff = get_env!("RETRY_BACKOFF").and_then(|v| v.parse().ok());
+    cfg.retry_max_delay = get_env!("RETRY_MAX_DELAY").and_then(|v| v.parse().ok());
+    cfg.manifest =
+        get_env!("MANIFEST").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.checksum = get_env!("CHECKSUM");
+    cfg.split_size = get_env!("SPLIT_SIZE");
+    cfg.upload_chunk_size = get_env!("UPLOAD_CHUNK_SIZE");
+    cfg.upload_buffer_size = get_env!("UPLOAD_BUFFER_SIZE");
+    cfg.estimate_bandwidth = get_env!("ESTIMATE_BANDWIDTH");
+    cfg.log_level = get_env!("LOG_LEVEL");
+    cfg.log_file = get_env!("LOG_FILE");
+    cfg.log_json =
+        get_env!("LOG_JSON").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.json = get_env!("JSON").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.report = get_env!("REPORT");
+    cfg.quiet = get_env!("QUIET").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.progress =
+        get_env!("PROGRESS").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.incremental =
+        get_env!("INCREMENTAL").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.since_manifest = get_env!("SINCE_MANIFEST");
+    cfg.repo = get_env!("REPO");
+    cfg.chunker = get_env!("CHUNKER");
+    cfg.catalog = get_env!("CATALOG");
+    cfg.seq = get_env!("SEQ").and_then(|v| v.parse().ok());
+    cfg.name_vars = get_env!("NAME_VARS")
+        .map(|v| {
+            split_list(&v)
+                .iter()
+                .map(|var| parse_name_var(var))
+                .collect()
+        })
+        .transpose()
+        .map_err(|e| anyhow::anyhow!("SSBT_NAME_VARS: {e}"))?;
+    cfg.lock = get_env!("LOCK").map(|v| v == "true" || v == "1" || v.eq_ignore_ascii_case("yes"));
+    cfg.wait_for_lock = get_env!("WAIT_FOR_LOCK");
+    cfg.keep_last = get_env!("KEEP_LAST").and_then(|v| v.parse().ok());
+    cfg.keep_daily = get_env!("KEEP_DAILY").and_then(|v| v.parse().ok());
+    cfg.keep_weekly = get_env!("KEEP_WEEKLY").and_then(|v| v.parse().ok());
+    cfg.keep_monthly = get_env!("KEEP_MONTHLY").and_then(|v| v.parse().ok());
+    let recipients = get_env!("ENCRYPT_RECIPIENTS").map(|v| split_list(&v));
+    let passphrase = get_env!("ENCRYPT_PASSPHRASE");
+    let identities = get_env!("ENCRYPT_IDENTITIES").map(|v| split_list(&v));
+    if recipients.is_some() || passphrase.is_some() || identities.is_some() {
+        cfg.encrypt = Some(EncryptConfig {
+            recipients,
+            passphrase,
+            identities,
+        });
+    }
+    cfg.healthcheck_url = get_env!("HEALTHCHECK_URL");
+    cfg.healthcheck_timeout = get_env!("HEALTHCHECK_TIMEOUT");
+    cfg.healthcheck_retries = get_env!("HEALTHCHECK_RETRIES").and_then(|v| v.parse().ok());
+    let webhook = get_env!("NOTIFY_WEBHOOK");
+    let healthcheck = get_env!("NOTIFY_HEALTHCHECK");
+    let slack = get_env!("NOTIFY_SLACK");
+    let discord = get_env!("NOTIFY_DISCORD");
+    if webhook.is_some() || healthcheck.is_some() || slack.is_some() || discord.is_some() {
+        cfg.notify = Some(NotifyConfig {
+            webhook,
+            healthcheck,
+            slack,
+            discord,
+            ..NotifyConfig::default()
+        });
+    }
+    let textfile = get_env!("METRICS_TEXTFILE");
+    let pushgateway = get_env!("METRICS_PUSHGATEWAY");
+    if textfile.is_some() || pushgateway.is_some() {
+        cfg.metrics = Some(MetricsConfig {
+            textfile,
+            pushgateway,
+            ..MetricsConfig::default()
+        });
+    }
+    if let Some(host) = get_env!("DOCKER_HOST") {
+        cfg.docker = Some(DockerConfig {
+            host: Some(host),
+            ..DockerConfig::default()
+        });
+    }
+    if let Some(namespaces) = get_env!("K8S_NAMESPACES") {
+        cfg.k8s = Some(KubernetesConfig {
+            namespaces: Some(split_list(&namespaces)),
+            ..KubernetesConfig::default()
+        });
+    }
+    Ok(cfg)
+}
+
+/// Splits a comma-separated value, ignoring empty items.
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
 }
 
-/// Reads YAML or JSON config from file
-fn read_config_file(path: &str) -> anyhow::Result<Config> {
-    let content = fs::read_to_string(path)?;
-    let lower = path.to_lowercase();
-    let cfg = if lower.ends_with(".json") {
-        serde_json::from_str(&content)?
-    } else {
-        serde_yaml::from_str(&content)?
+/// Merges the selected profile (`--profile`, `SSBT_PROFILE` or the file's
+/// own `profile`) over the rest of the config file. Options set in the
+/// profile replace the shared ones, lists included.
+fn apply_profile(mut file: Config, name: Option<String>) -> anyhow::Result<Config> {
+    let profiles = file.profiles.take().unwrap_or_default();
+    let Some(name) = name.or(file.profile.take()).filter(|n| !n.is_empty()) else {
+        return Ok(file);
     };
-    Ok(cfg)
+    let Some(profile) = profiles.get(&name) else {
+        let available: Vec<&str> = profiles.keys().map(String::as_str).collect();
+        return Err(match available.as_slice() {
+            [] => anyhow!("profile {name} not found, no profiles are configured"),
+            _ => anyhow!(
+                "profile {name} not found (available: {})",
+                available.join(", ")
+            ),
+        });
+    };
+    let mut merged = merge_configs(Config::default(), file, profile.clone());
+    merged.profile = Some(name);
+    merged.profiles = None;
+    Ok(merged)
+}
+
+/// Parses a `Name:Value` header definition
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("invalid header '{s}', expected Name:Value"))?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(format!("invalid header '{s}', header name is empty"));
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// Parses a `name=value` query parameter
+fn parse_query(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid query parameter '{s}', expected name=value"))?;
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(format!("invalid query parameter '{s}', name is empty"));
+    }
+    Ok((name.to_string(), value.trim().to_string()))
+}
+
+/// Parses a `name=value` naming template variable
+fn parse_name_var(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid name variable '{s}', expected name=value"))?;
+    let name = name.trim();
+    if name.is_empty() || name.contains('%') {
+        return Err(format!(
+            "invalid name variable '{s}', the name is empty or has a %"
+        ));
+    }
+    Ok((name.to_string(), value.trim().to_string()))
 }
 
 /// Converts CLI struct into Config
 fn cli_to_config(cli: &Cli) -> Config {
     Config {
-        output: cli.output.clone(),
+        output: if cli.output.is_empty() {
+            None
+        } else {
+            Some(cli.output.clone())
+        },
         config: cli.config.clone(),
+        profile: cli.profile.clone(),
+        profiles: None,
         format: cli.format.clone(),
         authentication: cli.authentication.clone(),
+        authentication_file: cli.authentication_file.clone(),
+        authentication_cmd: cli.authentication_cmd.clone(),
+        auth_type: cli.auth_type.clone(),
+        auth_header: cli.auth_header.clone(),
+        headers: if cli.headers.is_empty() {
+            None
+        } else {
+            Some(cli.headers.iter().cloned().collect::<BTreeMap<_, _>>())
+        },
+        http_query: if cli.http_query.is_empty() {
+            None
+        } else {
+            Some(cli.http_query.iter().cloned().collect::<BTreeMap<_, _>>())
+        },
+        http_method: cli.http_method.clone(),
+        proxy: cli.proxy.clone(),
+        ca_cert: cli.ca_cert.clone(),
+        client_cert: cli.client_cert.clone(),
+        client_key: cli.client_key.clone(),
+        insecure: cli.insecure.then_some(true),
         protocol: cli.protocol.clone(),
         dry: Some(cli.dry),
         max_size: Some(cli.max_size),
+        on_size_exceeded: cli.on_size_exceeded.clone(),
         before: cli.before.clone(),
         after: cli.after.clone(),
+        after_on_failure: cli.after_on_failure.then_some(true),
+        on_success: if cli.on_success.is_empty() {
+            None
+        } else {
+            Some(cli.on_success.clone())
+        },
+        on_failure: if cli.on_failure.is_empty() {
+            None
+        } else {
+            Some(cli.on_failure.clone())
+        },
         paths: if cli.paths.is_empty() {
             None
         } else {
-            Some(cli.paths.clone())
+            Some(cli.paths.iter().cloned().map(SourcePath::from).collect())
         },
         skip: if cli.skip.is_empty() {
             None
         } else {
             Some(cli.skip.clone())
         },
+        include: if cli.include.is_empty() {
+            None
+        } else {
+            Some(cli.include.clone())
+        },
+        exclude_from: (!cli.exclude_from.is_empty()).then(|| cli.exclude_from.clone()),
+        ssbtignore: cli.no_ssbtignore.then_some(false),
+        sort_files: cli.no_sort.then_some(false),
+        flatten: cli.flatten.then_some(true),
+        walk_threads: cli.walk_threads,
+        stream_walk: cli.stream_walk.then_some(true),
+        symlinks: cli.symlinks.clone(),
+        preserve_permissions: cli.no_preserve_permissions.then_some(false),
+        preserve_empty_dirs: cli.no_empty_dirs.then_some(false),
+        skip_larger_than: cli.skip_larger_than.clone(),
+        modified_within: cli.modified_within.clone(),
+        modified_before: cli.modified_before.clone(),
+        dedup: cli.dedup.then_some(true),
+        hard_links: cli.no_hard_links.then_some(false),
         compress: Some(cli.compress),
+        no_compress_extensions: cli.no_compress_extensions.as_deref().map(split_list),
+        compression_algorithm: cli.compression_algorithm.clone(),
+        overwrite: cli.overwrite.clone(),
+        fsync: cli.no_fsync.then_some(false),
+        files_from: cli.files_from.clone(),
+        stdin_name: cli.stdin_name.clone(),
+        zip_password: cli.zip_password.clone(),
+        zip_encryption: cli.zip_encryption.clone(),
+        on_read_error: cli.on_read_error.clone(),
+        read_retries: cli.read_retries,
+        jobs: cli.jobs,
+        stabilize: cli.stabilize.then_some(true),
+        on_change: cli.on_change.clone(),
+        vss: cli.vss.then_some(true),
+        verify_upload: cli.verify_upload.then_some(true),
+        ssh_key: cli.ssh_key.clone(),
+        ssh_key_passphrase: None,
+        ssh_accept_unknown_hosts: cli.ssh_accept_unknown_hosts.then_some(true),
+        ftp_active: cli.ftp_active.then_some(true),
+        retries: cli.retries,
+        retry_backoff: cli.retry_backoff,
+        retry_max_delay: cli.retry_max_delay,
+        manifest: cli.no_manifest.then_some(false),
+        checksum: cli.checksum.clone(),
+        split_size: cli.split_size.clone(),
+        upload_chunk_size: cli.upload_chunk_size.clone(),
+        upload_buffer_size: cli.upload_buffer_size.clone(),
+        estimate_bandwidth: cli.estimate_bandwidth.clone(),
+        log_level: cli.log_level.clone(),
+        log_file: cli.log_file.clone(),
+        log_json: cli.log_json.then_some(true),
+        json: cli.json.then_some(true),
+        report: cli.report.clone(),
+        quiet: cli.quiet.then_some(true),
+        progress: cli.no_progress.then_some(false),
+        incremental: cli.incremental.then_some(true),
+        since_manifest: cli.since_manifest.clone(),
+        repo: cli.repo.clone(),
+        chunker: cli.chunker.clone(),
+        catalog: cli.catalog.clone(),
+        seq: None,
+        name_vars: if cli.name_vars.is_empty() {
+            None
+        } else {
+            Some(cli.name_vars.iter().cloned().collect::<BTreeMap<_, _>>())
+        },
+        lock: cli.no_lock.then_some(false),
+        wait_for_lock: cli.wait_for_lock.clone(),
+        keep_last: cli.keep_last,
+        keep_daily: cli.keep_daily,
+        keep_weekly: cli.keep_weekly,
+        keep_monthly: cli.keep_monthly,
+        healthcheck_url: cli.healthcheck_url.clone(),
+        healthcheck_timeout: cli.healthcheck_timeout.clone(),
+        healthcheck_retries: cli.healthcheck_retries,
+        notify: None,
+        metrics: None,
+        docker: None,
+        sources: None,
+        virtual_files: None,
+        k8s: None,
+        encrypt: (!cli.recipient.is_empty()).then(|| EncryptConfig {
+            recipients: Some(cli.recipient.clone()),
+            ..EncryptConfig::default()
+        }),
     }
 }
 
@@ -230,15 +1285,186 @@ fn merge_configs(env: Config, file: Config, cli: Config) -> Config {
     Config {
         output: pick(env.output, file.output, cli.output),
         config: pick(env.config, file.config, cli.config),
+        profile: pick(env.profile, file.profile, cli.profile),
+        profiles: pick(env.profiles, file.profiles, cli.profiles),
         format: pick(env.format, file.format, cli.format),
         authentication: pick(env.authentication, file.authentication, cli.authentication),
+        authentication_file: pick(
+            env.authentication_file,
+            file.authentication_file,
+            cli.authentication_file,
+        ),
+        authentication_cmd: pick(
+            env.authentication_cmd,
+            file.authentication_cmd,
+            cli.authentication_cmd,
+        ),
+        auth_type: pick(env.auth_type, file.auth_type, cli.auth_type),
+        auth_header: pick(env.auth_header, file.auth_header, cli.auth_header),
+        headers: pick(env.headers, file.headers, cli.headers),
+        http_query: pick(env.http_query, file.http_query, cli.http_query),
+        http_method: pick(env.http_method, file.http_method, cli.http_method),
+        proxy: pick(env.proxy, file.proxy, cli.proxy),
+        ca_cert: pick(env.ca_cert, file.ca_cert, cli.ca_cert),
+        client_cert: pick(env.client_cert, file.client_cert, cli.client_cert),
+        client_key: pick(env.client_key, file.client_key, cli.client_key),
+        insecure: pick(env.insecure, file.insecure, cli.insecure),
         protocol: pick(env.protocol, file.protocol, cli.protocol),
         dry: pick(env.dry, file.dry, cli.dry),
         max_size: pick(env.max_size, file.max_size, cli.max_size),
+        on_size_exceeded: pick(
+            env.on_size_exceeded,
+            file.on_size_exceeded,
+            cli.on_size_exceeded,
+        ),
         before: pick(env.before, file.before, cli.before),
         after: pick(env.after, file.after, cli.after),
+        after_on_failure: pick(
+            env.after_on_failure,
+            file.after_on_failure,
+            cli.after_on_failure,
+        ),
+        on_success: pick(env.on_success, file.on_success, cli.on_success),
+        on_failure: pick(env.on_failure, file.on_failure, cli.on_failure),
         paths: pick(env.paths, file.paths, cli.paths),
         skip: pick(env.skip, file.skip, cli.skip),
+        include: pick(env.include, file.include, cli.include),
+        exclude_from: pick(env.exclude_from, file.exclude_from, cli.exclude_from),
+        ssbtignore: pick(env.ssbtignore, file.ssbtignore, cli.ssbtignore),
+        sort_files: pick(env.sort_files, file.sort_files, cli.sort_files),
+        flatten: pick(env.flatten, file.flatten, cli.flatten),
+        walk_threads: pick(env.walk_threads, file.walk_threads, cli.walk_threads),
+        stream_walk: pick(env.stream_walk, file.stream_walk, cli.stream_walk),
+        symlinks: pick(env.symlinks, file.symlinks, cli.symlinks),
+        preserve_permissions: pick(
+            env.preserve_permissions,
+            file.preserve_permissions,
+            cli.preserve_permissions,
+        ),
+        preserve_empty_dirs: pick(
+            env.preserve_empty_dirs,
+            file.preserve_empty_dirs,
+            cli.preserve_empty_dirs,
+        ),
+        skip_larger_than: pick(
+            env.skip_larger_than,
+            file.skip_larger_than,
+            cli.skip_larger_than,
+        ),
+        modified_within: pick(
+            env.modified_within,
+            file.modified_within,
+            cli.modified_within,
+        ),
+        modified_before: pick(
+            env.modified_before,
+            file.modified_before,
+            cli.modified_before,
+        ),
+        dedup: pick(env.dedup, file.dedup, cli.dedup),
+        hard_links: pick(env.hard_links, file.hard_links, cli.hard_links),
         compress: pick(env.compress, file.compress, cli.compress),
+        no_compress_extensions: pick(
+            env.no_compress_extensions,
+            file.no_compress_extensions,
+            cli.no_compress_extensions,
+        ),
+        compression_algorithm: pick(
+            env.compression_algorithm,
+            file.compression_algorithm,
+            cli.compression_algorithm,
+        ),
+        overwrite: pick(env.overwrite, file.overwrite, cli.overwrite),
+        fsync: pick(env.fsync, file.fsync, cli.fsync),
+        files_from: pick(env.files_from, file.files_from, cli.files_from),
+        stdin_name: pick(env.stdin_name, file.stdin_name, cli.stdin_name),
+        zip_password: pick(env.zip_password, file.zip_password, cli.zip_password),
+        zip_encryption: pick(env.zip_encryption, file.zip_encryption, cli.zip_encryption),
+        on_read_error: pick(env.on_read_error, file.on_read_error, cli.on_read_error),
+        read_retries: pick(env.read_retries, file.read_retries, cli.read_retries),
+        jobs: pick(env.jobs, file.jobs, cli.jobs),
+        stabilize: pick(env.stabilize, file.stabilize, cli.stabilize),
+        on_change: pick(env.on_change, file.on_change, cli.on_change),
+        vss: pick(env.vss, file.vss, cli.vss),
+        verify_upload: pick(env.verify_upload, file.verify_upload, cli.verify_upload),
+        ssh_key: pick(env.ssh_key, file.ssh_key, cli.ssh_key),
+        ssh_key_passphrase: pick(
+            env.ssh_key_passphrase,
+            file.ssh_key_passphrase,
+            cli.ssh_key_passphrase,
+        ),
+        ssh_accept_unknown_hosts: pick(
+            env.ssh_accept_unknown_hosts,
+            file.ssh_accept_unknown_hosts,
+            cli.ssh_accept_unknown_hosts,
+        ),
+        ftp_active: pick(env.ftp_active, file.ftp_active, cli.ftp_active),
+        retries: pick(env.retries, file.retries, cli.retries),
+        retry_backoff: pick(env.retry_backoff, file.retry_backoff, cli.retry_backoff),
+        retry_max_delay: pick(
+            env.retry_max_delay,
+            file.retry_max_delay,
+            cli.retry_max_delay,
+        ),
+        manifest: pick(env.manifest, file.manifest, cli.manifest),
+        checksum: pick(env.checksum, file.checksum, cli.checksum),
+        split_size: pick(env.split_size, file.split_size, cli.split_size),
+        upload_chunk_size: pick(
+            env.upload_chunk_size,
+            file.upload_chunk_size,
+            cli.upload_chunk_size,
+        ),
+        upload_buffer_size: pick(
+            env.upload_buffer_size,
+            file.upload_buffer_size,
+            cli.upload_buffer_size,
+        ),
+        estimate_bandwidth: pick(
+            env.estimate_bandwidth,
+            file.estimate_bandwidth,
+            cli.estimate_bandwidth,
+        ),
+        log_level: pick(env.log_level, file.log_level, cli.log_level),
+        log_file: pick(env.log_file, file.log_file, cli.log_file),
+        log_json: pick(env.log_json, file.log_json, cli.log_json),
+        json: pick(env.json, file.json, cli.json),
+        report: pick(env.report, file.report, cli.report),
+        quiet: pick(env.quiet, file.quiet, cli.quiet),
+        progress: pick(env.progress, file.progress, cli.progress),
+        incremental: pick(env.incremental, file.incremental, cli.incremental),
+        since_manifest: pick(env.since_manifest, file.since_manifest, cli.since_manifest),
+        repo: pick(env.repo, file.repo, cli.repo),
+        chunker: pick(env.chunker, file.chunker, cli.chunker),
+        catalog: pick(env.catalog, file.catalog, cli.catalog),
+        seq: pick(env.seq, file.seq, cli.seq),
+        name_vars: pick(env.name_vars, file.name_vars, cli.name_vars),
+        lock: pick(env.lock, file.lock, cli.lock),
+        wait_for_lock: pick(env.wait_for_lock, file.wait_for_lock, cli.wait_for_lock),
+        keep_last: pick(env.keep_last, file.keep_last, cli.keep_last),
+        keep_daily: pick(env.keep_daily, file.keep_daily, cli.keep_daily),
+        keep_weekly: pick(env.keep_weekly, file.keep_weekly, cli.keep_weekly),
+        keep_monthly: pick(env.keep_monthly, file.keep_monthly, cli.keep_monthly),
+        healthcheck_url: pick(
+            env.healthcheck_url,
+            file.healthcheck_url,
+            cli.healthcheck_url,
+        ),
+        healthcheck_timeout: pick(
+            env.healthcheck_timeout,
+            file.healthcheck_timeout,
+            cli.healthcheck_timeout,
+        ),
+        healthcheck_retries: pick(
+            env.healthcheck_retries,
+            file.healthcheck_retries,
+            cli.healthcheck_retries,
+        ),
+        notify: pick(env.notify, file.notify, cli.notify),
+        metrics: pick(env.metrics, file.metrics, cli.metrics),
+        docker: pick(env.docker, file.docker, cli.docker),
+        sources: pick(env.sources, file.sources, cli.sources),
+        virtual_files: pick(env.virtual_files, file.virtual_files, cli.virtual_files),
+        k8s: pick(env.k8s, file.k8s, cli.k8s),
+        encrypt: pick(env.encrypt, file.encrypt, cli.encrypt),
     }
 }
@@ -0,0 +1,222 @@
+//! Prometheus metrics about each run, written for the node_exporter textfile
+//! collector and/or pushed to a Pushgateway, configured in the `metrics`
+//! section. The time of the last success and the failure count carry over
+//! from the metrics written by the previous run.
+
+use anyhow::{Context, Result, bail};
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::Config;
+use crate::fs_utils::write_atomic;
+use crate::notify::RunSummary;
+use crate::process::BackupOutcome;
+use crate::sink::send_net::blocking_http_client;
+use ssbt_lib::MetricsConfig;
+
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+pub struct Metrics {
+    config: MetricsConfig,
+    client: Option<reqwest::blocking::Client>,
+}
+
+/// What the previous run left behind.
+#[derive(Debug, Default)]
+struct Previous {
+    last_success: Option<f64>,
+    failures: f64,
+}
+
+impl Metrics {
+    /// Returns `None` when no metrics are configured.
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let Some(metrics) = config.metrics.clone() else {
+            return Ok(None);
+        };
+        if metrics.textfile.is_none() && metrics.pushgateway.is_none() {
+            return Ok(None);
+        }
+        let client = match metrics.pushgateway {
+            Some(_) => Some(blocking_http_client(config, TIMEOUT)?),
+            None => None,
+        };
+        Ok(Some(Metrics {
+            config: metrics,
+            client,
+        }))
+    }
+
+    /// Writes and pushes the metrics of a finished run. Failures are
+    /// warnings, they don't change the outcome of the backup.
+    pub fn record(&self, summary: &RunSummary, outcome: Option<&BackupOutcome>) {
+        let job = self.config.job.as_deref().unwrap_or(&summary.job);
+        if let Some(path) = &self.config.textfile {
+            let written = self.write_textfile(Path::new(path), job, summary, outcome);
+            warn_on_error("textfile", written);
+        }
+        if let (Some(url), Some(client)) = (&self.config.pushgateway, &self.client) {
+            warn_on_error("Pushgateway", push(client, url, job, summary, outcome));
+        }
+    }
+
+    fn write_textfile(
+        &self,
+        path: &Path,
+        job: &str,
+        summary: &RunSummary,
+        outcome: Option<&BackupOutcome>,
+    ) -> Result<()> {
+        let previous = match fs::read_to_string(path) {
+            Ok(text) => parse_previous(&text, job),
+            Err(_) => Previous::default(),
+        };
+        let labels = format!("{{job=\"{}\"}}", escape_label(job));
+        let text = exposition(&labels, &previous, summary, outcome);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        // The collector must never see a half-written file
+        write_atomic(path, text.as_bytes()).with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+/// Replaces the metrics of `job` on the Pushgateway at `url`.
+fn push(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    job: &str,
+    summary: &RunSummary,
+    outcome: Option<&BackupOutcome>,
+) -> Result<()> {
+    let base = url.trim_end_matches('/');
+    let previous = match client.get(format!("{base}/metrics")).send() {
+        Ok(response) if response.status().is_success() => parse_previous(&response.text()?, job),
+        _ => Previous::default(),
+    };
+
+    let mut push_url = reqwest::Url::parse(base).with_context(|| format!("invalid URL {url}"))?;
+    push_url
+        .path_segments_mut()
+        .map_err(|()| anyhow::anyhow!("invalid URL {url}"))?
+        .pop_if_empty()
+        .extend(["metrics", "job", job]);
+    // The Pushgateway adds the `job` label of the URL itself
+    let text = exposition("", &previous, summary, outcome);
+    let response = client.put(push_url).body(text).send()?;
+    if !response.status().is_success() {
+        bail!("{url} returned status {}", response.status());
+    }
+    Ok(())
+}
+
+/// The metrics in the Prometheus text format, each with `labels`.
+fn exposition(
+    labels: &str,
+    previous: &Previous,
+    summary: &RunSummary,
+    outcome: Option<&BackupOutcome>,
+) -> String {
+    let finished = summary.started_at.timestamp_millis() as f64 / 1000.0 + summary.duration_secs;
+    let last_success = match summary.success {
+        true => Some(finished),
+        false => previous.last_success,
+    };
+    let failures = previous.failures + if summary.success { 0.0 } else { 1.0 };
+
+    let mut metrics = vec![
+        (
+            "ssbt_last_run_timestamp_seconds",
+            "gauge",
+            "When the last backup run finished.",
+            Some(finished),
+        ),
+        (
+            "ssbt_last_run_success",
+            "gauge",
+            "Whether the last backup run succeeded (1) or failed (0).",
+            Some(if summary.success { 1.0 } else { 0.0 }),
+        ),
+        (
+            "ssbt_last_run_duration_seconds",
+            "gauge",
+            "How long the last backup run took.",
+            Some(summary.duration_secs),
+        ),
+        (
+            "ssbt_last_run_files",
+            "gauge",
+            "Files backed up by the last run.",
+            Some(summary.files as f64),
+        ),
+        (
+            "ssbt_last_run_size_bytes",
+            "gauge",
+            "Total size of the files backed up by the last run.",
+            Some(summary.size as f64),
+        ),
+        (
+            "ssbt_last_success_timestamp_seconds",
+            "gauge",
+            "When the last successful backup run finished.",
+            last_success,
+        ),
+        (
+            "ssbt_failures_total",
+            "counter",
+            "Failed backup runs.",
+            Some(failures),
+        ),
+    ];
+    if let Some(size) = outcome.map(|o| o.archive_size).filter(|size| *size > 0) {
+        metrics.push((
+            "ssbt_last_archive_size_bytes",
+            "gauge",
+            "Size of the archive written by the last successful run.",
+            Some(size as f64),
+        ));
+    }
+
+    let mut text = String::new();
+    for (name, kind, help, value) in metrics {
+        let Some(value) = value else {
+            continue;
+        };
+        let _ = writeln!(text, "# HELP {name} {help}");
+        let _ = writeln!(text, "# TYPE {name} {kind}");
+        let _ = writeln!(text, "{name}{labels} {value}");
+    }
+    text
+}
+
+/// The carried over metrics of `job` in a text format exposition.
+fn parse_previous(text: &str, job: &str) -> Previous {
+    let job_label = format!("job=\"{}\"", escape_label(job));
+    let value = |name: &str| {
+        text.lines()
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.strip_prefix(name))
+            .filter(|rest| rest.starts_with('{') && rest.contains(&job_label))
+            .find_map(|rest| rest.rsplit(' ').next()?.parse::<f64>().ok())
+    };
+    Previous {
+        last_success: value("ssbt_last_success_timestamp_seconds"),
+        failures: value("ssbt_failures_total").unwrap_or(0.0),
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn warn_on_error(what: &str, result: Result<()>) {
+    if let Err(e) = result {
+        warn!("{what} metrics failed: {e:#}");
+    }
+}
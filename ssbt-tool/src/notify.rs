@@ -0,0 +1,396 @@
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Utc};
+use lettre::{
+    Message, SmtpTransport, Transport, message::Mailbox,
+    transport::smtp::authentication::Credentials,
+};
+use serde::Serialize;
+use serde_json::json;
+use std::{str::FromStr, time::Duration};
+
+use crate::Config;
+use crate::fs_utils::{encode_size, parse_duration};
+use crate::json_output::RunResult;
+use crate::process::BackupOutcome;
+use crate::secrets::resolve_secret;
+use crate::sink::retry::RetryPolicy;
+use crate::sink::send_net::blocking_http_client;
+use ssbt_lib::{EmailConfig, NotifyConfig};
+use tracing::warn;
+
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Discord rejects messages longer than this.
+const DISCORD_LIMIT: usize = 2000;
+
+/// Which outcomes trigger webhook, chat and email notifications.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NotifyOn {
+    #[default]
+    Always,
+    Failure,
+    Success,
+}
+
+impl FromStr for NotifyOn {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(NotifyOn::Always),
+            "failure" => Ok(NotifyOn::Failure),
+            "success" => Ok(NotifyOn::Success),
+            other => Err(anyhow!(
+                "unsupported notify.on: {other} (expected always, failure or success)"
+            )),
+        }
+    }
+}
+
+/// Outcome of a single run, sent as JSON to webhooks.
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    pub job: String,
+    pub success: bool,
+    pub started_at: DateTime<Utc>,
+    pub duration_secs: f64,
+    pub files: usize,
+    pub size: u64,
+    pub output: Option<String>,
+    pub error: Option<String>,
+}
+
+impl RunSummary {
+    pub fn new(job: String, output: Option<String>) -> Self {
+        RunSummary {
+            job,
+            success: false,
+            started_at: Utc::now(),
+            duration_secs: 0.0,
+            files: 0,
+            size: 0,
+            output,
+            error: None,
+        }
+    }
+
+    fn title(&self) -> String {
+        format!(
+            "[ssbt] {} {}",
+            self.job,
+            if self.success { "succeeded" } else { "FAILED" }
+        )
+    }
+
+    fn text(&self, outcome: Option<&BackupOutcome>) -> String {
+        let mut text = format!(
+            "Job: {}\nStatus: {}\nStarted: {}\nDuration: {:.1}s\nFiles: {}\nSize: {}\n",
+            self.job,
+            if self.success { "success" } else { "FAILED" },
+            self.started_at.format("%Y-%m-%d %H:%M:%S UTC"),
+            self.duration_secs,
+            self.files,
+            encode_size(self.size),
+        );
+        if let Some(output) = &self.output {
+            text.push_str(&format!("Output: {output}\n"));
+        }
+        if let Some(outcome) = outcome.filter(|o| o.archive_size > 0) {
+            text.push_str(&format!(
+                "Archive size: {}\n",
+                encode_size(outcome.archive_size)
+            ));
+            for archive in &outcome.archives {
+                text.push_str(&format!("Stored at: {archive}\n"));
+            }
+        }
+        if let Some(error) = &self.error {
+            text.push_str(&format!("Error: {error}\n"));
+        }
+        text
+    }
+}
+
+/// Sends run notifications configured in the `notify` section, and pings
+/// the healthcheck.
+///
+/// Notification failures are reported as warnings and never change the
+/// outcome of the backup itself.
+pub struct Notifier {
+    config: NotifyConfig,
+    on: NotifyOn,
+    healthcheck: Option<Healthcheck>,
+    client: reqwest::blocking::Client,
+}
+
+/// A healthchecks.io style monitor, pinged at `<url>/start`, `<url>` and
+/// `<url>/fail`.
+struct Healthcheck {
+    url: String,
+    timeout: Duration,
+    retry: RetryPolicy,
+}
+
+impl Notifier {
+    /// Returns `None` when nothing is configured.
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let url = config
+            .healthcheck_url
+            .clone()
+            .or_else(|| config.notify.as_ref()?.healthcheck.clone())
+            .filter(|url| !url.is_empty());
+        if config.notify.is_none() && url.is_none() {
+            return Ok(None);
+        }
+        let notify = config.notify.clone().unwrap_or_default();
+        let on = match notify.on.as_deref() {
+            Some(on) => on.parse()?,
+            None => NotifyOn::default(),
+        };
+        let healthcheck = match url {
+            Some(url) => Some(Healthcheck {
+                url,
+                timeout: match config.healthcheck_timeout.as_deref() {
+                    Some(timeout) => {
+                        parse_duration(timeout).context("invalid healthcheck_timeout")?
+                    }
+                    None => Duration::from_secs(10),
+                },
+                retry: RetryPolicy {
+                    retries: config.healthcheck_retries.unwrap_or(2),
+                    backoff: Duration::from_secs(1),
+                    max_delay: Duration::from_secs(10),
+                },
+            }),
+            None => None,
+        };
+        let client = blocking_http_client(config, TIMEOUT)?;
+        Ok(Some(Notifier {
+            config: notify,
+            on,
+            healthcheck,
+            client,
+        }))
+    }
+
+    pub fn job_name(&self) -> String {
+        self.config
+            .job_name
+            .clone()
+            .unwrap_or_else(|| "ssbt".to_string())
+    }
+
+    /// Tells the healthcheck a run has started, so it can also alert on runs that hang.
+    pub fn start(&self) {
+        if let Some(healthcheck) = &self.healthcheck {
+            let ping = self.ping(healthcheck, Some("/start"), None);
+            warn_on_error("healthcheck start ping", ping);
+        }
+    }
+
+    pub fn finish(&self, summary: &RunSummary, outcome: Option<&BackupOutcome>) {
+        if let Some(healthcheck) = &self.healthcheck {
+            let ping = match &summary.error {
+                None => self.ping(healthcheck, None, None),
+                Some(error) => self.ping(healthcheck, Some("/fail"), Some(error)),
+            };
+            warn_on_error("healthcheck ping", ping);
+        }
+
+        let wanted = match self.on {
+            NotifyOn::Always => true,
+            NotifyOn::Failure => !summary.success,
+            NotifyOn::Success => summary.success,
+        };
+        if !wanted {
+            return;
+        }
+
+        if let Some(url) = &self.config.webhook {
+            warn_on_error("webhook", self.webhook(url, summary, outcome));
+        }
+        if let Some(url) = &self.config.slack {
+            let text = format!("*{}*\n```{}```", summary.title(), summary.text(outcome));
+            warn_on_error("Slack", self.post_json(url, &json!({ "text": text })));
+        }
+        if let Some(url) = &self.config.discord {
+            let mut content = format!("**{}**\n```{}```", summary.title(), summary.text(outcome));
+            if content.chars().count() > DISCORD_LIMIT {
+                content = content.chars().take(DISCORD_LIMIT - 4).collect::<String>() + "…```";
+            }
+            let message = json!({ "content": content });
+            warn_on_error("Discord", self.post_json(url, &message));
+        }
+        if let Some(email) = &self.config.email {
+            warn_on_error("email", send_email(email, summary, outcome));
+        }
+    }
+
+    /// GETs the healthcheck URL with `suffix`, or POSTs `body` to it, retrying
+    /// failed pings: a monitor that is down for a moment shouldn't report a
+    /// missed run.
+    fn ping(
+        &self,
+        healthcheck: &Healthcheck,
+        suffix: Option<&str>,
+        body: Option<&str>,
+    ) -> Result<()> {
+        let url = match suffix {
+            Some(suffix) => format!("{}{suffix}", healthcheck.url.trim_end_matches('/')),
+            None => healthcheck.url.clone(),
+        };
+        let ping = || -> Result<()> {
+            let request = match body {
+                Some(body) => self.client.post(&url).body(body.to_string()),
+                None => self.client.get(&url),
+            };
+            let response = request.timeout(healthcheck.timeout).send()?;
+            if !response.status().is_success() {
+                bail!("{url} returned status {}", response.status());
+            }
+            Ok(())
+        };
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match ping() {
+                Ok(()) => return Ok(()),
+                Err(e) => match healthcheck
+                    .retry
+                    .next_delay("healthcheck ping", attempt, &e)
+                {
+                    Some(delay) => std::thread::sleep(delay),
+                    None => return Err(e),
+                },
+            }
+        }
+    }
+
+    /// POSTs the run result as JSON, or the filled in `webhook_template`.
+    fn webhook(
+        &self,
+        url: &str,
+        summary: &RunSummary,
+        outcome: Option<&BackupOutcome>,
+    ) -> Result<()> {
+        let body = match &self.config.webhook_template {
+            Some(template) => {
+                let body = fill_template(template, summary, outcome);
+                serde_json::from_str::<serde_json::Value>(&body)
+                    .context("webhook_template isn't valid JSON once filled in")?;
+                body.into_bytes()
+            }
+            None => serde_json::to_vec(&RunResult::new(None, summary, outcome))?,
+        };
+        self.post(url, body)
+    }
+
+    fn post_json(&self, url: &str, message: &serde_json::Value) -> Result<()> {
+        self.post(url, serde_json::to_vec(message)?)
+    }
+
+    fn post(&self, url: &str, body: Vec<u8>) -> Result<()> {
+        let response = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()?;
+        if !response.status().is_success() {
+            bail!("{url} returned status {}", response.status());
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the `%name%` placeholders of `template` with the values of the
+/// run, escaped to fit inside JSON strings.
+fn fill_template(template: &str, summary: &RunSummary, outcome: Option<&BackupOutcome>) -> String {
+    let values = [
+        ("%job%", summary.job.clone()),
+        (
+            "%status%",
+            (if summary.success {
+                "success"
+            } else {
+                "failure"
+            })
+            .to_string(),
+        ),
+        ("%success%", summary.success.to_string()),
+        ("%started_at%", summary.started_at.to_rfc3339()),
+        ("%duration%", format!("{:.1}", summary.duration_secs)),
+        ("%files%", summary.files.to_string()),
+        ("%size%", summary.size.to_string()),
+        (
+            "%archive_size%",
+            outcome.map_or(0, |o| o.archive_size).to_string(),
+        ),
+        (
+            "%archives%",
+            outcome.map(|o| o.archives.join(", ")).unwrap_or_default(),
+        ),
+        ("%output%", summary.output.clone().unwrap_or_default()),
+        ("%error%", summary.error.clone().unwrap_or_default()),
+        ("%message%", summary.text(outcome)),
+    ];
+    let mut filled = template.to_string();
+    for (placeholder, value) in values {
+        let escaped = serde_json::to_string(&value).unwrap_or_default();
+        filled = filled.replace(placeholder, &escaped[1..escaped.len() - 1]);
+    }
+    filled
+}
+
+fn send_email(
+    email: &EmailConfig,
+    summary: &RunSummary,
+    outcome: Option<&BackupOutcome>,
+) -> Result<()> {
+    let host = email
+        .smtp_host
+        .as_deref()
+        .context("notify.email.smtp_host is required")?;
+    let from: Mailbox = email
+        .from
+        .as_deref()
+        .context("notify.email.from is required")?
+        .parse()?;
+    let recipients = email.to.as_deref().unwrap_or_default();
+    if recipients.is_empty() {
+        bail!("notify.email.to needs at least one recipient");
+    }
+
+    let mut message = Message::builder().from(from).subject(summary.title());
+    for to in recipients {
+        message = message.to(to.parse()?);
+    }
+    let message = message.body(summary.text(outcome))?;
+
+    let mut transport = match email.smtp_security.as_deref().unwrap_or("starttls") {
+        "starttls" => SmtpTransport::starttls_relay(host)?,
+        "tls" => SmtpTransport::relay(host)?,
+        "none" => SmtpTransport::builder_dangerous(host),
+        other => bail!("unsupported smtp_security: {other} (expected starttls, tls or none)"),
+    }
+    .timeout(Some(TIMEOUT));
+    if let Some(port) = email.smtp_port {
+        transport = transport.port(port);
+    }
+    if let Some(user) = &email.smtp_user {
+        let password = match email.smtp_password.as_deref() {
+            Some(password) => resolve_secret(password)?,
+            None => String::new(),
+        };
+        transport = transport.credentials(Credentials::new(user.clone(), password));
+    }
+
+    transport.build().send(&message)?;
+    Ok(())
+}
+
+fn warn_on_error(what: &str, result: Result<()>) {
+    if let Err(e) = result {
+        warn!("{what} notification failed: {e:#}");
+    }
+}
@@ -1,2 +0,0 @@
-pub mod tar;
-pub mod zip;
@@ -0,0 +1,102 @@
+//! `--report`: a JSON document describing a finished run, successful or not,
+//! written to a file for backup dashboards to pick up.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use ssbt_lib::Totals;
+use std::fs;
+use std::path::Path;
+
+use crate::fs_utils::write_atomic;
+use crate::notify::RunSummary;
+use crate::process::BackupOutcome;
+
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    /// Catalog id of the run, if it was recorded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<&'a str>,
+    #[serde(flatten)]
+    pub summary: &'a RunSummary,
+    pub finished_at: DateTime<Utc>,
+    /// The steps that ran, in order.
+    pub phases: Vec<Phase>,
+    /// Bytes of the files backed up; absent for failed runs.
+    pub bytes_read: Option<u64>,
+    /// Bytes of the archive; absent for failed runs and repository backups.
+    pub bytes_written: Option<u64>,
+    /// `bytes_written / bytes_read`.
+    pub compression_ratio: Option<f64>,
+    /// Where the archive was stored, as paths or URLs.
+    pub destinations: &'a [String],
+    /// Files that were found but aren't in the backup.
+    pub skipped: Vec<Skipped>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Phase {
+    pub name: &'static str,
+    pub duration_secs: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Skipped {
+    pub path: String,
+    pub reason: String,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(
+        id: Option<&'a str>,
+        summary: &'a RunSummary,
+        totals: &Totals,
+        outcome: Option<&'a BackupOutcome>,
+    ) -> Self {
+        let bytes_read = outcome.map(|o| o.bytes_read);
+        let bytes_written = outcome.map(|o| o.archive_size).filter(|size| *size > 0);
+        let compression_ratio = match (bytes_written, bytes_read) {
+            (Some(written), Some(read)) if read > 0 => Some(written as f64 / read as f64),
+            _ => None,
+        };
+        let left_out = totals.left_out.iter().map(|path| Skipped {
+            path: path.to_string_lossy().into_owned(),
+            reason: "left out to stay within max_size".to_string(),
+        });
+        let unreadable = outcome
+            .into_iter()
+            .flat_map(|o| &o.skipped)
+            .map(|(path, reason)| Skipped {
+                path: path.to_string_lossy().into_owned(),
+                reason: reason.clone(),
+            });
+        Report {
+            id,
+            summary,
+            finished_at: Utc::now(),
+            phases: totals
+                .phases
+                .iter()
+                .map(|(name, duration)| Phase {
+                    name,
+                    duration_secs: duration.as_secs_f64(),
+                })
+                .collect(),
+            bytes_read,
+            bytes_written,
+            compression_ratio,
+            destinations: outcome.map_or(&[], |o| o.archives.as_slice()),
+            skipped: left_out.chain(unreadable).collect(),
+        }
+    }
+
+    /// Replaces `path` with the report.
+    pub fn write(&self, path: &str) -> Result<()> {
+        let path = Path::new(path);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        write_atomic(path, &serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("writing the report {}", path.display()))
+    }
+}